@@ -1,7 +1,10 @@
 //! A generic Tokio TCP client implementation.
 
+use io::Readiness;
 use tcp::TcpStream;
-use reactor::{self, ReactorHandle, NewTask};
+use reactor::{self, ReactorHandle, NewTask, Task, Tick};
+use util::future::{self, Complete, Val};
+use std::io;
 use std::net::SocketAddr;
 
 /// Connect to the given `addr` then handle the `TcpStream` on the task created
@@ -52,19 +55,87 @@ use std::net::SocketAddr;
 pub fn connect<T>(reactor: &ReactorHandle, addr: SocketAddr, new_task: T)
         where T: NewTask
 {
+    // Nobody is waiting on the connection, failures are only logged
+    drop(try_connect(reactor, addr, new_task));
+}
+
+/// Same as `connect`, but returns a future that completes once the
+/// connection is established and the task created by `new_task` is
+/// scheduled.
+///
+/// The task is only created once the socket is connected. Failing to
+/// connect, or to create the task, is reported as the future's error.
+pub fn try_connect<T>(reactor: &ReactorHandle, addr: SocketAddr, new_task: T)
+        -> Val<(), io::Error>
+        where T: NewTask
+{
+    let (c, val) = future::pair();
+
     reactor.oneshot(move || {
         // Create a new Tokio TcpStream from the Mio socket
         let socket = match TcpStream::connect(&addr) {
             Ok(s) => s,
-            Err(_) => unimplemented!(),
+            Err(e) => {
+                debug!("failed to connect; err={:?}", e);
+                c.error(e);
+                return Ok(());
+            }
         };
 
-        let task = match new_task.new_task(socket) {
-            Ok(d) => d,
-            Err(_) => unimplemented!(),
-        };
+        try!(reactor::schedule(Connecting {
+            socket: Some(socket),
+            new_task: new_task,
+            connected: Some(c),
+        }));
 
-        try!(reactor::schedule(task));
         Ok(())
     });
+
+    val
+}
+
+// Waits for the socket to connect before creating the task
+struct Connecting<T> {
+    socket: Option<TcpStream>,
+    new_task: T,
+    connected: Option<Complete<(), io::Error>>,
+}
+
+impl<T: NewTask> Connecting<T> {
+    fn start(&mut self) -> io::Result<()> {
+        let socket = self.socket.take().unwrap();
+
+        // Fails with `NotConnected` if the connection was refused
+        try!(socket.peer_addr());
+
+        let task = try!(self.new_task.new_task(socket));
+        try!(reactor::schedule(task));
+
+        Ok(())
+    }
+}
+
+impl<T: NewTask> Task for Connecting<T> {
+    fn tick(&mut self) -> io::Result<Tick> {
+        // The socket becomes writable once the connection is established or
+        // has failed
+        if !self.socket.as_ref().unwrap().is_writable() {
+            return Ok(Tick::WouldBlock);
+        }
+
+        let c = self.connected.take().unwrap();
+
+        match self.start() {
+            Ok(()) => {
+                trace!("connected");
+                c.complete(());
+            }
+            Err(e) => {
+                debug!("failed to connect; err={:?}", e);
+                c.error(e);
+            }
+        }
+
+        Ok(Tick::Final)
+    }
 }
@@ -109,6 +109,19 @@ pub use self::ready::{Readiness, Ready};
 pub use self::transport::Transport;
 use std::io;
 
+/// Shuts down the write half of a source, signaling the end of stream to the
+/// peer.
+///
+/// Transports call this once they have been closed and flushed. Sources
+/// without a write half to shutdown, such as in memory buffers, may rely on
+/// the default, which does nothing.
+pub trait Shutdown {
+    /// Shutdown the write half of the source
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 /// A refinement of `std::io::Read` for reading from non-blocking sources.
 ///
 /// When reading from a non-blocking source, receiving a
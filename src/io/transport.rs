@@ -24,6 +24,25 @@ pub trait Transport: Readiness {
     /// Write a message frame to the `Transport`
     fn write(&mut self, req: Self::In) -> io::Result<Option<()>>;
 
+    /// Write a batch of message frames to the `Transport`
+    ///
+    /// Transports that are able to coalesce the frames into fewer writes to
+    /// the socket should override this. By default, each frame is written
+    /// with `Transport::write`.
+    fn write_batch(&mut self, reqs: Vec<Self::In>) -> io::Result<Option<()>> {
+        if reqs.is_empty() {
+            return self.flush();
+        }
+
+        let mut flush = None;
+
+        for req in reqs {
+            flush = try!(self.write(req));
+        }
+
+        Ok(flush)
+    }
+
     /// Flush pending writes to the socket
     ///
     /// Since the backing source is non-blocking, there is no guarantee that a
@@ -33,4 +52,28 @@ pub trait Transport: Readiness {
     /// to write any remaining data in the write buffer to the underlying
     /// source.
     fn flush(&mut self) -> io::Result<Option<()>>;
+
+    /// Close the transport
+    ///
+    /// Called once the transport will not be used to write any more frames,
+    /// allowing protocols that require a close handshake to write their
+    /// closing frame. Returns the same as `Transport::flush`, the remaining
+    /// data is written by calling `Transport::flush`.
+    ///
+    /// By default, the transport is flushed.
+    fn close(&mut self) -> io::Result<Option<()>> {
+        self.flush()
+    }
+
+    /// Shutdown the write half of the underlying source
+    ///
+    /// Called by the dispatcher once the closed transport has been flushed,
+    /// right before it is dropped, so that the peer sees a clean end of
+    /// stream after the last frame. Transports backed by a `TcpStream`
+    /// usually call `TcpStream::shutdown` with `Shutdown::Write`.
+    ///
+    /// By default, nothing is done.
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
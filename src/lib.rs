@@ -28,6 +28,7 @@
 #![deny(warnings, missing_docs)]
 
 extern crate mio;
+extern crate net2;
 extern crate slab;
 extern crate futures;
 extern crate take;
@@ -44,6 +45,7 @@ pub mod proto;
 pub mod reactor;
 pub mod server;
 pub mod tcp;
+pub mod udp;
 pub mod util;
 
 mod service;
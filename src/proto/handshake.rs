@@ -0,0 +1,347 @@
+use io::{Readiness, Transport, TryRead, TryWrite};
+use std::io;
+
+/// Negotiates the protocol version with the peer before handing the
+/// connection to the protocol's transport.
+///
+/// Both peers write the list of versions they support, as a length byte
+/// followed by one byte per version, and read the list of the other peer.
+/// Each peer then picks the highest version supported by both, so no further
+/// round trip is needed, and creates the protocol's transport by calling
+/// `new_transport` with the connection and the agreed on version.
+///
+/// Until the handshake completes, the transport is not writable and reads
+/// return `Ok(None)`. If the peers do not support a common version, reading
+/// fails with `InvalidData`.
+///
+/// ```rust,ignore
+/// let framing = LengthDelimited::new(Header::U32Be);
+///
+/// pipeline::serve(&reactor.handle(), addr, service, move |stream| {
+///     Ok(VersionHandshake::new(stream, &[1, 2], move |stream, _version| framing.transport(stream)))
+/// });
+/// ```
+pub struct VersionHandshake<T, F, U> {
+    // Taken once the handshake completes
+    io: Option<T>,
+    new_transport: Option<F>,
+    transport: Option<U>,
+    versions: Vec<u8>,
+    version: Option<u8>,
+    // Handshake bytes that have not been written yet
+    wr: Vec<u8>,
+    // Number of versions supported by the peer, once read
+    peer_len: Option<usize>,
+    peer_versions: Vec<u8>,
+}
+
+impl<T, F, U> VersionHandshake<T, F, U>
+    where T: TryRead + TryWrite + Readiness,
+          F: FnOnce(T, u8) -> U,
+          U: Transport,
+{
+    /// Create a new `VersionHandshake` supporting `versions`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `versions` is empty or holds more than 255
+    /// versions.
+    pub fn new(io: T, versions: &[u8], new_transport: F) -> VersionHandshake<T, F, U> {
+        assert!(!versions.is_empty(), "at least one version must be supported");
+        assert!(versions.len() <= u8::max_value() as usize, "too many versions");
+
+        let mut wr = vec![versions.len() as u8];
+        wr.extend_from_slice(versions);
+
+        VersionHandshake {
+            io: Some(io),
+            new_transport: Some(new_transport),
+            transport: None,
+            versions: versions.to_vec(),
+            version: None,
+            wr: wr,
+            peer_len: None,
+            peer_versions: vec![],
+        }
+    }
+
+    /// Returns the agreed on version, once the handshake has completed
+    pub fn version(&self) -> Option<u8> {
+        self.version
+    }
+
+    /// Returns a reference to the protocol's transport, once the handshake
+    /// has completed
+    pub fn get_ref(&self) -> Option<&U> {
+        self.transport.as_ref()
+    }
+
+    /// Returns a mutable reference to the protocol's transport, once the
+    /// handshake has completed
+    pub fn get_mut(&mut self) -> Option<&mut U> {
+        self.transport.as_mut()
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        {
+            let io = match self.io {
+                Some(ref mut io) => io,
+                None => return Ok(()),
+            };
+
+            while !self.wr.is_empty() {
+                match try!(io.try_write(&self.wr)) {
+                    Some(n) => {
+                        self.wr.drain(..n);
+                    }
+                    None => break,
+                }
+            }
+
+            // Only the handshake bytes are read, anything following them
+            // belongs to the protocol's transport
+            let mut buf = [0; 256];
+
+            loop {
+                let need = match self.peer_len {
+                    Some(len) => len - self.peer_versions.len(),
+                    None => 1,
+                };
+
+                if need == 0 {
+                    break;
+                }
+
+                match try!(io.try_read(&mut buf[..need])) {
+                    Some(0) => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during the version handshake"));
+                    }
+                    Some(n) => {
+                        if self.peer_len.is_none() {
+                            self.peer_len = Some(buf[0] as usize);
+                        } else {
+                            self.peer_versions.extend_from_slice(&buf[..n]);
+                        }
+                    }
+                    None => return Ok(()),
+                }
+            }
+
+            if !self.wr.is_empty() {
+                return Ok(());
+            }
+        }
+
+        let version = match self.versions.iter().filter(|v| self.peer_versions.contains(*v)).max() {
+            Some(&version) => version,
+            None => {
+                debug!("no common protocol version; versions={:?}; peer={:?}", self.versions, self.peer_versions);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "no common protocol version"));
+            }
+        };
+
+        trace!("negotiated protocol version; version={}", version);
+
+        let io = self.io.take().unwrap();
+        let new_transport = self.new_transport.take().unwrap();
+
+        self.version = Some(version);
+        self.transport = Some(new_transport(io, version));
+
+        Ok(())
+    }
+}
+
+impl<T, F, U> Readiness for VersionHandshake<T, F, U>
+    where T: Readiness,
+          U: Readiness,
+{
+    fn is_readable(&self) -> bool {
+        match self.transport {
+            Some(ref transport) => transport.is_readable(),
+            None => self.io.as_ref().map_or(false, |io| io.is_readable()),
+        }
+    }
+
+    fn is_writable(&self) -> bool {
+        match self.transport {
+            Some(ref transport) => transport.is_writable(),
+            None => false,
+        }
+    }
+}
+
+impl<T, F, U> Transport for VersionHandshake<T, F, U>
+    where T: TryRead + TryWrite + Readiness,
+          F: FnOnce(T, u8) -> U,
+          U: Transport,
+{
+    type In = U::In;
+    type Out = U::Out;
+
+    fn read(&mut self) -> io::Result<Option<U::Out>> {
+        try!(self.handshake());
+
+        match self.transport {
+            Some(ref mut transport) => transport.read(),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, frame: U::In) -> io::Result<Option<()>> {
+        match self.transport {
+            Some(ref mut transport) => transport.write(frame),
+            None => Err(io::Error::new(io::ErrorKind::Other, "version handshake in progress")),
+        }
+    }
+
+    fn write_batch(&mut self, frames: Vec<U::In>) -> io::Result<Option<()>> {
+        if self.transport.is_none() && frames.is_empty() {
+            return self.flush();
+        }
+
+        match self.transport {
+            Some(ref mut transport) => transport.write_batch(frames),
+            None => Err(io::Error::new(io::ErrorKind::Other, "version handshake in progress")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        try!(self.handshake());
+
+        match self.transport {
+            Some(ref mut transport) => transport.flush(),
+            None => Ok(None),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<Option<()>> {
+        match self.transport {
+            Some(ref mut transport) => transport.close(),
+            None => Ok(Some(())),
+        }
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        match self.transport {
+            Some(ref mut transport) => transport.shutdown(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::{Readiness, Shutdown, Transport};
+    use proto::{Header, LengthDelimited, LengthDelimitedTransport};
+    use proto::pipeline::Frame;
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    type Buf = Arc<Mutex<Vec<u8>>>;
+
+    // One end of an in memory connection
+    struct End {
+        rd: Buf,
+        wr: Buf,
+    }
+
+    fn connection() -> (End, End) {
+        let a: Buf = Arc::new(Mutex::new(vec![]));
+        let b: Buf = Arc::new(Mutex::new(vec![]));
+
+        (End { rd: a.clone(), wr: b.clone() }, End { rd: b, wr: a })
+    }
+
+    impl Read for End {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut rd = self.rd.lock().unwrap();
+
+            if rd.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+            }
+
+            let n = if buf.len() < rd.len() { buf.len() } else { rd.len() };
+            buf[..n].copy_from_slice(&rd[..n]);
+            rd.drain(..n);
+
+            Ok(n)
+        }
+    }
+
+    impl Write for End {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.wr.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shutdown for End {}
+
+    impl Readiness for End {
+        fn is_readable(&self) -> bool {
+            !self.rd.lock().unwrap().is_empty()
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    fn framed(io: End, _: u8) -> LengthDelimitedTransport<End> {
+        LengthDelimited::new(Header::U16Be).transport(io)
+    }
+
+    #[test]
+    fn test_negotiates_highest_shared_version() {
+        let (a, b) = connection();
+
+        let mut client = VersionHandshake::new(a, &[1, 2, 3], framed);
+        let mut server = VersionHandshake::new(b, &[2, 3, 4], framed);
+
+        assert!(client.read().unwrap().is_none());
+        assert!(!client.is_writable());
+
+        // The server has everything it needs after a single read
+        assert!(server.read().unwrap().is_none());
+        assert_eq!(Some(3), server.version());
+
+        // Data written right after the handshake is not consumed by it
+        server.write(Frame::Message(b"hello".to_vec())).unwrap();
+
+        match client.read().unwrap() {
+            Some(Frame::Message(payload)) => assert_eq!(b"hello".to_vec(), payload),
+            _ => panic!("expected message frame"),
+        }
+
+        assert_eq!(Some(3), client.version());
+        assert!(client.is_writable());
+    }
+
+    #[test]
+    fn test_fails_without_shared_version() {
+        let (a, b) = connection();
+
+        let mut client = VersionHandshake::new(a, &[1], framed);
+        let mut server = VersionHandshake::new(b, &[2, 3], framed);
+
+        assert!(client.read().unwrap().is_none());
+
+        match server.read() {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("expected the handshake to fail"),
+        }
+
+        match client.read() {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("expected the handshake to fail"),
+        }
+
+        assert_eq!(None, client.version());
+    }
+}
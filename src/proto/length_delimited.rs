@@ -0,0 +1,395 @@
+use io::{Readiness, Shutdown, Transport, TryRead, TryWrite};
+use proto::pipeline::Frame;
+use std::io;
+
+// Default max length of a frame's payload
+const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// The encoding of the length header preceding every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Header {
+    /// 2 byte big endian length
+    U16Be,
+    /// 2 byte little endian length
+    U16Le,
+    /// 4 byte big endian length
+    U32Be,
+    /// 4 byte little endian length
+    U32Le,
+}
+
+/// Builds transports for protocols where each frame is a length header
+/// followed by that many bytes of payload.
+///
+/// The transports read and write `Frame::Message` values holding the
+/// payload, so they can be used directly with the pipeline dispatchers.
+///
+/// ```rust,ignore
+/// let framing = LengthDelimited::new(Header::U32Be).max_frame_len(64 * 1024);
+///
+/// pipeline::serve(&reactor.handle(), addr, service, move |stream| Ok(framing.transport(stream)));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct LengthDelimited {
+    header: Header,
+    max_frame_len: usize,
+}
+
+/// A length delimited `Transport`.
+///
+/// Every read from the underlying I/O fills an internal buffer with as many
+/// bytes as are available, and frames are decoded from the buffer before the
+/// I/O is read from again. Several small frames arriving together are thus
+/// returned by successive calls to `read` with a single read from the I/O,
+/// and a frame split across reads is kept in the buffer until it is
+/// complete.
+///
+/// Created by `LengthDelimited::transport`.
+pub struct LengthDelimitedTransport<T> {
+    io: T,
+    header: Header,
+    max_frame_len: usize,
+    rd: Vec<u8>,
+    wr: Vec<u8>,
+}
+
+impl Header {
+    // Number of bytes of the header
+    fn len(&self) -> usize {
+        match *self {
+            Header::U16Be | Header::U16Le => 2,
+            Header::U32Be | Header::U32Le => 4,
+        }
+    }
+
+    // Max payload length the header can encode
+    fn max_len(&self) -> usize {
+        match *self {
+            Header::U16Be | Header::U16Le => u16::max_value() as usize,
+            Header::U32Be | Header::U32Le => u32::max_value() as usize,
+        }
+    }
+
+    fn decode(&self, buf: &[u8]) -> usize {
+        let bytes = &buf[..self.len()];
+
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| (n << 8) | b as usize);
+
+        match *self {
+            Header::U16Be | Header::U32Be => be(bytes),
+            Header::U16Le | Header::U32Le => {
+                let reversed: Vec<u8> = bytes.iter().rev().cloned().collect();
+                be(&reversed)
+            }
+        }
+    }
+
+    fn encode(&self, len: usize, dst: &mut Vec<u8>) {
+        let n = self.len();
+        let be = (0..n).map(|i| (len >> (8 * (n - i - 1))) as u8);
+
+        match *self {
+            Header::U16Be | Header::U32Be => dst.extend(be),
+            Header::U16Le | Header::U32Le => {
+                let mut bytes: Vec<u8> = be.collect();
+                bytes.reverse();
+                dst.extend(bytes);
+            }
+        }
+    }
+}
+
+impl LengthDelimited {
+    /// Create a new `LengthDelimited` using the given header encoding.
+    pub fn new(header: Header) -> LengthDelimited {
+        LengthDelimited {
+            header: header,
+            max_frame_len: MAX_FRAME_LEN,
+        }
+    }
+
+    /// Set the max length of a frame's payload. Defaults to 8MB.
+    ///
+    /// Reading a longer frame fails with `InvalidData`, and writing one fails
+    /// with `InvalidInput`. The limit is also capped by the largest length
+    /// the header can encode.
+    pub fn max_frame_len(mut self, val: usize) -> Self {
+        self.max_frame_len = val;
+        self
+    }
+
+    /// Create a transport framing `io`, usually a `TcpStream`.
+    ///
+    /// The write half of `io` is shutdown by `Transport::shutdown`, once the
+    /// transport has been closed and flushed.
+    pub fn transport<T>(&self, io: T) -> LengthDelimitedTransport<T>
+        where T: io::Read + io::Write + Readiness,
+    {
+        LengthDelimitedTransport {
+            io: io,
+            header: self.header,
+            max_frame_len: self.max_frame_len,
+            rd: vec![],
+            wr: vec![],
+        }
+    }
+}
+
+impl<T> LengthDelimitedTransport<T> {
+    /// Returns a reference to the underlying I/O
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Returns a mutable reference to the underlying I/O
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    fn max_frame_len(&self) -> usize {
+        if self.max_frame_len < self.header.max_len() {
+            self.max_frame_len
+        } else {
+            self.header.max_len()
+        }
+    }
+
+    // Returns the next frame's payload if it has been fully read
+    fn decode(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let header_len = self.header.len();
+
+        if self.rd.len() < header_len {
+            return Ok(None);
+        }
+
+        let len = self.header.decode(&self.rd);
+
+        if len > self.max_frame_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+        }
+
+        if self.rd.len() < header_len + len {
+            return Ok(None);
+        }
+
+        let payload = self.rd[header_len..header_len + len].to_vec();
+        self.rd.drain(..header_len + len);
+
+        Ok(Some(payload))
+    }
+
+    fn has_frame(&self) -> bool {
+        let header_len = self.header.len();
+
+        self.rd.len() >= header_len &&
+            self.rd.len() >= header_len + self.header.decode(&self.rd)
+    }
+}
+
+impl<T: Readiness> Readiness for LengthDelimitedTransport<T> {
+    fn is_readable(&self) -> bool {
+        self.has_frame() || self.io.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.wr.is_empty() || self.io.is_writable()
+    }
+}
+
+impl<T> Transport for LengthDelimitedTransport<T>
+    where T: io::Read + io::Write + Readiness + Shutdown,
+{
+    type In = Frame<Vec<u8>, io::Error>;
+    type Out = Frame<Vec<u8>, io::Error>;
+
+    fn read(&mut self) -> io::Result<Option<Frame<Vec<u8>, io::Error>>> {
+        let mut buf = [0; 4096];
+
+        loop {
+            if let Some(payload) = try!(self.decode()) {
+                return Ok(Some(Frame::Message(payload)));
+            }
+
+            match try!(self.io.try_read(&mut buf)) {
+                Some(0) => {
+                    if self.rd.is_empty() {
+                        return Ok(Some(Frame::Eof));
+                    }
+
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a frame"));
+                }
+                Some(n) => self.rd.extend_from_slice(&buf[..n]),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn write(&mut self, frame: Frame<Vec<u8>, io::Error>) -> io::Result<Option<()>> {
+        match frame {
+            Frame::Message(payload) => {
+                if payload.len() > self.max_frame_len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+                }
+
+                self.header.encode(payload.len(), &mut self.wr);
+                self.wr.extend_from_slice(&payload);
+            }
+            Frame::Error(e) => {
+                // Length delimited frames have no way of carrying an error
+                return Err(e);
+            }
+            Frame::Done | Frame::Eof => {}
+        }
+
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        while !self.wr.is_empty() {
+            match try!(self.io.try_write(&self.wr)) {
+                Some(n) => {
+                    self.wr.drain(..n);
+                }
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(()))
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.io.shutdown_write()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::{Readiness, Shutdown, Transport};
+    use proto::pipeline::Frame;
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+
+    // Returns one chunk per read. `None` chunks, and running out of chunks,
+    // would block.
+    struct Chunks {
+        rd: VecDeque<Option<Vec<u8>>>,
+        wr: Vec<u8>,
+        reads: usize,
+    }
+
+    impl Chunks {
+        fn new(chunks: Vec<Option<&[u8]>>) -> Chunks {
+            Chunks {
+                rd: chunks.into_iter().map(|c| c.map(|c| c.to_vec())).collect(),
+                wr: vec![],
+                reads: 0,
+            }
+        }
+    }
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+
+            match self.rd.pop_front() {
+                Some(Some(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                _ => Err(io::Error::new(io::ErrorKind::WouldBlock, "would block")),
+            }
+        }
+    }
+
+    impl Write for Chunks {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.wr.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shutdown for Chunks {}
+
+    impl Readiness for Chunks {
+        fn is_readable(&self) -> bool {
+            !self.rd.is_empty()
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    fn payload(frame: Option<Frame<Vec<u8>, io::Error>>) -> Vec<u8> {
+        match frame {
+            Some(Frame::Message(payload)) => payload,
+            _ => panic!("expected message frame"),
+        }
+    }
+
+    #[test]
+    fn test_reads_frames_split_across_reads() {
+        // Both the header and the payload are split across reads, followed by
+        // a zero length frame and the end of stream
+        let io = Chunks::new(vec![Some(&[0][..]), None,
+                                  Some(&[3, b'a'][..]), None,
+                                  Some(&[b'b', b'c', 0][..]), None,
+                                  Some(&[0][..]), Some(&[][..])]);
+
+        let mut transport = LengthDelimited::new(Header::U16Be).transport(io);
+
+        assert!(transport.read().unwrap().is_none());
+        assert!(transport.read().unwrap().is_none());
+        assert_eq!(b"abc".to_vec(), payload(transport.read().unwrap()));
+
+        // Only the first byte of the zero length frame's header has arrived
+        assert!(transport.read().unwrap().is_none());
+        assert_eq!(Vec::<u8>::new(), payload(transport.read().unwrap()));
+
+        match transport.read().unwrap() {
+            Some(Frame::Eof) => {}
+            _ => panic!("expected EOF"),
+        }
+    }
+
+    #[test]
+    fn test_decodes_buffered_frames_without_reading() {
+        // Two frames and the start of a third arrive in a single read
+        let io = Chunks::new(vec![Some(&[0, 1, b'a', 0, 2, b'b', b'c', 0][..]),
+                                  Some(&[1, b'd'][..])]);
+
+        let mut transport = LengthDelimited::new(Header::U16Be).transport(io);
+
+        assert_eq!(b"a".to_vec(), payload(transport.read().unwrap()));
+        assert_eq!(b"bc".to_vec(), payload(transport.read().unwrap()));
+        assert_eq!(1, transport.get_ref().reads);
+
+        // The partial frame is completed by the next read
+        assert_eq!(b"d".to_vec(), payload(transport.read().unwrap()));
+        assert_eq!(2, transport.get_ref().reads);
+    }
+
+    #[test]
+    fn test_rejects_oversized_frames() {
+        let io = Chunks::new(vec![Some(&[5, 0, 0, 0, b'a'][..])]);
+        let mut transport = LengthDelimited::new(Header::U32Le).max_frame_len(4).transport(io);
+
+        match transport.read() {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("expected oversized frame to fail"),
+        }
+
+        match transport.write(Frame::Message(vec![0; 5])) {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidInput, e.kind()),
+            Ok(_) => panic!("expected oversized frame to fail"),
+        }
+
+        transport.write(Frame::Message(b"abcd".to_vec())).unwrap();
+        assert_eq!(&[4, 0, 0, 0, b'a', b'b', b'c', b'd'], &transport.get_ref().wr[..]);
+    }
+}
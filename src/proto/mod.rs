@@ -1,3 +1,10 @@
 //! A collection of components for rapid protocol development
 
+pub mod multiplex;
 pub mod pipeline;
+
+mod handshake;
+mod length_delimited;
+
+pub use self::handshake::VersionHandshake;
+pub use self::length_delimited::{Header, LengthDelimited, LengthDelimitedTransport};
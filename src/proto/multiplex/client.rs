@@ -0,0 +1,1204 @@
+use {client, Service};
+use super::{Frame, RequestId, RequestIds, Transport, NewTransport};
+use reactor::{ReactorHandle, Task, Tick};
+use util::channel::{Receiver};
+use util::future::{self, Complete, Val};
+use util::timer::{Timer, Timeout};
+use futures::{self, Future, Poll, TaskHandle};
+use futures::stream::Stream;
+use mio::{self, channel};
+use std::{error, fmt, io, mem};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvError;
+
+/// Client `Service` for the multiplex protocol.
+///
+/// Initiated requests are sent to the client multiplex task running on the
+/// Reactor where they are tagged with a `RequestId` and written to the
+/// transport. The response is returned by completing the future. An error
+/// frame fails only the request carrying its `RequestId`; error frames for
+/// unknown requests are logged and dropped.
+///
+/// Requests are only written to the transport, and assigned a `RequestId`,
+/// once the transport is writable. At most 128 requests, or the capacity
+/// given to `connect_with_capacity`, are queued for the client task. Further
+/// calls wait for capacity on the handle, and are only handed to the client
+/// task as it catches up with the writes.
+///
+/// Dropping the returned future cancels interest in the response. A request
+/// that has not been written yet is discarded without being assigned an id.
+/// Otherwise, the response is discarded once it arrives, and only then is its
+/// `RequestId` made available again, so a late response is never delivered
+/// to a later request.
+///
+/// A `Frame::Rejected` frame fails the request carrying its `RequestId` with
+/// a `RequestRejected` error. Rejections for requests that have already been
+/// answered are dropped.
+///
+/// Requests made with `call_timeout` fail with `TimedOut` if the response
+/// does not arrive in time. The `RequestId` of a timed out request stays
+/// reserved for a grace period as long as its timeout, so that a late
+/// response arriving meanwhile is discarded. The id is made available again
+/// once the late response arrives or the grace period ends, whichever comes
+/// first, so a server that never responds does not exhaust the ids.
+///
+/// Requests made with `notify` are written as `Frame::OneWay` frames, which
+/// the server processes without writing a response.
+pub struct ClientHandle<T, U, E> {
+    // `None` once the handle has been closed
+    tx: Option<channel::Sender<Message<T, U, E>>>,
+    pending: Arc<AtomicUsize>,
+    allocated: Arc<AtomicUsize>,
+    // Cleared once the client task has shutdown
+    alive: Arc<AtomicBool>,
+    ready: Arc<Mutex<ReadyState>>,
+    capacity: Arc<Mutex<CapacityState<T, U, E>>>,
+}
+
+/// The error wrapped by the `io::Error` a request fails with when the server
+/// rejects it with a `Frame::Rejected` frame.
+///
+/// A rejected request was not processed by the server, so it is safe to
+/// retry. Check for it with `err.get_ref().map_or(false, |e| e.is::<RequestRejected>())`.
+#[derive(Debug)]
+pub struct RequestRejected;
+
+/// A `Stream` of the responses to the requests made with
+/// `ClientHandle::call_stream`, in the order they complete.
+///
+/// Every response is tagged with the index of its request. A failed request
+/// is yielded as an error tagged with its index, the following responses are
+/// still yielded. Dropping the stream does not cancel the requests.
+pub struct Responses<U, E> {
+    inner: Arc<Mutex<ResponsesInner<U, E>>>,
+}
+
+struct ResponsesInner<U, E> {
+    ready: VecDeque<(usize, Result<U, E>)>,
+    // Requests whose response has not been yielded yet
+    remaining: usize,
+    // Set once the stream of requests has ended
+    exhausted: bool,
+    task: Option<TaskHandle>,
+}
+
+// Makes the requests of `call_stream` as the stream yields them
+struct Dispatch<S, T, U, E>
+    where S: Stream<Item = T, Error = E>,
+{
+    reqs: S,
+    handle: ClientHandle<T, U, E>,
+    inner: Arc<Mutex<ResponsesInner<U, E>>>,
+    // Index of the next request
+    next: usize,
+}
+
+/// How the multiplex client writes requests to the transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Write every request as soon as it is received
+    Immediate,
+    /// Collect requests into batches written with a single call to
+    /// `Transport::write_batch`, allowing the transport to coalesce them.
+    ///
+    /// A batch is written once it holds `max_batch` requests, or once its
+    /// first request has waited for `max_delay`.
+    Coalesce {
+        /// Max number of requests in a batch
+        max_batch: usize,
+        /// Max time a request waits for the batch to fill up
+        max_delay: Duration,
+    },
+}
+
+// Messages sent from a `ClientHandle` to the client task
+enum Message<T, U, E> {
+    Request(T, Complete<U, E>, Option<Duration>),
+    Notify(T, Complete<(), E>),
+    Close(Complete<(), E>),
+}
+
+struct Client<T, E>
+    where T: Transport<RequestId = RequestId>,
+          E: From<io::Error> + Send + 'static,
+{
+    run: bool,
+    transport: T,
+    requests: Receiver<Message<T::In, T::Out, E>>,
+    in_flight: HashMap<RequestId, Complete<T::Out, E>>,
+    // Requests whose futures were dropped, or that timed out, before the
+    // response arrived
+    cancelled: HashSet<RequestId>,
+    // Fires once requests time out, and once the grace period of timed out
+    // requests ends. Created with the first request made with a timeout.
+    timer: Option<Timer<Expiry>>,
+    timeouts: HashMap<RequestId, Timeout>,
+    ids: RequestIds,
+    pending: Arc<AtomicUsize>,
+    allocated: Arc<AtomicUsize>,
+    flush_policy: FlushPolicy,
+    // Requests waiting to be written as a batch
+    batch: Vec<Frame<T::In, T::Error>>,
+    // One-way requests in the current batch, and the ones written but not
+    // flushed yet
+    batch_notified: Vec<Complete<(), E>>,
+    notified: Vec<Complete<(), E>>,
+    // Fires once the current batch has waited for `max_delay`
+    batch_timer: Option<Timer<()>>,
+    batch_timeout: Option<Timeout>,
+    // Set once the batch has waited for `max_delay`, until it is written
+    batch_expired: bool,
+    // Timeouts of the requests in the current batch, armed once the batch is
+    // written
+    batch_timeouts: Vec<(RequestId, Duration)>,
+    // Waiting on the connection to close
+    closing: Vec<Complete<(), E>>,
+    ready: Ready,
+    capacity: Capacity<T::In, T::Out, E>,
+    _alive: Alive,
+}
+
+enum Expiry {
+    // The response did not arrive within the timeout
+    Response(RequestId, Duration),
+    // The late response did not arrive within the grace period
+    Release(RequestId),
+}
+
+// Marks the connection as shutdown when dropped
+struct Alive(Arc<AtomicBool>);
+
+// Tracks whether the transport has been established, failing the waiters if
+// the connection is dropped before then
+struct Ready(Arc<Mutex<ReadyState>>);
+
+struct ReadyState {
+    status: ReadyStatus,
+    waiters: Vec<Complete<(), io::Error>>,
+}
+
+// Fails the requests waiting for capacity, and the ones made afterwards, once
+// the client task is dropped
+struct Capacity<T, U, E>(Arc<Mutex<CapacityState<T, U, E>>>)
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static;
+
+struct CapacityState<T, U, E> {
+    // Requests that can still be sent to the client task
+    available: usize,
+    // Requests waiting for capacity. The client task picks them up once it
+    // has received every request sent to it.
+    waiting: VecDeque<Message<T, U, E>>,
+    closed: bool,
+}
+
+#[derive(Clone)]
+enum ReadyStatus {
+    Pending,
+    Ready,
+    Failed(io::ErrorKind, String),
+}
+
+// Requests queued for the client task before calls wait for capacity
+const DEFAULT_CAPACITY: usize = 128;
+
+/// Connect to the given `addr` and handle using the given Transport and protocol multiplexing.
+pub fn connect<T>(reactor: &ReactorHandle, addr: SocketAddr, new_transport: T)
+        -> ClientHandle<T::In, T::Out, T::Error>
+        where T: NewTransport<RequestId = RequestId>,
+              T::Error: From<io::Error>,
+{
+    connect_with_flush_policy(reactor, addr, new_transport, FlushPolicy::Immediate)
+}
+
+/// Same as `connect`, but queues at most `capacity` requests for the client
+/// task before calls wait for capacity.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn connect_with_capacity<T>(reactor: &ReactorHandle,
+                                addr: SocketAddr,
+                                new_transport: T,
+                                capacity: usize)
+        -> ClientHandle<T::In, T::Out, T::Error>
+        where T: NewTransport<RequestId = RequestId>,
+              T::Error: From<io::Error>,
+{
+    bind(reactor, addr, new_transport, FlushPolicy::Immediate, capacity).0
+}
+
+/// Same as `connect`, but writes requests to the transport according to
+/// `flush_policy`.
+///
+/// # Panics
+///
+/// Panics if `flush_policy` coalesces batches of zero requests.
+pub fn connect_with_flush_policy<T>(reactor: &ReactorHandle,
+                                    addr: SocketAddr,
+                                    new_transport: T,
+                                    flush_policy: FlushPolicy)
+        -> ClientHandle<T::In, T::Out, T::Error>
+        where T: NewTransport<RequestId = RequestId>,
+              T::Error: From<io::Error>,
+{
+    bind(reactor, addr, new_transport, flush_policy, DEFAULT_CAPACITY).0
+}
+
+/// Same as `connect`, but returns a future that resolves to the handle once
+/// the connection is established and the transport is created.
+///
+/// Failing to connect, or to create the transport, is reported as the
+/// future's error.
+pub fn try_connect<T>(reactor: &ReactorHandle, addr: SocketAddr, new_transport: T)
+        -> Box<Future<Item = ClientHandle<T::In, T::Out, T::Error>, Error = io::Error>>
+        where T: NewTransport<RequestId = RequestId>,
+              T::Error: From<io::Error>,
+{
+    let (handle, connected) = bind(reactor, addr, new_transport, FlushPolicy::Immediate, DEFAULT_CAPACITY);
+    connected.map(move |()| handle).boxed()
+}
+
+fn bind<T>(reactor: &ReactorHandle,
+           addr: SocketAddr,
+           new_transport: T,
+           flush_policy: FlushPolicy,
+           capacity: usize)
+        -> (ClientHandle<T::In, T::Out, T::Error>, Val<(), io::Error>)
+        where T: NewTransport<RequestId = RequestId>,
+              T::Error: From<io::Error>,
+{
+    use take::Take;
+
+    if let FlushPolicy::Coalesce { max_batch, .. } = flush_policy {
+        assert!(max_batch > 0, "max batch must be greater than 0");
+    }
+
+    assert!(capacity > 0, "capacity must be greater than 0");
+
+    let (tx, rx) = channel::channel();
+    let pending = Arc::new(AtomicUsize::new(0));
+    let client_pending = pending.clone();
+    let allocated = Arc::new(AtomicUsize::new(0));
+    let client_allocated = allocated.clone();
+
+    let alive = Arc::new(AtomicBool::new(true));
+    let client_alive = Alive(alive.clone());
+
+    let ready = Arc::new(Mutex::new(ReadyState {
+        status: ReadyStatus::Pending,
+        waiters: vec![],
+    }));
+    let client_ready = Ready(ready.clone());
+
+    let capacity = Arc::new(Mutex::new(CapacityState {
+        available: capacity,
+        waiting: VecDeque::new(),
+        closed: false,
+    }));
+    let client_capacity = Capacity(capacity.clone());
+
+    let connected = client::try_connect(reactor, addr, Take::new(move |socket| {
+        // Let Tokio watch all the sources for events
+        let rx = try!(Receiver::watch(rx));
+
+        // Create the transport
+        let transport = try!(new_transport.new_transport(socket));
+
+        Ok(Client {
+            run: true,
+            transport: transport,
+            requests: rx,
+            in_flight: HashMap::with_capacity(16),
+            cancelled: HashSet::new(),
+            timer: None,
+            timeouts: HashMap::new(),
+            ids: RequestIds::new(),
+            pending: client_pending,
+            allocated: client_allocated,
+            flush_policy: flush_policy,
+            batch: vec![],
+            batch_notified: vec![],
+            notified: vec![],
+            batch_timer: None,
+            batch_timeout: None,
+            batch_expired: false,
+            batch_timeouts: vec![],
+            closing: vec![],
+            ready: client_ready,
+            capacity: client_capacity,
+            _alive: client_alive,
+        })
+    }));
+
+    let handle = ClientHandle {
+        tx: Some(tx),
+        pending: pending,
+        allocated: allocated,
+        alive: alive,
+        ready: ready,
+        capacity: capacity,
+    };
+
+    (handle, connected)
+}
+
+impl<T, U, E> ClientHandle<T, U, E> {
+    /// Returns the number of requests that have been written to the
+    /// transport, or are waiting in a batch to be written, and are waiting
+    /// for a response.
+    pub fn pending_requests(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of `RequestId`s that are allocated, including the
+    /// ids of cancelled and timed out requests that are still reserved for
+    /// their late response.
+    pub fn allocated_ids(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of calls waiting for capacity before their request
+    /// can be queued for the client task.
+    pub fn waiting_requests(&self) -> usize {
+        self.capacity.lock().unwrap().waiting.iter()
+            .filter(|msg| !msg.is_cancelled())
+            .count()
+    }
+
+    /// Returns true if the handle has been closed or the connection has
+    /// shutdown, in which case calls fail with `BrokenPipe`.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_none() || !self.alive.load(Ordering::Relaxed)
+    }
+
+    /// Returns a future that completes once the connection is established
+    /// and the transport is writable, for example once the protocol's
+    /// handshake has completed.
+    ///
+    /// Calls made before then wait for the transport, so awaiting `ready`
+    /// only avoids racing the handshake. The future fails if the connection
+    /// fails or closes before becoming ready.
+    pub fn ready(&self) -> Val<(), io::Error> {
+        let (c, val) = future::pair();
+
+        let status = {
+            let mut state = self.ready.lock().unwrap();
+
+            if state.status.is_pending() {
+                state.waiters.push(c);
+                return val;
+            }
+
+            state.status.clone()
+        };
+
+        status.complete(c);
+        val
+    }
+}
+
+impl<T, U, E> ClientHandle<T, U, E>
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    /// Close this handle.
+    ///
+    /// Requests made through a closed handle fail immediately with
+    /// `BrokenPipe`. Other clones of the handle are not affected; the
+    /// connection is only closed once every handle has been closed or
+    /// dropped.
+    ///
+    /// The returned future completes once the connection has been closed,
+    /// after all outstanding requests have completed.
+    pub fn close(&mut self) -> Val<(), E> {
+        let (c, val) = future::pair();
+
+        let tx = match self.tx.take() {
+            Some(tx) => tx,
+            None => {
+                c.error(E::from(io::Error::new(io::ErrorKind::BrokenPipe, "client handle already closed")));
+                return val;
+            }
+        };
+
+        if tx.send(Message::Close(c)).is_ok() {
+            return val;
+        }
+
+        // The client task has already shutdown
+        let (c, val) = future::pair();
+        c.complete(());
+        val
+    }
+
+    /// Make every request yielded by `reqs`, returning a stream of the
+    /// responses in the order they complete, tagged with the index of their
+    /// request.
+    ///
+    /// Requests are made as soon as `reqs` yields them, and are pipelined on
+    /// the connection like separate calls. If `reqs` fails, no more requests
+    /// are made and the error is yielded tagged with the index the next
+    /// request would have had.
+    pub fn call_stream<S>(&self, reqs: S) -> Responses<U, E>
+        where S: Stream<Item = T, Error = E>,
+    {
+        let inner = Arc::new(Mutex::new(ResponsesInner {
+            ready: VecDeque::new(),
+            remaining: 0,
+            exhausted: false,
+            task: None,
+        }));
+
+        Dispatch {
+            reqs: reqs,
+            handle: self.clone(),
+            inner: inner.clone(),
+            next: 0,
+        }.forget();
+
+        Responses { inner: inner }
+    }
+
+    /// Same as `call`, but fails with `TimedOut` if the response does not
+    /// arrive within `timeout` of the request being written.
+    pub fn call_timeout(&self, request: T, timeout: Duration) -> Val<U, E> {
+        self.request(request, Some(timeout))
+    }
+
+    /// Send a one-way request, for which the server writes no response.
+    ///
+    /// The request is written as a `Frame::OneWay` frame and is not assigned
+    /// a `RequestId`. The returned future completes once the request has
+    /// been flushed to the transport, which says nothing about the server
+    /// having processed it.
+    pub fn notify(&self, request: T) -> Val<(), E> {
+        self.send(|c| Message::Notify(request, c))
+    }
+
+    fn request(&self, request: T, timeout: Option<Duration>) -> Val<U, E> {
+        self.send(|c| Message::Request(request, c, timeout))
+    }
+
+    fn send<V, F>(&self, message: F) -> Val<V, E>
+        where V: Send + 'static,
+              F: FnOnce(Complete<V, E>) -> Message<T, U, E>,
+    {
+        let (c, val) = future::pair();
+
+        let tx = match self.tx {
+            Some(ref tx) => tx,
+            None => {
+                c.error(E::from(io::Error::new(io::ErrorKind::BrokenPipe, "client handle closed")));
+                return val;
+            }
+        };
+
+        {
+            let mut state = self.capacity.lock().unwrap();
+
+            if state.closed {
+                drop(state);
+                c.error(E::from(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")));
+                return val;
+            }
+
+            if state.available == 0 || !state.waiting.is_empty() {
+                // Wait for the client task to catch up
+                state.waiting.push_back(message(c));
+                return val;
+            }
+
+            state.available -= 1;
+        }
+
+        if tx.send(message(c)).is_err() {
+            // The client task has shutdown
+            let (c, val) = future::pair();
+            c.error(E::from(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")));
+            return val;
+        }
+
+        val
+    }
+}
+
+impl<T, U, E> Service for ClientHandle<T, U, E>
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    type Req = T;
+    type Resp = U;
+    type Error = E;
+    type Fut = Val<U, E>;
+
+    fn call(&self, request: T) -> Val<U, E> {
+        self.request(request, None)
+    }
+}
+
+impl<T, U, E> Clone for ClientHandle<T, U, E>
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: Send + 'static,
+{
+    fn clone(&self) -> ClientHandle<T, U, E> {
+        ClientHandle {
+            tx: self.tx.clone(),
+            pending: self.pending.clone(),
+            allocated: self.allocated.clone(),
+            alive: self.alive.clone(),
+            ready: self.ready.clone(),
+            capacity: self.capacity.clone(),
+        }
+    }
+}
+
+impl<U, E> Stream for Responses<U, E>
+    where U: Send + 'static,
+          E: Send + 'static,
+{
+    type Item = (usize, U);
+    type Error = (usize, E);
+
+    fn poll(&mut self, _: &mut futures::Task) -> Poll<Option<(usize, U)>, (usize, E)> {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.ready.pop_front() {
+            Some((i, res)) => {
+                inner.remaining -= 1;
+
+                match res {
+                    Ok(resp) => Poll::Ok(Some((i, resp))),
+                    Err(e) => Poll::Err((i, e)),
+                }
+            }
+            None if inner.remaining == 0 && inner.exhausted => Poll::Ok(None),
+            None => Poll::NotReady,
+        }
+    }
+
+    fn schedule(&mut self, task: &mut futures::Task) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.ready.is_empty() && (inner.remaining > 0 || !inner.exhausted) {
+            inner.task = Some(task.handle().clone());
+        } else {
+            task.handle().notify();
+        }
+    }
+}
+
+impl<U, E> ResponsesInner<U, E> {
+    // Queue a response, returning the task to notify
+    fn push(&mut self, i: usize, res: Result<U, E>) -> Option<TaskHandle> {
+        self.ready.push_back((i, res));
+        self.task.take()
+    }
+}
+
+impl<S, T, U, E> Dispatch<S, T, U, E>
+    where S: Stream<Item = T, Error = E>,
+          T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    // No more requests will be made, yielding the error of `reqs`, if any
+    fn finish(&mut self, err: Option<E>) {
+        let task = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.exhausted = true;
+
+            match err {
+                Some(e) => {
+                    inner.remaining += 1;
+                    inner.push(self.next, Err(e))
+                }
+                None => inner.task.take(),
+            }
+        };
+
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+}
+
+impl<S, T, U, E> Future for Dispatch<S, T, U, E>
+    where S: Stream<Item = T, Error = E>,
+          T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self, task: &mut futures::Task) -> Poll<(), ()> {
+        loop {
+            match self.reqs.poll(task) {
+                Poll::Ok(Some(req)) => {
+                    let i = self.next;
+                    self.next += 1;
+
+                    self.inner.lock().unwrap().remaining += 1;
+
+                    let inner = self.inner.clone();
+
+                    self.handle.request(req, None).then(move |res| {
+                        let task = inner.lock().unwrap().push(i, res);
+
+                        if let Some(task) = task {
+                            task.notify();
+                        }
+
+                        Ok::<(), ()>(())
+                    }).forget();
+                }
+                Poll::Ok(None) => {
+                    self.finish(None);
+                    return Poll::Ok(());
+                }
+                Poll::Err(e) => {
+                    self.finish(Some(e));
+                    return Poll::Ok(());
+                }
+                Poll::NotReady => return Poll::NotReady,
+            }
+        }
+    }
+
+    fn schedule(&mut self, task: &mut futures::Task) {
+        self.reqs.schedule(task)
+    }
+}
+
+impl fmt::Display for RequestRejected {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("request rejected")
+    }
+}
+
+impl error::Error for RequestRejected {
+    fn description(&self) -> &str {
+        "request rejected"
+    }
+}
+
+impl Ready {
+    fn set(&self) {
+        self.resolve(ReadyStatus::Ready);
+    }
+
+    fn fail(&self, err: &io::Error) {
+        self.resolve(ReadyStatus::Failed(err.kind(), err.to_string()));
+    }
+
+    fn resolve(&self, status: ReadyStatus) {
+        let waiters = {
+            let mut state = self.0.lock().unwrap();
+
+            if !state.status.is_pending() {
+                return;
+            }
+
+            state.status = status.clone();
+            mem::replace(&mut state.waiters, vec![])
+        };
+
+        // The waiters are completed outside of the lock
+        for c in waiters {
+            status.clone().complete(c);
+        }
+    }
+}
+
+impl ReadyStatus {
+    fn is_pending(&self) -> bool {
+        match *self {
+            ReadyStatus::Pending => true,
+            _ => false,
+        }
+    }
+
+    fn complete(self, c: Complete<(), io::Error>) {
+        match self {
+            ReadyStatus::Failed(kind, msg) => c.error(io::Error::new(kind, msg)),
+            _ => c.complete(()),
+        }
+    }
+}
+
+impl Drop for Ready {
+    fn drop(&mut self) {
+        let err = io::Error::new(io::ErrorKind::BrokenPipe, "connection closed before it was ready");
+        self.fail(&err);
+    }
+}
+
+impl<T, U, E> Message<T, U, E>
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: Send + 'static,
+{
+    fn is_cancelled(&self) -> bool {
+        match *self {
+            Message::Request(_, ref c, _) => c.is_cancelled(),
+            Message::Notify(_, ref c) => c.is_cancelled(),
+            Message::Close(_) => false,
+        }
+    }
+
+    // Close messages do not take capacity
+    fn takes_capacity(&self) -> bool {
+        match *self {
+            Message::Close(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl<T, U, E> Drop for Capacity<T, U, E>
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    fn drop(&mut self) {
+        let waiting = {
+            let mut state = self.0.lock().unwrap();
+            state.closed = true;
+            mem::replace(&mut state.waiting, VecDeque::new())
+        };
+
+        for msg in waiting {
+            let err = E::from(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+
+            match msg {
+                Message::Request(_, c, _) => c.error(err),
+                Message::Notify(_, c) => c.error(err),
+                Message::Close(c) => c.complete(()),
+            }
+        }
+    }
+}
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl<T, E> Client<T, E>
+    where T: Transport<Error = E, RequestId = RequestId>,
+          E: From<io::Error> + Send + 'static,
+{
+    fn complete(&mut self, id: RequestId, res: Result<T::Out, E>) {
+        match self.in_flight.remove(&id) {
+            Some(c) => {
+                self.ids.release(id);
+                self.clear_timeout(id);
+
+                match res {
+                    Ok(resp) => c.complete(resp),
+                    Err(e) => c.error(e),
+                }
+            }
+            None => {
+                if self.cancelled.remove(&id) {
+                    trace!("discarding response for cancelled request; id={:?}", id);
+                    self.ids.release(id);
+                    self.clear_timeout(id);
+                } else {
+                    debug!("received response for unknown request; id={:?}", id);
+                }
+            }
+        }
+    }
+
+    // Stop tracking requests whose futures have been dropped. Their ids stay
+    // allocated until the response arrives.
+    fn sweep_cancelled(&mut self) {
+        let cancelled: Vec<RequestId> = self.in_flight.iter()
+            .filter(|&(_, c)| c.is_cancelled())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in cancelled {
+            trace!("request cancelled; id={:?}", id);
+            self.in_flight.remove(&id);
+            self.cancelled.insert(id);
+        }
+    }
+
+    fn set_timeout(&mut self, id: RequestId, timeout: Duration) -> io::Result<()> {
+        self.set_expiry(id, timeout, Expiry::Response(id, timeout))
+    }
+
+    fn set_expiry(&mut self, id: RequestId, delay: Duration, expiry: Expiry) -> io::Result<()> {
+        if self.timer.is_none() {
+            self.timer = Some(try!(Timer::watch(mio::timer::Timer::default())));
+        }
+
+        let timer = self.timer.as_mut().unwrap();
+
+        match timer.set_timeout(delay, expiry) {
+            Ok(timeout) => {
+                self.timeouts.insert(id, timeout);
+                Ok(())
+            }
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "failed to set timeout")),
+        }
+    }
+
+    fn clear_timeout(&mut self, id: RequestId) {
+        if let Some(timeout) = self.timeouts.remove(&id) {
+            if let Some(ref mut timer) = self.timer {
+                timer.cancel_timeout(&timeout);
+            }
+        }
+    }
+
+    // Fail the requests that timed out. Their ids stay allocated until the
+    // response arrives or the grace period ends.
+    fn poll_timeouts(&mut self) -> io::Result<()> {
+        let mut expired = vec![];
+
+        if let Some(ref mut timer) = self.timer {
+            while let Some(expiry) = timer.poll() {
+                expired.push(expiry);
+            }
+        }
+
+        for expiry in expired {
+            match expiry {
+                Expiry::Response(id, timeout) => {
+                    self.timeouts.remove(&id);
+
+                    if let Some(c) = self.in_flight.remove(&id) {
+                        debug!("request timed out; id={:?}", id);
+                        self.cancelled.insert(id);
+                        try!(self.set_expiry(id, timeout, Expiry::Release(id)));
+                        c.error(E::from(io::Error::new(io::ErrorKind::TimedOut, "request timed out")));
+                    }
+                }
+                Expiry::Release(id) => {
+                    self.timeouts.remove(&id);
+
+                    if self.cancelled.remove(&id) {
+                        trace!("late response never arrived; id={:?}", id);
+                        self.ids.release(id);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Add a request to the current batch, starting its delay if it is the
+    // first one
+    fn push_batch(&mut self, frame: Frame<T::In, E>, max_delay: Duration) -> io::Result<()> {
+        if self.batch.is_empty() {
+            if self.batch_timer.is_none() {
+                self.batch_timer = Some(try!(Timer::watch(mio::timer::Timer::default())));
+            }
+
+            let timer = self.batch_timer.as_mut().unwrap();
+
+            match timer.set_timeout(max_delay, ()) {
+                Ok(timeout) => self.batch_timeout = Some(timeout),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "failed to set timeout")),
+            }
+        }
+
+        self.batch.push(frame);
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> io::Result<Option<()>> {
+        if let Some(timeout) = self.batch_timeout.take() {
+            if let Some(ref mut timer) = self.batch_timer {
+                timer.cancel_timeout(&timeout);
+            }
+        }
+
+        self.batch_expired = false;
+
+        let batch = mem::replace(&mut self.batch, vec![]);
+        trace!("writing request batch; len={}", batch.len());
+
+        let notified = mem::replace(&mut self.batch_notified, vec![]);
+        self.notified.extend(notified);
+
+        let flush = try!(self.transport.write_batch(batch));
+
+        // The requests are written, start their timeouts
+        for (id, timeout) in mem::replace(&mut self.batch_timeouts, vec![]) {
+            try!(self.set_timeout(id, timeout));
+        }
+
+        Ok(flush)
+    }
+
+    // Returns true if the current batch has waited for `max_delay`
+    fn poll_batch_timer(&mut self) -> bool {
+        let mut expired = false;
+
+        if let Some(ref mut timer) = self.batch_timer {
+            while let Some(()) = timer.poll() {
+                expired = true;
+            }
+        }
+
+        expired
+    }
+
+    // Returns the next message sent by the handles, freeing the capacity it
+    // took. Once every sent message has been received, the requests waiting
+    // for capacity are picked up in order.
+    fn next_message(&mut self) -> Result<Option<Message<T::In, T::Out, E>>, RecvError> {
+        let mut state = self.capacity.0.lock().unwrap();
+
+        match self.requests.recv() {
+            Ok(Some(msg)) => {
+                if msg.takes_capacity() {
+                    state.available += 1;
+                }
+
+                Ok(Some(msg))
+            }
+            Ok(None) => Ok(state.waiting.pop_front()),
+            Err(e) => {
+                match state.waiting.pop_front() {
+                    Some(msg) => Ok(Some(msg)),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    // Fail all in-flight requests, the transport will not produce any more
+    // responses
+    fn fail_in_flight(&mut self) {
+        self.timeouts.clear();
+        self.batch_timeouts.clear();
+
+        for (id, c) in self.in_flight.drain() {
+            self.ids.release(id);
+            c.error(E::from(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")));
+        }
+
+        for id in self.cancelled.drain() {
+            self.ids.release(id);
+        }
+
+        for c in self.batch_notified.drain(..).chain(self.notified.drain(..)) {
+            c.error(E::from(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")));
+        }
+
+        self.notify_closed();
+    }
+
+    // Complete the one-way requests once they have been flushed
+    fn notify_flushed(&mut self) {
+        for c in self.notified.drain(..) {
+            c.complete(());
+        }
+    }
+
+    fn notify_closed(&mut self) {
+        for c in self.closing.drain(..) {
+            c.complete(());
+        }
+    }
+}
+
+impl<T, E> Task for Client<T, E>
+    where T: Transport<Error = E, RequestId = RequestId>,
+          E: From<io::Error> + Send + 'static,
+{
+    fn tick(&mut self) -> io::Result<Tick> {
+        trace!("multiplex::Client::tick");
+
+        // The first action is always flushing the transport
+        let mut flush = try!(self.transport.flush());
+
+        self.sweep_cancelled();
+
+        // Process responses
+        loop {
+            match self.transport.read() {
+                Ok(Some(frame)) => {
+                    match frame {
+                        Frame::Message(id, resp) => {
+                            trace!("multiplex got response; id={:?}", id);
+                            self.complete(id, Ok(resp));
+                        }
+                        Frame::Error(id, e) => {
+                            trace!("multiplex got error; id={:?}", id);
+                            self.complete(id, Err(e));
+                        }
+                        Frame::Rejected(id) => {
+                            trace!("multiplex got rejection; id={:?}", id);
+                            let e = io::Error::new(io::ErrorKind::Other, RequestRejected);
+                            self.complete(id, Err(E::from(e)));
+                        }
+                        Frame::DuplicateId(id) => {
+                            // The request in flight with the id is still
+                            // answered
+                            debug!("server rejected a request reusing an id in flight; id={:?}", id);
+                        }
+                        Frame::OneWay(_) => {
+                            debug!("dropping one-way frame sent by the server");
+                        }
+                        Frame::Ping | Frame::Pong => {
+                            debug!("dropping keepalive frame sent by the server");
+                        }
+                        Frame::Done => {
+                            trace!("received Frame::Done");
+                            self.ready.fail(&io::Error::new(io::ErrorKind::BrokenPipe, "connection closed before it was ready"));
+                            self.fail_in_flight();
+                            self.pending.store(0, Ordering::Relaxed);
+                            self.allocated.store(0, Ordering::Relaxed);
+                            return Ok(Tick::Final);
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    self.ready.fail(&e);
+                    self.fail_in_flight();
+                    self.pending.store(0, Ordering::Relaxed);
+                    self.allocated.store(0, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        }
+
+        if self.transport.is_writable() {
+            self.ready.set();
+        }
+
+        try!(self.poll_timeouts());
+
+        // Process new requests
+        while self.run && self.transport.is_writable() {
+            match self.next_message() {
+                Ok(Some(Message::Request(req, c, timeout))) => {
+                    if c.is_cancelled() {
+                        // The future was dropped while the request was
+                        // waiting for the transport to become writable
+                        trace!("discarding cancelled request before write");
+                        continue;
+                    }
+
+                    let id = match self.ids.next() {
+                        Ok(id) => id,
+                        Err(e) => {
+                            c.error(E::from(e));
+                            continue;
+                        }
+                    };
+
+                    trace!("received request; id={:?}", id);
+
+                    let frame = Frame::Message(id, req);
+                    self.in_flight.insert(id, c);
+
+                    // Write the request to the transport
+                    match self.flush_policy {
+                        FlushPolicy::Immediate => {
+                            flush = try!(self.transport.write(frame));
+
+                            if let Some(timeout) = timeout {
+                                try!(self.set_timeout(id, timeout));
+                            }
+                        }
+                        FlushPolicy::Coalesce { max_batch, max_delay } => {
+                            try!(self.push_batch(frame, max_delay));
+
+                            if let Some(timeout) = timeout {
+                                self.batch_timeouts.push((id, timeout));
+                            }
+
+                            if self.batch.len() >= max_batch {
+                                flush = try!(self.write_batch());
+                            }
+                        }
+                    }
+                }
+                Ok(Some(Message::Notify(req, c))) => {
+                    if c.is_cancelled() {
+                        trace!("discarding cancelled one-way request before write");
+                        continue;
+                    }
+
+                    trace!("received one-way request");
+
+                    let frame = Frame::OneWay(req);
+
+                    match self.flush_policy {
+                        FlushPolicy::Immediate => {
+                            flush = try!(self.transport.write(frame));
+                            self.notified.push(c);
+                        }
+                        FlushPolicy::Coalesce { max_batch, max_delay } => {
+                            try!(self.push_batch(frame, max_delay));
+                            self.batch_notified.push(c);
+
+                            if self.batch.len() >= max_batch {
+                                flush = try!(self.write_batch());
+                            }
+                        }
+                    }
+                }
+                Ok(Some(Message::Close(c))) => {
+                    trace!("client handle closed");
+                    self.closing.push(c);
+                }
+                Ok(None) => {
+                    trace!("request queue is empty");
+                    break
+                }
+                Err(_) => {
+                    // An error on receive can only happen when the other half
+                    // disconnected. In this case, the client needs to be
+                    // shutdown
+                    self.run = false;
+                    break;
+                }
+            }
+        }
+
+        // Write a partial batch once it waited long enough, or once the
+        // client is shutting down, as soon as the transport is writable
+        if self.poll_batch_timer() {
+            self.batch_expired = true;
+        }
+
+        if !self.batch.is_empty() && (self.batch_expired || !self.run) && self.transport.is_writable() {
+            flush = try!(self.write_batch());
+        }
+
+        if flush.is_some() {
+            self.notify_flushed();
+        }
+
+        self.pending.store(self.in_flight.len(), Ordering::Relaxed);
+        self.allocated.store(self.ids.len(), Ordering::Relaxed);
+
+        if !self.run && flush.is_some() && self.in_flight.is_empty() && self.batch.is_empty() {
+            self.notify_closed();
+            return Ok(Tick::Final);
+        }
+
+        // Tick again later
+        Ok(Tick::WouldBlock)
+    }
+}
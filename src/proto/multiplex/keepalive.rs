@@ -0,0 +1,169 @@
+use super::Frame;
+use io::{Readiness, Transport};
+use util::timer::{Timer, Timeout};
+use mio;
+use std::io;
+use std::time::Duration;
+
+/// Sends a `Frame::Ping` on a client transport every `interval`, failing the
+/// connection if the peer does not answer with a `Frame::Pong` within
+/// `timeout`.
+///
+/// This detects dead peers faster than TCP keepalive. Pongs are consumed by
+/// the transport instead of being passed to the multiplex client. Since they
+/// are not tagged with a `RequestId`, they can never be mistaken for a
+/// response. The next ping is sent `interval` after the pong arrives.
+///
+/// A missing pong fails reads from the transport with `TimedOut`, which shuts
+/// down the client and fails the requests in flight.
+pub struct Keepalive<T> {
+    inner: T,
+    interval: Duration,
+    timeout: Duration,
+    // Created on the first read, since it must be registered with the task
+    // driving the transport
+    timer: Option<Timer<Event>>,
+    // Set while waiting on a pong
+    waiting: Option<Timeout>,
+}
+
+enum Event {
+    Ping,
+    Timeout,
+}
+
+impl<T> Keepalive<T> {
+    /// Create a new `Keepalive` wrapping `inner`
+    pub fn new(inner: T, interval: Duration, timeout: Duration) -> Keepalive<T> {
+        Keepalive {
+            inner: inner,
+            interval: interval,
+            timeout: timeout,
+            timer: None,
+            waiting: None,
+        }
+    }
+
+    /// Returns a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Readiness for Keepalive<T>
+    where T: Readiness,
+{
+    fn is_readable(&self) -> bool {
+        self.inner.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.is_writable()
+    }
+}
+
+impl<T, U, V, E, I> Transport for Keepalive<T>
+    where T: Transport<In = Frame<U, E, I>, Out = Frame<V, E, I>>,
+{
+    type In = Frame<U, E, I>;
+    type Out = Frame<V, E, I>;
+
+    fn read(&mut self) -> io::Result<Option<Frame<V, E, I>>> {
+        try!(self.poll_timer());
+
+        loop {
+            let frame = match try!(self.inner.read()) {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            match frame {
+                Frame::Pong => {}
+                frame => return Ok(Some(frame)),
+            }
+
+            trace!("keepalive got pong");
+
+            // Pongs that do not answer a ping are dropped as well
+            if let Some(timeout) = self.waiting.take() {
+                let timer = self.timer.as_mut().unwrap();
+                timer.cancel_timeout(&timeout);
+                try!(set_timeout(timer, self.interval, Event::Ping));
+            }
+        }
+    }
+
+    fn write(&mut self, frame: Frame<U, E, I>) -> io::Result<Option<()>> {
+        self.inner.write(frame)
+    }
+
+    fn write_batch(&mut self, frames: Vec<Frame<U, E, I>>) -> io::Result<Option<()>> {
+        self.inner.write_batch(frames)
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        self.inner.flush()
+    }
+
+    fn close(&mut self) -> io::Result<Option<()>> {
+        self.inner.close()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+impl<T, U, V, E, I> Keepalive<T>
+    where T: Transport<In = Frame<U, E, I>, Out = Frame<V, E, I>>,
+{
+    // Send the ping once the interval elapsed, and fail once the pong is late
+    fn poll_timer(&mut self) -> io::Result<()> {
+        if self.timer.is_none() {
+            let mut timer = try!(Timer::watch(mio::timer::Timer::default()));
+            try!(set_timeout(&mut timer, self.interval, Event::Ping));
+            self.timer = Some(timer);
+        }
+
+        let mut events = vec![];
+
+        if let Some(ref mut timer) = self.timer {
+            while let Some(event) = timer.poll() {
+                events.push(event);
+            }
+        }
+
+        for event in events {
+            match event {
+                Event::Ping => {
+                    trace!("keepalive sending ping");
+
+                    // The ping is flushed along with the other writes if the
+                    // transport is not able to write it right away
+                    try!(self.inner.write(Frame::Ping));
+
+                    let timer = self.timer.as_mut().unwrap();
+                    self.waiting = Some(try!(set_timeout(timer, self.timeout, Event::Timeout)));
+                }
+                Event::Timeout => {
+                    debug!("keepalive timed out waiting on pong");
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "keepalive timed out"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn set_timeout(timer: &mut Timer<Event>, delay: Duration, event: Event) -> io::Result<Timeout> {
+    match timer.set_timeout(delay, event) {
+        Ok(timeout) => Ok(timeout),
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "failed to set timeout")),
+    }
+}
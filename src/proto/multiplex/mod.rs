@@ -0,0 +1,177 @@
+//! A dispatcher for multiplexing protocols
+//!
+//! This module contains reusable components for quickly implementing clients
+//! and servers for multiplex based protocols.
+//!
+//! # Multiplexing
+//!
+//! Multiplexing allows multiple request / response exchanges to be in flight
+//! on a single connection at the same time. Every frame is tagged with a
+//! `RequestId` identifying the exchange it belongs to, which allows responses
+//! to be written in whatever order they complete rather than in the order the
+//! requests were received.
+//!
+//! Unlike pipelining, a request that takes a long time to process does not
+//! delay the responses to requests received after it. Protocols that require
+//! in-order delivery can use `OrderedServer`, which still processes requests
+//! concurrently but writes the responses in `RequestId` order.
+//!
+//! # Usage
+//!
+//! The multiplex dispatchers take a generic `Transport` that reads and writes
+//! `Frame` messages tagged with their `RequestId`. The protocol is exposed
+//! using a `Service`.
+
+mod client;
+mod keepalive;
+mod ordered;
+mod request_id;
+mod server;
+mod stream;
+mod window;
+
+pub use self::client::{connect, connect_with_capacity, connect_with_flush_policy, try_connect, ClientHandle, FlushPolicy, RequestRejected, Responses};
+pub use self::keepalive::Keepalive;
+pub use self::ordered::OrderedServer;
+pub use self::request_id::RequestIds;
+pub use self::server::{serve, Server};
+pub use self::stream::{StreamFrame, StreamId, StreamMux, StreamSink, StreamSource};
+pub use self::window::{Window, WindowSize};
+
+use io::Readiness;
+use tcp::TcpStream;
+use std::{fmt, io};
+use std::hash::Hash;
+
+/// Identifies a request / response exchange on a multiplexed transport
+pub type RequestId = u64;
+
+/// A multiplexed protocol frame
+///
+/// Frames are tagged with the id of the request they belong to. `RequestId`
+/// is used by default, but the server accepts any id type supported by the
+/// transport, such as string correlation ids.
+#[derive(Debug)]
+pub enum Frame<T, E, I = RequestId> {
+    /// Either a request or a response
+    Message(I, T),
+    /// Error
+    Error(I, E),
+    /// The request was rejected by the server without being processed
+    Rejected(I),
+    /// A request reusing the id of a request still in flight was rejected
+    /// by the server without being processed
+    ///
+    /// Unlike `Frame::Rejected`, it does not answer the request in flight
+    /// with that id, whose response is still written.
+    DuplicateId(I),
+    /// A one-way request, which is not tagged with an id since no response
+    /// is written for it
+    OneWay(T),
+    /// A keepalive ping, answered by the peer with a `Frame::Pong`
+    Ping,
+    /// The answer to a `Frame::Ping`
+    Pong,
+    /// Final frame sent in each transport direction
+    Done,
+}
+
+/// A specialization of `io::Transport` supporting the requirements of
+/// multiplex based protocols.
+///
+/// `io::Transport` should be implemented instead of this trait.
+pub trait Transport: Readiness {
+    /// Messages written to the transport
+    type In: Send + 'static;
+
+    /// Messages read from the transport
+    type Out: Send + 'static;
+
+    /// Errors
+    type Error: Send + 'static;
+
+    /// Ids used to tag frames with the request they belong to
+    type RequestId: Eq + Hash + Clone + fmt::Debug + Send + 'static;
+
+    /// Read a message from the `Transport`
+    fn read(&mut self) -> io::Result<Option<Frame<Self::Out, Self::Error, Self::RequestId>>>;
+
+    /// Write a message to the `Transport`
+    fn write(&mut self, req: Frame<Self::In, Self::Error, Self::RequestId>) -> io::Result<Option<()>>;
+
+    /// Write a batch of messages to the `Transport`
+    ///
+    /// Implemented by delegating to `io::Transport::write_batch`.
+    fn write_batch(&mut self, reqs: Vec<Frame<Self::In, Self::Error, Self::RequestId>>) -> io::Result<Option<()>>;
+
+    /// Flush pending writes to the socket
+    fn flush(&mut self) -> io::Result<Option<()>>;
+}
+
+/// A specialization of `io::NewTransport` supporting the requirements of
+/// multiplex based protocols.
+///
+/// `io::NewTransport` should be implemented instead of this trait.
+pub trait NewTransport: Send + 'static {
+    /// Messages written to the transport
+    type In: Send + 'static;
+
+    /// Messages read from the transport
+    type Out: Send + 'static;
+
+    /// Errors
+    type Error: Send + 'static;
+
+    /// Ids used to tag frames with the request they belong to
+    type RequestId: Eq + Hash + Clone + fmt::Debug + Send + 'static;
+
+    /// Transport returned
+    type Item: Transport<In = Self::In, Out = Self::Out, Error = Self::Error, RequestId = Self::RequestId>;
+
+    /// Create and return a new `Transport`
+    fn new_transport(&self, socket: TcpStream) -> io::Result<Self::Item>;
+}
+
+impl<T, U, V, E, I> Transport for T
+    where T: ::io::Transport<In = Frame<U, E, I>, Out = Frame<V, E, I>>,
+          U: Send + 'static,
+          V: Send + 'static,
+          E: Send + 'static,
+          I: Eq + Hash + Clone + fmt::Debug + Send + 'static,
+{
+    type In = U;
+    type Out = V;
+    type Error = E;
+    type RequestId = I;
+
+    fn read(&mut self) -> io::Result<Option<Frame<V, E, I>>> {
+        ::io::Transport::read(self)
+    }
+
+    fn write(&mut self, req: Frame<U, E, I>) -> io::Result<Option<()>> {
+        ::io::Transport::write(self, req)
+    }
+
+    fn write_batch(&mut self, reqs: Vec<Frame<U, E, I>>) -> io::Result<Option<()>> {
+        ::io::Transport::write_batch(self, reqs)
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        ::io::Transport::flush(self)
+    }
+}
+
+impl<F, T> NewTransport for F
+    where F: Fn(TcpStream) -> io::Result<T> + Send + 'static,
+          T: Transport,
+{
+    type In = T::In;
+    type Out = T::Out;
+    type Error = T::Error;
+    type RequestId = T::RequestId;
+    type Item = T;
+
+    fn new_transport(&self, socket: TcpStream) -> io::Result<T> {
+        self(socket)
+    }
+}
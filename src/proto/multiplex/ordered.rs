@@ -0,0 +1,195 @@
+use {Service};
+use super::{Frame, RequestId, Transport};
+use reactor::{Task, Tick};
+use util::future::AwaitSet;
+use std::io;
+use std::collections::HashMap;
+
+// Default max number of completed responses buffered while waiting on a
+// lower numbered request
+const MAX_BUFFERED: usize = 128;
+
+/// A multiplex server `Task` that dispatches requests concurrently but writes
+/// responses in `RequestId` order.
+///
+/// Request ids are expected to be consecutive. A completed response is
+/// buffered until the responses to all lower numbered requests have been
+/// written. If the number of buffered responses exceeds the configured bound,
+/// the request at the head of the gap is assumed to be lost and the server
+/// fails with an error.
+pub struct OrderedServer<S, T>
+    where S: Service,
+{
+    run: bool,
+    service: S,
+    transport: T,
+    // One-way requests have no id since they are not answered
+    in_flight: AwaitSet<Option<RequestId>, S::Fut>,
+    // Completed responses waiting on lower numbered requests
+    buffered: HashMap<RequestId, Result<S::Resp, S::Error>>,
+    // Id of the next response to write
+    next: RequestId,
+    max_buffered: usize,
+    // Error returned by `Transport::read`, reported once in-flight responses
+    // have been written
+    read_error: Option<io::Error>,
+}
+
+impl<S, T> OrderedServer<S, T>
+    where S: Service,
+{
+    /// Create a new ordered multiplex `Server` dispatcher with the given
+    /// service and transport. The first request is expected to have id 0.
+    pub fn new(service: S, transport: T) -> io::Result<OrderedServer<S, T>> {
+        Ok(OrderedServer {
+            run: true,
+            service: service,
+            transport: transport,
+            in_flight: try!(AwaitSet::with_capacity(16)),
+            buffered: HashMap::new(),
+            next: 0,
+            max_buffered: MAX_BUFFERED,
+            read_error: None,
+        })
+    }
+
+    /// Set the id of the first request. Defaults to 0.
+    pub fn first_request_id(mut self, id: RequestId) -> Self {
+        self.next = id;
+        self
+    }
+
+    /// Set the max number of completed responses buffered while waiting on a
+    /// lower numbered request. Defaults to 128.
+    pub fn max_buffered(mut self, val: usize) -> Self {
+        self.max_buffered = val;
+        self
+    }
+
+    fn gap_error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("response reorder gap; missing request id {}", self.next))
+    }
+}
+
+impl<S, T, E> Task for OrderedServer<S, T>
+    where S: Service<Error = E>,
+          T: Transport<In=S::Resp, Out=S::Req, RequestId = RequestId>,
+          T::Error: From<E>,
+          E: Send + 'static,
+{
+    fn tick(&mut self) -> io::Result<Tick> {
+        trace!("multiplex::OrderedServer::tick");
+
+        // The first action is always flushing the transport
+        let mut flush = try!(self.transport.flush());
+
+        // Buffer completed responses
+        while let Some((id, res)) = self.in_flight.poll() {
+            match id {
+                Some(id) => {
+                    trace!("got in_flight response; id={:?}", id);
+                    self.buffered.insert(id, res);
+                }
+                None => {
+                    // No response is written for a one-way request, so the log
+                    // is the only trace of its failure
+                    match res {
+                        Ok(_) => trace!("one-way request completed"),
+                        Err(_) => debug!("one-way request failed"),
+                    }
+                }
+            }
+        }
+
+        // Write responses as long as the next one in order is available
+        while self.transport.is_writable() {
+            let id = self.next;
+
+            match self.buffered.remove(&id) {
+                Some(Ok(val)) => {
+                    flush = try!(self.transport.write(Frame::Message(id, val)));
+                }
+                Some(Err(e)) => {
+                    flush = try!(self.transport.write(Frame::Error(id, e.into())));
+                }
+                None => break,
+            }
+
+            self.next += 1;
+        }
+
+        if self.buffered.len() > self.max_buffered {
+            debug!("reorder buffer full; buffered={}", self.buffered.len());
+            return Err(self.gap_error());
+        }
+
+        // Process new requests as long as the server is accepting
+        while self.run {
+            trace!("multiplex trying to read transport");
+            match self.transport.read() {
+                Ok(Some(frame)) => {
+                    match frame {
+                        Frame::Message(id, req) => {
+                            trace!("multiplex got request; id={:?}", id);
+
+                            if id < self.next {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, "request id already answered"));
+                            }
+
+                            let resp = self.service.call(req);
+                            self.in_flight.push(Some(id), resp);
+                        }
+                        Frame::OneWay(req) => {
+                            trace!("multiplex got one-way request");
+
+                            // Not answered, so it holds back no response
+                            let resp = self.service.call(req);
+                            self.in_flight.push(None, resp);
+                        }
+                        Frame::Ping => {
+                            trace!("multiplex got ping");
+                            flush = try!(self.transport.write(Frame::Pong));
+                        }
+                        Frame::Pong => {
+                            debug!("dropping pong sent by the client");
+                        }
+                        Frame::Done => {
+                            trace!("received Frame::Done");
+                            self.run = false;
+                            break;
+                        }
+                        Frame::Error(..) | Frame::Rejected(..) | Frame::DuplicateId(..) => {
+                            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "An error occurred."));
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    trace!("transport read failed; err={:?}", e);
+                    self.run = false;
+                    self.read_error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        if !self.run && self.in_flight.is_empty() {
+            if !self.buffered.is_empty() && !self.buffered.contains_key(&self.next) {
+                // No request is left that could fill the gap
+                return Err(self.gap_error());
+            }
+
+            if flush.is_some() && self.buffered.is_empty() {
+                if let Some(e) = self.read_error.take() {
+                    return Err(e);
+                }
+
+                return Ok(Tick::Final);
+            }
+        }
+
+        // Tick again later
+        Ok(Tick::WouldBlock)
+    }
+}
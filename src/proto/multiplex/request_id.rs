@@ -0,0 +1,101 @@
+use super::RequestId;
+use std::io;
+use std::collections::HashSet;
+
+/// Allocates `RequestId` values for requests in flight on a multiplexed
+/// transport.
+///
+/// Ids are allocated in increasing order, wrapping around once the end of
+/// the window is reached. An id is never handed out again while the request
+/// it was allocated for is still in flight.
+pub struct RequestIds {
+    next: RequestId,
+    window: RequestId,
+    in_flight: HashSet<RequestId>,
+}
+
+impl RequestIds {
+    /// Create a new `RequestIds` allocating from the full `RequestId` range.
+    pub fn new() -> RequestIds {
+        RequestIds::with_window(RequestId::max_value())
+    }
+
+    /// Create a new `RequestIds` allocating ids in the range `0..window`.
+    ///
+    /// At most `window` requests may be in flight at any time.
+    pub fn with_window(window: RequestId) -> RequestIds {
+        assert!(window > 0, "request id window must not be empty");
+
+        RequestIds {
+            next: 0,
+            window: window,
+            in_flight: HashSet::new(),
+        }
+    }
+
+    /// Returns the number of allocated ids that have not been released.
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Returns true if no ids are currently allocated.
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    /// Allocate a new id.
+    ///
+    /// Returns an error if every id in the window is in flight.
+    pub fn next(&mut self) -> io::Result<RequestId> {
+        if self.in_flight.len() as RequestId >= self.window {
+            return Err(io::Error::new(io::ErrorKind::Other, "request id window exhausted"));
+        }
+
+        loop {
+            let id = self.next;
+
+            self.next = if id + 1 == self.window { 0 } else { id + 1 };
+
+            if self.in_flight.insert(id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    /// Release the given id, allowing it to be allocated again.
+    ///
+    /// Returns false if the id was not allocated.
+    pub fn release(&mut self, id: RequestId) -> bool {
+        self.in_flight.remove(&id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ids_wrap_without_reusing_live_ids() {
+        let mut ids = RequestIds::with_window(3);
+
+        assert_eq!(0, ids.next().unwrap());
+        assert_eq!(1, ids.next().unwrap());
+        assert_eq!(2, ids.next().unwrap());
+
+        // Every id is in flight
+        assert!(ids.next().is_err());
+
+        assert!(ids.release(1));
+
+        // Wraps around, skipping the live 0 and 2
+        assert_eq!(1, ids.next().unwrap());
+        assert!(ids.next().is_err());
+
+        assert!(ids.release(2));
+        assert!(ids.release(0));
+
+        assert_eq!(2, ids.next().unwrap());
+        assert_eq!(0, ids.next().unwrap());
+        assert_eq!(3, ids.len());
+    }
+}
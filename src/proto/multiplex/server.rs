@@ -0,0 +1,293 @@
+use {Service, NewService};
+use super::{Frame, Transport, NewTransport};
+use reactor::{ReactorHandle, Task, Tick};
+use server::{self, ServerHandle};
+use util::future::AwaitSet;
+use std::io;
+use std::net::SocketAddr;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+
+/// A server `Task` that dispatches `Transport` messages to a `Service` using
+/// protocol multiplexing.
+///
+/// Responses are written as soon as they complete, tagged with the
+/// id of the request they answer. The server does not interpret request ids,
+/// so any id type supported by the transport may be used.
+///
+/// A request reusing the id of a request that is still in flight would make
+/// the two responses indistinguishable. By default, such a request closes the
+/// connection; see `reject_duplicate_ids` to reject only the duplicate
+/// instead.
+///
+/// When several responses are ready to be written, they are written in the
+/// order they completed unless requests are prioritized with `priority`.
+pub struct Server<S, T>
+    where S: Service,
+          T: Transport,
+{
+    run: bool,
+    service: S,
+    transport: T,
+    // Futures are tagged with the request id and priority. One-way requests
+    // have no id since there is no response to write.
+    in_flight: AwaitSet<(Option<T::RequestId>, u32), S::Fut>,
+    // Completed responses waiting on the transport, highest rank first
+    ready: BinaryHeap<Ready<T::RequestId, S::Resp, S::Error>>,
+    // Number of responses that have completed, used to age them
+    completed: u64,
+    priority: Option<Box<Fn(&S::Req) -> u32>>,
+    // Ids of the requests that are in flight
+    live: HashSet<T::RequestId>,
+    // Write `Frame::DuplicateId` for a request reusing a live id, instead of
+    // closing the connection
+    reject_duplicates: bool,
+    // Error returned by `Transport::read`, reported once in-flight responses
+    // have been written
+    read_error: Option<io::Error>,
+}
+
+// A completed response
+struct Ready<I, T, E> {
+    rank: i64,
+    seq: u64,
+    id: I,
+    res: Result<T, E>,
+}
+
+impl<S, T> Server<S, T>
+    where S: Service,
+          T: Transport,
+{
+    /// Create a new multiplex `Server` dispatcher with the given service and
+    /// transport
+    pub fn new(service: S, transport: T) -> io::Result<Server<S, T>> {
+        Ok(Server {
+            run: true,
+            service: service,
+            transport: transport,
+            in_flight: try!(AwaitSet::with_capacity(16)),
+            ready: BinaryHeap::new(),
+            completed: 0,
+            priority: None,
+            live: HashSet::new(),
+            reject_duplicates: false,
+            read_error: None,
+        })
+    }
+
+    /// Reject a request reusing the id of a request that is still in flight
+    /// by writing a `Frame::DuplicateId`, instead of closing the connection.
+    ///
+    /// The rejection is distinct from the frames answering a request, so the
+    /// peer does not mistake it for the response to the original request,
+    /// which is still processed.
+    pub fn reject_duplicate_ids(mut self) -> Self {
+        self.reject_duplicates = true;
+        self
+    }
+
+    /// Prioritize requests using `f`, which returns the priority of a
+    /// request. When several responses are ready to be written, those of
+    /// higher priority requests are written first.
+    ///
+    /// Responses age as other responses complete after them: a response
+    /// outranks one completing `n` responses later as long as its priority is
+    /// no more than `n` lower. This bounds how long a low priority response
+    /// waits behind a steady stream of higher priority ones.
+    pub fn priority<F>(mut self, f: F) -> Self
+        where F: Fn(&S::Req) -> u32 + 'static,
+    {
+        self.priority = Some(Box::new(f));
+        self
+    }
+
+    fn push_ready(&mut self, id: T::RequestId, priority: u32, res: Result<S::Resp, S::Error>) {
+        let seq = self.completed;
+        self.completed += 1;
+
+        self.ready.push(Ready {
+            rank: priority as i64 - seq as i64,
+            seq: seq,
+            id: id,
+            res: res,
+        });
+    }
+}
+
+/// Listen on `addr`, handling each connection with a multiplex `Server`.
+///
+/// For every accepted connection, a transport is created using
+/// `new_transport` and a fresh service instance is created using
+/// `new_service`.
+///
+/// ```rust,ignore
+/// multiplex::serve(&reactor.handle(), addr, service, MyTransport::new);
+/// ```
+pub fn serve<S, T>(reactor: &ReactorHandle,
+                   addr: SocketAddr,
+                   new_service: S,
+                   new_transport: T) -> io::Result<ServerHandle>
+    where S: NewService + Send + 'static,
+          T: NewTransport<In = S::Resp, Out = S::Req>,
+          T::Error: From<S::Error>,
+{
+    server::listen(reactor, addr, move |stream| {
+        let service = try!(new_service.new_service());
+        let transport = try!(new_transport.new_transport(stream));
+
+        Server::new(service, transport)
+    })
+}
+
+impl<S, T, E> Task for Server<S, T>
+    where S: Service<Error = E>,
+          T: Transport<In=S::Resp, Out=S::Req>,
+          T::Error: From<E>,
+          E: Send + 'static,
+{
+    fn tick(&mut self) -> io::Result<Tick> {
+        trace!("multiplex::Server::tick");
+
+        // The first action is always flushing the transport
+        let mut flush = try!(self.transport.flush());
+
+        // Collect completed responses
+        while let Some(((id, priority), res)) = self.in_flight.poll() {
+            match id {
+                Some(id) => self.push_ready(id, priority, res),
+                None => {
+                    // No response is written for a one-way request, so the log
+                    // is the only trace of its failure
+                    match res {
+                        Ok(_) => trace!("one-way request completed"),
+                        Err(_) => debug!("one-way request failed"),
+                    }
+                }
+            }
+        }
+
+        // Write them, highest rank first
+        while self.transport.is_writable() {
+            trace!("multiplex transport is writable");
+
+            let ready = match self.ready.pop() {
+                Some(ready) => ready,
+                None => {
+                    trace!("no response ready for write");
+                    break;
+                }
+            };
+
+            self.live.remove(&ready.id);
+
+            match ready.res {
+                Ok(val) => {
+                    trace!("got in_flight value; id={:?}", ready.id);
+                    flush = try!(self.transport.write(Frame::Message(ready.id, val)));
+                }
+                Err(e) => {
+                    trace!("got in_flight error; id={:?}", ready.id);
+                    flush = try!(self.transport.write(Frame::Error(ready.id, e.into())));
+                }
+            }
+        }
+
+        // Process new requests as long as the server is accepting
+        while self.run {
+            trace!("multiplex trying to read transport");
+            match self.transport.read() {
+                Ok(Some(frame)) => {
+                    match frame {
+                        Frame::Message(id, req) => {
+                            trace!("multiplex got request; id={:?}", id);
+
+                            if self.live.contains(&id) {
+                                if self.reject_duplicates {
+                                    debug!("rejecting request with duplicate id; id={:?}", id);
+                                    flush = try!(self.transport.write(Frame::DuplicateId(id)));
+                                    continue;
+                                }
+
+                                debug!("duplicate request id, closing connection; id={:?}", id);
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, "duplicate request id"));
+                            }
+
+                            let priority = self.priority.as_ref().map_or(0, |f| f(&req));
+                            let resp = self.service.call(req);
+                            self.live.insert(id.clone());
+                            self.in_flight.push((Some(id), priority), resp);
+                        }
+                        Frame::OneWay(req) => {
+                            trace!("multiplex got one-way request");
+
+                            // Processed like any other request, but there is
+                            // no response to write
+                            let resp = self.service.call(req);
+                            self.in_flight.push((None, 0), resp);
+                        }
+                        Frame::Ping => {
+                            trace!("multiplex got ping");
+                            flush = try!(self.transport.write(Frame::Pong));
+                        }
+                        Frame::Pong => {
+                            debug!("dropping pong sent by the client");
+                        }
+                        Frame::Done => {
+                            trace!("received Frame::Done");
+                            self.run = false;
+                            break;
+                        }
+                        Frame::Error(..) | Frame::Rejected(..) | Frame::DuplicateId(..) => {
+                            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "An error occurred."));
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    trace!("transport read failed; err={:?}", e);
+                    self.run = false;
+                    self.read_error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        // Same shutdown conditions as the pipeline server: no longer reading,
+        // everything flushed, and no responses left to write.
+        if !self.run && flush.is_some() && self.in_flight.is_empty() && self.ready.is_empty() {
+            if let Some(e) = self.read_error.take() {
+                return Err(e);
+            }
+
+            return Ok(Tick::Final);
+        }
+
+        // Tick again later
+        Ok(Tick::WouldBlock)
+    }
+}
+
+impl<I, T, E> PartialEq for Ready<I, T, E> {
+    fn eq(&self, other: &Ready<I, T, E>) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl<I, T, E> Eq for Ready<I, T, E> {}
+
+impl<I, T, E> PartialOrd for Ready<I, T, E> {
+    fn partial_cmp(&self, other: &Ready<I, T, E>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<I, T, E> Ord for Ready<I, T, E> {
+    fn cmp(&self, other: &Ready<I, T, E>) -> Ordering {
+        // Higher ranks first, ties go to the response that completed first
+        match self.rank.cmp(&other.rank) {
+            Ordering::Equal => other.seq.cmp(&self.seq),
+            ord => ord,
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use super::{Frame, RequestId};
+use io::{Readiness, Transport};
+use std::{cmp, io, mem};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// Default number of bytes that may be written to a stream before the peer
+// reads them
+const WINDOW: usize = 64 * 1024;
+
+/// Identifies a logical stream on a `StreamMux`
+pub type StreamId = RequestId;
+
+/// The messages exchanged by `StreamMux` peers.
+///
+/// Each message is sent as a `Frame::Message` tagged with the id of its
+/// stream. A `Frame::Error` resets the stream, and `Frame::Done` ends every
+/// stream of the connection.
+#[derive(Debug)]
+pub enum StreamFrame {
+    /// Bytes written to the stream
+    Data(Vec<u8>),
+    /// Allows the peer to write that many more bytes to the stream
+    Credit(usize),
+    /// The peer will not write to the stream anymore
+    Close,
+}
+
+/// Carries any number of bidirectional byte streams over a single
+/// transport, similar to HTTP/2 streams.
+///
+/// Either peer opens a stream with `StreamMux::open_stream`, and the streams
+/// opened by the other peer are returned by `StreamMux::accept`. Streams
+/// opened by the `client` side have odd ids, the ones opened by the `server`
+/// side have even ids, so both may open streams at the same time.
+///
+/// Each stream is flow controlled: at most `window` bytes may be written to
+/// it before the peer reads them, after which its `StreamSink` is not
+/// writable. Reading from the `StreamSource` grants the peer more credit.
+///
+/// The `StreamMux` does not read or write anything on its own, the task
+/// driving the connection calls `StreamMux::tick` whenever the transport or
+/// one of the streams is ready.
+pub struct StreamMux<T> {
+    inner: T,
+    streams: Arc<Mutex<Streams>>,
+    window: usize,
+    next_id: StreamId,
+    // Highest id of a stream opened by the peer
+    last_peer_id: StreamId,
+    // Streams opened by the peer that have not been accepted yet
+    accepted: VecDeque<(StreamSink, StreamSource)>,
+    done: bool,
+}
+
+/// Writes to a logical stream of a `StreamMux`.
+///
+/// Writes fail with `WouldBlock` once the stream's window is full. The data
+/// is written to the transport by the next `StreamMux::tick`. Dropping the
+/// sink closes the stream for writing.
+pub struct StreamSink {
+    id: StreamId,
+    streams: Arc<Mutex<Streams>>,
+}
+
+/// Reads from a logical stream of a `StreamMux`.
+///
+/// Reads fail with `WouldBlock` until the next data is read from the
+/// transport, and return 0 once the peer has closed the stream.
+pub struct StreamSource {
+    id: StreamId,
+    streams: Arc<Mutex<Streams>>,
+}
+
+type Streams = HashMap<StreamId, Stream>;
+
+struct Stream {
+    // Written by the sink, not written to the transport yet
+    wr: Vec<u8>,
+    // Number of bytes the sink may still write
+    send_credit: usize,
+    wr_closed: bool,
+    close_sent: bool,
+    // Read from the transport, not read by the source yet
+    rd: Vec<u8>,
+    // Number of bytes the peer may still write
+    recv_credit: usize,
+    // Number of bytes read by the source that were not granted back to the
+    // peer yet
+    consumed: usize,
+    eof: bool,
+    reset: bool,
+    // Number of sinks and sources of the stream that are still alive
+    handles: usize,
+}
+
+impl<T> StreamMux<T> {
+    /// Create a new `StreamMux` for the peer that initiated the connection
+    pub fn client(inner: T) -> StreamMux<T> {
+        StreamMux::new(inner, 1)
+    }
+
+    /// Create a new `StreamMux` for the peer that accepted the connection
+    pub fn server(inner: T) -> StreamMux<T> {
+        StreamMux::new(inner, 2)
+    }
+
+    fn new(inner: T, first_id: StreamId) -> StreamMux<T> {
+        StreamMux {
+            inner: inner,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            window: WINDOW,
+            next_id: first_id,
+            last_peer_id: 0,
+            accepted: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Set the number of bytes that may be written to a stream before the
+    /// peer reads them. Defaults to 64KB.
+    ///
+    /// Both peers must use the same window.
+    pub fn window(mut self, val: usize) -> Self {
+        assert!(val > 0, "window must be greater than 0");
+        self.window = val;
+        self
+    }
+
+    /// Open a new stream.
+    ///
+    /// The peer is told about the stream once something is written to it.
+    pub fn open_stream(&mut self) -> (StreamSink, StreamSource) {
+        let id = self.next_id;
+        self.next_id += 2;
+
+        trace!("opening stream; id={}", id);
+
+        self.streams.lock().unwrap().insert(id, Stream::new(self.window));
+        handles(&self.streams, id)
+    }
+
+    /// Returns the next stream opened by the peer, if any.
+    ///
+    /// New streams are read from the transport by `StreamMux::tick`.
+    pub fn accept(&mut self) -> Option<(StreamSink, StreamSource)> {
+        self.accepted.pop_front()
+    }
+
+    /// Returns the number of open streams
+    pub fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// Returns true if the peer has ended the connection
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T, E> StreamMux<T>
+    where T: Transport<In = Frame<StreamFrame, E>, Out = Frame<StreamFrame, E>>,
+{
+    /// Read the available frames from the transport, and write the data,
+    /// credit, and closes of every stream.
+    pub fn tick(&mut self) -> io::Result<()> {
+        try!(self.read_frames());
+        self.write_frames()
+    }
+
+    fn read_frames(&mut self) -> io::Result<()> {
+        while !self.done {
+            let frame = match try!(self.inner.read()) {
+                Some(frame) => frame,
+                None => return Ok(()),
+            };
+
+            let mut streams = self.streams.lock().unwrap();
+
+            match frame {
+                Frame::Message(id, frame) => {
+                    if !streams.contains_key(&id) {
+                        if id % 2 == self.next_id % 2 || id <= self.last_peer_id {
+                            trace!("frame for closed stream; id={}", id);
+                            continue;
+                        }
+
+                        trace!("peer opened stream; id={}", id);
+
+                        self.last_peer_id = id;
+                        streams.insert(id, Stream::new(self.window));
+
+                        let accepted = handles(&self.streams, id);
+                        self.accepted.push_back(accepted);
+                    }
+
+                    let stream = streams.get_mut(&id).unwrap();
+
+                    match frame {
+                        StreamFrame::Data(data) => {
+                            if data.len() > stream.recv_credit {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, "peer exceeded the stream window"));
+                            }
+
+                            stream.recv_credit -= data.len();
+                            stream.rd.extend_from_slice(&data);
+                        }
+                        StreamFrame::Credit(n) => {
+                            stream.send_credit = match stream.send_credit.checked_add(n) {
+                                Some(credit) => credit,
+                                None => return Err(io::Error::new(io::ErrorKind::InvalidData, "peer overflowed the stream credit")),
+                            };
+                        }
+                        StreamFrame::Close => stream.eof = true,
+                    }
+                }
+                Frame::Error(id, _) => {
+                    trace!("peer reset stream; id={}", id);
+
+                    if let Some(stream) = streams.get_mut(&id) {
+                        stream.reset = true;
+                        // Nothing is written to a reset stream
+                        stream.close_sent = true;
+                    }
+                }
+                Frame::Rejected(id) | Frame::DuplicateId(id) => {
+                    trace!("ignoring rejected frame; id={}", id);
+                }
+                Frame::OneWay(_) | Frame::Ping | Frame::Pong => {
+                    trace!("ignoring frame not tagged with a stream");
+                }
+                Frame::Done => {
+                    debug!("peer ended the connection");
+
+                    self.done = true;
+
+                    for stream in streams.values_mut() {
+                        stream.eof = true;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_frames(&mut self) -> io::Result<()> {
+        // Credit is granted back once half of the window has been read, so
+        // that the peer is not stalled while the credit is in flight
+        let threshold = self.window / 2;
+        let mut frames = vec![];
+
+        {
+            let mut streams = self.streams.lock().unwrap();
+
+            // Write the streams in a fixed order
+            let mut ids: Vec<StreamId> = streams.keys().cloned().collect();
+            ids.sort();
+
+            for id in ids {
+                let remove = {
+                    let stream = streams.get_mut(&id).unwrap();
+
+                    if !stream.wr.is_empty() && !stream.reset {
+                        let data = mem::replace(&mut stream.wr, vec![]);
+                        frames.push(Frame::Message(id, StreamFrame::Data(data)));
+                    }
+
+                    if stream.consumed > 0 && stream.consumed >= threshold && !stream.eof {
+                        frames.push(Frame::Message(id, StreamFrame::Credit(stream.consumed)));
+                        stream.recv_credit += stream.consumed;
+                        stream.consumed = 0;
+                    }
+
+                    if stream.wr_closed && !stream.close_sent {
+                        frames.push(Frame::Message(id, StreamFrame::Close));
+                        stream.close_sent = true;
+                    }
+
+                    stream.handles == 0 && stream.close_sent && (stream.eof || stream.reset)
+                };
+
+                if remove {
+                    trace!("stream closed; id={}", id);
+                    streams.remove(&id);
+                }
+            }
+        }
+
+        // Also flushes the transport when there is nothing to write
+        try!(self.inner.write_batch(frames));
+        Ok(())
+    }
+}
+
+impl<T: Readiness> Readiness for StreamMux<T> {
+    fn is_readable(&self) -> bool {
+        self.inner.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.is_writable()
+    }
+}
+
+impl StreamSink {
+    /// Returns the id of the stream
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Close the stream for writing.
+    ///
+    /// The data written so far is still written to the transport, followed
+    /// by a `StreamFrame::Close`.
+    pub fn close(&self) {
+        with_stream(&self.streams, self.id, |stream| stream.wr_closed = true);
+    }
+}
+
+impl io::Write for StreamSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        with_stream(&self.streams, self.id, |stream| {
+            if stream.reset {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset by peer"));
+            }
+
+            if stream.wr_closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed"));
+            }
+
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            let n = cmp::min(buf.len(), stream.send_credit);
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "stream window full"));
+            }
+
+            stream.wr.extend_from_slice(&buf[..n]);
+            stream.send_credit -= n;
+
+            Ok(n)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // The data is written to the transport by the `StreamMux`
+        Ok(())
+    }
+}
+
+impl Readiness for StreamSink {
+    fn is_readable(&self) -> bool {
+        false
+    }
+
+    fn is_writable(&self) -> bool {
+        with_stream(&self.streams, self.id, |stream| stream.send_credit > 0)
+    }
+}
+
+impl Drop for StreamSink {
+    fn drop(&mut self) {
+        with_stream(&self.streams, self.id, |stream| {
+            stream.wr_closed = true;
+            stream.handles -= 1;
+        });
+    }
+}
+
+impl StreamSource {
+    /// Returns the id of the stream
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+}
+
+impl io::Read for StreamSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        with_stream(&self.streams, self.id, |stream| {
+            if stream.rd.is_empty() {
+                if stream.reset {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream reset by peer"));
+                }
+
+                if stream.eof {
+                    return Ok(0);
+                }
+
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data available"));
+            }
+
+            let n = cmp::min(buf.len(), stream.rd.len());
+
+            buf[..n].copy_from_slice(&stream.rd[..n]);
+            stream.rd.drain(..n);
+            stream.consumed += n;
+
+            Ok(n)
+        })
+    }
+}
+
+impl Readiness for StreamSource {
+    fn is_readable(&self) -> bool {
+        with_stream(&self.streams, self.id, |stream| {
+            !stream.rd.is_empty() || stream.eof || stream.reset
+        })
+    }
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for StreamSource {
+    fn drop(&mut self) {
+        with_stream(&self.streams, self.id, |stream| stream.handles -= 1);
+    }
+}
+
+impl Stream {
+    fn new(window: usize) -> Stream {
+        Stream {
+            wr: vec![],
+            send_credit: window,
+            wr_closed: false,
+            close_sent: false,
+            rd: vec![],
+            recv_credit: window,
+            consumed: 0,
+            eof: false,
+            reset: false,
+            handles: 2,
+        }
+    }
+}
+
+fn handles(streams: &Arc<Mutex<Streams>>, id: StreamId) -> (StreamSink, StreamSource) {
+    let sink = StreamSink {
+        id: id,
+        streams: streams.clone(),
+    };
+
+    let source = StreamSource {
+        id: id,
+        streams: streams.clone(),
+    };
+
+    (sink, source)
+}
+
+// Streams are only removed once their sink and source are dropped
+fn with_stream<F, R>(streams: &Mutex<Streams>, id: StreamId, f: F) -> R
+    where F: FnOnce(&mut Stream) -> R,
+{
+    let mut streams = streams.lock().unwrap();
+    f(streams.get_mut(&id).expect("stream removed while in use"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::{Readiness, TryRead};
+    use proto::multiplex::Frame;
+    use util::test::MockTransport;
+    use std::io::{self, Read, Write};
+    use std::usize;
+
+    type Mock = MockTransport<Frame<StreamFrame, ()>, Frame<StreamFrame, ()>>;
+
+    // Hands the frames written to `from` to the reader of `to`
+    fn deliver(from: &Mock, to: &Mock) {
+        for frame in from.take_written() {
+            to.push_read(frame);
+        }
+    }
+
+    fn read_available(source: &mut StreamSource) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let mut data = vec![];
+
+        while let Some(n) = source.try_read(&mut buf).unwrap() {
+            if n == 0 {
+                break;
+            }
+
+            data.extend_from_slice(&buf[..n]);
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_interleaved_streams() {
+        let client_io: Mock = MockTransport::new(vec![]);
+        let server_io: Mock = MockTransport::new(vec![]);
+
+        let mut client = StreamMux::client(client_io.clone());
+        let mut server = StreamMux::server(server_io.clone());
+
+        let (mut sink_a, mut source_a) = client.open_stream();
+        let (mut sink_b, mut source_b) = client.open_stream();
+        assert!(sink_a.id() != sink_b.id());
+
+        sink_a.write_all(b"a1 ").unwrap();
+        sink_b.write_all(b"b1 ").unwrap();
+        client.tick().unwrap();
+
+        sink_b.write_all(b"b2").unwrap();
+        sink_a.write_all(b"a2").unwrap();
+        client.tick().unwrap();
+
+        deliver(&client_io, &server_io);
+        server.tick().unwrap();
+
+        let (mut peer_sink_a, mut peer_a) = server.accept().unwrap();
+        let (mut peer_sink_b, mut peer_b) = server.accept().unwrap();
+        assert!(server.accept().is_none());
+
+        assert_eq!(sink_a.id(), peer_a.id());
+        assert_eq!(b"a1 a2".to_vec(), read_available(&mut peer_a));
+        assert_eq!(b"b1 b2".to_vec(), read_available(&mut peer_b));
+
+        // Respond on both streams, closing the first one
+        peer_sink_b.write_all(b"B").unwrap();
+        peer_sink_a.write_all(b"A").unwrap();
+        drop(peer_sink_a);
+        server.tick().unwrap();
+
+        deliver(&server_io, &client_io);
+        client.tick().unwrap();
+
+        assert_eq!(b"A".to_vec(), read_available(&mut source_a));
+        assert_eq!(0, source_a.read(&mut [0; 8]).unwrap());
+        assert_eq!(b"B".to_vec(), read_available(&mut source_b));
+        assert!(source_b.try_read(&mut [0; 8]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_stream_window_limits_writes() {
+        let client_io: Mock = MockTransport::new(vec![]);
+        let server_io: Mock = MockTransport::new(vec![]);
+
+        let mut client = StreamMux::client(client_io.clone()).window(8);
+        let mut server = StreamMux::server(server_io.clone()).window(8);
+
+        let (mut sink, _source) = client.open_stream();
+
+        assert_eq!(8, sink.write(&[0; 20]).unwrap());
+        assert!(!sink.is_writable());
+        assert_eq!(io::ErrorKind::WouldBlock, sink.write(&[0; 12]).unwrap_err().kind());
+
+        client.tick().unwrap();
+        deliver(&client_io, &server_io);
+        server.tick().unwrap();
+
+        // Reading half of the window grants it back to the client
+        let (_peer_sink, mut peer) = server.accept().unwrap();
+        assert_eq!(4, peer.read(&mut [0; 4]).unwrap());
+        server.tick().unwrap();
+
+        deliver(&server_io, &client_io);
+        client.tick().unwrap();
+
+        assert!(sink.is_writable());
+        assert_eq!(4, sink.write(&[0; 12]).unwrap());
+    }
+
+    #[test]
+    fn test_stream_credit_overflow_fails_connection() {
+        let client_io: Mock = MockTransport::new(vec![]);
+        let mut client = StreamMux::client(client_io.clone()).window(8);
+
+        let (sink, _source) = client.open_stream();
+
+        // The stream already has credit for the initial window
+        client_io.push_read(Frame::Message(sink.id(), StreamFrame::Credit(usize::MAX)));
+
+        match client.tick() {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("expected the connection to fail"),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use super::Frame;
+use io::{Readiness, Transport};
+use std::io;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Limits the number of requests a client transport has in flight to the
+/// window size.
+///
+/// A request is in flight from the time it is written until a response,
+/// error, or rejection frame with its id is read. While the window is full,
+/// the transport is not writable, so the multiplex client holds on to new
+/// requests instead of sending requests the server would drop or reject.
+///
+/// The window is meant for the client side of a connection, since it counts
+/// written messages as requests.
+pub struct Window<T, I> {
+    inner: T,
+    size: WindowSize,
+    in_flight: HashSet<I>,
+}
+
+/// The size of a `Window`, which may be shared with other threads in order
+/// to adjust it at runtime, for example once the protocol has negotiated it.
+///
+/// A new size applies the next time the transport's readiness is checked.
+#[derive(Clone)]
+pub struct WindowSize {
+    size: Arc<AtomicUsize>,
+}
+
+impl<T, I> Window<T, I>
+    where I: Hash + Eq,
+{
+    /// Create a new `Window` wrapping `inner`
+    pub fn new(inner: T, size: WindowSize) -> Window<T, I> {
+        Window {
+            inner: inner,
+            size: size,
+            in_flight: HashSet::new(),
+        }
+    }
+
+    /// Returns the size of the window
+    pub fn size(&self) -> &WindowSize {
+        &self.size
+    }
+
+    /// Returns the number of requests in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Returns a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn track<U, E>(&mut self, frame: &Frame<U, E, I>)
+        where I: Clone,
+    {
+        if let Frame::Message(ref id, _) = *frame {
+            self.in_flight.insert(id.clone());
+        }
+    }
+}
+
+impl WindowSize {
+    /// Create a new `WindowSize` allowing `size` requests in flight
+    pub fn new(size: usize) -> WindowSize {
+        WindowSize { size: Arc::new(AtomicUsize::new(size)) }
+    }
+
+    /// Returns the number of requests allowed in flight
+    pub fn get(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Set the number of requests allowed in flight.
+    ///
+    /// Shrinking the window below the number of requests in flight does not
+    /// affect them, new requests wait until enough of them complete.
+    pub fn set(&self, size: usize) {
+        self.size.store(size, Ordering::SeqCst);
+    }
+}
+
+impl<T, I> Readiness for Window<T, I>
+    where T: Readiness,
+          I: Hash + Eq,
+{
+    fn is_readable(&self) -> bool {
+        self.inner.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        if self.in_flight.len() >= self.size.get() {
+            trace!("window full; in_flight={}", self.in_flight.len());
+            return false;
+        }
+
+        self.inner.is_writable()
+    }
+}
+
+impl<T, U, V, E, I> Transport for Window<T, I>
+    where T: Transport<In = Frame<U, E, I>, Out = Frame<V, E, I>>,
+          I: Hash + Eq + Clone,
+{
+    type In = Frame<U, E, I>;
+    type Out = Frame<V, E, I>;
+
+    fn read(&mut self) -> io::Result<Option<Frame<V, E, I>>> {
+        let frame = try!(self.inner.read());
+
+        match frame {
+            Some(Frame::Message(ref id, _)) |
+            Some(Frame::Error(ref id, _)) |
+            Some(Frame::Rejected(ref id)) => {
+                self.in_flight.remove(id);
+            }
+            _ => {}
+        }
+
+        Ok(frame)
+    }
+
+    fn write(&mut self, frame: Frame<U, E, I>) -> io::Result<Option<()>> {
+        self.track(&frame);
+        self.inner.write(frame)
+    }
+
+    fn write_batch(&mut self, frames: Vec<Frame<U, E, I>>) -> io::Result<Option<()>> {
+        for frame in &frames {
+            self.track(frame);
+        }
+
+        self.inner.write_batch(frames)
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        self.inner.flush()
+    }
+
+    fn close(&mut self) -> io::Result<Option<()>> {
+        self.inner.close()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
@@ -1,5 +1,5 @@
 use {client, Service};
-use super::{Frame, Transport, NewTransport};
+use super::{Frame, PipelineFrame, Transport, NewTransport};
 use reactor::{ReactorHandle, Task, Tick};
 use util::channel::{Receiver};
 use util::future::{self, Complete, Val};
@@ -97,14 +97,32 @@ impl<T, E> Task for Client<T, E>
         loop {
             match self.transport.read() {
                 Ok(Some(frame)) => {
-                    match frame {
-                        Frame::Message(resp) => {
+                    let frame = match frame.as_message() {
+                        Ok(resp) => {
                             trace!("pipeline got response");
 
                             let c = self.in_flight.remove(0);
                             c.complete(resp);
+                            continue;
                         }
-                        Frame::Error(e) => {
+                        Err(frame) => frame,
+                    };
+
+                    if frame.is_done() {
+                        trace!("transport is done");
+
+                        if !self.in_flight.is_empty() {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed with requests in flight"));
+                        }
+
+                        // No response is expected, but no more requests
+                        // can be sent either
+                        self.run = false;
+                        break;
+                    }
+
+                    match frame.as_error() {
+                        Ok(e) => {
                             if !self.in_flight.is_empty() {
                                 let c = self.in_flight.remove(0);
                                 c.error(e);
@@ -112,8 +130,9 @@ impl<T, E> Task for Client<T, E>
                                 return Err(io::Error::new(io::ErrorKind::BrokenPipe, "An error occurred."));
                             }
                         }
-                        Frame::Done => {
-                            unimplemented!();
+                        Err(_) => {
+                            // Control frames are not paired with requests
+                            trace!("pipeline client ignoring control frame");
                         }
                     }
                 }
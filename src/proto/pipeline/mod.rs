@@ -14,8 +14,8 @@
 //! for each request, the response must be received before sending another
 //! request on the same connection.
 //!
-//! Another protocol dispatching strategy is multiplexing (which will be
-//! included in Tokio soon).
+//! Another protocol dispatching strategy is multiplexing, see the `multiplex`
+//! module.
 //!
 //! # Usage
 //!
@@ -28,13 +28,14 @@ mod client;
 mod server;
 
 pub use self::client::{connect, ClientHandle};
-pub use self::server::Server;
+pub use self::server::{new_task, serve, FrameErrorPolicy, ResponseOrder, Server, ServerFactory, ServerMetrics, ShutdownHandle};
 
 use io::{Readiness};
 use tcp::TcpStream;
 use std::io;
 
 /// A pipelined protocol frame
+#[derive(Debug)]
 pub enum Frame<T, E> {
     /// Either a request or a response
     Message(T),
@@ -42,6 +43,42 @@ pub enum Frame<T, E> {
     Error(E),
     /// Final frame sent in each transport direction
     Done,
+    /// The transport reached a clean end of stream
+    ///
+    /// Only returned by `Transport::read`, once the peer has closed its half
+    /// of the connection without sending `Frame::Done`. It is never written.
+    Eof,
+}
+
+/// A frame read from a pipeline transport
+///
+/// `Frame` implements the trait for protocols made only of messages and
+/// errors. Protocols carrying control traffic, such as ping, settings or
+/// window update frames, on the same connection define their own frame type.
+/// The server dispatcher hands its control frames to the handler set with
+/// `Server::on_control` instead of treating them as requests.
+pub trait PipelineFrame: Sized {
+    /// Requests or responses carried by the frame
+    type Message;
+
+    /// Errors carried by the frame
+    type Error;
+
+    /// Protocol specific control messages carried by the frame
+    type Control;
+
+    /// Returns the message carried by the frame, or the frame back
+    fn as_message(self) -> Result<Self::Message, Self>;
+
+    /// Returns true if the peer will not send any more frames, as is the
+    /// case for `Frame::Done` and `Frame::Eof`
+    fn is_done(&self) -> bool;
+
+    /// Returns the error carried by the frame, or the frame back
+    fn as_error(self) -> Result<Self::Error, Self>;
+
+    /// Returns the control message carried by the frame, or the frame back
+    fn control(self) -> Result<Self::Control, Self>;
 }
 
 /// Error returned as an Error frame or an io::Error that occurerred during
@@ -67,14 +104,42 @@ pub trait Transport: Readiness {
     /// Errors
     type Error: Send + 'static; // TODO: rename
 
+    /// Frames read from the transport
+    type Frame: PipelineFrame<Message = Self::Out, Error = Self::Error>;
+
     /// Read a message from the `Transport`
-    fn read(&mut self) -> io::Result<Option<Frame<Self::Out, Self::Error>>>;
+    ///
+    /// Returns `Ok(None)` only when no frame can be read without blocking,
+    /// in which case the transport is read from again once it becomes
+    /// readable. Once the underlying source reaches the end of stream, the
+    /// transport must return `Frame::Eof` rather than `Ok(None)`: the source
+    /// will not become readable again, so the dispatcher would wait forever.
+    /// Errors, including an end of stream in the middle of a frame, are
+    /// returned as `Err`.
+    fn read(&mut self) -> io::Result<Option<Self::Frame>>;
 
     /// Write a message to the `Transport`
     fn write(&mut self, req: Frame<Self::In, Self::Error>) -> io::Result<Option<()>>;
 
+    /// Write a batch of messages to the `Transport`
+    ///
+    /// Implemented by delegating to `io::Transport::write_batch`.
+    fn write_batch(&mut self, reqs: Vec<Frame<Self::In, Self::Error>>) -> io::Result<Option<()>>;
+
     /// Flush pending writes to the socket
     fn flush(&mut self) -> io::Result<Option<()>>;
+
+    /// Close the transport, writing any closing frame required by the
+    /// protocol
+    fn close(&mut self) -> io::Result<Option<()>> {
+        self.flush()
+    }
+
+    /// Shutdown the write half of the underlying source once the transport
+    /// has been closed and flushed
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 /// A specialization of `io::NewTransport` supporting the requirements of
@@ -98,27 +163,41 @@ pub trait NewTransport: Send + 'static {
     fn new_transport(&self, socket: TcpStream) -> io::Result<Self::Item>;
 }
 
-impl<T, U, V, E> Transport for T
-    where T: ::io::Transport<In = Frame<U, E>, Out = Frame<V, E>>,
+impl<T, U, F> Transport for T
+    where T: ::io::Transport<In = Frame<U, F::Error>, Out = F>,
           U: Send + 'static,
-          V: Send + 'static,
-          E: Send + 'static,
+          F: PipelineFrame,
+          F::Message: Send + 'static,
+          F::Error: Send + 'static,
 {
     type In = U;
-    type Out = V;
-    type Error = E;
+    type Out = F::Message;
+    type Error = F::Error;
+    type Frame = F;
 
-    fn read(&mut self) -> io::Result<Option<Frame<V, E>>> {
+    fn read(&mut self) -> io::Result<Option<F>> {
         ::io::Transport::read(self)
     }
 
-    fn write(&mut self, req: Frame<U, E>) -> io::Result<Option<()>> {
+    fn write(&mut self, req: Frame<U, F::Error>) -> io::Result<Option<()>> {
         ::io::Transport::write(self, req)
     }
 
+    fn write_batch(&mut self, reqs: Vec<Frame<U, F::Error>>) -> io::Result<Option<()>> {
+        ::io::Transport::write_batch(self, reqs)
+    }
+
     fn flush(&mut self) -> io::Result<Option<()>> {
         ::io::Transport::flush(self)
     }
+
+    fn close(&mut self) -> io::Result<Option<()>> {
+        ::io::Transport::close(self)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        ::io::Transport::shutdown(self)
+    }
 }
 
 impl<F, T> NewTransport for F
@@ -135,6 +214,39 @@ impl<F, T> NewTransport for F
     }
 }
 
+impl<T, E> PipelineFrame for Frame<T, E> {
+    type Message = T;
+    type Error = E;
+
+    // Plain frames carry no control traffic
+    type Control = ();
+
+    fn as_message(self) -> Result<T, Frame<T, E>> {
+        match self {
+            Frame::Message(msg) => Ok(msg),
+            frame => Err(frame),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match *self {
+            Frame::Done | Frame::Eof => true,
+            _ => false,
+        }
+    }
+
+    fn as_error(self) -> Result<E, Frame<T, E>> {
+        match self {
+            Frame::Error(e) => Ok(e),
+            frame => Err(frame),
+        }
+    }
+
+    fn control(self) -> Result<(), Frame<T, E>> {
+        Err(self)
+    }
+}
+
 impl From<Error<io::Error>> for io::Error {
     fn from(err: Error<io::Error>) -> Self {
         match err {
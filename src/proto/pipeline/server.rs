@@ -1,98 +1,917 @@
-use {Service};
-use super::{Error, Frame, Transport};
-use reactor::{Task, Tick};
-use util::future::AwaitQueue;
+use {Service, NewService};
+use super::{Error, Frame, PipelineFrame, Transport, NewTransport};
+use reactor::{self, ReactorHandle, Task, Tick, NewTask};
+use server::{self, ServerHandle};
+use tcp::TcpStream;
+use util::channel::Receiver;
+use util::future::{self, AwaitQueue, Complete, Val};
+use futures::Poll;
+use mio::channel;
+use std::collections::VecDeque;
 use std::io;
+use std::net::SocketAddr;
+
+// Default max number of requests read in a single tick before yielding to
+// other tasks
+const MAX_READS_PER_TICK: usize = 32;
+
+// Default number of in-flight requests at which the server stops reading and
+// the number it must drain to before reading resumes
+const HIGH_WATER: usize = 128;
+const LOW_WATER: usize = 64;
 
 /// A server `Task` that dispatches `Transport` messages to a `Service` using
 /// protocol pipelining.
-pub struct Server<S, T>
+///
+/// Responses are written in the order the requests were read, see
+/// `ResponseOrder::Fifo`.
+pub struct Server<S, T, M = ()>
     where S: Service,
+          T: Transport,
 {
     run: bool,
     service: S,
     transport: T,
     in_flight: AwaitQueue<S::Fut>,
+    // Error returned by `Transport::read`, reported once in-flight responses
+    // have been written
+    read_error: Option<io::Error>,
+    // Error returned by `Service::poll_ready`, written to the transport once
+    // in-flight responses have been written
+    service_error: Option<S::Error>,
+    // Shutdown signal, only created once a `ShutdownHandle` is requested
+    shutdown: Option<(channel::Sender<()>, Receiver<()>)>,
+    // Max number of requests read in a single tick
+    max_reads_per_tick: usize,
+    // Stop reading once this many requests are in flight
+    high_water: usize,
+    // Resume reading once in-flight requests have drained to this many
+    low_water: usize,
+    // True when reading is paused due to the high-water mark
+    paused: bool,
+    // Waiting on the server to finish flushing
+    flushed: Flushed,
+    metrics: M,
+    // Stop reading once this many requests have been read
+    max_requests: Option<usize>,
+    // Number of requests read. Requests are tagged with a per-connection
+    // sequence number in the logs, responses are written in the same order so
+    // counting responses is enough to correlate the two.
+    requests: usize,
+    responses: usize,
+    // How `Frame::Error` read from the transport is handled
+    on_frame_error: FrameErrorPolicy,
+    hooks: Hooks<S, T>,
+    // Set once `Transport::close` has been called
+    closed: bool,
+    // Set once an upgrade has been requested
+    upgraded: bool,
+    // Replies to control frames, tagged with the number of requests read
+    // before the control frame. A reply is written once the responses to
+    // those requests have been.
+    control_replies: VecDeque<(usize, S::Resp)>,
+    // Only read a request once the previous response has been flushed
+    half_duplex: bool,
+    // Set while written data may not have been flushed yet
+    dirty: bool,
+    // Only the per-request traces of one in this many requests are logged
+    sample_traces: usize,
+}
+
+// The callbacks applied to each request and response
+struct Hooks<S, T>
+    where S: Service,
+          T: Transport,
+{
+    // Turns responses into transport messages right before they are written
+    map_response: Box<FnMut(S::Resp) -> T::In + Send>,
+    // Turns service errors into errors written to the transport, or into the
+    // error closing the connection
+    on_service_error: Box<FnMut(S::Error) -> Result<T::Error, io::Error> + Send>,
+    // Requests after which the connection is upgraded
+    upgrade: Option<Box<Fn(&S::Req) -> bool + Send>>,
+    // Requests not matching the filter are dropped without being dispatched
+    filter: Option<Box<Fn(&S::Req) -> bool + Send>>,
+    // Handles the control frames read from the transport
+    control: Option<Box<FnMut(<T::Frame as PipelineFrame>::Control) -> Option<S::Resp> + Send>>,
+}
+
+// Writes service errors to the transport
+fn error_frame<E, U: From<E>>(e: E) -> Result<U, io::Error> {
+    Ok(U::from(e))
+}
+
+// Closes the connection on service errors the transport can't represent
+fn close_on_error<E, U>(_: E) -> Result<U, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "service error"))
+}
+
+// Completes with an error if the server is dropped before flushing
+struct Flushed(Vec<Complete<(), io::Error>>);
+
+/// How a pipeline `Server` handles a `Frame::Error` read from the transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameErrorPolicy {
+    /// Close the connection. This is the default.
+    Close,
+    /// Discard the frame and continue reading.
+    ///
+    /// No response is written for a skipped frame. This is only correct for
+    /// protocols that are able to resync after a corrupt frame and where the
+    /// peer does not expect a response to it, otherwise the responses to the
+    /// following requests would be paired with the wrong requests.
+    Skip,
+}
+
+/// The order in which a pipeline `Server` writes responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseOrder {
+    /// Responses are written in the order the requests were read.
+    ///
+    /// Pipelining peers pair responses with requests by their position, so
+    /// a response that completes before the responses to earlier requests
+    /// waits for them to be written first. A slow request holds back the
+    /// responses to all the requests read after it.
+    Fifo,
+}
+
+/// Callbacks invoked by a pipeline `Server` as it processes requests.
+///
+/// All callbacks default to doing nothing, and `()` implements the trait
+/// without any overhead.
+pub trait ServerMetrics: Send + 'static {
+    /// Called when a request is read from the transport.
+    fn on_request(&mut self) {}
+
+    /// Called when a response, or an error in place of a response, is
+    /// written to the transport.
+    fn on_response(&mut self) {}
+
+    /// Called once per tick with the number of in-flight requests.
+    fn on_queue_depth(&mut self, _depth: usize) {}
+}
+
+impl ServerMetrics for () {}
+
+/// Signals a pipeline `Server` to stop reading new requests.
+///
+/// Requests that have already been read are still processed and their
+/// responses written before the server completes.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: channel::Sender<()>,
 }
 
 
 impl<S, T> Server<S, T>
     where S: Service,
+          T: Transport<In = S::Resp>,
+          T::Error: From<S::Error>,
 {
     /// Create a new pipeline `Server` dispatcher with the given service and
     /// transport
     pub fn new(service: S, transport: T) -> io::Result<Server<S, T>> {
+        Server::with_capacity(service, transport, 16)
+    }
+
+    /// Create a new pipeline `Server` dispatcher with the given service and
+    /// transport, tracking up to `capacity` in-flight requests before the
+    /// internal queue reallocates.
+    ///
+    /// The capacity is a sizing hint, not a limit on the number of requests
+    /// that may be concurrently dispatched. A peer pipelining more than
+    /// `capacity` requests before reading any responses will cause the queue
+    /// to grow.
+    pub fn with_capacity(service: S, transport: T, capacity: usize) -> io::Result<Server<S, T>> {
+        Server::build(service,
+                      transport,
+                      capacity,
+                      (),
+                      Box::new(|resp: S::Resp| resp),
+                      Box::new(error_frame::<S::Error, T::Error>))
+    }
+
+    /// Create a new pipeline `Server` dispatcher with the given service and
+    /// transport, reporting activity to `metrics`.
+    pub fn with_metrics<M>(service: S, transport: T, metrics: M) -> io::Result<Server<S, T, M>>
+        where M: ServerMetrics,
+    {
+        Server::build(service,
+                      transport,
+                      16,
+                      metrics,
+                      Box::new(|resp: S::Resp| resp),
+                      Box::new(error_frame::<S::Error, T::Error>))
+    }
+}
+
+impl<S, T> Server<S, T>
+    where S: Service,
+          T: Transport,
+{
+    /// Create a new pipeline `Server` dispatcher with the given service and
+    /// transport, writing `f(resp)` in place of every response.
+    ///
+    /// Unlike `new`, the service's responses do not need to be of the type
+    /// written to the transport, see `map_response`, and neither do its
+    /// errors. Service errors close the connection unless `on_service_error`
+    /// is set.
+    pub fn with_map_response<F>(service: S, transport: T, f: F) -> io::Result<Server<S, T>>
+        where F: FnMut(S::Resp) -> T::In + Send + 'static,
+    {
+        Server::build(service, transport, 16, (), Box::new(f), Box::new(close_on_error::<S::Error, T::Error>))
+    }
+}
+
+impl<S, T, M> Server<S, T, M>
+    where S: Service,
+          T: Transport,
+{
+    fn build(service: S,
+             transport: T,
+             capacity: usize,
+             metrics: M,
+             map_response: Box<FnMut(S::Resp) -> T::In + Send>,
+             on_service_error: Box<FnMut(S::Error) -> Result<T::Error, io::Error> + Send>) -> io::Result<Server<S, T, M>> {
         Ok(Server {
             run: true,
             service: service,
             transport: transport,
-            in_flight: try!(AwaitQueue::with_capacity(16)),
+            in_flight: try!(AwaitQueue::with_capacity(capacity)),
+            read_error: None,
+            service_error: None,
+            shutdown: None,
+            max_reads_per_tick: MAX_READS_PER_TICK,
+            high_water: HIGH_WATER,
+            low_water: LOW_WATER,
+            paused: false,
+            flushed: Flushed(vec![]),
+            metrics: metrics,
+            max_requests: None,
+            requests: 0,
+            responses: 0,
+            on_frame_error: FrameErrorPolicy::Close,
+            hooks: Hooks {
+                map_response: map_response,
+                on_service_error: on_service_error,
+                upgrade: None,
+                filter: None,
+                control: None,
+            },
+            closed: false,
+            upgraded: false,
+            control_replies: VecDeque::new(),
+            half_duplex: false,
+            // The transport may hold data written before the server was
+            // created
+            dirty: true,
+            sample_traces: 1,
         })
     }
+
+    /// Set the max number of requests read from the transport in a single
+    /// tick.
+    ///
+    /// Once reached, the server yields to other tasks on the reactor and
+    /// resumes reading on its next tick. Defaults to 32.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is zero.
+    pub fn max_reads_per_tick(mut self, val: usize) -> Self {
+        assert!(val > 0, "max reads per tick must be positive");
+
+        self.max_reads_per_tick = val;
+        self
+    }
+
+    /// Set how `Frame::Error` read from the transport is handled.
+    ///
+    /// Defaults to `FrameErrorPolicy::Close`. See `FrameErrorPolicy::Skip`
+    /// for the requirements on the protocol before skipping errors.
+    pub fn on_frame_error(mut self, policy: FrameErrorPolicy) -> Self {
+        self.on_frame_error = policy;
+        self
+    }
+
+    /// Transform every response right before it is written to the transport.
+    ///
+    /// This is lighter than wrapping the service with `MapResponse` for
+    /// simple last-mile changes, such as adding a header. Replies to control
+    /// frames are passed to `f` as well, errors written in place of a
+    /// response are not.
+    ///
+    /// `f` replaces any transform set earlier. To write responses of another
+    /// type than the service's, create the server with `with_map_response`.
+    pub fn map_response<F>(mut self, f: F) -> Self
+        where F: FnMut(S::Resp) -> T::In + Send + 'static,
+    {
+        self.hooks.map_response = Box::new(f);
+        self
+    }
+
+    /// Set how the errors returned by the service are handled.
+    ///
+    /// `f` turns each error into either an error written to the transport in
+    /// place of the response, or the `io::Error` closing the connection. This
+    /// applies to the errors returned by `Service::poll_ready` as well.
+    ///
+    /// Servers created with `new`, `with_capacity` or `with_metrics` write
+    /// the errors converted with `From`. Servers created with
+    /// `with_map_response` close the connection.
+    ///
+    /// When the connection is closed, the responses to the requests read
+    /// before the failed one are still written, the requests read after it
+    /// are dropped.
+    pub fn on_service_error<F>(mut self, f: F) -> Self
+        where F: FnMut(S::Error) -> Result<T::Error, io::Error> + Send + 'static,
+    {
+        self.hooks.on_service_error = Box::new(f);
+        self
+    }
+
+    /// Only dispatch the requests matching `f`.
+    ///
+    /// Requests for which `f` returns false, such as keepalive frames, are
+    /// dropped as they are read. They are not passed to the service and no
+    /// response is written for them.
+    pub fn filter<F>(mut self, f: F) -> Self
+        where F: Fn(&S::Req) -> bool + Send + 'static,
+    {
+        self.hooks.filter = Some(Box::new(f));
+        self
+    }
+
+    /// Pass the control frames read from the transport to `f`.
+    ///
+    /// Frames for which `PipelineFrame::control` returns a control message,
+    /// such as pings or settings, are handed to `f` as they are read, instead
+    /// of being dispatched to the service. The reply returned by `f`, if any,
+    /// takes the place of a response: it is written after the responses to
+    /// the requests read before the control frame, and before the responses
+    /// to the requests read after it.
+    ///
+    /// Without a handler, control frames are dropped.
+    pub fn on_control<F>(mut self, f: F) -> Self
+        where F: FnMut(<T::Frame as PipelineFrame>::Control) -> Option<S::Resp> + Send + 'static,
+    {
+        self.hooks.control = Some(Box::new(f));
+        self
+    }
+
+    /// Only read a request once the response to the previous one has been
+    /// fully flushed to the transport.
+    ///
+    /// By default, the server keeps reading requests while earlier responses
+    /// are still being computed or written. Strictly half-duplex protocols,
+    /// where the peer is not able to handle pipelining, require the server
+    /// to wait instead.
+    pub fn half_duplex(mut self) -> Self {
+        self.half_duplex = true;
+        self
+    }
+
+    /// Upgrade the connection after requests matching `f`.
+    ///
+    /// Once a request matching `f` is read, the server stops reading and
+    /// completes after writing the responses, leaving any data following the
+    /// request buffered in the transport. `is_upgraded` then returns true,
+    /// and `into_inner` recovers the transport so that it can be handed to
+    /// the new protocol. The transport is not closed.
+    ///
+    /// Recovering the transport requires driving the server from another
+    /// task, since the reactor drops completed tasks.
+    pub fn upgrade_on<F>(mut self, f: F) -> Self
+        where F: Fn(&S::Req) -> bool + Send + 'static,
+    {
+        self.hooks.upgrade = Some(Box::new(f));
+        self
+    }
+
+    /// Returns the order in which responses are written
+    pub fn response_order(&self) -> ResponseOrder {
+        ResponseOrder::Fifo
+    }
+
+    /// Returns true if the server stopped reading because an upgrade was
+    /// requested. See `upgrade_on`.
+    pub fn is_upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    /// Consume the server, returning its service and transport.
+    ///
+    /// This is usually called once `tick` returned `Tick::Final` with an
+    /// upgrade requested. Responses that are still in flight are dropped.
+    pub fn into_inner(self) -> (S, T) {
+        (self.service, self.transport)
+    }
+
+    /// Only log the per-request trace lines of one in every `n` requests.
+    ///
+    /// This keeps the traces of busy connections readable. Requests are
+    /// sampled by their sequence number, so the lines of a sampled request
+    /// and of its response are logged together and can still be correlated.
+    /// Defaults to 1, logging every request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn sample_traces(mut self, n: usize) -> Self {
+        assert!(n > 0, "trace sample rate must be positive");
+
+        self.sample_traces = n;
+        self
+    }
+
+    /// Set the max number of requests handled by the server.
+    ///
+    /// Once `val` requests have been read, the server stops reading and
+    /// completes after writing their responses. Setting this to 1 implements
+    /// a request / reply once protocol. By default, there is no limit.
+    ///
+    /// The server is still tracked by the reactor as a regular task, since it
+    /// has to wait for the request to arrive and for the response to
+    /// complete.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is zero.
+    pub fn max_requests(mut self, val: usize) -> Self {
+        assert!(val > 0, "max requests must be positive");
+
+        self.max_requests = Some(val);
+        self
+    }
+
+    /// Set the in-flight request water marks.
+    ///
+    /// Once `high` requests are in flight, the server stops reading from the
+    /// transport, applying backpressure to the peer, until the number of
+    /// in-flight requests has drained to `low`. Defaults to 128 and 64.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `low` is greater than `high` or `high` is zero.
+    pub fn in_flight_water_marks(mut self, high: usize, low: usize) -> Self {
+        assert!(high > 0, "high-water mark must be positive");
+        assert!(low <= high, "low-water mark must not exceed the high-water mark");
+
+        self.high_water = high;
+        self.low_water = low;
+        self
+    }
+
+    /// Returns a handle that may be used to gracefully shutdown the server
+    /// from another task or thread.
+    pub fn shutdown_handle(&mut self) -> io::Result<ShutdownHandle> {
+        if self.shutdown.is_none() {
+            let (tx, rx) = channel::channel();
+            let rx = try!(Receiver::watch(rx));
+
+            self.shutdown = Some((tx, rx));
+        }
+
+        let tx = self.shutdown.as_ref().unwrap().0.clone();
+        Ok(ShutdownHandle { tx: tx })
+    }
+
+    /// Returns a future that completes once the server has stopped reading
+    /// requests and every response has been flushed to the transport.
+    ///
+    /// Combined with a `ShutdownHandle`, this allows waiting for a connection
+    /// to drain before closing it. Partial flushes reported by the transport
+    /// do not complete the future. If the server fails or is dropped first,
+    /// the future completes with an error.
+    pub fn flushed(&mut self) -> Val<(), io::Error> {
+        let (c, val) = future::pair();
+        self.flushed.0.push(c);
+        val
+    }
+
+    // Returns the next control reply, if the responses to the requests read
+    // before its control frame have all been written
+    fn next_control_reply(&mut self) -> Option<T::In> {
+        match self.control_replies.front() {
+            Some(&(seq, _)) if seq <= self.responses => {}
+            _ => return None,
+        }
+
+        let (_, reply) = self.control_replies.pop_front().unwrap();
+        Some((self.hooks.map_response)(reply))
+    }
+
+    // Returns true if the traces of the request with the given sequence
+    // number are logged
+    fn sampled(&self, seq: usize) -> bool {
+        seq % self.sample_traces == 0
+    }
+
+    fn poll_shutdown(&mut self) {
+        let signaled = match self.shutdown {
+            Some((_, ref rx)) => rx.recv().ok().and_then(|v| v).is_some(),
+            None => false,
+        };
+
+        if signaled {
+            trace!("pipeline server received shutdown signal");
+            self.run = false;
+            self.shutdown = None;
+        }
+    }
 }
 
-impl<S, T, E> Task for Server<S, T>
+impl Drop for Flushed {
+    fn drop(&mut self) {
+        for c in self.0.drain(..) {
+            c.error(io::Error::new(io::ErrorKind::BrokenPipe, "server closed before flushing"));
+        }
+    }
+}
+
+/// A `NewTask` that dispatches connections to pipeline `Server` tasks.
+///
+/// Created by `new_task`.
+pub struct ServerFactory<S, T> {
+    new_service: S,
+    new_transport: T,
+}
+
+/// Returns a `NewTask` that handles each connection with a pipeline `Server`.
+///
+/// For every accepted `TcpStream`, a transport is created using
+/// `new_transport` and a fresh service instance is created using
+/// `new_service`.
+///
+/// ```rust,ignore
+/// server::listen(&reactor.handle(), addr, pipeline::new_task(service, LineTransport::new));
+/// ```
+pub fn new_task<S, T>(new_service: S, new_transport: T) -> ServerFactory<S, T>
+    where S: NewService,
+          T: NewTransport,
+{
+    ServerFactory {
+        new_service: new_service,
+        new_transport: new_transport,
+    }
+}
+
+/// Listen on `addr`, handling each connection with a pipeline `Server`.
+///
+/// This is the same as calling `server::listen` with `new_task`.
+///
+/// ```rust,ignore
+/// pipeline::serve(&reactor.handle(), addr, service, LineTransport::new);
+/// ```
+pub fn serve<S, T>(reactor: &ReactorHandle,
+                   addr: SocketAddr,
+                   new_service: S,
+                   new_transport: T) -> io::Result<ServerHandle>
+    where S: NewService + Send + 'static,
+          T: NewTransport<In = S::Resp, Out = S::Req>,
+          T::Error: From<S::Error>,
+          S::Error: From<Error<T::Error>>,
+{
+    server::listen(reactor, addr, new_task(new_service, new_transport))
+}
+
+impl<S, T> NewTask for ServerFactory<S, T>
+    where S: NewService + Send + 'static,
+          T: NewTransport<In = S::Resp, Out = S::Req>,
+          T::Error: From<S::Error>,
+          S::Error: From<Error<T::Error>>,
+{
+    type Item = Server<S::Item, T::Item>;
+
+    fn new_task(&self, stream: TcpStream) -> io::Result<Self::Item> {
+        let service = try!(self.new_service.new_service());
+        let transport = try!(self.new_transport.new_transport(stream));
+
+        Server::new(service, transport)
+    }
+}
+
+impl ShutdownHandle {
+    /// Stop the server from reading new requests.
+    ///
+    /// Does nothing if the server has already completed.
+    pub fn shutdown(&self) {
+        let _ = self.tx.send(());
+    }
+}
+
+impl<S, T, M, E> Task for Server<S, T, M>
     where S: Service<Error = E>,
-          T: Transport<In=S::Resp, Out=S::Req>,
+          T: Transport<Out=S::Req>,
           E: From<Error<T::Error>> + Send + 'static,
+          M: ServerMetrics,
 {
     fn tick(&mut self) -> io::Result<Tick> {
         trace!("pipeline::Server::tick");
 
-        // The first action is always flushing the transport
-        let mut flush = try!(self.transport.flush());
+        // The first action is always flushing the transport, unless nothing
+        // has been written since the last complete flush
+        let mut flush = if self.dirty {
+            try!(self.transport.flush())
+        } else {
+            Some(())
+        };
+
+        self.dirty = flush.is_none();
 
-        // Handle completed responses
-        while self.transport.is_writable() {
+        // Handle completed responses. All the responses that are ready are
+        // written as a single batch, allowing the transport to coalesce them.
+        if self.transport.is_writable() {
             trace!("pipeline transport is writable");
 
-            // Try to get the next completed future
-            match self.in_flight.poll() {
-                Some(Ok(val)) => {
-                    trace!("got in_flight value");
-                    flush = try!(self.transport.write(Frame::Message(val)));
+            let mut batch = vec![];
+            let mut failed = None;
+            let first = self.responses;
+
+            // Get all the completed futures
+            for res in self.in_flight.poll_all() {
+                while let Some(reply) = self.next_control_reply() {
+                    batch.push(Frame::Message(reply));
                 }
-                Some(Err(_)) => unimplemented!(),
-                None => {
-                    trace!("no response ready for write");
-                    break;
+
+                let sampled = self.sampled(self.responses);
+
+                match res {
+                    Ok(val) => {
+                        if sampled {
+                            trace!("got in_flight value; seq={}", self.responses);
+                        }
+
+                        batch.push(Frame::Message((self.hooks.map_response)(val)));
+                    }
+                    Err(e) => {
+                        // Responses are written in the order the requests
+                        // were received, so writing the error in place of
+                        // the response keeps the remaining in-flight
+                        // requests paired with their responses.
+                        if sampled {
+                            trace!("got in_flight error; seq={}", self.responses);
+                        }
+
+                        match (self.hooks.on_service_error)(e) {
+                            Ok(e) => batch.push(Frame::Error(e)),
+                            Err(e) => {
+                                failed = Some(e);
+                                break;
+                            }
+                        }
+                    }
                 }
+
+                self.responses += 1;
+                self.metrics.on_response();
+            }
+
+            while let Some(reply) = self.next_control_reply() {
+                batch.push(Frame::Message(reply));
+            }
+
+            if batch.is_empty() {
+                trace!("no response ready for write");
+            } else {
+                trace!("writing response batch; len={}", batch.len());
+                flush = try!(self.transport.write_batch(batch));
+                self.dirty = flush.is_none();
+                trace!("pipeline wrote responses; seq={}..{}", first, self.responses);
             }
+
+            if let Some(e) = failed {
+                // The responses written above are flushed on a best effort
+                // basis, the requests still in flight are dropped with the
+                // server
+                debug!("service error closes the connection; seq={}", self.responses);
+                try!(self.transport.flush());
+                return Err(e);
+            }
+        }
+
+        if self.run {
+            self.poll_shutdown();
+        }
+
+        if self.run && try!(reactor::is_draining()) {
+            // Finish the requests in flight, but don't read new ones
+            trace!("reactor draining; pipeline server stops reading");
+            self.run = false;
+        }
+
+        if self.paused && self.in_flight.len() <= self.low_water {
+            trace!("pipeline server resuming reads; in_flight={}", self.in_flight.len());
+            self.paused = false;
         }
 
+        let mut reads = 0;
+        let mut yielding = false;
+
         // Process new requests as long as the server is accepting
-        while self.run {
-            trace!("pipeline trying to read transport");
+        while self.run && !self.paused {
+            if self.half_duplex && (flush.is_none() || !self.in_flight.is_empty()) {
+                // The transport notifies the task once the response is
+                // flushed, as does the in-flight queue once it completes.
+                trace!("pipeline server waiting on response; in_flight={}", self.in_flight.len());
+                break;
+            }
+
+            if self.in_flight.len() >= self.high_water {
+                // Stop reading until the service catches up. The in-flight
+                // queue notifies the task as responses complete.
+                trace!("pipeline server pausing reads; in_flight={}", self.in_flight.len());
+                self.paused = true;
+                break;
+            }
+
+            if reads == self.max_reads_per_tick {
+                // The transport may still have requests to read, but don't
+                // starve the other tasks on the reactor.
+                trace!("pipeline server yielding");
+                yielding = true;
+                break;
+            }
+
+            // Only read a request once the service is able to process it
+            match self.service.poll_ready() {
+                Poll::Ok(()) => {}
+                Poll::NotReady => {
+                    trace!("pipeline service not ready");
+                    break;
+                }
+                Poll::Err(e) => {
+                    trace!("pipeline service failed");
+                    self.run = false;
+                    self.service_error = Some(e);
+                    break;
+                }
+            }
+
+            if self.sampled(self.requests) {
+                trace!("pipeline trying to read transport");
+            }
+
             match self.transport.read() {
                 Ok(Some(frame)) => {
-                    match frame {
-                        Frame::Message(req) => {
-                            trace!("pipeline got request");
+                    reads += 1;
+
+                    let frame = match frame.as_message() {
+                        Ok(req) => {
+                            if !self.hooks.filter.as_ref().map_or(true, |f| f(&req)) {
+                                trace!("pipeline filtered request");
+                                continue;
+                            }
+
+                            let seq = self.requests;
+                            let sampled = self.sampled(seq);
+
+                            if sampled {
+                                trace!("pipeline got request; seq={}", seq);
+                            }
+
+                            self.metrics.on_request();
+
+                            let upgrade = self.hooks.upgrade.as_ref().map_or(false, |f| f(&req));
+
                             let resp = self.service.call(req);
-                            self.in_flight.push(resp)
+                            self.in_flight.push(resp);
+
+                            if sampled {
+                                trace!("pipeline dispatched request; seq={}; in_flight={}", seq, self.in_flight.len());
+                            }
+
+                            self.requests += 1;
+
+                            if upgrade {
+                                trace!("pipeline upgrade requested; seq={}", seq);
+                                self.upgraded = true;
+                                self.run = false;
+                                break;
+                            }
+
+                            if Some(self.requests) == self.max_requests {
+                                trace!("pipeline server read max requests");
+                                self.run = false;
+                                break;
+                            }
+
+                            continue;
                         }
-                        Frame::Done => {
-                            trace!("received Frame::Done");
-                            // At this point, we just return. This works
-                            // because tick() will be called again and go
-                            // through the read-cycle again.
-                            self.run = false;
-                            break;
+                        Err(frame) => frame,
+                    };
+
+                    if frame.is_done() {
+                        trace!("transport is done");
+                        // At this point, we just return. This works because
+                        // tick() will be called again and go through the
+                        // read-cycle again. The peer will not send any more
+                        // requests, but the responses in flight are still
+                        // written.
+                        self.run = false;
+                        break;
+                    }
+
+                    let frame = match frame.as_error() {
+                        Ok(_) => {
+                            match self.on_frame_error {
+                                FrameErrorPolicy::Close => {
+                                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "An error occurred."));
+                                }
+                                FrameErrorPolicy::Skip => {
+                                    debug!("skipping error frame; seq={}", self.requests);
+                                }
+                            }
+
+                            continue;
+                        }
+                        Err(frame) => frame,
+                    };
+
+                    match frame.control() {
+                        Ok(control) => {
+                            trace!("pipeline got control frame; seq={}", self.requests);
+
+                            let reply = match self.hooks.control {
+                                Some(ref mut f) => f(control),
+                                None => {
+                                    debug!("no control frame handler; dropping frame");
+                                    None
+                                }
+                            };
+
+                            if let Some(reply) = reply {
+                                self.control_replies.push_back((self.requests, reply));
+                            }
                         }
-                        Frame::Error(_) => {
-                            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "An error occurred."));
+                        Err(_) => {
+                            debug!("dropping unrecognized frame; seq={}", self.requests);
                         }
                     }
                 }
                 Ok(None) => break,
-                Err(e) => panic!(e.to_string()),
+                Err(e) => {
+                    trace!("transport read failed; err={:?}", e);
+                    // Stop reading, but hold on to the error until the
+                    // responses that are already in flight have been written
+                    self.run = false;
+                    self.read_error = Some(e);
+                    break;
+                }
             }
         }
 
+        self.metrics.on_queue_depth(self.in_flight.len());
+
+        // Reply to the control frames read after the last in-flight response
+        // was written
+        if self.transport.is_writable() {
+            let mut batch = vec![];
+
+            while let Some(reply) = self.next_control_reply() {
+                batch.push(Frame::Message(reply));
+            }
+
+            if !batch.is_empty() {
+                trace!("writing control replies; len={}", batch.len());
+                flush = try!(self.transport.write_batch(batch));
+                self.dirty = flush.is_none();
+            }
+        }
+
+        // The service failure is written in place of the response to the
+        // next request, after the responses to the requests read before it
+        if self.in_flight.is_empty() && self.transport.is_writable() {
+            if let Some(e) = self.service_error.take() {
+                match (self.hooks.on_service_error)(e) {
+                    Ok(e) => {
+                        trace!("writing service error");
+                        flush = try!(self.transport.write(Frame::Error(e)));
+                        self.dirty = flush.is_none();
+                    }
+                    Err(e) => {
+                        debug!("service error closes the connection");
+                        try!(self.transport.flush());
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        // `flush` holds the result of the last write, but once the server is
+        // done running, retry an incomplete flush before checking whether it
+        // is able to complete. The transport may have caught up while the
+        // requests were being read.
+        if !self.run && self.dirty {
+            flush = try!(self.transport.flush());
+            self.dirty = flush.is_none();
+        }
+
         // Clean shutdown of the pipeline server can happen when
         //
         // 1. The server is done running, this is signaled by Transport::read()
-        //    returning Frame::Done.
+        //    returning Frame::Done, Frame::Eof or an error, or by a
+        //    `ShutdownHandle`.
         //
         // 2. The transport is done writing all data to the socket, this is
         //    signaled by Transport::flush() returning Ok(Some(())).
@@ -102,11 +921,539 @@ impl<S, T, E> Task for Server<S, T>
         // It is necessary to perfom these three checks in order to handle the
         // case where the client shuts down half the socket.
         //
-        if !self.run && flush.is_some() && self.in_flight.is_empty() {
+        // Once they hold, the transport is closed, unless reading from it
+        // failed, and the server completes when the close is flushed.
+        //
+        if !self.run && flush.is_some() && self.in_flight.is_empty() && self.control_replies.is_empty() && self.service_error.is_none() && !self.closed {
+            self.closed = true;
+
+            // An upgraded transport is handed off as is
+            if self.read_error.is_none() && !self.upgraded {
+                trace!("closing transport");
+                flush = try!(self.transport.close());
+                self.dirty = flush.is_none();
+            }
+        }
+
+        if !self.run && flush.is_some() && self.in_flight.is_empty() && self.control_replies.is_empty() && self.service_error.is_none() {
+            for c in self.flushed.0.drain(..) {
+                c.complete(());
+            }
+
+            if let Some(e) = self.read_error.take() {
+                return Err(e);
+            }
+
+            // The peer sees a clean end of stream once the last response has
+            // been flushed, before the socket is dropped
+            if !self.upgraded {
+                trace!("shutting down transport");
+                try!(self.transport.shutdown());
+            }
+
             return Ok(Tick::Final);
         }
 
+        if yielding {
+            return Ok(Tick::Yield);
+        }
+
         // Tick again later
         Ok(Tick::WouldBlock)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ResponseOrder, Server, ServerMetrics};
+    use {Service};
+    use proto::pipeline::Frame;
+    use reactor::{self, Reactor, Task, Tick};
+    use simple_service;
+    use util::future::{self, Complete, Val};
+    use util::test::{self, MockTransport};
+    use std::io;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    type Msg = Frame<u32, io::Error>;
+
+    #[test]
+    fn test_logs_correlate_requests_and_responses() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let mut frames: Vec<Msg> = (0..3).map(Frame::Message).collect();
+            frames.push(Frame::Done);
+
+            let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+            let transport = MockTransport::new(frames);
+            let mock = transport.clone();
+
+            let mut server = try!(Server::new(service, transport));
+
+            // Only the lines logged by this reactor thread are captured
+            test::capture_logs();
+
+            // The responses complete immediately, so ticking the server
+            // directly drives it to completion
+            loop {
+                if let Tick::Final = try!(server.tick()) {
+                    break;
+                }
+            }
+
+            let lines = test::captured_logs("tokio::proto::pipeline::server");
+            tx.send((mock.take_written().len(), lines)).unwrap();
+            Ok(())
+        });
+
+        let (written, lines) = rx.recv().unwrap();
+        assert_eq!(3, written);
+        handle.shutdown();
+
+        let position = |line: String| {
+            lines.iter().position(|l| *l == line)
+                .expect(&format!("missing log line: {}", line))
+        };
+
+        for seq in 0..3 {
+            let request = position(format!("pipeline got request; seq={}", seq));
+            let response = position(format!("got in_flight value; seq={}", seq));
+
+            assert!(request < response);
+        }
+    }
+
+    #[test]
+    fn test_flushes_only_after_writes() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+            let transport = MockTransport::new(vec![]);
+            let mock = transport.clone();
+
+            let mut server = try!(Server::new(service, transport));
+            let mut flushes = vec![];
+
+            try!(server.tick());
+            let base = mock.flushes();
+
+            // Nothing was written
+            try!(server.tick());
+            flushes.push(mock.flushes() - base);
+
+            // The request is read, then its response is written without
+            // being fully flushed
+            mock.set_flushed(false);
+            mock.push_read(Frame::Message(1));
+            try!(server.tick());
+            try!(server.tick());
+            flushes.push(mock.flushes() - base);
+
+            // The incomplete flush is retried until it completes
+            try!(server.tick());
+            flushes.push(mock.flushes() - base);
+
+            mock.set_flushed(true);
+            try!(server.tick());
+            flushes.push(mock.flushes() - base);
+
+            try!(server.tick());
+            flushes.push(mock.flushes() - base);
+
+            tx.send(flushes).unwrap();
+            Ok(())
+        });
+
+        assert_eq!(vec![0, 0, 1, 2, 2], rx.recv().unwrap());
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_sampled_traces() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let mut frames: Vec<Msg> = (0..100).map(Frame::Message).collect();
+            frames.push(Frame::Done);
+
+            let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+            let transport = MockTransport::new(frames);
+
+            let mut server = try!(Server::new(service, transport)).sample_traces(10);
+
+            test::capture_logs();
+
+            loop {
+                if let Tick::Final = try!(server.tick()) {
+                    break;
+                }
+            }
+
+            tx.send(test::captured_logs("tokio::proto::pipeline::server")).unwrap();
+            Ok(())
+        });
+
+        let lines = rx.recv().unwrap();
+        handle.shutdown();
+
+        let logged = |line: String| lines.iter().any(|l| *l == line);
+
+        for seq in 0..100 {
+            let sampled = seq % 10 == 0;
+
+            assert_eq!(sampled, logged(format!("pipeline got request; seq={}", seq)));
+            assert_eq!(sampled, logged(format!("got in_flight value; seq={}", seq)));
+        }
+    }
+
+    #[test]
+    fn test_done_waits_for_queued_responses_to_flush() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+            let transport = MockTransport::new(vec![Frame::Message(1)]);
+            let mock = transport.clone();
+            let flusher = transport.clone();
+
+            mock.set_flushed(false);
+
+            // Request 2 is read after the response to request 1 has been
+            // written in the same tick. Reading it lets the transport catch up
+            // on the write, without dispatching it.
+            let mut server = try!(Server::new(service, transport)).filter(move |req| {
+                if *req == 2 {
+                    flusher.set_flushed(true);
+                    return false;
+                }
+
+                true
+            });
+
+            let mut ticks = vec![];
+
+            let is_final = |tick: Tick| {
+                match tick {
+                    Tick::Final => true,
+                    _ => false,
+                }
+            };
+
+            // Request 1 is read
+            ticks.push(is_final(try!(server.tick())));
+
+            // Its response is written without being flushed, then request 2
+            // and `Frame::Done` are read. The incomplete flush is retried
+            // before checking whether the server is done.
+            mock.push_read(Frame::Message(2));
+            mock.push_read(Frame::Done);
+            ticks.push(is_final(try!(server.tick())));
+
+            let written: Vec<u32> = mock.take_written().into_iter()
+                .filter_map(|frame| {
+                    match frame {
+                        Frame::Message(v) => Some(v),
+                        _ => None,
+                    }
+                })
+                .collect();
+
+            tx.send((ticks, written)).unwrap();
+            Ok(())
+        });
+
+        let (ticks, written) = rx.recv().unwrap();
+
+        assert_eq!(vec![false, true], ticks);
+        assert_eq!(vec![1], written);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_shuts_down_transport_after_final_flush() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+            let transport = MockTransport::new(vec![Frame::Message(1), Frame::Done]);
+            let mock = transport.clone();
+
+            mock.set_flushed(false);
+
+            let mut server = try!(Server::new(service, transport));
+            let mut shutdowns = vec![];
+
+            // The response is written and the transport closed, but neither
+            // is flushed
+            try!(server.tick());
+            try!(server.tick());
+            shutdowns.push(mock.shutdowns());
+
+            mock.set_flushed(true);
+
+            let is_final = match try!(server.tick()) {
+                Tick::Final => true,
+                _ => false,
+            };
+
+            shutdowns.push(mock.shutdowns());
+
+            tx.send((shutdowns, is_final)).unwrap();
+            Ok(())
+        });
+
+        let (shutdowns, is_final) = rx.recv().unwrap();
+
+        assert!(is_final);
+        assert_eq!(vec![0, 1], shutdowns);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_responses_written_in_request_order() {
+        // Responses are completed by the test
+        struct Deferred {
+            pending: Arc<Mutex<Vec<Complete<u32, io::Error>>>>,
+        }
+
+        impl Service for Deferred {
+            type Req = u32;
+            type Resp = u32;
+            type Error = io::Error;
+            type Fut = Val<u32, io::Error>;
+
+            fn call(&self, _: u32) -> Val<u32, io::Error> {
+                let (c, val) = future::pair();
+                self.pending.lock().unwrap().push(c);
+                val
+            }
+        }
+
+        struct Events(mpsc::Sender<&'static str>);
+
+        impl ServerMetrics for Events {
+            fn on_request(&mut self) {
+                let _ = self.0.send("request");
+            }
+
+            fn on_response(&mut self) {
+                let _ = self.0.send("response");
+            }
+        }
+
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+        let pending = Arc::new(Mutex::new(vec![]));
+
+        let transport: MockTransport<Msg, Msg> = MockTransport::new(vec![Frame::Message(1), Frame::Message(2)]);
+        let mock = transport.clone();
+        let service = Deferred { pending: pending.clone() };
+
+        handle.oneshot(move || {
+            let server = try!(Server::with_metrics(service, transport, Events(tx)));
+            assert_eq!(ResponseOrder::Fifo, server.response_order());
+
+            try!(reactor::schedule(server));
+            Ok(())
+        });
+
+        assert_eq!("request", rx.recv().unwrap());
+        assert_eq!("request", rx.recv().unwrap());
+
+        let mut pending: Vec<_> = pending.lock().unwrap().drain(..).collect();
+        let second = pending.pop().unwrap();
+        let first = pending.pop().unwrap();
+
+        // The second response waits for the first one
+        second.complete(2);
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        assert!(mock.take_written().is_empty());
+
+        first.complete(1);
+        assert_eq!("response", rx.recv().unwrap());
+        assert_eq!("response", rx.recv().unwrap());
+
+        // The responses are counted before being written, wait for the
+        // server's tick to finish
+        let (done_tx, done_rx) = mpsc::channel();
+        handle.oneshot(move || done_tx.send(()).unwrap());
+        done_rx.recv().unwrap();
+
+        let written: Vec<u32> = mock.take_written().into_iter()
+            .filter_map(|frame| {
+                match frame {
+                    Frame::Message(v) => Some(v),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        assert_eq!(vec![1, 2], written);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_control_frames_are_passed_to_handler() {
+        use proto::pipeline::PipelineFrame;
+
+        // A protocol interleaving pings with its requests
+        enum ReqFrame {
+            Call(u32),
+            Ping(u32),
+            Done,
+        }
+
+        impl PipelineFrame for ReqFrame {
+            type Message = u32;
+            type Error = io::Error;
+            type Control = u32;
+
+            fn as_message(self) -> Result<u32, ReqFrame> {
+                match self {
+                    ReqFrame::Call(n) => Ok(n),
+                    frame => Err(frame),
+                }
+            }
+
+            fn is_done(&self) -> bool {
+                match *self {
+                    ReqFrame::Done => true,
+                    _ => false,
+                }
+            }
+
+            fn as_error(self) -> Result<io::Error, ReqFrame> {
+                Err(self)
+            }
+
+            fn control(self) -> Result<u32, ReqFrame> {
+                match self {
+                    ReqFrame::Ping(n) => Ok(n),
+                    frame => Err(frame),
+                }
+            }
+        }
+
+        // The response to the first request is completed by the test
+        struct Deferred {
+            pending: Arc<Mutex<Vec<Complete<u32, io::Error>>>>,
+        }
+
+        impl Service for Deferred {
+            type Req = u32;
+            type Resp = u32;
+            type Error = io::Error;
+            type Fut = Val<u32, io::Error>;
+
+            fn call(&self, n: u32) -> Val<u32, io::Error> {
+                let (c, val) = future::pair();
+
+                if n == 1 {
+                    self.pending.lock().unwrap().push(c);
+                } else {
+                    c.complete(n);
+                }
+
+                val
+            }
+        }
+
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let pending = Arc::new(Mutex::new(vec![]));
+            let service = Deferred { pending: pending.clone() };
+
+            let frames = vec![
+                ReqFrame::Call(1),
+                ReqFrame::Ping(7),
+                ReqFrame::Call(2),
+                ReqFrame::Done,
+            ];
+
+            let transport: MockTransport<Msg, ReqFrame> = MockTransport::new(frames);
+            let mock = transport.clone();
+
+            let pings = Arc::new(Mutex::new(vec![]));
+            let pings2 = pings.clone();
+
+            // Pings are answered with a pong carrying the ping's value
+            let mut server = try!(Server::new(service, transport))
+                .on_control(move |n| {
+                    pings2.lock().unwrap().push(n);
+                    Some(n * 100)
+                });
+
+            if let Tick::Final = try!(server.tick()) {
+                panic!("server completed with a request in flight");
+            }
+
+            // The pong waits for the response to the first request
+            let before = mock.take_written().len();
+
+            let c = pending.lock().unwrap().pop().unwrap();
+            c.complete(1);
+
+            loop {
+                if let Tick::Final = try!(server.tick()) {
+                    break;
+                }
+            }
+
+            let written: Vec<u32> = mock.take_written().into_iter()
+                .filter_map(|frame| {
+                    match frame {
+                        Frame::Message(v) => Some(v),
+                        _ => None,
+                    }
+                })
+                .collect();
+
+            let pings = pings.lock().unwrap().clone();
+
+            tx.send((before, written, pings)).unwrap();
+            Ok(())
+        });
+
+        let (before, written, pings) = rx.recv().unwrap();
+
+        assert_eq!(0, before);
+        assert_eq!(vec![1, 700, 2], written);
+        assert_eq!(vec![7], pings);
+
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,81 @@
+use reactor::{Task, Tick};
+use util::channel::Receiver;
+use mio::channel;
+use std::io;
+
+/// A handle to a task scheduled with `ReactorHandle::spawn`, used to cancel
+/// the task.
+#[derive(Clone)]
+pub struct TaskHandle {
+    tx: channel::Sender<()>,
+}
+
+/// Wraps a task, dropping it once cancelled
+pub struct Cancellable<T> {
+    task: T,
+    // The cancellation channel is only watched once the task is ticked on the
+    // reactor, so that the source is associated with the task
+    rx: Option<channel::Receiver<()>>,
+    cancel: Option<Receiver<()>>,
+}
+
+/// Returns a new `TaskHandle` along with the receiving end of its
+/// cancellation channel
+pub fn task_handle() -> (TaskHandle, channel::Receiver<()>) {
+    let (tx, rx) = channel::channel();
+    (TaskHandle { tx: tx }, rx)
+}
+
+impl TaskHandle {
+    /// Cancel the task.
+    ///
+    /// Instead of being ticked again, the task is dropped. Does nothing if
+    /// the task has already completed.
+    pub fn cancel(&self) {
+        let _ = self.tx.send(());
+    }
+}
+
+impl<T: Task> Cancellable<T> {
+    /// Wrap `task`, dropping it once a value is received on `rx`
+    pub fn new(task: T, rx: channel::Receiver<()>) -> Cancellable<T> {
+        Cancellable {
+            task: task,
+            rx: Some(rx),
+            cancel: None,
+        }
+    }
+
+    fn is_cancelled(&mut self) -> bool {
+        let res = match self.cancel {
+            Some(ref rx) => rx.recv(),
+            None => return false,
+        };
+
+        match res {
+            Ok(Some(())) => true,
+            Ok(None) => false,
+            Err(_) => {
+                // All handles have been dropped, the task can no longer be
+                // cancelled
+                self.cancel = None;
+                false
+            }
+        }
+    }
+}
+
+impl<T: Task> Task for Cancellable<T> {
+    fn tick(&mut self) -> io::Result<Tick> {
+        if let Some(rx) = self.rx.take() {
+            self.cancel = Some(try!(Receiver::watch(rx)));
+        }
+
+        if self.is_cancelled() {
+            trace!("task cancelled");
+            return Ok(Tick::Final);
+        }
+
+        self.task.tick()
+    }
+}
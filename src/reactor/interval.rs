@@ -0,0 +1,71 @@
+use reactor::{Task, Tick};
+use util::timer::Timer;
+use mio::timer;
+use std::io;
+use std::time::Duration;
+
+/// A task invoking a callback every time the period elapses.
+///
+/// The task is driven by a timer registered with the reactor, so it sleeps
+/// between invocations rather than being ticked repeatedly. The period is
+/// measured from when the callback was last invoked and is subject to the
+/// resolution of the timer.
+///
+/// The task runs until the callback returns an error.
+///
+/// ```rust,ignore
+/// handle.oneshot(|| {
+///     reactor::schedule(Interval::new(Duration::from_secs(1), || {
+///         println!("heartbeat");
+///         Ok(())
+///     })).unwrap();
+/// });
+/// ```
+pub struct Interval<F> {
+    period: Duration,
+    f: F,
+    // Created on the first tick, since it must be registered with the reactor
+    timer: Option<Timer<()>>,
+}
+
+impl<F> Interval<F>
+    where F: FnMut() -> io::Result<()>,
+{
+    /// Create a new `Interval` invoking `f` every `period`
+    pub fn new(period: Duration, f: F) -> Interval<F> {
+        Interval {
+            period: period,
+            f: f,
+            timer: None,
+        }
+    }
+}
+
+impl<F> Task for Interval<F>
+    where F: FnMut() -> io::Result<()>,
+{
+    fn tick(&mut self) -> io::Result<Tick> {
+        if self.timer.is_none() {
+            let mut timer = try!(Timer::watch(timer::Timer::default()));
+            try!(set_timeout(&mut timer, self.period));
+            self.timer = Some(timer);
+        }
+
+        let timer = self.timer.as_mut().unwrap();
+
+        while let Some(()) = timer.poll() {
+            trace!("interval elapsed");
+            try!((self.f)());
+            try!(set_timeout(timer, self.period));
+        }
+
+        Ok(Tick::WouldBlock)
+    }
+}
+
+fn set_timeout(timer: &mut Timer<()>, delay: Duration) -> io::Result<()> {
+    match timer.set_timeout(delay, ()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "failed to set timeout")),
+    }
+}
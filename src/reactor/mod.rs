@@ -46,6 +46,18 @@
 //! ready, the reactor schedules the task for execution again, at which
 //! time the Task will be able to make further progress.
 //!
+//! A task that is able to make more progress, but wants to give other tasks a
+//! chance to run first, returns `Ok(Tick::Yield)`. The reactor will tick the
+//! task again after processing the current round of ready tasks.
+//!
+//! # Fairness
+//!
+//! Each task is ticked at most once per round. A task that returns
+//! `Tick::WouldBlock` after making progress on its sources may have more work
+//! to do, so like a yielding task, it is queued to be ticked again after the
+//! other ready tasks. A task that always has more work to do cannot keep the
+//! others from running.
+//!
 //! When the task has completed its work, it returns `Ok(Tick::Final)`, at
 //! which time the reactor will drop the task and it will no longer be called
 //! again.
@@ -56,7 +68,7 @@
 //! with Tokio currently include:
 //!
 //! * `TcpListener` and `TcpStream` for working with the TCP protocol.
-//! * `AwaitQueue` for waiting for the completion of a future
+//! * `AwaitQueue` and `AwaitSet` for waiting for the completion of futures
 //! * `Receiver` for waiting for messages on a channel.
 //! * `Timer` for getting notified after a set time interval.
 //!
@@ -92,25 +104,34 @@
 //! `Ok(None)` would-block. Given this, it is important to consume the source
 //! as long as it is ready.
 
+mod cancel;
+mod interval;
 mod reactor;
 mod source;
 mod task;
 
+pub use self::cancel::TaskHandle;
+pub use self::interval::Interval;
 pub use self::reactor::{
     Config,
     Reactor,
     ReactorHandle,
+    ReactorStats,
     Error,
     Result,
     schedule,
     oneshot,
     register_source,
+    did_advance,
     shutdown,
+    drain,
+    is_draining,
 };
 pub use self::source::Source;
 pub use self::task::{
     Task,
     Tick,
+    Computation,
     NewTask,
     IntoTick,
 };
@@ -1,14 +1,19 @@
 //! The non-blocking event driven core of Tokio
 //!
 use io::Ready;
-use reactor::task::{Task, IntoTick, Tick};
+use reactor::cancel::{self, Cancellable, TaskHandle};
+use reactor::task::{Computation, Computing, Task, IntoTick, Tick};
+use util::future::{self, Val};
 use reactor::source::{self, Source};
 use mio::{Evented, Events, EventSet, Poll, PollOpt, Token};
 use mio::channel::{self, Sender, Receiver};
 use slab::Slab;
 use std::{io, thread, usize};
 use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::VecDeque;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::time::{Duration, Instant};
 
 /// Reactor configuration options
 #[derive(Debug)]
@@ -23,6 +28,25 @@ pub struct Reactor {
     tx: Sender<Op>,
     rx: Receiver<Op>,
     config: Config,
+    stats: Arc<Stats>,
+}
+
+/// A snapshot of a `Reactor`'s task counters, returned by
+/// `ReactorHandle::stats`.
+///
+/// Functions run with `oneshot` are not counted as tasks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReactorStats {
+    /// Number of tasks spawned on the reactor
+    pub spawned: usize,
+    /// Number of tasks that completed, failed, or were dropped when draining
+    /// timed out
+    pub completed: usize,
+    /// Number of tasks currently running on the reactor
+    pub live: usize,
+    /// Number of tasks waiting in the ready queue at the end of the last
+    /// iteration of the event loop
+    pub ready: usize,
 }
 
 /// A specialized `Result` for Reactor operations.
@@ -47,6 +71,15 @@ pub struct EventLoop {
     // possible to create slabs per service category, in which case the
     // concrete type would be known.
     tasks: Slab<TaskCell, Token>,
+    // Tasks that are able to make further progress, either because they
+    // returned `Tick::Yield` or advanced on their sources, waiting to be
+    // ticked again. Each task is ticked once per iteration of the event loop,
+    // in the order they were queued.
+    ready: VecDeque<Token>,
+    // True once every task has been ticked after draining started
+    drain_notified: bool,
+    // Task counters, shared with the reactor's handles
+    stats: Arc<Stats>,
     // Data that is shared at runtime to tasks via a thread-local. This is
     // splilt out to make the borrow checker happy
     rt: Rt,
@@ -74,6 +107,9 @@ struct Rt {
     // New tasks that have been created during a task invocation and are
     // pending being registered with the EventLoop.
     staged_tasks: Stack<Box<Task>>,
+    // Set once the reactor starts draining, tasks that have not completed by
+    // the deadline are dropped
+    drain_deadline: Cell<Option<Instant>>,
 }
 
 /// Info associated with the currently running task
@@ -85,8 +121,11 @@ pub struct TaskRef {
 
 /// Internal task storage
 struct TaskCell {
+    token: Token,
     tick_num: u64,
     poll_num: u64,
+    // True while the task is in the ready queue
+    queued: bool,
     task: Box<Task>,
 }
 
@@ -94,6 +133,14 @@ struct TaskCell {
 #[derive(Clone)]
 pub struct ReactorHandle {
     tx: Sender<Op>,
+    stats: Arc<Stats>,
+}
+
+// Updated by the event loop, read from any thread
+struct Stats {
+    spawned: AtomicUsize,
+    completed: AtomicUsize,
+    ready: AtomicUsize,
 }
 
 enum Op {
@@ -147,18 +194,31 @@ impl Reactor {
             tx: tx,
             rx: rx,
             config: config,
+            stats: Arc::new(Stats {
+                spawned: AtomicUsize::new(0),
+                completed: AtomicUsize::new(0),
+                ready: AtomicUsize::new(0),
+            }),
         })
     }
 
     /// Return a handle for the `Reactor`
     pub fn handle(&self) -> ReactorHandle {
-        ReactorHandle { tx: self.tx.clone() }
+        ReactorHandle {
+            tx: self.tx.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Returns a snapshot of the reactor's task counters
+    pub fn stats(&self) -> ReactorStats {
+        self.stats.snapshot()
     }
 
     /// Runs the `Reactor` on the current thread, blocking the thread until the
     /// reactor shuts down.
     pub fn run(self) -> io::Result<()> {
-        let Reactor { config, rx, .. } = self;
+        let Reactor { config, rx, stats, .. } = self;
 
         let id = NEXT_EVENT_LOOP_ID.fetch_add(1, Ordering::Relaxed);
 
@@ -166,6 +226,9 @@ impl Reactor {
             rx: rx,
             events: Events::with_capacity(256),
             tasks: Slab::new(100),
+            ready: VecDeque::new(),
+            drain_notified: false,
+            stats: stats,
             rt: Rt {
                 run: Cell::new(true),
                 id: id,
@@ -175,6 +238,7 @@ impl Reactor {
                 current_task: None,
                 current_task_did_advance: Cell::new(false),
                 staged_tasks: Stack::with_capacity(128),
+                drain_deadline: Cell::new(None),
             },
         };
 
@@ -201,12 +265,49 @@ impl ReactorHandle {
         self.tx.send(Op::Schedule(Box::new(task))).ok().unwrap();
     }
 
+    /// Schedule the given `Task` on the reactor, returning a `TaskHandle`
+    /// that may be used to cancel it.
+    pub fn spawn<T: Task + Send + 'static>(&self, task: T) -> TaskHandle {
+        let (handle, rx) = cancel::task_handle();
+
+        // The cancellation channel is watched from the reactor thread, so the
+        // task is wrapped once it gets there
+        self.oneshot(move || {
+            try!(schedule(Cancellable::new(task, rx)));
+            Ok(())
+        });
+
+        handle
+    }
+
+    /// Schedule the given `Computation` on the reactor, returning a future
+    /// that resolves to the value the task completes with.
+    ///
+    /// The future fails if the task fails, or if it is dropped before
+    /// completing, for example when the reactor shuts down. Dropping the
+    /// future drops the task the next time it is ticked.
+    pub fn compute<T>(&self, task: T) -> Val<T::Item, io::Error>
+        where T: Computation + Send + 'static,
+    {
+        let (c, val) = future::pair();
+        self.schedule(Computing::new(task, c));
+        val
+    }
+
     /// Run the given function on the reactor
     pub fn oneshot<F: FnOnce() -> T + Send + 'static, T: IntoTick>(&self, f: F) {
         use take::Take;
         self.schedule(Take::new(f))
     }
 
+    /// Returns a snapshot of the reactor's task counters.
+    ///
+    /// The counters are updated by the reactor thread as it runs, so they
+    /// may lag behind tasks that were just scheduled.
+    pub fn stats(&self) -> ReactorStats {
+        self.stats.snapshot()
+    }
+
     /// Shutdown the reactor
     pub fn shutdown(&self) {
         self.oneshot(|| {
@@ -214,6 +315,17 @@ impl ReactorHandle {
             Ok(())
         });
     }
+
+    /// Shutdown the reactor once all tasks have completed, waiting at most
+    /// `timeout`.
+    ///
+    /// See `reactor::drain` for details.
+    pub fn drain(&self, timeout: Duration) {
+        self.oneshot(move || {
+            try!(drain(timeout));
+            Ok(())
+        });
+    }
 }
 
 /*
@@ -266,6 +378,16 @@ pub fn register_source<E: Evented>(evented: &E, interest: Ready) -> Result<Sourc
 }
 
 
+/// Returns true if the currently running task has made progress on any of its
+/// sources during the current tick.
+///
+/// # Panics
+///
+/// If not currently on a reactor thread, this function panics.
+pub fn did_advance() -> Result<bool> {
+    with_current_rt(|rt| Ok(rt.current_task_did_advance.get()))
+}
+
 /// Shutdown the currently running `Reactor`.
 ///
 /// # Panics
@@ -275,6 +397,36 @@ pub fn shutdown() -> Result<()> {
     with_current_rt(|rt| Ok(rt.shutdown()))
 }
 
+/// Start draining the currently running `Reactor`.
+///
+/// Every task is ticked once more, during which `is_draining` returns true,
+/// giving tasks the chance to stop accepting new work, flush, and complete.
+/// The reactor shuts down once all tasks have completed. Tasks that have not
+/// completed once `timeout` elapses are dropped.
+///
+/// # Panics
+///
+/// If not currently on a reactor thread, this function panics.
+pub fn drain(timeout: Duration) -> Result<()> {
+    with_current_rt(|rt| {
+        if rt.drain_deadline.get().is_none() {
+            debug!("draining reactor; timeout={:?}", timeout);
+            rt.drain_deadline.set(Some(Instant::now() + timeout));
+        }
+
+        Ok(())
+    })
+}
+
+/// Returns true if the currently running `Reactor` is draining.
+///
+/// # Panics
+///
+/// If not currently on a reactor thread, this function panics.
+pub fn is_draining() -> Result<bool> {
+    with_current_rt(|rt| Ok(rt.drain_deadline.get().is_some()))
+}
+
 fn with_current_rt<F: FnOnce(&Rt) -> Result<R>, R>(f: F) -> Result<R> {
     if !CURRENT_RT.is_set() {
         return Err(Error::OffThread);
@@ -331,7 +483,24 @@ impl EventLoop {
         try!(self.rt.poll.register(&self.rx, OP_RECEIVER, EventSet::readable(), PollOpt::edge()));
 
         while self.rt.run() {
-            try!(self.rt.poll.poll(&mut self.events, None));
+            // Queued tasks are ready to make progress, so don't block waiting
+            // for I/O events when there are any. While draining, don't block
+            // past the deadline.
+            let timeout = if !self.ready.is_empty() {
+                Some(Duration::from_millis(0))
+            } else {
+                self.rt.drain_deadline.get().map(|deadline| {
+                    let now = Instant::now();
+
+                    if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_millis(0)
+                    }
+                })
+            };
+
+            try!(self.rt.poll.poll(&mut self.events, timeout));
             self.rt.poll_num += 1;
 
             trace!("event loop iteration; num={:?}", self.rt.poll_num);
@@ -345,11 +514,65 @@ impl EventLoop {
             // Next dispatch events to tasks
             self.dispatch_events();
 
+            // Finally, give tasks that are ready another turn
+            self.dispatch_ready();
+
+            self.stats.ready.store(self.ready.len(), Ordering::Relaxed);
+
+            if self.rt.drain_deadline.get().is_some() {
+                self.drain();
+            }
         }
 
         Ok(())
     }
 
+    // Make progress on draining the reactor, shutting it down once all tasks
+    // have completed or the deadline has been reached.
+    fn drain(&mut self) {
+        if !self.drain_notified {
+            self.drain_notified = true;
+
+            // Tasks may already have been ticked during this iteration, so
+            // start a new one in order for every task to be ticked again.
+            self.rt.poll_num += 1;
+
+            // Give every task a chance to observe the reactor draining
+            for token in self.task_tokens() {
+                self.execute_task(token);
+                self.process_queued();
+            }
+        }
+
+        if self.tasks.iter().next().is_none() {
+            debug!("reactor drained");
+            self.rt.shutdown();
+            return;
+        }
+
+        let deadline = self.rt.drain_deadline.get().unwrap();
+
+        if Instant::now() >= deadline {
+            debug!("reactor drain timed out; dropping remaining tasks");
+
+            for token in self.task_tokens() {
+                let tasks = &mut self.tasks;
+
+                self.rt.scope(None, || {
+                    let _ = tasks.remove(token);
+                });
+
+                self.stats.completed.fetch_add(1, Ordering::SeqCst);
+            }
+
+            self.rt.shutdown();
+        }
+    }
+
+    fn task_tokens(&self) -> Vec<Token> {
+        self.tasks.iter().map(|task| task.token).collect()
+    }
+
     fn update_source_readiness(&mut self) {
         for i in 0..self.events.len() {
             let event = self.events.get(i).unwrap();
@@ -384,6 +607,41 @@ impl EventLoop {
         }
     }
 
+    fn dispatch_ready(&mut self) {
+        // Only tick the tasks that were queued before this point. Tasks that
+        // are queued again are ticked on the next iteration.
+        for _ in 0..self.ready.len() {
+            if !self.rt.run() {
+                return;
+            }
+
+            let token = self.ready.pop_front().unwrap();
+
+            let requeue = match self.tasks.get_mut(token) {
+                Some(task) => {
+                    if task.poll_num == self.rt.poll_num {
+                        // The task has already been ticked during this
+                        // iteration of the event loop, try again on the next
+                        // one.
+                        true
+                    } else {
+                        task.queued = false;
+                        false
+                    }
+                }
+                // The task completed since being queued
+                None => continue,
+            };
+
+            if requeue {
+                self.ready.push_back(token);
+            } else {
+                self.execute_task(token);
+                self.process_queued();
+            }
+        }
+    }
+
     fn process_source(&mut self, token: Token) {
         let task = match self.rt.sources.borrow()[token].task() {
             Some(t) => t,
@@ -442,17 +700,25 @@ impl EventLoop {
     }
 
     fn add_task(&mut self, task: Box<Task>) {
-        let task = TaskCell {
-            tick_num: 0,
-            poll_num: 0,
-            task: task,
-        };
+        // The number of tasks is not bounded, so the slab doubles in size
+        // once it is full
+        if !self.tasks.has_available() {
+            let additional = self.tasks.count();
+            trace!("growing task slab; additional={}", additional);
+            self.tasks.grow(additional);
+        }
 
-        let token = match self.tasks.insert(task) {
-            Ok(token) => token,
-            Err(_) => unimplemented!(),
-        };
+        let token = self.tasks.insert_with(move |token| {
+            TaskCell {
+                token: token,
+                tick_num: 0,
+                poll_num: 0,
+                queued: false,
+                task: task,
+            }
+        }).expect("task slab full after growing");
 
+        self.stats.spawned.fetch_add(1, Ordering::SeqCst);
         self.execute_task(token);
     }
 
@@ -470,71 +736,61 @@ impl EventLoop {
 
             task.poll_num = self.rt.poll_num;
 
-            while self.rt.run() {
-                // Increment the task's tick_num
-                task.tick_num += 1;
-
-                trace!("running task; task={:?}; tick={:?}", token, task.tick_num);
+            if !self.rt.run() {
+                return;
+            }
 
-                let task_ref = TaskRef {
-                    token: token,
-                    tick_num: task.tick_num,
-                };
+            // Increment the task's tick_num
+            task.tick_num += 1;
 
-                // Run the task while setting the current event loop variable
-                let res = self.rt.scope(Some(task_ref), || task.task.tick());
+            trace!("running task; task={:?}; tick={:?}", token, task.tick_num);
 
-                match res {
-                    Ok(Tick::Final) => {
-                        debug!("finalizing task; token={:?}", token);
-                        task_shutdown = true;
-                        break;
-                    }
-                    Ok(Tick::Yield) => {
-
-                        // # Thoughts
-                        //
-                        // It is possible for a task to process sources without
-                        // hitting a would block. The problem here is that
-                        // there is no way for the reactor to detect that the
-                        // task is still ready. Imagine that the task reads
-                        // from a Transport that has data buffered in memory
-                        // and the read does not deplete the buffer. The
-                        // transport is still ready and the read did not hit
-                        // the underlying socket. Now, imagine the task
-                        // continues to work and reads from a source returning
-                        // would-block. In this case, the reactor observed that
-                        // 100% of the sources accessed by the task are not
-                        // ready.
-                        //
-                        // Now, the current requirement for implementing a task
-                        // is to read sources until a would-block his hit, but
-                        // this may not be desirable in all cases.
-                        //
-                        // One solution would be to add a Tick:Yield return
-                        // value.
-
-                        unimplemented!();
-                    }
-                    Ok(Tick::WouldBlock) => {
-                        // Task would have blocked. In this case, we must determine
-                        // if the FSM made any progress at all. If progress was
-                        // made, call the FSM until no progress was made.
+            let task_ref = TaskRef {
+                token: token,
+                tick_num: task.tick_num,
+            };
 
-                        if !self.rt.current_task_did_advance.get() {
-                            trace!("current task made no progress");
-                            return;
-                        }
+            // Run the task while setting the current event loop variable
+            let res = self.rt.scope(Some(task_ref), || task.task.tick());
 
+            let ready = match res {
+                Ok(Tick::Final) => {
+                    debug!("finalizing task; token={:?}", token);
+                    task_shutdown = true;
+                    false
+                }
+                Ok(Tick::Yield) => {
+                    // The task is able to make further progress but is
+                    // letting other tasks run first. Since it may not have
+                    // hit a would-block on any of its sources, the reactor
+                    // can't rely on readiness events to tick it again.
+                    trace!("task yielded; token={:?}", token);
+                    true
+                }
+                Ok(Tick::WouldBlock) => {
+                    // Task would have blocked. In this case, we must determine
+                    // if the FSM made any progress at all. If progress was
+                    // made, the task may be able to make more, so it is ticked
+                    // again once the other ready tasks had their turn.
+                    if self.rt.current_task_did_advance.get() {
                         trace!("current task made progress");
+                        true
+                    } else {
+                        trace!("current task made no progress");
+                        false
                     }
-                    Err(_) => {
-                        // Task returned an error, in this case it has to be
-                        // cleaned up
-                        task_shutdown = true;
-                        break;
-                    }
                 }
+                Err(_) => {
+                    // Task returned an error, in this case it has to be
+                    // cleaned up
+                    task_shutdown = true;
+                    false
+                }
+            };
+
+            if ready && !task.queued {
+                task.queued = true;
+                self.ready.push_back(token);
             }
         }
 
@@ -546,6 +802,24 @@ impl EventLoop {
                 // TODO: Should the current task info be set?
                 let _ = tasks.remove(token);
             });
+
+            self.stats.completed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Stats {
+    fn snapshot(&self) -> ReactorStats {
+        // Tasks are counted as completed after being counted as spawned, so
+        // loading `completed` first keeps `live` from underflowing
+        let completed = self.completed.load(Ordering::SeqCst);
+        let spawned = self.spawned.load(Ordering::SeqCst);
+
+        ReactorStats {
+            spawned: spawned,
+            completed: completed,
+            live: spawned - completed,
+            ready: self.ready.load(Ordering::Relaxed),
         }
     }
 }
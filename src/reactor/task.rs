@@ -1,4 +1,5 @@
 use tcp::TcpStream;
+use util::future::Complete;
 use take::Take;
 use std::io;
 
@@ -26,13 +27,32 @@ pub trait Task {
     }
 }
 
+/// A `Task` that completes with a value.
+///
+/// Spawned with `ReactorHandle::compute`, which returns a future resolving to
+/// the value once the task returns `Tick::Final`.
+pub trait Computation: Task {
+    /// The value the task completes with
+    type Item: Send + 'static;
+
+    /// Returns the value of the task, called once after the task returned
+    /// `Tick::Final`.
+    ///
+    /// Returning `None` fails the future with an `Other` error.
+    fn take_value(&mut self) -> Option<Self::Item>;
+}
+
 /// Creates new `Task` values
-pub trait NewTask: Send + 'static {
+///
+/// `Io` is the I/O source each task is created with. Connection oriented
+/// tasks are created with the `TcpStream` of the connection, while datagram
+/// based tasks can be created with a `UdpSocket`.
+pub trait NewTask<Io = TcpStream>: Send + 'static {
     /// The `Task` value created by this factory
     type Item: Task;
 
     /// Create and return a new `Task` value
-    fn new_task(&self, stream: TcpStream) -> io::Result<Self::Item>;
+    fn new_task(&self, io: Io) -> io::Result<Self::Item>;
 }
 
 /// Informs the `Reactor` how to process the current task
@@ -40,6 +60,9 @@ pub enum Tick {
     /// The task would have blocked and has more work to do
     WouldBlock,
     /// The task could do more work, but is yielding execution
+    ///
+    /// The reactor ticks the task again once other ready tasks have had a
+    /// chance to run, without waiting for any of its sources to become ready.
     Yield,
     /// The task has completed all work
     Final,
@@ -110,24 +133,84 @@ impl IntoTick for io::Result<Tick> {
     }
 }
 
-impl<T, U> NewTask for T
-    where T: Fn(TcpStream) -> io::Result<U> + Send + 'static,
+impl<T, U, Io> NewTask<Io> for T
+    where T: Fn(Io) -> io::Result<U> + Send + 'static,
           U: Task,
 {
     type Item = U;
 
-    fn new_task(&self, stream: TcpStream) -> io::Result<Self::Item> {
-        self(stream)
+    fn new_task(&self, io: Io) -> io::Result<Self::Item> {
+        self(io)
     }
 }
 
-impl<T, U> NewTask for Take<T>
-    where T: FnOnce(TcpStream) -> io::Result<U> + Send + 'static,
+impl<T, U, Io> NewTask<Io> for Take<T>
+    where T: FnOnce(Io) -> io::Result<U> + Send + 'static,
           U: Task,
 {
     type Item = U;
 
-    fn new_task(&self, stream: TcpStream) -> io::Result<U> {
-        self.take()(stream)
+    fn new_task(&self, io: Io) -> io::Result<U> {
+        self.take()(io)
+    }
+}
+
+/// Drives a `Computation`, sending its value to the future returned by
+/// `ReactorHandle::compute`.
+///
+/// Only created by `ReactorHandle::compute`, the type is not exported from
+/// the `reactor` module.
+#[doc(hidden)]
+pub struct Computing<T: Computation> {
+    task: T,
+    // Taken once the task has completed
+    complete: Option<Complete<T::Item, io::Error>>,
+}
+
+impl<T: Computation> Computing<T> {
+    /// Wrap `task`, completing `complete` with its value
+    #[doc(hidden)]
+    pub fn new(task: T, complete: Complete<T::Item, io::Error>) -> Computing<T> {
+        Computing {
+            task: task,
+            complete: Some(complete),
+        }
+    }
+}
+
+impl<T: Computation> Task for Computing<T> {
+    fn tick(&mut self) -> io::Result<Tick> {
+        if self.complete.as_ref().map_or(true, |c| c.is_cancelled()) {
+            // Nobody is interested in the value anymore
+            trace!("computation cancelled");
+            return Ok(Tick::Final);
+        }
+
+        match self.task.tick() {
+            Ok(Tick::Final) => {
+                let c = self.complete.take().unwrap();
+
+                match self.task.take_value() {
+                    Some(val) => c.complete(val),
+                    None => c.error(io::Error::new(io::ErrorKind::Other, "task completed without a value")),
+                }
+
+                Ok(Tick::Final)
+            }
+            Ok(tick) => Ok(tick),
+            Err(e) => {
+                debug!("computation failed; err={:?}", e);
+                self.complete.take().unwrap().error(e);
+                Ok(Tick::Final)
+            }
+        }
+    }
+}
+
+impl<T: Computation> Drop for Computing<T> {
+    fn drop(&mut self) {
+        if let Some(c) = self.complete.take() {
+            c.error(io::Error::new(io::ErrorKind::Other, "task dropped before completing"));
+        }
     }
 }
@@ -1,19 +1,113 @@
 //! A generic Tokio TCP server implementation.
 
-use tcp::{TcpListener, TcpStream};
+use tcp::{ListenerConfig, TcpConfig, TcpListener, TcpStream};
+use udp::UdpSocket;
 use reactor::{self, ReactorHandle, Task, NewTask, Tick};
-use mio::tcp as mio;
+use util::channel::Receiver;
+use futures::Poll;
 use std::io;
+use std::cell::Cell;
 use std::net::SocketAddr;
+use std::rc::Rc;
 
 /// A handle to a running server.
 pub struct ServerHandle {
     local_addr: SocketAddr,
 }
 
-struct Listener<T> {
+/// Creates an `Upgrade` for every accepted connection.
+///
+/// Used with `Builder::upgrade` to wrap connections, for example with TLS,
+/// before they are passed to the `NewTask`.
+pub trait NewUpgrade: Send + 'static {
+    /// The I/O type produced by the upgrade
+    type Io;
+
+    /// The `Upgrade` value created by this factory
+    type Item: Upgrade<Io = Self::Io>;
+
+    /// Start upgrading the accepted `stream`
+    fn new_upgrade(&self, stream: TcpStream) -> io::Result<Self::Item>;
+}
+
+/// An in-progress upgrade of an accepted connection, such as a TLS handshake.
+pub trait Upgrade {
+    /// The I/O type produced by the upgrade
+    type Io;
+
+    /// Make progress on the upgrade.
+    ///
+    /// Returns `Ok(None)` if the upgrade would block, in which case it is
+    /// polled again once one of its sources becomes ready.
+    fn poll_upgrade(&mut self) -> io::Result<Option<Self::Io>>;
+}
+
+/// Configures a TCP server before it starts listening.
+///
+/// `listen` uses the default configuration, the builder sets the socket
+/// options of the listener and of the accepted connections, limits the number
+/// of open connections and upgrades connections before handing them to the
+/// `NewTask`. All the options may be combined.
+///
+/// ```rust,ignore
+/// server::Builder::new()
+///     .listener_config(ListenerConfig::new().backlog(4096))
+///     .tcp_config(TcpConfig::new().nodelay(true))
+///     .max_connections(1024)
+///     .upgrade(TlsAcceptor::new(config))
+///     .listen(&reactor.handle(), addr, new_task);
+/// ```
+pub struct Builder<U = ()> {
+    listener: ListenerConfig,
+    config: TcpConfig,
+    max_connections: Option<usize>,
+    upgrade: U,
+}
+
+/// Accepts connections on a `TcpListener`, applying the socket options of a
+/// `TcpConfig` to them.
+///
+/// This is the accept step of `listen`, decoupled from creating and
+/// scheduling the connection tasks, so that it can be driven by the caller.
+/// `poll_accept` must be called from a task running on the reactor, which is
+/// notified once another connection is ready to be accepted.
+pub struct Acceptor {
     socket: TcpListener,
-    new_task: T,
+    config: TcpConfig,
+}
+
+struct Listener {
+    acceptor: Acceptor,
+    dispatch: Dispatch,
+    // Stop accepting connections while this many are open
+    max_connections: Option<usize>,
+    connections: Rc<Cell<usize>>,
+    // Notified as connections close
+    closed_tx: ::mio::channel::Sender<()>,
+    closed: Receiver<()>,
+}
+
+// Handles an accepted connection. The `Connection` guard, if any, must be
+// dropped along with the connection's task.
+type Dispatch = Box<FnMut(TcpStream, Option<Connection>) -> io::Result<()>>;
+
+// Tracks an open connection for the listener enforcing `max_connections`
+struct Connection {
+    connections: Rc<Cell<usize>>,
+    closed: ::mio::channel::Sender<()>,
+}
+
+// A connection task that holds on to its `Connection` guard
+struct Tracked<T> {
+    task: T,
+    _connection: Option<Connection>,
+}
+
+// Drives an upgrade to completion, then creates the connection's task
+struct Upgrading<U, T> {
+    upgrade: U,
+    new_task: Rc<T>,
+    connection: Option<Connection>,
 }
 
 /// Spawn a new `Task` that binds to the given `addr` then accepts all incoming
@@ -71,8 +165,138 @@ struct Listener<T> {
 pub fn listen<T>(reactor: &ReactorHandle, addr: SocketAddr, new_task: T) -> io::Result<ServerHandle>
         where T: NewTask
 {
+    Builder::new().listen(reactor, addr, new_task)
+}
+
+impl Builder {
+    /// Create a new `Builder` with the default configuration
+    pub fn new() -> Builder {
+        Builder {
+            listener: ListenerConfig::default(),
+            config: TcpConfig::default(),
+            max_connections: None,
+            upgrade: (),
+        }
+    }
 
-    let socket = try!(mio::TcpListener::bind(&addr));
+    /// Spawn a new `Task` that binds to the given `addr` then accepts all
+    /// incoming connections; dispatching them to tasks created by
+    /// `new_task`.
+    pub fn listen<T>(self, reactor: &ReactorHandle, addr: SocketAddr, new_task: T) -> io::Result<ServerHandle>
+        where T: NewTask
+    {
+        let Builder { listener, config, max_connections, .. } = self;
+
+        spawn_listener(reactor, addr, listener, config, max_connections, move || -> Dispatch {
+            Box::new(move |socket, connection| {
+                let task = try!(new_task.new_task(socket));
+
+                try!(reactor::schedule(Tracked {
+                    task: task,
+                    _connection: connection,
+                }));
+
+                Ok(())
+            })
+        })
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl<U> Builder<U> {
+    /// Bind the listener with the options in `listener`, such as the backlog
+    /// size.
+    pub fn listener_config(mut self, listener: ListenerConfig) -> Self {
+        self.listener = listener;
+        self
+    }
+
+    /// Apply the socket options in `config` to every accepted connection
+    /// before it is passed to the `NewTask`, or to the upgrade.
+    pub fn tcp_config(mut self, config: TcpConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Stop accepting connections while `max` connections are open.
+    ///
+    /// A connection is open until its task completes, including the time
+    /// spent upgrading it. Connections that arrive while the limit is
+    /// reached wait in the listen backlog.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        assert!(max > 0, "max connections must be greater than 0");
+
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Upgrade every accepted connection using `upgrade`. The `Io` produced
+    /// by the upgrade is passed to the `NewTask` once the upgrade completes.
+    ///
+    /// Connections that fail to upgrade are dropped without creating a task.
+    pub fn upgrade<V>(self, upgrade: V) -> Builder<V>
+        where V: NewUpgrade,
+    {
+        Builder {
+            listener: self.listener,
+            config: self.config,
+            max_connections: self.max_connections,
+            upgrade: upgrade,
+        }
+    }
+}
+
+impl<U> Builder<U>
+    where U: NewUpgrade,
+{
+    /// Spawn a new `Task` that binds to the given `addr` then accepts all
+    /// incoming connections; upgrading them before dispatching them to tasks
+    /// created by `new_task`.
+    pub fn listen<T>(self, reactor: &ReactorHandle, addr: SocketAddr, new_task: T) -> io::Result<ServerHandle>
+        where T: NewTask<U::Io>,
+    {
+        let Builder { listener, config, max_connections, upgrade } = self;
+
+        spawn_listener(reactor, addr, listener, config, max_connections, move || -> Dispatch {
+            // The factory is shared by all the connections being upgraded on
+            // the reactor
+            let new_task = Rc::new(new_task);
+
+            Box::new(move |socket, connection| {
+                let upgrade = try!(upgrade.new_upgrade(socket));
+
+                try!(reactor::schedule(Upgrading {
+                    upgrade: upgrade,
+                    new_task: new_task.clone(),
+                    connection: connection,
+                }));
+
+                Ok(())
+            })
+        })
+    }
+}
+
+// Bind the listener and spawn its task, `dispatch` is called on the reactor to
+// create the connection handler.
+fn spawn_listener<F>(reactor: &ReactorHandle,
+                     addr: SocketAddr,
+                     listener: ListenerConfig,
+                     config: TcpConfig,
+                     max_connections: Option<usize>,
+                     dispatch: F) -> io::Result<ServerHandle>
+        where F: FnOnce() -> Dispatch + Send + 'static,
+{
+    let socket = try!(listener.bind(&addr));
     let addr = try!(socket.local_addr());
 
     reactor.oneshot(move || {
@@ -83,7 +307,8 @@ pub fn listen<T>(reactor: &ReactorHandle, addr: SocketAddr, new_task: T) -> io::
         };
 
         // Initialize the new listener
-        let listener = Listener::new(socket, new_task);
+        let acceptor = Acceptor::new(socket, config);
+        let listener = try!(Listener::new(acceptor, max_connections, dispatch()));
 
         // Register the listener with the Reactor
         try!(reactor::schedule(listener));
@@ -95,34 +320,235 @@ pub fn listen<T>(reactor: &ReactorHandle, addr: SocketAddr, new_task: T) -> io::
     })
 }
 
+/// Spawn a new `Task` created by `new_task` that handles all datagrams
+/// received on a UDP socket bound to the given `addr`.
+///
+/// ```rust,no_run
+/// use tokio::server;
+/// use tokio::udp::UdpSocket;
+/// use tokio::reactor::*;
+/// use std::io;
+///
+/// struct Echo {
+///     socket: UdpSocket,
+///     buf: Box<[u8]>,
+/// }
+///
+/// impl Task for Echo {
+///     fn tick(&mut self) -> io::Result<Tick> {
+///         while let Some((n, addr)) = try!(self.socket.recv_from(&mut self.buf)) {
+///             try!(self.socket.send_to(&self.buf[..n], &addr));
+///         }
+///
+///         Ok(Tick::WouldBlock)
+///     }
+/// }
+///
+/// let reactor = Reactor::default().unwrap();
+///
+/// // Launch the server
+/// server::listen_udp(&reactor.handle(),
+///                    "0.0.0.0:3245".parse().unwrap(),
+///                    |socket| Ok(Echo { socket: socket, buf: vec![0; 1024].into_boxed_slice() }));
+///
+/// // Run the reactor
+/// reactor.run().unwrap();
+///
+/// ```
+pub fn listen_udp<T>(reactor: &ReactorHandle, addr: SocketAddr, new_task: T) -> io::Result<ServerHandle>
+        where T: NewTask<UdpSocket>
+{
+    let socket = try!(::mio::udp::UdpSocket::bind(&addr));
+    let addr = try!(socket.local_addr());
+
+    reactor.oneshot(move || {
+        // Create a new Tokio UdpSocket from the Mio socket
+        let socket = try!(UdpSocket::watch(socket));
+        let task = try!(new_task.new_task(socket));
+
+        try!(reactor::schedule(task));
+        Ok(())
+    });
+
+    Ok(ServerHandle {
+        local_addr: addr,
+    })
+}
+
 impl ServerHandle {
-    /// Returns the local socket address of the `TcpListener` for this server.
+    /// Returns the local socket address of the socket for this server.
     pub fn local_addr(&self) -> &SocketAddr {
         &self.local_addr
     }
 }
 
-impl<T> Listener<T> {
-    fn new(socket: TcpListener, new_task: T) -> Listener<T> {
-        Listener {
+impl Acceptor {
+    /// Create a new `Acceptor` accepting connections on `socket`.
+    pub fn new(socket: TcpListener, config: TcpConfig) -> Acceptor {
+        Acceptor {
             socket: socket,
-            new_task: new_task,
+            config: config,
+        }
+    }
+
+    /// Returns the local socket address of the listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Accept the next connection, returning it along with the address of
+    /// the remote peer.
+    ///
+    /// Returns `Poll::NotReady` if there is no pending connection.
+    ///
+    /// A connection that the socket options of the `TcpConfig` can't be
+    /// applied to, typically because the peer already reset it, is dropped
+    /// with the error logged, and the next pending connection is accepted.
+    /// The error concerns that connection only, returning it would close the
+    /// listener.
+    pub fn poll_accept(&mut self) -> Poll<(TcpStream, SocketAddr), io::Error> {
+        loop {
+            let (socket, addr) = match self.socket.accept_with_addr() {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => return Poll::NotReady,
+                Err(e) => return Poll::Err(e),
+            };
+
+            if let Err(e) = self.config.apply(&socket) {
+                warn!("failed to apply socket options, dropping connection; addr={}; err={:?}", addr, e);
+                continue;
+            }
+
+            return match TcpStream::watch_accepted(socket, addr) {
+                Ok(socket) => Poll::Ok((socket, addr)),
+                Err(e) => Poll::Err(e),
+            };
         }
     }
 }
 
-impl<T: NewTask> Task for Listener<T> {
+impl Listener {
+    fn new(acceptor: Acceptor,
+           max_connections: Option<usize>,
+           dispatch: Dispatch) -> io::Result<Listener> {
+        let (tx, rx) = ::mio::channel::channel();
+
+        Ok(Listener {
+            acceptor: acceptor,
+            dispatch: dispatch,
+            max_connections: max_connections,
+            connections: Rc::new(Cell::new(0)),
+            closed_tx: tx,
+            closed: try!(Receiver::watch(rx)),
+        })
+    }
+
+    fn at_capacity(&self) -> bool {
+        let max = match self.max_connections {
+            Some(max) => max,
+            None => return false,
+        };
+
+        // Consume the close notifications, the listener is notified again on
+        // the next one
+        while let Ok(Some(())) = self.closed.recv() {}
+
+        self.connections.get() >= max
+    }
+
+    fn connection(&self) -> Option<Connection> {
+        self.max_connections.map(|_| {
+            self.connections.set(self.connections.get() + 1);
+
+            Connection {
+                connections: self.connections.clone(),
+                closed: self.closed_tx.clone(),
+            }
+        })
+    }
+}
+
+impl Task for Listener {
     fn tick(&mut self) -> io::Result<Tick> {
         debug!("listener task ticked");
 
+        // Stop accepting connections once the reactor is draining
+        if try!(reactor::is_draining()) {
+            debug!("reactor draining; closing listener");
+            return Ok(Tick::Final);
+        }
+
         // As long as there are sockets to accept, accept and process them
-        while let Some(socket) = try!(self.socket.accept()) {
-            let socket = try!(TcpStream::watch(socket));
-            let task = try!(self.new_task.new_task(socket));
+        while !self.at_capacity() {
+            let socket = match self.acceptor.poll_accept() {
+                Poll::Ok((socket, _)) => socket,
+                Poll::NotReady => break,
+                Poll::Err(e) => return Err(e),
+            };
 
-            try!(reactor::schedule(task));
+            let connection = self.connection();
+
+            try!((self.dispatch)(socket, connection));
         }
 
         Ok(Tick::WouldBlock)
     }
 }
+
+impl<U, T> Task for Upgrading<U, T>
+    where U: Upgrade,
+          T: NewTask<U::Io>,
+{
+    fn tick(&mut self) -> io::Result<Tick> {
+        match self.upgrade.poll_upgrade() {
+            Ok(Some(io)) => {
+                trace!("connection upgraded");
+                let task = try!(self.new_task.new_task(io));
+
+                try!(reactor::schedule(Tracked {
+                    task: task,
+                    _connection: self.connection.take(),
+                }));
+
+                Ok(Tick::Final)
+            }
+            Ok(None) => Ok(Tick::WouldBlock),
+            Err(e) => {
+                // Drop the connection
+                debug!("failed to upgrade connection; err={:?}", e);
+                Ok(Tick::Final)
+            }
+        }
+    }
+}
+
+impl<T: Task> Task for Tracked<T> {
+    fn tick(&mut self) -> io::Result<Tick> {
+        self.task.tick()
+    }
+
+    fn oneshot(&self) -> bool {
+        // Oneshot tasks are dropped right after being ticked, which releases
+        // the connection
+        self.task.oneshot()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.set(self.connections.get() - 1);
+        let _ = self.closed.send(());
+    }
+}
+
+impl<F, U> NewUpgrade for F
+    where F: Fn(TcpStream) -> io::Result<U> + Send + 'static,
+          U: Upgrade,
+{
+    type Io = U::Io;
+    type Item = U;
+
+    fn new_upgrade(&self, stream: TcpStream) -> io::Result<U> {
+        self(stream)
+    }
+}
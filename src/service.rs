@@ -1,4 +1,4 @@
-use futures::{Future, IntoFuture};
+use futures::{Future, IntoFuture, Poll};
 use std::io;
 use std::marker::PhantomData;
 use std::sync::{Arc, Mutex};
@@ -143,6 +143,18 @@ pub trait Service: Send + 'static {
 
     /// Process the request and return the response asynchronously.
     fn call(&self, req: Self::Req) -> Self::Fut;
+
+    /// Returns `Poll::Ok(())` when the service is able to process a request.
+    ///
+    /// Dispatchers check that the service is ready before reading the next
+    /// request. A service that is not ready is responsible for notifying the
+    /// dispatching task once it becomes ready, for example by watching a
+    /// source while being polled on the reactor.
+    ///
+    /// By default, the service is always ready.
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        Poll::Ok(())
+    }
 }
 
 /// Creates new `Service` values.
@@ -3,8 +3,10 @@
 use io::{Readiness, Ready};
 use reactor::{self, Source};
 use mio::tcp as mio;
+use net2::TcpBuilder;
 use std::io::{self, Read, Write};
-use std::net::SocketAddr;
+use std::net::{Shutdown, SocketAddr};
+use std::time::Duration;
 
 /// A TCP server socket.
 pub struct TcpListener {
@@ -26,6 +28,13 @@ impl TcpListener {
         TcpListener::watch(mio)
     }
 
+    /// Same as `bind`, but applies the socket options in `config` to the
+    /// listener.
+    pub fn bind_with_config(addr: &SocketAddr, config: &ListenerConfig) -> io::Result<TcpListener> {
+        let mio = try!(config.bind(addr));
+        TcpListener::watch(mio)
+    }
+
     /// Create and return a new `TcpListener` backed by the given Mio
     /// TcpListener.
     ///
@@ -51,14 +60,19 @@ impl TcpListener {
     /// TCP connection, `Ok(None)` is returned. In the event of an error,
     /// `Err(error)` is returned.
     pub fn accept(&self) -> io::Result<Option<mio::TcpStream>> {
+        Ok(try!(self.accept_with_addr()).map(|(socket, _)| socket))
+    }
+
+    /// Same as `accept`, but also returns the address of the remote peer.
+    pub fn accept_with_addr(&self) -> io::Result<Option<(mio::TcpStream, SocketAddr)>> {
         if !self.source.is_readable() {
             return Ok(None);
         }
 
         match self.mio.accept() {
-            Ok(Some((socket, _))) => {
+            Ok(Some((socket, addr))) => {
                 self.source.advance();
-                Ok(Some(socket))
+                Ok(Some((socket, addr)))
             }
             Ok(None) => {
                 self.source.unset_readable();
@@ -82,10 +96,166 @@ impl Readiness for TcpListener {
     }
 }
 
+/// Socket options applied to accepted `TcpStream`s.
+///
+/// By default, no options are applied and accepted streams use the system
+/// defaults.
+#[derive(Debug, Clone, Default)]
+pub struct TcpConfig {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl TcpConfig {
+    /// Create a `TcpConfig` with default values
+    pub fn new() -> TcpConfig {
+        TcpConfig::default()
+    }
+
+    /// Set `TCP_NODELAY` on accepted streams, disabling Nagle's algorithm.
+    pub fn nodelay(mut self, val: bool) -> Self {
+        self.nodelay = val;
+        self
+    }
+
+    /// Enable TCP keepalive on accepted streams, sending probes once a
+    /// connection has been idle for the given duration.
+    pub fn keepalive(mut self, val: Option<Duration>) -> Self {
+        self.keepalive = val;
+        self
+    }
+
+    /// Set the size of the `SO_SNDBUF` send buffer of accepted streams.
+    ///
+    /// Larger buffers improve the throughput of links with a high
+    /// bandwidth-delay product. The OS may adjust the size.
+    pub fn send_buffer_size(mut self, val: usize) -> Self {
+        self.send_buffer_size = Some(val);
+        self
+    }
+
+    /// Set the size of the `SO_RCVBUF` receive buffer of accepted streams.
+    ///
+    /// The OS may adjust the size.
+    pub fn recv_buffer_size(mut self, val: usize) -> Self {
+        self.recv_buffer_size = Some(val);
+        self
+    }
+
+    /// Apply the configured options to the given stream.
+    pub fn apply(&self, stream: &mio::TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            try!(stream.set_nodelay(true));
+        }
+
+        if let Some(keepalive) = self.keepalive {
+            try!(stream.set_keepalive(Some(keepalive)));
+        }
+
+        if let Some(size) = self.send_buffer_size {
+            try!(stream.set_send_buffer_size(size));
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            try!(stream.set_recv_buffer_size(size));
+        }
+
+        Ok(())
+    }
+}
+
+/// Socket options applied to a `TcpListener` when it is bound.
+///
+/// The defaults match `TcpListener::bind`: a backlog of 1024 connections and
+/// `SO_REUSEADDR` set on Unix platforms.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    backlog: i32,
+    reuse_address: bool,
+    reuse_port: bool,
+}
+
+impl ListenerConfig {
+    /// Create a `ListenerConfig` with default values
+    pub fn new() -> ListenerConfig {
+        ListenerConfig::default()
+    }
+
+    /// Set the max number of connections queued by the OS until they are
+    /// accepted. Connections arriving while the backlog is full are dropped
+    /// or refused.
+    pub fn backlog(mut self, val: i32) -> Self {
+        self.backlog = val;
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on the listener, allowing a restarted server to
+    /// bind its address while connections of the previous one are still in
+    /// `TIME_WAIT`.
+    pub fn reuse_address(mut self, val: bool) -> Self {
+        self.reuse_address = val;
+        self
+    }
+
+    /// Set `SO_REUSEPORT` on the listener, allowing several listeners to bind
+    /// the same address. This is only supported on Unix platforms, binding
+    /// fails on the other platforms.
+    pub fn reuse_port(mut self, val: bool) -> Self {
+        self.reuse_port = val;
+        self
+    }
+
+    /// Bind a listener to `addr` with the configured options.
+    pub fn bind(&self, addr: &SocketAddr) -> io::Result<mio::TcpListener> {
+        let builder = try!(match *addr {
+            SocketAddr::V4(..) => TcpBuilder::new_v4(),
+            SocketAddr::V6(..) => TcpBuilder::new_v6(),
+        });
+
+        try!(builder.reuse_address(self.reuse_address));
+
+        if self.reuse_port {
+            try!(set_reuse_port(&builder));
+        }
+
+        try!(builder.bind(addr));
+        let listener = try!(builder.listen(self.backlog));
+
+        mio::TcpListener::from_listener(listener, addr)
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> ListenerConfig {
+        ListenerConfig {
+            backlog: 1024,
+            reuse_address: cfg!(unix),
+            reuse_port: false,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_reuse_port(builder: &TcpBuilder) -> io::Result<()> {
+    use net2::unix::UnixTcpBuilderExt;
+
+    try!(builder.reuse_port(true));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_: &TcpBuilder) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "SO_REUSEPORT is not supported on this platform"))
+}
+
 /// A TCP stream between a local socket and a remote socket.
 pub struct TcpStream {
     mio: mio::TcpStream,
     source: Source,
+    // Captured when the stream is accepted
+    peer_addr: Option<SocketAddr>,
 }
 
 impl TcpStream {
@@ -112,9 +282,83 @@ impl TcpStream {
         Ok(TcpStream {
             mio: mio,
             source: source,
+            peer_addr: None,
         })
     }
 
+    /// Same as `watch`, for a stream accepted from `peer_addr`.
+    ///
+    /// The address is returned by `peer_addr` without querying the socket.
+    pub fn watch_accepted(mio: mio::TcpStream, peer_addr: SocketAddr) -> io::Result<TcpStream> {
+        let mut stream = try!(TcpStream::watch(mio));
+        stream.peer_addr = Some(peer_addr);
+        Ok(stream)
+    }
+
+    /// Returns the socket address of the remote peer of this connection.
+    ///
+    /// For streams accepted by a `server` listener, this is the address
+    /// captured when the connection was accepted. It does not require a
+    /// system call and remains available once the peer has closed the
+    /// connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.peer_addr {
+            Some(addr) => Ok(addr),
+            None => self.mio.peer_addr(),
+        }
+    }
+
+    /// Returns the socket address of the local half of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.mio.local_addr()
+    }
+
+    /// Sets the value of the `TCP_NODELAY` option on this socket.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.mio.set_nodelay(nodelay)
+    }
+
+    /// Gets the value of the `TCP_NODELAY` option on this socket.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.mio.nodelay()
+    }
+
+    /// Sets the keepalive idle time on this socket, `None` disables
+    /// keepalive.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        self.mio.set_keepalive(keepalive)
+    }
+
+    /// Returns the keepalive idle time on this socket, if enabled.
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.mio.keepalive()
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.mio.shutdown(how)
+    }
+
+    /// Sets the size of the `SO_SNDBUF` send buffer of this socket.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.mio.set_send_buffer_size(size)
+    }
+
+    /// Gets the size of the `SO_SNDBUF` send buffer of this socket.
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        self.mio.send_buffer_size()
+    }
+
+    /// Sets the size of the `SO_RCVBUF` receive buffer of this socket.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.mio.set_recv_buffer_size(size)
+    }
+
+    /// Gets the size of the `SO_RCVBUF` receive buffer of this socket.
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        self.mio.recv_buffer_size()
+    }
+
     /// Pull some bytes from this stream into the specified buffer, returning
     /// how many bytes were read.
     ///
@@ -177,6 +421,16 @@ impl Readiness for TcpStream {
     }
 }
 
+impl ::io::Shutdown for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        match self.shutdown(Shutdown::Write) {
+            // The peer already closed the connection
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            res => res,
+        }
+    }
+}
+
 impl io::Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         TcpStream::read(&*self, buf)
@@ -0,0 +1,105 @@
+//! Tokio aware UDP primitives
+
+use io::{Readiness, Ready};
+use reactor::{self, Source};
+use mio::udp as mio;
+use std::io;
+use std::net::SocketAddr;
+
+/// A UDP socket.
+pub struct UdpSocket {
+    mio: mio::UdpSocket,
+    source: Source,
+}
+
+impl UdpSocket {
+    /// Creates a new `UdpSocket` which will be bound to the specified address.
+    ///
+    /// Binding with a port number of 0 will request that the OS assigns a port
+    /// to this socket. The port allocated can be queried via the local_addr
+    /// method.
+    pub fn bind(addr: &SocketAddr) -> io::Result<UdpSocket> {
+        let mio = try!(mio::UdpSocket::bind(addr));
+        UdpSocket::watch(mio)
+    }
+
+    /// Create and return a new `UdpSocket` backed by the given Mio
+    /// UdpSocket.
+    ///
+    /// This turns an existing socket into a Tokio aware socket.
+    pub fn watch(mio: mio::UdpSocket) -> io::Result<UdpSocket> {
+        let source = try!(reactor::register_source(&mio, Ready::all()));
+
+        Ok(UdpSocket {
+            mio: mio,
+            source: source,
+        })
+    }
+
+    /// Returns the local socket address of this socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.mio.local_addr()
+    }
+
+    /// Send a datagram to the given `target` address, returning how many bytes
+    /// were written.
+    ///
+    /// This is a non-blocking function. If the socket is not ready to send,
+    /// `Ok(None)` is returned.
+    pub fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<Option<usize>> {
+        if !self.source.is_writable() {
+            return Ok(None);
+        }
+
+        match self.mio.send_to(buf, target) {
+            Ok(Some(n)) => {
+                self.source.advance();
+                Ok(Some(n))
+            }
+            Ok(None) => {
+                self.source.unset_writable();
+                Ok(None)
+            }
+            Err(e) => {
+                self.source.advance();
+                Err(e)
+            }
+        }
+    }
+
+    /// Receive a datagram, returning how many bytes were read and the address
+    /// the datagram was sent from.
+    ///
+    /// This is a non-blocking function. If there is no pending datagram,
+    /// `Ok(None)` is returned.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        if !self.source.is_readable() {
+            return Ok(None);
+        }
+
+        match self.mio.recv_from(buf) {
+            Ok(Some(res)) => {
+                self.source.advance();
+                Ok(Some(res))
+            }
+            Ok(None) => {
+                self.source.unset_readable();
+                Ok(None)
+            }
+            Err(e) => {
+                self.source.advance();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Readiness for UdpSocket {
+    fn is_readable(&self) -> bool {
+        self.source.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.source.is_writable()
+    }
+}
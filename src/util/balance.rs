@@ -0,0 +1,138 @@
+//! Balance requests across connections to several replicas.
+
+use {Service};
+use proto::multiplex::ClientHandle;
+use futures::{self, Future};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Dispatches each request to the least loaded of several multiplex client
+/// connections, usually to different replicas of a service.
+///
+/// The load of a connection is the number of requests dispatched to it by
+/// the `Balance` that have not completed yet. Connections that have shutdown
+/// are skipped, and requests fail with `NotConnected` once every connection
+/// has shutdown.
+pub struct Balance<T, U, E> {
+    backends: Vec<Backend<T, U, E>>,
+    max_pending: Option<usize>,
+    at_capacity: AtCapacity,
+}
+
+/// How a `Balance` handles a request when every connection has `max_pending`
+/// requests in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtCapacity {
+    /// Dispatch the request to the least loaded connection anyway, where it
+    /// waits for the connection to catch up
+    Queue,
+    /// Fail the request with a `WouldBlock` error
+    Fail,
+}
+
+struct Backend<T, U, E> {
+    client: ClientHandle<T, U, E>,
+    pending: Arc<AtomicUsize>,
+}
+
+// Held by an in-flight request, releasing its slot once dropped
+struct Pending(Arc<AtomicUsize>);
+
+impl<T, U, E> Balance<T, U, E>
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    /// Create a new `Balance` dispatching to `clients`.
+    pub fn new(clients: Vec<ClientHandle<T, U, E>>) -> Balance<T, U, E> {
+        let backends = clients.into_iter()
+            .map(|client| {
+                Backend {
+                    client: client,
+                    pending: Arc::new(AtomicUsize::new(0)),
+                }
+            })
+            .collect();
+
+        Balance {
+            backends: backends,
+            max_pending: None,
+            at_capacity: AtCapacity::Queue,
+        }
+    }
+
+    /// Set the max number of in-flight requests per connection, after which
+    /// requests are handled according to `at_capacity`. By default, there is
+    /// no limit.
+    pub fn max_pending(mut self, val: usize) -> Self {
+        self.max_pending = Some(val);
+        self
+    }
+
+    /// Set how requests are handled once every connection is at capacity.
+    /// Defaults to `AtCapacity::Queue`.
+    pub fn at_capacity(mut self, val: AtCapacity) -> Self {
+        self.at_capacity = val;
+        self
+    }
+
+    /// Returns the number of in-flight requests of each connection, in the
+    /// order the connections were given to `Balance::new`.
+    pub fn pending_requests(&self) -> Vec<usize> {
+        self.backends.iter()
+            .map(|backend| backend.pending.load(Ordering::SeqCst))
+            .collect()
+    }
+}
+
+impl<T, U, E> Service for Balance<T, U, E>
+    where T: Send + 'static,
+          U: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    type Req = T;
+    type Resp = U;
+    type Error = E;
+    type Fut = Box<Future<Item = U, Error = E>>;
+
+    fn call(&self, req: T) -> Self::Fut {
+        let backend = self.backends.iter()
+            .filter(|backend| !backend.client.is_closed())
+            .min_by_key(|backend| backend.pending.load(Ordering::SeqCst));
+
+        let backend = match backend {
+            Some(backend) => backend,
+            None => {
+                debug!("no live backends");
+                let err = io::Error::new(io::ErrorKind::NotConnected, "no live backends");
+                return futures::failed(E::from(err)).boxed();
+            }
+        };
+
+        let pending = backend.pending.load(Ordering::SeqCst);
+
+        if self.max_pending.map_or(false, |max| pending >= max) {
+            trace!("all backends at capacity");
+
+            if self.at_capacity == AtCapacity::Fail {
+                let err = io::Error::new(io::ErrorKind::WouldBlock, "all backends at capacity");
+                return futures::failed(E::from(err)).boxed();
+            }
+        }
+
+        backend.pending.fetch_add(1, Ordering::SeqCst);
+        let slot = Pending(backend.pending.clone());
+
+        backend.client.call(req).then(move |res| {
+            drop(slot);
+            res
+        }).boxed()
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,169 @@
+//! Run blocking request handlers on a thread pool.
+
+use {Service};
+use util::future::{self, Complete, Val};
+use std::{io, panic, thread};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// A `Service` running a blocking handler on a pool of worker threads.
+///
+/// Handlers that perform blocking I/O or CPU heavy work would stall the
+/// reactor if they were called directly by the dispatcher. Each call is
+/// instead handed to one of the workers, and its future completes once the
+/// handler returns. Calls wait for a free worker in the order they were
+/// made.
+///
+/// A handler that panics fails its call with an `io::Error`, the worker
+/// keeps processing the next calls. The workers exit once every clone of the
+/// `Blocking` has been dropped and the queued calls are processed.
+pub struct Blocking<R, T, E> {
+    tx: Arc<Mutex<mpsc::Sender<Job<R, T, E>>>>,
+}
+
+type Job<R, T, E> = (R, Complete<T, E>);
+
+impl<R, T, E> Blocking<R, T, E>
+    where R: Send + 'static,
+          T: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    /// Create a new `Blocking` running `handler` on `threads` worker threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero, or if a worker thread cannot be spawned.
+    pub fn new<F>(threads: usize, handler: F) -> Blocking<R, T, E>
+        where F: Fn(R) -> Result<T, E> + Send + Sync + 'static,
+    {
+        assert!(threads > 0, "threads must be greater than zero");
+
+        let (tx, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let handler = Arc::new(handler);
+
+        for i in 0..threads {
+            let rx = rx.clone();
+            let handler = handler.clone();
+
+            thread::Builder::new()
+                .name(format!("tokio-blocking-{}", i))
+                .spawn(move || work(&rx, &*handler))
+                .unwrap();
+        }
+
+        Blocking { tx: Arc::new(Mutex::new(tx)) }
+    }
+}
+
+impl<R, T, E> Service for Blocking<R, T, E>
+    where R: Send + 'static,
+          T: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+{
+    type Req = R;
+    type Resp = T;
+    type Error = E;
+    type Fut = Val<T, E>;
+
+    fn call(&self, req: R) -> Val<T, E> {
+        let (c, val) = future::pair();
+
+        if let Err(mpsc::SendError((_, c))) = self.tx.lock().unwrap().send((req, c)) {
+            c.error(E::from(io::Error::new(io::ErrorKind::Other, "blocking workers have shutdown")));
+        }
+
+        val
+    }
+}
+
+impl<R, T, E> Clone for Blocking<R, T, E> {
+    fn clone(&self) -> Blocking<R, T, E> {
+        Blocking { tx: self.tx.clone() }
+    }
+}
+
+// Run the jobs until every sender has been dropped
+fn work<R, T, E, F>(rx: &Mutex<mpsc::Receiver<Job<R, T, E>>>, handler: &F)
+    where T: Send + 'static,
+          E: From<io::Error> + Send + 'static,
+          F: Fn(R) -> Result<T, E>,
+{
+    loop {
+        // The lock is released before the handler runs
+        let job = rx.lock().unwrap().recv();
+
+        let (req, c) = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| handler(req))) {
+            Ok(Ok(resp)) => c.complete(resp),
+            Ok(Err(e)) => c.error(e),
+            Err(_) => {
+                debug!("blocking handler panicked");
+                c.error(E::from(io::Error::new(io::ErrorKind::Other, "blocking handler panicked")));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use reactor::Reactor;
+    use util::test::block_on;
+    use futures::Future;
+    use std::{io, thread};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_blocking_handler_does_not_stall_reactor() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let service = Blocking::new(2, |ms: u64| {
+            thread::sleep(Duration::from_millis(ms));
+            Ok::<&'static str, io::Error>("handled")
+        });
+
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+
+        handle.oneshot(move || {
+            service.call(200).then(move |res| {
+                tx.send(res.unwrap()).unwrap();
+                Ok::<(), ()>(())
+            }).forget();
+        });
+
+        // Ticked while the handler is still sleeping
+        handle.oneshot(move || {
+            tx2.send("ticked").unwrap();
+        });
+
+        assert_eq!("ticked", rx.recv().unwrap());
+        assert_eq!("handled", rx.recv().unwrap());
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_blocking_handler_panic_fails_call() {
+        let service = Blocking::new(1, |req: u32| {
+            if req == 0 {
+                panic!("boom");
+            }
+
+            Ok::<u32, io::Error>(req)
+        });
+
+        assert!(block_on(service.call(0)).is_err());
+
+        // The worker survives the panic
+        assert_eq!(1, block_on(service.call(1)).unwrap());
+    }
+}
@@ -0,0 +1,342 @@
+//! Cache the responses of a service.
+
+use {Service};
+use util::future::{self, Complete};
+use futures::{self, Future};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Memoizes the responses of the wrapped service by request key.
+///
+/// The key is extracted from the request by a function given to
+/// `Cache::new`. A request whose key has a cached response completes
+/// immediately without calling the wrapped service. Otherwise, the request
+/// is dispatched and a successful response is cached, evicting the oldest
+/// entry once the cache is at capacity.
+///
+/// Requests for a key that is already being dispatched wait for that
+/// response instead of calling the wrapped service again. Errors are never
+/// cached: if the dispatched request fails, the waiting requests are
+/// dispatched individually. The same goes for a dispatched request whose
+/// future is dropped before completing.
+pub struct Cache<K, S: Service> {
+    inner: Arc<S>,
+    key: Box<Fn(&S::Req) -> K + Send>,
+    capacity: usize,
+    ttl: Option<Duration>,
+    state: Arc<Mutex<State<K, S::Resp>>>,
+}
+
+struct State<K, R> {
+    entries: HashMap<K, Entry<R>>,
+    // Keys of the entries, oldest first
+    order: VecDeque<K>,
+    // Requests waiting on a response being dispatched, by key
+    pending: HashMap<K, Vec<Complete<R, ()>>>,
+}
+
+struct Entry<R> {
+    resp: R,
+    inserted: Instant,
+}
+
+// Held by the dispatched request. If it is dropped before completing, the
+// key stops being pending and the waiting requests are dispatched
+// individually.
+struct Leader<K: Hash + Eq, R: Send + 'static> {
+    state: Arc<Mutex<State<K, R>>>,
+    key: K,
+    done: bool,
+}
+
+impl<K, S> Cache<K, S>
+    where K: Hash + Eq + Clone + Send + 'static,
+          S: Service + Sync,
+          S::Resp: Clone,
+{
+    /// Create a new `Cache` holding up to `capacity` responses, keyed by the
+    /// value returned by `key`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `capacity` is 0.
+    pub fn new<F>(inner: S, capacity: usize, key: F) -> Cache<K, S>
+        where F: Fn(&S::Req) -> K + Send + 'static,
+    {
+        assert!(capacity > 0, "capacity must be greater than 0");
+
+        Cache {
+            inner: Arc::new(inner),
+            key: Box::new(key),
+            capacity: capacity,
+            ttl: None,
+            state: Arc::new(Mutex::new(State {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                pending: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Set how long a response stays cached. By default, responses stay
+    /// cached until they are evicted.
+    pub fn ttl(mut self, val: Duration) -> Self {
+        self.ttl = Some(val);
+        self
+    }
+}
+
+impl<K, S> Service for Cache<K, S>
+    where K: Hash + Eq + Clone + Send + 'static,
+          S: Service + Sync,
+          S::Resp: Clone,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let key = (self.key)(&req);
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(resp) = state.get(&key, self.ttl) {
+            trace!("cache hit");
+            return futures::finished(resp).boxed();
+        }
+
+        if let Some(waiters) = state.pending.get_mut(&key) {
+            trace!("waiting on in-flight request");
+
+            let (c, val) = future::pair();
+            waiters.push(c);
+
+            let inner = self.inner.clone();
+
+            return val.then(move |res| {
+                match res {
+                    Ok(resp) => futures::finished(resp).boxed(),
+                    // The dispatched request failed
+                    Err(()) => inner.call(req).boxed(),
+                }
+            }).boxed();
+        }
+
+        state.pending.insert(key.clone(), vec![]);
+
+        // The lock must not be held while calling the service, the response
+        // may complete immediately.
+        drop(state);
+
+        let mut leader = Leader {
+            state: self.state.clone(),
+            key: key,
+            done: false,
+        };
+
+        let capacity = self.capacity;
+
+        self.inner.call(req).then(move |res| {
+            let mut state = leader.state.lock().unwrap();
+            let waiters = state.pending.remove(&leader.key).unwrap_or(vec![]);
+
+            leader.done = true;
+
+            match res {
+                Ok(resp) => {
+                    state.insert(leader.key.clone(), resp.clone(), capacity);
+                    drop(state);
+
+                    for waiter in waiters {
+                        waiter.complete(resp.clone());
+                    }
+
+                    Ok(resp)
+                }
+                Err(e) => {
+                    drop(state);
+
+                    for waiter in waiters {
+                        waiter.error(());
+                    }
+
+                    Err(e)
+                }
+            }
+        }).boxed()
+    }
+}
+
+impl<K: Hash + Eq, R: Send + 'static> Drop for Leader<K, R> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        trace!("dispatched request dropped before completing");
+
+        let waiters = self.state.lock().unwrap().pending.remove(&self.key).unwrap_or(vec![]);
+
+        for waiter in waiters {
+            waiter.error(());
+        }
+    }
+}
+
+impl<K, R> State<K, R>
+    where K: Hash + Eq + Clone,
+          R: Clone,
+{
+    fn get(&mut self, key: &K, ttl: Option<Duration>) -> Option<R> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => ttl.map_or(false, |ttl| entry.inserted.elapsed() >= ttl),
+            None => return None,
+        };
+
+        if expired {
+            trace!("cached response expired");
+            self.remove(key);
+            return None;
+        }
+
+        self.entries.get(key).map(|entry| entry.resp.clone())
+    }
+
+    fn insert(&mut self, key: K, resp: R, capacity: usize) {
+        self.remove(&key);
+
+        self.entries.insert(key.clone(), Entry {
+            resp: resp,
+            inserted: Instant::now(),
+        });
+        self.order.push_back(key);
+
+        while self.order.len() > capacity {
+            if let Some(key) = self.order.pop_front() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::future::{self, Complete, Val};
+    use util::test::block_on;
+    use futures::Future;
+    use std::thread;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Responses are completed by the test
+    struct Deferred {
+        calls: Arc<AtomicUsize>,
+        pending: Arc<Mutex<Vec<Complete<u32, ()>>>>,
+    }
+
+    impl Service for Deferred {
+        type Req = u32;
+        type Resp = u32;
+        type Error = ();
+        type Fut = Val<u32, ()>;
+
+        fn call(&self, _: u32) -> Val<u32, ()> {
+            let (c, val) = future::pair();
+
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.pending.lock().unwrap().push(c);
+
+            val
+        }
+    }
+
+    fn deferred() -> (Deferred, Arc<AtomicUsize>, Arc<Mutex<Vec<Complete<u32, ()>>>>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let pending = Arc::new(Mutex::new(vec![]));
+
+        let service = Deferred {
+            calls: calls.clone(),
+            pending: pending.clone(),
+        };
+
+        (service, calls, pending)
+    }
+
+    #[test]
+    fn test_cache_hits_and_coalesces_misses() {
+        let (service, calls, pending) = deferred();
+        let cache = Cache::new(service, 16, |req: &u32| *req);
+
+        // Concurrent identical requests are dispatched once
+        let a = cache.call(1);
+        let b = cache.call(1);
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        pending.lock().unwrap().remove(0).complete(10);
+
+        assert_eq!(Ok(10), block_on(a));
+        assert_eq!(Ok(10), block_on(b));
+
+        // The response is cached
+        assert_eq!(Ok(10), block_on(cache.call(1)));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_cache_does_not_cache_errors() {
+        let (service, calls, pending) = deferred();
+        let cache = Cache::new(service, 16, |req: &u32| *req);
+
+        let a = cache.call(1);
+        pending.lock().unwrap().remove(0).error(());
+        assert_eq!(Err(()), block_on(a));
+
+        let b = cache.call(1);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        pending.lock().unwrap().remove(0).complete(10);
+        assert_eq!(Ok(10), block_on(b));
+    }
+
+    #[test]
+    fn test_cache_dispatches_again_after_leader_dropped() {
+        let (service, calls, pending) = deferred();
+        let cache = Cache::new(service, 16, |req: &u32| *req);
+
+        let a = cache.call(1);
+        let b = cache.call(1);
+        drop(a);
+
+        // The waiting request is dispatched on its own
+        let (tx, rx) = mpsc::channel();
+
+        b.then(move |res| {
+            tx.send(res).unwrap();
+            Ok::<(), ()>(())
+        }).forget();
+
+        while pending.lock().unwrap().len() < 2 {
+            thread::yield_now();
+        }
+
+        pending.lock().unwrap().remove(1).complete(10);
+        assert_eq!(Ok(10), rx.recv().unwrap());
+
+        // So are later requests for the key
+        let c = cache.call(1);
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+
+        pending.lock().unwrap().remove(1).complete(20);
+        assert_eq!(Ok(20), block_on(c));
+    }
+}
@@ -0,0 +1,277 @@
+//! Stop calling a service that keeps failing.
+
+use {Service};
+use futures::{self, Future};
+use std::{error, fmt, io};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Fails calls fast once the wrapped service has failed too many calls in a
+/// row, giving a dead backend time to recover instead of piling more
+/// requests onto it.
+///
+/// The circuit starts out closed, dispatching every call. After `failures`
+/// consecutive calls have failed, the circuit opens: calls fail immediately
+/// with a `CircuitOpen` error for the `cooldown` period. The first call made
+/// after the cooldown is dispatched as a probe while the circuit is half
+/// open, calls made while the probe is in flight fail fast as well. A
+/// successful probe closes the circuit, a failed one opens it for another
+/// cooldown.
+///
+/// Any successful call resets the count of consecutive failures.
+pub struct CircuitBreaker<S> {
+    inner: Arc<S>,
+    breaker: Arc<Mutex<Breaker>>,
+}
+
+/// The error wrapped by the `io::Error` a call fails with while the circuit
+/// is open.
+///
+/// The call was not dispatched. Check for it with
+/// `err.get_ref().map_or(false, |e| e.is::<CircuitOpen>())`.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+/// The state of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls are dispatched
+    Closed,
+    /// Calls fail fast until the cooldown has elapsed
+    Open,
+    /// A probe is in flight, or the next call is dispatched as a probe
+    HalfOpen,
+}
+
+struct Breaker {
+    max_failures: usize,
+    cooldown: Duration,
+    circuit: Circuit,
+}
+
+enum Circuit {
+    Closed { failures: usize },
+    Open { until: Instant },
+    // A probe is in flight
+    HalfOpen,
+}
+
+// Held by the probe call. If the probe is dropped before completing, the
+// circuit is opened again so that the next call probes instead.
+struct Probe(Arc<Mutex<Breaker>>);
+
+impl<S: Service> CircuitBreaker<S> {
+    /// Create a new `CircuitBreaker` opening after `failures` consecutive
+    /// failed calls to `inner`, for `cooldown` at a time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `failures` is zero.
+    pub fn new(inner: S, failures: usize, cooldown: Duration) -> CircuitBreaker<S> {
+        assert!(failures > 0, "failures must be greater than zero");
+
+        CircuitBreaker {
+            inner: Arc::new(inner),
+            breaker: Arc::new(Mutex::new(Breaker {
+                max_failures: failures,
+                cooldown: cooldown,
+                circuit: Circuit::Closed { failures: 0 },
+            })),
+        }
+    }
+
+    /// Returns the state of the circuit
+    pub fn state(&self) -> CircuitState {
+        match self.breaker.lock().unwrap().circuit {
+            Circuit::Closed { .. } => CircuitState::Closed,
+            Circuit::Open { until } if Instant::now() < until => CircuitState::Open,
+            Circuit::Open { .. } | Circuit::HalfOpen => CircuitState::HalfOpen,
+        }
+    }
+}
+
+impl<S> Service for CircuitBreaker<S>
+    where S: Service + Sync,
+          S::Error: From<io::Error>,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let probe = {
+            let mut breaker = self.breaker.lock().unwrap();
+
+            let probe = match breaker.circuit {
+                Circuit::Closed { .. } => false,
+                Circuit::Open { until } if Instant::now() >= until => true,
+                Circuit::Open { .. } | Circuit::HalfOpen => {
+                    trace!("circuit open; failing call");
+                    let err = io::Error::new(io::ErrorKind::Other, CircuitOpen);
+                    return futures::failed(S::Error::from(err)).boxed();
+                }
+            };
+
+            if probe {
+                debug!("circuit half open; probing");
+                breaker.circuit = Circuit::HalfOpen;
+                Some(Probe(self.breaker.clone()))
+            } else {
+                None
+            }
+        };
+
+        let breaker = self.breaker.clone();
+
+        self.inner.call(req).then(move |res| {
+            breaker.lock().unwrap().record(probe.is_some(), res.is_ok());
+            drop(probe);
+            res
+        }).boxed()
+    }
+}
+
+impl<S> Clone for CircuitBreaker<S> {
+    fn clone(&self) -> CircuitBreaker<S> {
+        CircuitBreaker {
+            inner: self.inner.clone(),
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+impl Breaker {
+    fn record(&mut self, probe: bool, ok: bool) {
+        let failures = match self.circuit {
+            Circuit::Closed { failures } => failures + 1,
+            _ if probe => self.max_failures,
+            // Calls dispatched before the circuit opened say nothing about
+            // the backend's recovery
+            _ => return,
+        };
+
+        if ok {
+            if probe {
+                debug!("probe succeeded; closing circuit");
+            }
+
+            self.circuit = Circuit::Closed { failures: 0 };
+            return;
+        }
+
+        if failures >= self.max_failures {
+            debug!("opening circuit; failures={}", failures);
+            self.circuit = Circuit::Open { until: Instant::now() + self.cooldown };
+        } else {
+            self.circuit = Circuit::Closed { failures: failures };
+        }
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        let mut breaker = self.0.lock().unwrap();
+
+        if let Circuit::HalfOpen = breaker.circuit {
+            trace!("probe dropped before completing");
+            breaker.circuit = Circuit::Open { until: Instant::now() };
+        }
+    }
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("circuit open")
+    }
+}
+
+impl error::Error for CircuitOpen {
+    fn description(&self) -> &str {
+        "circuit open"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::test::block_on;
+    use futures::{self, Done};
+    use std::{io, thread};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // Fails while `fail` is set, counting the calls it receives
+    struct Flaky {
+        fail: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service for Flaky {
+        type Req = ();
+        type Resp = ();
+        type Error = io::Error;
+        type Fut = Done<(), io::Error>;
+
+        fn call(&self, _: ()) -> Done<(), io::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if self.fail.load(Ordering::SeqCst) {
+                futures::done(Err(io::Error::new(io::ErrorKind::Other, "backend down")))
+            } else {
+                futures::done(Ok(()))
+            }
+        }
+    }
+
+    fn is_circuit_open(res: Result<(), io::Error>) -> bool {
+        match res {
+            Err(e) => e.get_ref().map_or(false, |e| e.is::<CircuitOpen>()),
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_circuit_opens_and_recovers() {
+        let fail = Arc::new(AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let service = Flaky { fail: fail.clone(), calls: calls.clone() };
+        let breaker = CircuitBreaker::new(service, 3, Duration::from_millis(50));
+
+        // A success resets the consecutive failures
+        assert!(block_on(breaker.call(())).is_err());
+        assert!(block_on(breaker.call(())).is_err());
+        fail.store(false, Ordering::SeqCst);
+        assert!(block_on(breaker.call(())).is_ok());
+        fail.store(true, Ordering::SeqCst);
+
+        for _ in 0..3 {
+            assert!(!is_circuit_open(block_on(breaker.call(()))));
+        }
+
+        assert_eq!(CircuitState::Open, breaker.state());
+        assert_eq!(6, calls.load(Ordering::SeqCst));
+
+        // Calls fail fast during the cooldown
+        assert!(is_circuit_open(block_on(breaker.call(()))));
+        assert_eq!(6, calls.load(Ordering::SeqCst));
+
+        // A failed probe opens the circuit again
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+        assert!(!is_circuit_open(block_on(breaker.call(()))));
+        assert_eq!(7, calls.load(Ordering::SeqCst));
+        assert!(is_circuit_open(block_on(breaker.call(()))));
+
+        // A successful probe closes it
+        thread::sleep(Duration::from_millis(60));
+        fail.store(false, Ordering::SeqCst);
+        assert!(block_on(breaker.call(())).is_ok());
+        assert_eq!(CircuitState::Closed, breaker.state());
+        assert!(block_on(breaker.call(())).is_ok());
+        assert_eq!(9, calls.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,97 @@
+//! Deduplicate retried requests.
+
+use {Service};
+use util::cache::Cache;
+use std::hash::Hash;
+
+/// Processes requests carrying the same idempotency key only once.
+///
+/// The key is extracted from the request by a function given to
+/// `Dedup::new`. A request whose key was seen within the window of the most
+/// recent `window` keys is answered with the response to the original
+/// request, without calling the wrapped service again. A request that is
+/// still in flight is shared by its duplicates. This makes it safe for
+/// clients to retry requests whose response was lost.
+///
+/// Only successful responses are remembered, so a request that failed is
+/// processed again when it is retried. A key that fell out of the window is
+/// treated as a new request.
+pub struct Dedup<K, S: Service> {
+    inner: Cache<K, S>,
+}
+
+impl<K, S> Dedup<K, S>
+    where K: Hash + Eq + Clone + Send + 'static,
+          S: Service + Sync,
+          S::Resp: Clone,
+{
+    /// Create a new `Dedup` remembering the responses to the last `window`
+    /// keys returned by `key`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `window` is 0.
+    pub fn new<F>(inner: S, window: usize, key: F) -> Dedup<K, S>
+        where F: Fn(&S::Req) -> K + Send + 'static,
+    {
+        Dedup { inner: Cache::new(inner, window, key) }
+    }
+}
+
+impl<K, S> Service for Dedup<K, S>
+    where K: Hash + Eq + Clone + Send + 'static,
+          S: Service + Sync,
+          S::Resp: Clone,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = <Cache<K, S> as Service>::Fut;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::test::block_on;
+    use futures::{self, Finished};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Requests are `(key, value)` pairs, answered with the number of calls
+    // made so far
+    struct Counter {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service for Counter {
+        type Req = (u32, u32);
+        type Resp = usize;
+        type Error = ();
+        type Fut = Finished<usize, ()>;
+
+        fn call(&self, _: (u32, u32)) -> Finished<usize, ()> {
+            futures::finished(self.calls.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+    }
+
+    #[test]
+    fn test_dedup_processes_keyed_request_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let dedup = Dedup::new(Counter { calls: calls.clone() }, 2, |req: &(u32, u32)| req.0);
+
+        assert_eq!(Ok(1), block_on(dedup.call((1, 10))));
+        assert_eq!(Ok(1), block_on(dedup.call((1, 10))));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        // Key 1 falls out of the window, and is then processed again
+        assert_eq!(Ok(2), block_on(dedup.call((2, 20))));
+        assert_eq!(Ok(3), block_on(dedup.call((3, 30))));
+        assert_eq!(Ok(4), block_on(dedup.call((1, 10))));
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,115 @@
+//! Delays driven by a reactor task, shared by the service middleware.
+
+use reactor::{self, ReactorHandle, Task, Tick};
+use util::channel::Receiver;
+use util::future::{self, Complete, Val};
+use util::timer::Timer;
+use mio::{self, channel};
+use std::io;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A handle to a reactor task completing futures once their delay elapsed.
+///
+/// The task completes once every handle has been dropped and the remaining
+/// delays have elapsed.
+#[derive(Clone)]
+pub struct Delays {
+    tx: channel::Sender<(Duration, Complete<(), io::Error>)>,
+}
+
+// Reactor task firing the delays requested through `Delays`
+struct DelayTimer {
+    // Watched on the first tick, so that the sources are associated with the
+    // task
+    rx: Option<channel::Receiver<(Duration, Complete<(), io::Error>)>>,
+    requests: Option<Receiver<(Duration, Complete<(), io::Error>)>>,
+    timer: Option<Timer<u64>>,
+    waiting: HashMap<u64, Complete<(), io::Error>>,
+    next: u64,
+    run: bool,
+}
+
+impl Delays {
+    /// Spawn the timer task on the given reactor
+    pub fn new(reactor: &ReactorHandle) -> Delays {
+        let (tx, rx) = channel::channel();
+
+        reactor.oneshot(move || {
+            try!(reactor::schedule(DelayTimer {
+                rx: Some(rx),
+                requests: None,
+                timer: None,
+                waiting: HashMap::new(),
+                next: 0,
+                run: true,
+            }));
+
+            Ok(())
+        });
+
+        Delays { tx: tx }
+    }
+
+    /// Returns a future completing once `delay` has elapsed, or `None` if the
+    /// timer task is not running.
+    pub fn delay(&self, delay: Duration) -> Option<Val<(), io::Error>> {
+        let (c, val) = future::pair();
+
+        if self.tx.send((delay, c)).is_err() {
+            return None;
+        }
+
+        Some(val)
+    }
+}
+
+impl Task for DelayTimer {
+    fn tick(&mut self) -> io::Result<Tick> {
+        if let Some(rx) = self.rx.take() {
+            self.requests = Some(try!(Receiver::watch(rx)));
+            self.timer = Some(try!(Timer::watch(mio::timer::Timer::default())));
+        }
+
+        let timer = self.timer.as_mut().unwrap();
+
+        // Fire expired delays
+        while let Some(key) = timer.poll() {
+            if let Some(c) = self.waiting.remove(&key) {
+                c.complete(());
+            }
+        }
+
+        // Arm new delays. Delays whose future has been dropped are still
+        // armed, completing them is a no-op.
+        while self.run {
+            match self.requests.as_ref().unwrap().recv() {
+                Ok(Some((delay, c))) => {
+                    let key = self.next;
+                    self.next += 1;
+
+                    match timer.set_timeout(delay, key) {
+                        Ok(_) => {
+                            self.waiting.insert(key, c);
+                        }
+                        Err(_) => {
+                            c.error(io::Error::new(io::ErrorKind::Other, "failed to set timeout"));
+                        }
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    // All `Delays` handles have been dropped, finish once the
+                    // remaining delays have elapsed
+                    self.run = false;
+                }
+            }
+        }
+
+        if !self.run && self.waiting.is_empty() {
+            return Ok(Tick::Final);
+        }
+
+        Ok(Tick::WouldBlock)
+    }
+}
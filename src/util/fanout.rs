@@ -0,0 +1,214 @@
+//! Scatter a request to several services and gather the responses.
+
+use {Service};
+use util::future::{self, Complete, Val};
+use futures::Future;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+/// Dispatches every request to all the wrapped services concurrently.
+///
+/// Once every service has responded, the responses are combined by the
+/// reducer into a single response. The reducer receives the responses in the
+/// order the services were given to `Fanout::new`.
+///
+/// Dispatching a request to several services requires a copy of it, hence
+/// the `Clone` bound on the request type.
+pub struct Fanout<S, F> {
+    services: Vec<S>,
+    reducer: Arc<F>,
+    on_error: ErrorPolicy,
+}
+
+/// How a `Fanout` handles a service failing a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Fail the request with the first error, without waiting on the other
+    /// services
+    FailFast,
+    /// Reduce the responses of the services that succeeded. The request only
+    /// fails, with the last error, if every service failed.
+    BestEffort,
+}
+
+struct State<T, E> {
+    responses: Vec<Option<T>>,
+    remaining: usize,
+    error: Option<E>,
+    // Taken once the request has completed
+    complete: Option<Complete<T, E>>,
+}
+
+impl<S, F> Fanout<S, F>
+    where S: Service,
+          S::Req: Clone,
+          F: Fn(Vec<S::Resp>) -> S::Resp + Send + Sync + 'static,
+{
+    /// Create a new `Fanout` dispatching to `services` and combining their
+    /// responses with `reducer`.
+    pub fn new(services: Vec<S>, reducer: F) -> Fanout<S, F> {
+        Fanout {
+            services: services,
+            reducer: Arc::new(reducer),
+            on_error: ErrorPolicy::FailFast,
+        }
+    }
+
+    /// Set how a service failing a request is handled. Defaults to
+    /// `ErrorPolicy::FailFast`.
+    pub fn on_error(mut self, val: ErrorPolicy) -> Self {
+        self.on_error = val;
+        self
+    }
+}
+
+impl<S, F> Service for Fanout<S, F>
+    where S: Service,
+          S::Req: Clone,
+          F: Fn(Vec<S::Resp>) -> S::Resp + Send + Sync + 'static,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Val<S::Resp, S::Error>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let (c, val) = future::pair();
+
+        if self.services.is_empty() {
+            c.complete((self.reducer)(vec![]));
+            return val;
+        }
+
+        let state = Arc::new(Mutex::new(State {
+            responses: self.services.iter().map(|_| None).collect(),
+            remaining: self.services.len(),
+            error: None,
+            complete: Some(c),
+        }));
+
+        for (i, service) in self.services.iter().enumerate() {
+            let state = state.clone();
+            let reducer = self.reducer.clone();
+            let on_error = self.on_error;
+
+            service.call(req.clone()).then(move |res| {
+                gather(&state, &*reducer, on_error, i, res);
+                Ok::<(), ()>(())
+            }).forget();
+        }
+
+        val
+    }
+}
+
+// Record the result of the `i`th service, completing the request once it is
+// known
+fn gather<T, E, F>(state: &Mutex<State<T, E>>,
+                   reducer: &F,
+                   on_error: ErrorPolicy,
+                   i: usize,
+                   res: Result<T, E>)
+        where T: Send + 'static,
+              E: Send + 'static,
+              F: Fn(Vec<T>) -> T,
+{
+    let mut state = state.lock().unwrap();
+
+    if state.complete.is_none() {
+        // Already failed
+        return;
+    }
+
+    match res {
+        Ok(resp) => state.responses[i] = Some(resp),
+        Err(e) => {
+            if on_error == ErrorPolicy::FailFast {
+                trace!("fanout request failed; service={}", i);
+                let c = state.complete.take().unwrap();
+                drop(state);
+
+                c.error(e);
+                return;
+            }
+
+            state.error = Some(e);
+        }
+    }
+
+    state.remaining -= 1;
+
+    if state.remaining > 0 {
+        return;
+    }
+
+    let c = state.complete.take().unwrap();
+    let responses: Vec<T> = mem::replace(&mut state.responses, vec![])
+        .into_iter()
+        .filter_map(|resp| resp)
+        .collect();
+    let error = state.error.take();
+
+    // The reducer and the completion run outside of the lock
+    drop(state);
+
+    match error {
+        Some(e) if responses.is_empty() => c.error(e),
+        _ => c.complete(reducer(responses)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::test::block_on;
+    use futures::{self, Done};
+
+    // Responds with its name, or fails
+    struct Backend {
+        name: &'static str,
+        fail: bool,
+    }
+
+    impl Service for Backend {
+        type Req = String;
+        type Resp = String;
+        type Error = String;
+        type Fut = Done<String, String>;
+
+        fn call(&self, req: String) -> Done<String, String> {
+            if self.fail {
+                futures::done(Err(format!("{} failed", self.name)))
+            } else {
+                futures::done(Ok(format!("{}:{}", self.name, req)))
+            }
+        }
+    }
+
+    fn backend(name: &'static str, fail: bool) -> Backend {
+        Backend { name: name, fail: fail }
+    }
+
+    fn concat(resps: Vec<String>) -> String {
+        resps.join(",")
+    }
+
+    #[test]
+    fn test_fanout_reduces_responses() {
+        let fanout = Fanout::new(vec![backend("a", false), backend("b", false)], concat);
+
+        assert_eq!(Ok("a:x,b:x".to_string()), block_on(fanout.call("x".to_string())));
+    }
+
+    #[test]
+    fn test_fanout_error_policy() {
+        let services = || vec![backend("a", false), backend("b", true)];
+
+        let fanout = Fanout::new(services(), concat);
+        assert_eq!(Err("b failed".to_string()), block_on(fanout.call("x".to_string())));
+
+        let fanout = Fanout::new(services(), concat).on_error(ErrorPolicy::BestEffort);
+        assert_eq!(Ok("a:x".to_string()), block_on(fanout.call("x".to_string())));
+    }
+}
@@ -4,11 +4,14 @@
 //!
 //! Anything that would require waiting for a value on a `Task` needs to be
 //! Tokio aware, including waiting for futures to complete. `Await` (to be
-//! written) and `AwaitQueue` provide facilities for notifying the Reactor when
-//! a future completes and may be polled.
+//! written), `AwaitQueue`, and `AwaitSet` provide facilities for notifying the
+//! Reactor when a future completes and may be polled.
 
 mod queue;
+mod registration;
+mod set;
 mod val;
 
 pub use self::queue::AwaitQueue;
+pub use self::set::AwaitSet;
 pub use self::val::{pair, Complete, Cancellation, Val};
@@ -1,9 +1,9 @@
 use io::Ready;
 use reactor::{self, Source};
-use mio::{self, Evented, EventSet, SetReadiness, Poll, PollOpt, Token};
+use util::future::registration::Registration;
+use mio::EventSet;
 use futures::{Future, Task};
-use std::io;
-use std::cell::RefCell;
+use std::{io, mem};
 use std::sync::{Arc, Mutex};
 
 /// Processes multiple Futures and return their completed values in order they
@@ -17,23 +17,20 @@ pub struct AwaitQueue<T: Future> {
     next_val: Arc<Mutex<Option<Result<T::Item, T::Error>>>>,
     remaining: Vec<T>,
     in_flight: bool,
+    capacity: usize,
     source: Source,
     registration: Registration,
 }
 
-// TODO: Extract
-struct Registration {
-    inner: RefCell<Option<(mio::Registration, SetReadiness)>>,
-}
-
 impl<T> AwaitQueue<T>
     where T: Future
 {
-    /// Create an `AwaitQueue` with an initial capacity of `n`
+    /// Create an `AwaitQueue` with a capacity of `n`
+    ///
+    /// The capacity is enforced by `try_push`. `push` grows the queue past
+    /// the capacity when needed.
     pub fn with_capacity(n: usize) -> io::Result<AwaitQueue<T>> {
-        let registration = Registration {
-            inner: RefCell::new(None),
-        };
+        let registration = Registration::new();
 
         let source = try!(reactor::register_source(&registration, Ready::readable()));
 
@@ -41,6 +38,7 @@ impl<T> AwaitQueue<T>
             next_val: Arc::new(Mutex::new(None)),
             remaining: Vec::with_capacity(n),
             in_flight: false,
+            capacity: n,
             source: source,
             registration: registration,
         })
@@ -62,7 +60,14 @@ impl<T> AwaitQueue<T>
         self.len() == 0
     }
 
+    /// Returns the max number of futures accepted by `try_push`.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
     /// Push a new future for processing.
+    ///
+    /// The queue grows past its capacity if needed.
     pub fn push(&mut self, future: T) {
         if self.in_flight {
             self.remaining.push(future);
@@ -72,21 +77,28 @@ impl<T> AwaitQueue<T>
         self.schedule_future(future);
     }
 
+    /// Push a new future for processing if the queue is not at capacity.
+    ///
+    /// If the queue is full, the future is returned back to the caller.
+    pub fn try_push(&mut self, future: T) -> Result<(), T> {
+        if self.len() >= self.capacity {
+            return Err(future);
+        }
+
+        self.push(future);
+        Ok(())
+    }
+
     /// Poll for the next completed value.
     ///
     /// If the future at the head of the `AwaitQueue` is complete, the result
     /// will be returned and the next future will begin being processed. If the
     /// future at the head of the queue is not ready, `None` is returned.
     pub fn poll(&mut self) -> Option<Result<T::Item, T::Error>> {
-        if !self.source.is_readable() {
-            return None;
-        }
-
+        // The value is checked even if the source has not been notified yet
+        // since the next future may have completed while it was scheduled.
         let v = self.next_val.lock().unwrap().take();
 
-        // The queue is not going to be readable anymore
-        self.source.unset_readable();
-
         if let Some(v) = v {
             // No futures are in flight
             self.in_flight = false;
@@ -105,9 +117,58 @@ impl<T> AwaitQueue<T>
             return Some(v);
         }
 
+        // The queue is not going to be readable until the next future
+        // completes
+        self.source.unset_readable();
+
         None
     }
 
+    /// Poll for every completed value at the head of the queue.
+    ///
+    /// Returns the values in order, stopping at the first future that is not
+    /// ready. This is the same as calling `poll` until it returns `None`,
+    /// which allows the values to be handled as a batch.
+    pub fn poll_all(&mut self) -> Vec<Result<T::Item, T::Error>> {
+        let mut ready = vec![];
+
+        while let Some(v) = self.poll() {
+            ready.push(v);
+        }
+
+        ready
+    }
+
+    /// Remove every future from the queue, returning the values that are
+    /// ready.
+    ///
+    /// Values are returned in order for as long as the futures at the head of
+    /// the queue have completed. The remaining futures are dropped, and the
+    /// value of the future in flight, if it has not completed yet, is
+    /// discarded once it completes. The queue is empty afterwards, even if
+    /// dropping one of the futures panics.
+    pub fn drain(&mut self) -> Vec<Result<T::Item, T::Error>> {
+        let mut ready = vec![];
+
+        while let Some(v) = self.next_val.lock().unwrap().take() {
+            ready.push(v);
+            self.in_flight = false;
+            self.schedule_next();
+        }
+
+        // Detach from the future in flight, its value is never returned
+        self.next_val = Arc::new(Mutex::new(None));
+        self.in_flight = false;
+
+        self.registration.set_readiness().unwrap()
+            .set_readiness(EventSet::none()).unwrap();
+
+        // Reset the state before dropping the futures
+        drop(mem::replace(&mut self.remaining, vec![]));
+
+        ready
+    }
+
     fn schedule_next(&mut self) {
         if self.remaining.len() > 0 {
             let f = self.remaining.remove(0);
@@ -132,45 +193,200 @@ impl<T> AwaitQueue<T>
     }
 }
 
-impl Registration {
-    fn set_readiness(&self) -> Option<SetReadiness> {
-        let inner = self.inner.borrow();
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reactor::{self, Reactor, Tick};
+    use util::future;
+    use futures::{self, Finished, Future, Poll, Task};
+    use std::{io, panic};
+    use std::sync::mpsc;
+
+    type BoxFuture = Box<Future<Item = u32, Error = ()>>;
+
+    // Never completes, and panics when dropped
+    struct PanicOnDrop;
 
-        match *inner {
-            Some((_, ref set_readiness)) => Some(set_readiness.clone()),
-            _ => None,
+    impl Future for PanicOnDrop {
+        type Item = u32;
+        type Error = ();
+
+        fn poll(&mut self, _: &mut Task) -> Poll<u32, ()> {
+            Poll::NotReady
         }
-    }
-}
 
-impl Evented for Registration {
-    fn register(&self, poll: &Poll, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
-        let mut inner = self.inner.borrow_mut();
+        fn schedule(&mut self, _: &mut Task) {
+        }
+    }
 
-        if inner.is_some() {
-            return Err(io::Error::new(io::ErrorKind::Other, "already registered"));
+    impl Drop for PanicOnDrop {
+        fn drop(&mut self) {
+            panic!("dropped");
         }
+    }
 
-        let mio = mio::Registration::new(poll, token, interest, opts);
-        *inner = Some(mio);
-        Ok(())
+    #[test]
+    fn test_try_push_at_capacity() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let mut queue = AwaitQueue::with_capacity(2).unwrap();
+
+            assert!(queue.try_push(futures::finished::<u32, ()>(1)).is_ok());
+            assert!(queue.try_push(futures::finished(2)).is_ok());
+            assert!(queue.try_push(futures::finished(3)).is_err());
+
+            tx.send(queue.len()).unwrap();
+        });
+
+        assert_eq!(2, rx.recv().unwrap());
+
+        handle.shutdown();
     }
 
-    fn reregister(&self, poll: &Poll, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
-        let inner = self.inner.borrow();
+    #[test]
+    fn test_try_push_after_drain() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
 
-        match *inner {
-            Some((ref r, _)) => r.update(poll, token, interest, opts),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "not registered")),
-        }
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let mut queue: Option<AwaitQueue<Finished<u32, ()>>> = None;
+            let mut rejected = None;
+
+            reactor::schedule(move || -> io::Result<Tick> {
+                // The queue must be created on the task that polls it
+                if queue.is_none() {
+                    let mut q = try!(AwaitQueue::with_capacity(1));
+
+                    assert!(q.try_push(futures::finished(1)).is_ok());
+                    rejected = q.try_push(futures::finished(2)).err();
+                    assert!(rejected.is_some());
+
+                    queue = Some(q);
+                }
+
+                let queue = queue.as_mut().unwrap();
+
+                while let Some(res) = queue.poll() {
+                    tx.send(res).unwrap();
+
+                    // Draining the queue frees up capacity
+                    if let Some(f) = rejected.take() {
+                        assert!(queue.try_push(f).is_ok());
+                    }
+                }
+
+                if queue.is_empty() {
+                    Ok(Tick::Final)
+                } else {
+                    Ok(Tick::WouldBlock)
+                }
+            })
+        });
+
+        assert_eq!(Ok(1), rx.recv().unwrap());
+        assert_eq!(Ok(2), rx.recv().unwrap());
+
+        handle.shutdown();
     }
 
-    fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        let inner = self.inner.borrow();
+    #[test]
+    fn test_poll_all_returns_ready_values() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
 
-        match *inner {
-            Some((ref r, _)) => r.deregister(poll),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "not registered")),
-        }
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let (_c, val) = future::pair::<u32, ()>();
+            let mut queue: AwaitQueue<BoxFuture> = AwaitQueue::with_capacity(4).unwrap();
+
+            // The first three are ready, the fourth is pending
+            queue.push(futures::finished(1).boxed());
+            queue.push(futures::failed(()).boxed());
+            queue.push(futures::finished(3).boxed());
+            queue.push(val.boxed());
+
+            tx.send((queue.poll_all(), queue.len())).unwrap();
+        });
+
+        let (ready, len) = rx.recv().unwrap();
+
+        assert_eq!(vec![Ok(1), Err(()), Ok(3)], ready);
+        assert_eq!(1, len);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_drain_returns_ready_values() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let (c, val) = future::pair::<u32, ()>();
+            let mut queue: AwaitQueue<BoxFuture> = AwaitQueue::with_capacity(4).unwrap();
+
+            // The first two complete immediately, the third is pending
+            queue.push(futures::finished(1).boxed());
+            queue.push(futures::finished(2).boxed());
+            queue.push(val.boxed());
+            queue.push(futures::finished(4).boxed());
+
+            let drained = queue.drain();
+
+            // Values of futures completing after the drain are discarded
+            c.complete(3);
+
+            tx.send((drained, queue.is_empty(), queue.poll())).unwrap();
+        });
+
+        let (drained, is_empty, polled) = rx.recv().unwrap();
+
+        assert_eq!(vec![Ok(1), Ok(2)], drained);
+        assert!(is_empty);
+        assert_eq!(None, polled);
+
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_drain_is_consistent_when_a_future_panics_on_drop() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, rx) = mpsc::channel();
+
+        handle.oneshot(move || {
+            let (_c, val) = future::pair::<u32, ()>();
+            let mut queue: AwaitQueue<BoxFuture> = AwaitQueue::with_capacity(4).unwrap();
+
+            queue.push(val.boxed());
+            queue.push(Box::new(PanicOnDrop));
+
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| queue.drain()));
+
+            tx.send((res.is_err(), queue.is_empty(), queue.poll())).unwrap();
+        });
+
+        let (panicked, is_empty, polled) = rx.recv().unwrap();
+
+        assert!(panicked);
+        assert!(is_empty);
+        assert_eq!(None, polled);
+
+        handle.shutdown();
     }
 }
@@ -0,0 +1,58 @@
+use mio::{self, Evented, EventSet, SetReadiness, Poll, PollOpt, Token};
+use std::io;
+use std::cell::RefCell;
+
+/// An `Evented` value whose readiness is set manually, possibly from another
+/// thread, once a future completes.
+pub struct Registration {
+    inner: RefCell<Option<(mio::Registration, SetReadiness)>>,
+}
+
+impl Registration {
+    pub fn new() -> Registration {
+        Registration {
+            inner: RefCell::new(None),
+        }
+    }
+
+    pub fn set_readiness(&self) -> Option<SetReadiness> {
+        let inner = self.inner.borrow();
+
+        match *inner {
+            Some((_, ref set_readiness)) => Some(set_readiness.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Evented for Registration {
+    fn register(&self, poll: &Poll, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+
+        if inner.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other, "already registered"));
+        }
+
+        let mio = mio::Registration::new(poll, token, interest, opts);
+        *inner = Some(mio);
+        Ok(())
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        let inner = self.inner.borrow();
+
+        match *inner {
+            Some((ref r, _)) => r.update(poll, token, interest, opts),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "not registered")),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        let inner = self.inner.borrow();
+
+        match *inner {
+            Some((ref r, _)) => r.deregister(poll),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "not registered")),
+        }
+    }
+}
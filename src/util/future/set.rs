@@ -0,0 +1,105 @@
+use io::Ready;
+use reactor::{self, Source};
+use util::future::registration::Registration;
+use mio::EventSet;
+use futures::{Future, Task};
+use std::io;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Processes multiple Futures concurrently and returns their completed values,
+/// tagged with the key each future was pushed with, in the order that they
+/// complete.
+///
+/// Unlike `AwaitQueue`, a future that is slow to complete does not hold back
+/// the values of futures pushed after it.
+pub struct AwaitSet<K, T: Future> {
+    completed: Arc<Mutex<VecDeque<(K, Result<T::Item, T::Error>)>>>,
+    pending: usize,
+    source: Source,
+    registration: Registration,
+}
+
+impl<K, T> AwaitSet<K, T>
+    where K: Send + 'static,
+          T: Future,
+{
+    /// Create an `AwaitSet` with an initial capacity of `n`
+    pub fn with_capacity(n: usize) -> io::Result<AwaitSet<K, T>> {
+        let registration = Registration::new();
+
+        let source = try!(reactor::register_source(&registration, Ready::readable()));
+
+        Ok(AwaitSet {
+            completed: Arc::new(Mutex::new(VecDeque::with_capacity(n))),
+            pending: 0,
+            source: source,
+            registration: registration,
+        })
+    }
+
+    /// Return the number of futures that have been pushed and whose values
+    /// have not yet been returned by `poll`.
+    pub fn len(&self) -> usize {
+        self.pending
+    }
+
+    /// Returns true if there are no pending futures.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push a new future for processing, tagged with `key`.
+    pub fn push(&mut self, key: K, future: T) {
+        self.pending += 1;
+
+        let set_readiness = self.registration.set_readiness().unwrap();
+        let dst = self.completed.clone();
+
+        let f = future.then(move |res| {
+            trace!("future received value");
+
+            let mut completed = dst.lock().unwrap();
+            completed.push_back((key, res));
+            set_readiness.set_readiness(EventSet::readable()).unwrap();
+
+            Ok(())
+        });
+
+        Task::new().run(Box::new(f));
+    }
+
+    /// Poll for the next completed value.
+    ///
+    /// Returns the value of any future that has completed along with its key,
+    /// or `None` if no pushed future has completed since the last call.
+    pub fn poll(&mut self) -> Option<(K, Result<T::Item, T::Error>)> {
+        if !self.source.is_readable() {
+            return None;
+        }
+
+        let mut completed = self.completed.lock().unwrap();
+
+        match completed.pop_front() {
+            Some(v) => {
+                self.pending -= 1;
+
+                // Track progress at the source
+                self.source.advance();
+
+                Some(v)
+            }
+            None => {
+                // Unset the `Evented` readiness while holding the lock so that
+                // a future completing concurrently is not missed
+                self.registration.set_readiness().unwrap()
+                    .set_readiness(EventSet::none()).unwrap();
+
+                // The set is not going to be readable anymore
+                self.source.unset_readable();
+
+                None
+            }
+        }
+    }
+}
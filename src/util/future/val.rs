@@ -132,6 +132,15 @@ impl<T, E> Complete<T, E>
         self.inner.complete(None, true);
     }
 
+    /// Returns true if the consuming end has cancelled interest in the
+    /// future by dropping `Val` without consuming the value.
+    pub fn is_cancelled(&self) -> bool {
+        match *self.inner.state.lock().unwrap() {
+            Cancelled => true,
+            _ => false,
+        }
+    }
+
     /// Returns a `Future` representing the consuming end cancelling interest
     /// in the future.
     ///
@@ -196,6 +205,11 @@ impl<T, E> Inner<T, E> {
 
         match state.take() {
             Init { consumer, .. } => cb = consumer,
+            Cancelled => {
+                // Nobody is interested in the value, discard it
+                *state = Cancelled;
+                return;
+            }
             s => {
                 if res.is_some() {
                     panic!("attempting to complete already completed future");
@@ -398,6 +412,24 @@ mod test {
         c.abort();
     }
 
+    #[test]
+    fn test_is_cancelled() {
+        let (c, val) = pair::<u32, ()>();
+
+        assert!(!c.is_cancelled());
+
+        drop(val);
+        assert!(c.is_cancelled());
+    }
+
+    #[test]
+    fn test_complete_after_cancel_discards_value() {
+        let (c, val) = pair::<u32, ()>();
+
+        drop(val);
+        c.complete(123);
+    }
+
     #[test]
     fn test_cancellation_future() {
         let (c, val) = pair::<u32, ()>();
@@ -0,0 +1,111 @@
+//! Instrument the calls made to a service.
+
+use {Service};
+use futures::{Future, Poll};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Measures the latency of every call to the wrapped service, passing it to
+/// a function.
+///
+/// The latency is the wall-clock time from `call` until the returned future
+/// completes, successfully or not. This allows tracking latency percentiles,
+/// for example by feeding the durations to a histogram, without changing the
+/// service itself. Nothing is recorded for futures dropped before they
+/// complete.
+pub struct Timed<S, F> {
+    inner: S,
+    f: Arc<F>,
+}
+
+impl<S, F> Timed<S, F> {
+    /// Create a new `Timed` passing the latency of the calls to `inner` to
+    /// `f`.
+    pub fn new(inner: S, f: F) -> Timed<S, F> {
+        Timed {
+            inner: inner,
+            f: Arc::new(f),
+        }
+    }
+}
+
+impl<S, F> Service for Timed<S, F>
+    where S: Service,
+          F: Fn(Duration) + Send + Sync + 'static,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let f = self.f.clone();
+        let start = Instant::now();
+
+        self.inner.call(req).then(move |res| {
+            f(start.elapsed());
+            res
+        }).boxed()
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), S::Error> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<S: Clone, F> Clone for Timed<S, F> {
+    fn clone(&self) -> Timed<S, F> {
+        Timed {
+            inner: self.inner.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service, simple_service};
+    use util::future;
+    use util::test::block_on;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timed_records_call_latency() {
+        // Requests are answered after sleeping for the given number of ms,
+        // requests of 0 are never answered
+        let service = simple_service(|ms: u64| {
+            let (c, val) = future::pair::<u64, io::Error>();
+
+            if ms > 0 {
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(ms));
+                    c.complete(ms);
+                });
+            }
+
+            val
+        });
+
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let sink = recorded.clone();
+
+        let timed = Timed::new(service, move |latency| sink.lock().unwrap().push(latency));
+
+        assert_eq!(50, block_on(timed.call(50)).unwrap());
+
+        {
+            let recorded = recorded.lock().unwrap();
+            assert_eq!(1, recorded.len());
+            assert!(recorded[0] >= Duration::from_millis(50));
+            assert!(recorded[0] < Duration::from_secs(1));
+        }
+
+        // Dropped calls are not recorded
+        drop(timed.call(0));
+        assert_eq!(1, recorded.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,175 @@
+//! Limit the number of requests a `Service` processes concurrently.
+
+use {Service};
+use util::future::{self, Complete, Val};
+use futures::{Future, Poll};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Limits the number of in-flight calls to the wrapped service.
+///
+/// Once the limit is reached, calls wait for an in-flight call to complete
+/// before being dispatched to the wrapped service. Waiting calls are
+/// dispatched in the order they were made.
+///
+/// Like `SharedService`, calls to the wrapped service are serialized by a
+/// mutex, which also lets `poll_ready` be forwarded to it.
+pub struct ConcurrencyLimit<S: Service> {
+    inner: Arc<Mutex<S>>,
+    state: Arc<Mutex<State<S::Error>>>,
+}
+
+struct State<E: Send + 'static> {
+    max: usize,
+    in_flight: usize,
+    waiters: VecDeque<Complete<Permit<E>, E>>,
+}
+
+// Held for the duration of an in-flight call. Dropping the permit hands it to
+// the next waiter or frees the slot.
+struct Permit<E: Send + 'static> {
+    state: Arc<Mutex<State<E>>>,
+}
+
+impl<S: Service> ConcurrencyLimit<S> {
+    /// Create a new `ConcurrencyLimit` allowing at most `max` concurrent
+    /// calls to `inner`.
+    pub fn new(inner: S, max: usize) -> ConcurrencyLimit<S> {
+        assert!(max > 0, "concurrency limit must be greater than zero");
+
+        ConcurrencyLimit {
+            inner: Arc::new(Mutex::new(inner)),
+            state: Arc::new(Mutex::new(State {
+                max: max,
+                in_flight: 0,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+}
+
+impl<S: Service> Service for ConcurrencyLimit<S> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let waiter: Val<Permit<S::Error>, S::Error> = {
+            let mut state = self.state.lock().unwrap();
+
+            if state.in_flight < state.max {
+                state.in_flight += 1;
+                drop(state);
+
+                let permit = Permit { state: self.state.clone() };
+
+                return Box::new(self.inner.lock().unwrap().call(req).then(move |res| {
+                    drop(permit);
+                    res
+                }));
+            }
+
+            let (c, val) = future::pair();
+            state.waiters.push_back(c);
+            val
+        };
+
+        let inner = self.inner.clone();
+
+        Box::new(waiter.and_then(move |permit| {
+            inner.lock().unwrap().call(req).then(move |res| {
+                drop(permit);
+                res
+            })
+        }))
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), S::Error> {
+        self.inner.lock().unwrap().poll_ready()
+    }
+}
+
+impl<S: Service> Clone for ConcurrencyLimit<S> {
+    fn clone(&self) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit {
+            inner: self.inner.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<E: Send + 'static> Drop for Permit<E> {
+    fn drop(&mut self) {
+        let waiter = {
+            let mut state = self.state.lock().unwrap();
+
+            match state.waiters.pop_front() {
+                Some(waiter) => waiter,
+                None => {
+                    state.in_flight -= 1;
+                    return;
+                }
+            }
+        };
+
+        // Hand the slot to the next waiter, outside of the lock. If the waiter
+        // has been cancelled, the permit is dropped and passed along again.
+        waiter.complete(Permit { state: self.state.clone() });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::future::{self, Complete, Val};
+    use futures::Future;
+    use std::sync::Mutex;
+    use std::sync::mpsc::{self, Sender};
+
+    // Hands the `Complete` for every call to the test
+    struct Calls {
+        tx: Mutex<Sender<(u32, Complete<u32, ()>)>>,
+    }
+
+    impl Service for Calls {
+        type Req = u32;
+        type Resp = u32;
+        type Error = ();
+        type Fut = Val<u32, ()>;
+
+        fn call(&self, req: u32) -> Val<u32, ()> {
+            let (c, val) = future::pair();
+            self.tx.lock().unwrap().send((req, c)).unwrap();
+            val
+        }
+    }
+
+    #[test]
+    fn test_limit_serializes_calls() {
+        let (tx, calls) = mpsc::channel();
+        let (resp_tx, resps) = mpsc::channel();
+
+        let limit = ConcurrencyLimit::new(Calls { tx: Mutex::new(tx) }, 1);
+
+        for i in 0..3 {
+            let resp_tx = resp_tx.clone();
+
+            limit.call(i).then(move |res| {
+                resp_tx.send(res).unwrap();
+                res
+            }).forget();
+        }
+
+        for i in 0..3 {
+            // Only a single call reaches the inner service at a time
+            let (req, c) = calls.recv().unwrap();
+            assert_eq!(i, req);
+            assert!(calls.try_recv().is_err());
+
+            c.complete(req * 10);
+            assert_eq!(Ok(i * 10), resps.recv().unwrap());
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Adapt the errors and responses of a service.
+
+use {Service};
+use futures::{Future, Poll};
+use std::sync::Arc;
+
+/// Maps the errors returned by the wrapped service using a function.
+///
+/// This is useful for adapting a service to the error type expected by a
+/// protocol dispatcher or by other middleware.
+pub struct MapErr<S, F> {
+    inner: S,
+    f: Arc<F>,
+}
+
+/// Maps the responses returned by the wrapped service using a function.
+pub struct MapResponse<S, F> {
+    inner: S,
+    f: Arc<F>,
+}
+
+impl<S, F> MapErr<S, F> {
+    /// Create a new `MapErr` passing the errors returned by `inner` through
+    /// `f`.
+    pub fn new(inner: S, f: F) -> MapErr<S, F> {
+        MapErr {
+            inner: inner,
+            f: Arc::new(f),
+        }
+    }
+}
+
+impl<S, F, E> Service for MapErr<S, F>
+    where S: Service,
+          F: Fn(S::Error) -> E + Send + Sync + 'static,
+          E: Send + 'static,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = E;
+    type Fut = Box<Future<Item = S::Resp, Error = E>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let f = self.f.clone();
+        self.inner.call(req).map_err(move |e| f(e)).boxed()
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), E> {
+        match self.inner.poll_ready() {
+            Poll::Ok(()) => Poll::Ok(()),
+            Poll::Err(e) => Poll::Err((self.f)(e)),
+            Poll::NotReady => Poll::NotReady,
+        }
+    }
+}
+
+impl<S: Clone, F> Clone for MapErr<S, F> {
+    fn clone(&self) -> MapErr<S, F> {
+        MapErr {
+            inner: self.inner.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+impl<S, F> MapResponse<S, F> {
+    /// Create a new `MapResponse` passing the responses returned by `inner`
+    /// through `f`.
+    pub fn new(inner: S, f: F) -> MapResponse<S, F> {
+        MapResponse {
+            inner: inner,
+            f: Arc::new(f),
+        }
+    }
+}
+
+impl<S, F, R> Service for MapResponse<S, F>
+    where S: Service,
+          F: Fn(S::Resp) -> R + Send + Sync + 'static,
+          R: Send + 'static,
+{
+    type Req = S::Req;
+    type Resp = R;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = R, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let f = self.f.clone();
+        self.inner.call(req).map(move |resp| f(resp)).boxed()
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), S::Error> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<S: Clone, F> Clone for MapResponse<S, F> {
+    fn clone(&self) -> MapResponse<S, F> {
+        MapResponse {
+            inner: self.inner.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
@@ -1,5 +1,26 @@
 //! Utilities for writing Tokio applications
 
+pub mod balance;
+pub mod blocking;
+pub mod cache;
 pub mod channel;
+pub mod circuit;
+pub mod dedup;
+mod delay;
+pub mod fanout;
 pub mod future;
+pub mod instrument;
+pub mod limit;
+pub mod map_err;
+pub mod pool;
+pub mod ratelimit;
+pub mod retry;
+pub mod router;
+pub mod service_fn;
+pub mod shed;
+pub mod shared;
+pub mod test;
+pub mod timeout;
 pub mod timer;
+pub mod transport;
+pub mod validate;
@@ -0,0 +1,91 @@
+//! Reuse client connections across requests.
+
+use {Service};
+use proto::multiplex::{self, ClientHandle, NewTransport, RequestId};
+use reactor::ReactorHandle;
+use util::future::Val;
+use std::io;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+/// A pool of multiplex client connections, keyed by address.
+///
+/// Up to `max_per_addr` connections are kept open to each address. Requests
+/// are issued on the connection with the fewest pending requests, and a new
+/// connection is only opened when every existing one is busy.
+///
+/// Connections that have shutdown are evicted from the pool rather than
+/// handed out, so the next request to the address transparently reconnects.
+pub struct ClientPool<T: NewTransport> {
+    reactor: ReactorHandle,
+    new_transport: T,
+    max_per_addr: usize,
+    clients: Mutex<HashMap<SocketAddr, Vec<ClientHandle<T::In, T::Out, T::Error>>>>,
+}
+
+impl<T> ClientPool<T>
+    where T: NewTransport<RequestId = RequestId> + Clone,
+          T::Error: From<io::Error>,
+{
+    /// Create a new `ClientPool` connecting using `new_transport` on the
+    /// given reactor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_per_addr` is zero.
+    pub fn new(reactor: &ReactorHandle, new_transport: T, max_per_addr: usize) -> ClientPool<T> {
+        assert!(max_per_addr > 0, "max_per_addr must be positive");
+
+        ClientPool {
+            reactor: reactor.clone(),
+            new_transport: new_transport,
+            max_per_addr: max_per_addr,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Issue a request to the given address.
+    pub fn call(&self, addr: SocketAddr, req: T::In) -> Val<T::Out, T::Error> {
+        let mut clients = self.clients.lock().unwrap();
+        let conns = clients.entry(addr).or_insert_with(Vec::new);
+
+        evict(conns);
+
+        let idle = conns.iter().any(|c| c.pending_requests() == 0);
+
+        if conns.is_empty() || (!idle && conns.len() < self.max_per_addr) {
+            debug!("opening pooled connection; addr={:?}", addr);
+            conns.push(multiplex::connect(&self.reactor, addr, self.new_transport.clone()));
+        }
+
+        conns.iter()
+            .min_by_key(|c| c.pending_requests())
+            .unwrap()
+            .call(req)
+    }
+
+    /// Returns the number of live connections to the given address.
+    pub fn connections(&self, addr: &SocketAddr) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+
+        match clients.get_mut(addr) {
+            Some(conns) => {
+                evict(conns);
+                conns.len()
+            }
+            None => 0,
+        }
+    }
+}
+
+fn evict<T, U, E>(conns: &mut Vec<ClientHandle<T, U, E>>) {
+    conns.retain(|c| {
+        if c.is_closed() {
+            debug!("evicting closed pooled connection");
+            return false;
+        }
+
+        true
+    });
+}
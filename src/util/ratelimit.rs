@@ -0,0 +1,210 @@
+//! Limit the rate of requests a `Service` processes.
+
+use {Service};
+use reactor::ReactorHandle;
+use util::delay::Delays;
+use futures::{self, Future};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits the rate of calls to the wrapped service using a token bucket.
+///
+/// Every call takes a token from the bucket, which is refilled at `rate`
+/// tokens per second up to the burst capacity. Calls made while the bucket is
+/// empty wait for their token before being dispatched to the wrapped service,
+/// in the order they were made.
+///
+/// The burst capacity bounds how many calls may be dispatched at once after
+/// the service has been idle. It defaults to 1, spacing every call evenly.
+pub struct RateLimit<S> {
+    inner: Arc<S>,
+    bucket: Arc<Mutex<Bucket>>,
+    timer: Delays,
+}
+
+struct Bucket {
+    // Tokens per second
+    rate: f64,
+    burst: f64,
+    // Negative once calls are waiting on tokens that have not been refilled
+    // yet
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl<S: Service> RateLimit<S> {
+    /// Create a new `RateLimit` allowing `rate` calls per second to `inner`.
+    ///
+    /// Waiting calls are delayed by a task running on the given reactor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is zero.
+    pub fn new(reactor: &ReactorHandle, inner: S, rate: u32) -> RateLimit<S> {
+        assert!(rate > 0, "rate must be greater than zero");
+
+        RateLimit {
+            inner: Arc::new(inner),
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: rate as f64,
+                burst: 1.0,
+                tokens: 1.0,
+                refilled: Instant::now(),
+            })),
+            timer: Delays::new(reactor),
+        }
+    }
+
+    /// Set the burst capacity of the bucket, the bucket starts out full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is zero.
+    pub fn burst(self, val: u32) -> Self {
+        assert!(val > 0, "burst must be greater than zero");
+
+        {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.burst = val as f64;
+            bucket.tokens = val as f64;
+        }
+
+        self
+    }
+}
+
+impl<S> Service for RateLimit<S>
+    where S: Service + Sync,
+          S::Error: From<io::Error>,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let wait = self.bucket.lock().unwrap().take();
+
+        let wait = match wait {
+            Some(wait) => wait,
+            None => return self.inner.call(req).boxed(),
+        };
+
+        trace!("rate limited; wait={:?}", wait);
+
+        let delay = match self.timer.delay(wait) {
+            Some(delay) => delay,
+            None => {
+                let err = io::Error::new(io::ErrorKind::Other, "rate limit timer is not running");
+                return futures::failed(S::Error::from(err)).boxed();
+            }
+        };
+
+        let inner = self.inner.clone();
+
+        delay.then(move |res| {
+            match res {
+                Ok(()) => inner.call(req).boxed(),
+                Err(e) => futures::failed(S::Error::from(e)).boxed(),
+            }
+        }).boxed()
+    }
+}
+
+impl<S> Clone for RateLimit<S> {
+    fn clone(&self) -> RateLimit<S> {
+        RateLimit {
+            inner: self.inner.clone(),
+            bucket: self.bucket.clone(),
+            timer: self.timer.clone(),
+        }
+    }
+}
+
+impl Bucket {
+    // Take a token, returning how long the call must wait for it if the
+    // bucket is empty
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            return None;
+        }
+
+        // The token is handed out once the calls waiting ahead of this one
+        // have been served
+        let wait = -self.tokens / self.rate;
+        let secs = wait.trunc();
+        let nanos = (wait - secs) * 1_000_000_000.0;
+
+        Some(Duration::new(secs as u64, nanos as u32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service, simple_service};
+    use reactor::Reactor;
+    use futures::{self, Future};
+    use std::io;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_rate_limit_bounds_bursts() {
+        let reactor = Reactor::default().unwrap();
+        let handle = reactor.handle();
+        reactor.spawn();
+
+        let (tx, calls) = mpsc::channel();
+        let tx = Mutex::new(tx);
+
+        let service = simple_service(move |req: u32| {
+            tx.lock().unwrap().send(req).unwrap();
+            futures::finished::<u32, io::Error>(req)
+        });
+
+        let limit = RateLimit::new(&handle, service, 10).burst(3);
+        let (done_tx, done_rx) = mpsc::channel();
+        let start = Instant::now();
+
+        for i in 0..6 {
+            let done_tx = done_tx.clone();
+
+            limit.call(i).then(move |res| {
+                done_tx.send(res.is_ok()).unwrap();
+                Ok::<(), ()>(())
+            }).forget();
+        }
+
+        // The burst is dispatched right away
+        for i in 0..3 {
+            assert_eq!(Ok(i), calls.try_recv());
+        }
+
+        assert!(calls.try_recv().is_err());
+
+        // The remaining calls are dispatched at the configured rate, the last
+        // one 300ms after the burst
+        let mut rest: Vec<u32> = (3..6).map(|_| calls.recv().unwrap()).collect();
+        rest.sort();
+        assert_eq!(vec![3, 4, 5], rest);
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        for _ in 0..6 {
+            assert!(done_rx.recv().unwrap());
+        }
+
+        handle.shutdown();
+    }
+}
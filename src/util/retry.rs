@@ -0,0 +1,148 @@
+//! Retry requests that fail with transient errors.
+
+use {Service};
+use futures::{self, Future, Poll};
+use std::sync::{Arc, Mutex};
+
+/// Re-issues failed requests to the wrapped service.
+///
+/// The predicate is called with the request and the error it failed with
+/// and decides whether the request may be re-issued. Since only the caller
+/// knows which requests are idempotent, the predicate is expected to look at
+/// the request as well as the error.
+///
+/// Re-issuing a request requires a copy of it, hence the `Clone` bound on
+/// the request type. Like `SharedService`, calls to the wrapped service are
+/// serialized by a mutex, which also lets `poll_ready` be forwarded to it.
+pub struct Retry<S, F> {
+    inner: Arc<Mutex<S>>,
+    predicate: Arc<F>,
+    max_retries: usize,
+}
+
+impl<S, F> Retry<S, F>
+    where S: Service,
+          S::Req: Clone,
+          F: Fn(&S::Req, &S::Error) -> bool + Send + Sync + 'static,
+{
+    /// Create a new `Retry` re-issuing a failed request up to `max_retries`
+    /// times as long as `predicate` returns true.
+    pub fn new(inner: S, max_retries: usize, predicate: F) -> Retry<S, F> {
+        Retry {
+            inner: Arc::new(Mutex::new(inner)),
+            predicate: Arc::new(predicate),
+            max_retries: max_retries,
+        }
+    }
+}
+
+impl<S, F> Service for Retry<S, F>
+    where S: Service,
+          S::Req: Clone,
+          F: Fn(&S::Req, &S::Error) -> bool + Send + Sync + 'static,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        attempt(self.inner.clone(), self.predicate.clone(), req, self.max_retries)
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), S::Error> {
+        self.inner.lock().unwrap().poll_ready()
+    }
+}
+
+impl<S, F> Clone for Retry<S, F> {
+    fn clone(&self) -> Retry<S, F> {
+        Retry {
+            inner: self.inner.clone(),
+            predicate: self.predicate.clone(),
+            max_retries: self.max_retries,
+        }
+    }
+}
+
+fn attempt<S, F>(inner: Arc<Mutex<S>>, predicate: Arc<F>, req: S::Req, remaining: usize)
+        -> Box<Future<Item = S::Resp, Error = S::Error>>
+        where S: Service,
+              S::Req: Clone,
+              F: Fn(&S::Req, &S::Error) -> bool + Send + Sync + 'static,
+{
+    let resp = inner.lock().unwrap().call(req.clone());
+
+    Box::new(resp.or_else(move |err| {
+        if remaining > 0 && predicate(&req, &err) {
+            trace!("retrying request; remaining={:?}", remaining);
+            attempt(inner, predicate, req, remaining - 1)
+        } else {
+            Box::new(futures::failed(err))
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use futures::{self, Done, Future};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    // Fails the first `failures` calls
+    struct Flaky {
+        calls: Arc<AtomicUsize>,
+        failures: usize,
+    }
+
+    impl Service for Flaky {
+        type Req = &'static str;
+        type Resp = usize;
+        type Error = ();
+        type Fut = Done<usize, ()>;
+
+        fn call(&self, _: &'static str) -> Done<usize, ()> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if n < self.failures {
+                futures::done(Err(()))
+            } else {
+                futures::done(Ok(n))
+            }
+        }
+    }
+
+    fn call<S: Service>(service: &S, req: S::Req) -> Result<S::Resp, S::Error> {
+        let (tx, rx) = mpsc::channel();
+
+        service.call(req).then(move |res| {
+            tx.send(res).unwrap();
+            Ok::<(), ()>(())
+        }).forget();
+
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn test_retries_until_success() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let flaky = Flaky { calls: calls.clone(), failures: 2 };
+        let retry = Retry::new(flaky, 5, |_: &&'static str, _: &()| true);
+
+        assert_eq!(Ok(2), call(&retry, "get"));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_does_not_retry_rejected_requests() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let flaky = Flaky { calls: calls.clone(), failures: 2 };
+        let retry = Retry::new(flaky, 5, |req: &&'static str, _: &()| *req == "get");
+
+        assert_eq!(Err(()), call(&retry, "put"));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,119 @@
+//! Route requests to one of several services.
+
+use {Service};
+use futures::{self, Future};
+use std::io;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Dispatches each request to the service registered for the request's key.
+///
+/// The key is extracted from the request by a function given to
+/// `Router::new`. Every route is a service of the same type, so they all
+/// share the same request, response, and error types; routes of different
+/// types can be boxed into a common service type.
+///
+/// Requests without a matching route are dispatched to the default route, if
+/// one is set, and otherwise fail with a `NotFound` error.
+pub struct Router<K, S: Service> {
+    key: Box<Fn(&S::Req) -> K + Send>,
+    routes: HashMap<K, S>,
+    default: Option<S>,
+}
+
+impl<K, S> Router<K, S>
+    where K: Hash + Eq + Send + 'static,
+          S: Service,
+          S::Error: From<io::Error>,
+{
+    /// Create a new `Router` without any routes, routing requests by the key
+    /// returned by `key`.
+    pub fn new<F>(key: F) -> Router<K, S>
+        where F: Fn(&S::Req) -> K + Send + 'static,
+    {
+        Router {
+            key: Box::new(key),
+            routes: HashMap::new(),
+            default: None,
+        }
+    }
+
+    /// Route requests with the given key to `service`, replacing any service
+    /// previously routed to the key.
+    pub fn route(mut self, key: K, service: S) -> Self {
+        self.routes.insert(key, service);
+        self
+    }
+
+    /// Route requests that do not match any route to `service`.
+    pub fn default_route(mut self, service: S) -> Self {
+        self.default = Some(service);
+        self
+    }
+}
+
+impl<K, S> Service for Router<K, S>
+    where K: Hash + Eq + Send + 'static,
+          S: Service,
+          S::Error: From<io::Error>,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let key = (self.key)(&req);
+
+        match self.routes.get(&key).or(self.default.as_ref()) {
+            Some(service) => service.call(req).boxed(),
+            None => {
+                trace!("no route for request");
+                let err = io::Error::new(io::ErrorKind::NotFound, "no route for request");
+                futures::failed(S::Error::from(err)).boxed()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::test::block_on;
+    use futures::{self, Finished};
+    use std::io;
+
+    // Scales requests by a factor
+    struct Scale(u32);
+
+    impl Service for Scale {
+        type Req = u32;
+        type Resp = u32;
+        type Error = io::Error;
+        type Fut = Finished<u32, io::Error>;
+
+        fn call(&self, req: u32) -> Finished<u32, io::Error> {
+            futures::finished(req * self.0)
+        }
+    }
+
+    #[test]
+    fn test_routes_requests_by_key() {
+        let router = Router::new(|req: &u32| *req % 10)
+            .route(1, Scale(10))
+            .route(2, Scale(100));
+
+        assert_eq!(110, block_on(router.call(11)).unwrap());
+        assert_eq!(1200, block_on(router.call(12)).unwrap());
+
+        match block_on(router.call(13)) {
+            Err(e) => assert_eq!(io::ErrorKind::NotFound, e.kind()),
+            Ok(_) => panic!("expected unmatched request to fail"),
+        }
+
+        // Unmatched requests go to the default route once set
+        let router = router.default_route(Scale(1));
+        assert_eq!(13, block_on(router.call(13)).unwrap());
+    }
+}
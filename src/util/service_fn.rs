@@ -0,0 +1,64 @@
+//! Build services from closures.
+
+use {Service};
+use futures::{Future, IntoFuture};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+/// A `Service` backed by an `FnMut` closure.
+///
+/// Unlike `SimpleService`, the closure may mutate the state it captures.
+/// Calls are serialized by a mutex that is held while the closure runs, but
+/// not while the returned future completes.
+///
+/// Created by `service_fn`.
+pub struct ServiceFn<F, R, E> {
+    f: Mutex<F>,
+    _ty: PhantomData<Mutex<(R, E)>>,
+}
+
+/// Returns a `Service` backed by the given closure.
+///
+/// Errors produced by the closure's future are converted into the service's
+/// error type using `From`.
+///
+/// ```rust,ignore
+/// let mut count = 0;
+///
+/// let service = service_fn(move |req: Request| {
+///     count += 1;
+///     Ok::<_, MyError>(Response::new(count))
+/// });
+/// ```
+pub fn service_fn<F, R, E, S>(f: F) -> ServiceFn<F, R, E>
+    where F: FnMut(R) -> S + Send + 'static,
+          R: Send + 'static,
+          S: IntoFuture,
+          E: From<S::Error> + Send + 'static,
+{
+    ServiceFn {
+        f: Mutex::new(f),
+        _ty: PhantomData,
+    }
+}
+
+impl<F, R, E, S> Service for ServiceFn<F, R, E>
+    where F: FnMut(R) -> S + Send + 'static,
+          R: Send + 'static,
+          S: IntoFuture,
+          E: From<S::Error> + Send + 'static,
+{
+    type Req = R;
+    type Resp = S::Item;
+    type Error = E;
+    type Fut = Box<Future<Item = S::Item, Error = E>>;
+
+    fn call(&self, req: R) -> Self::Fut {
+        let fut = {
+            let mut f = self.f.lock().unwrap();
+            (*f)(req).into_future()
+        };
+
+        fut.map_err(E::from).boxed()
+    }
+}
@@ -0,0 +1,47 @@
+//! Share a single service between multiple dispatchers.
+
+use {Service};
+use futures::Poll;
+use std::sync::{Arc, Mutex};
+
+/// A `Clone` handle to a service that is not itself `Clone`.
+///
+/// All clones call the same service value. Calls are serialized by a mutex
+/// that is held while the wrapped service's `call` or `poll_ready` runs, but
+/// not while the returned future completes. This preserves the `&mut`
+/// requirement of `poll_ready` without requiring the service to be `Sync`.
+///
+/// Since `NewService` is implemented for every `Service + Clone`, a
+/// `SharedService` can be used as the service factory of a server, handing
+/// the same service to every connection.
+pub struct SharedService<S> {
+    inner: Arc<Mutex<S>>,
+}
+
+impl<S: Service> SharedService<S> {
+    /// Create a new `SharedService` wrapping `inner`
+    pub fn new(inner: S) -> SharedService<S> {
+        SharedService { inner: Arc::new(Mutex::new(inner)) }
+    }
+}
+
+impl<S: Service> Service for SharedService<S> {
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = S::Fut;
+
+    fn call(&self, req: S::Req) -> S::Fut {
+        self.inner.lock().unwrap().call(req)
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), S::Error> {
+        self.inner.lock().unwrap().poll_ready()
+    }
+}
+
+impl<S> Clone for SharedService<S> {
+    fn clone(&self) -> SharedService<S> {
+        SharedService { inner: self.inner.clone() }
+    }
+}
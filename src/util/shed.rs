@@ -0,0 +1,231 @@
+//! Reject calls while a service is overloaded.
+
+use {Service};
+use futures::{self, Future};
+use std::{error, fmt, io};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Weight of the latest latency in the moving average
+const EWMA_WEIGHT: f64 = 0.1;
+
+/// Rejects calls while a `Signal` reports that the wrapped service is
+/// overloaded, instead of queuing more work onto it and making the latency
+/// of every call worse.
+///
+/// Rejected calls fail immediately with an `Overloaded` error without being
+/// dispatched, leaving the caller free to retry elsewhere or later. The
+/// signal is checked on every call, calls dispatched before it went up are
+/// unaffected.
+pub struct LoadShed<S, G> {
+    inner: Arc<S>,
+    signal: Arc<G>,
+}
+
+/// Reports whether a service is overloaded.
+///
+/// Implemented for closures returning a `bool`, which makes for an easy way
+/// to shed load based on a queue depth tracked elsewhere.
+pub trait Signal: Send + Sync + 'static {
+    /// Returns true if calls should be rejected
+    fn is_overloaded(&self) -> bool;
+
+    /// Called with the latency of every dispatched call once it completes
+    fn record(&self, _latency: Duration) {}
+}
+
+/// A `Signal` going up once the moving average of the call latency exceeds
+/// a threshold.
+pub struct LatencyEwma {
+    threshold: Duration,
+    // Average latency in seconds, unset until a call completes
+    ewma: Mutex<Option<f64>>,
+}
+
+/// The error wrapped by the `io::Error` a call fails with when it is shed.
+///
+/// The call was not dispatched. Check for it with
+/// `err.get_ref().map_or(false, |e| e.is::<Overloaded>())`.
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl<S, G> LoadShed<S, G>
+    where S: Service,
+          G: Signal,
+{
+    /// Create a new `LoadShed` rejecting calls to `inner` while `signal`
+    /// reports overload.
+    pub fn new(inner: S, signal: G) -> LoadShed<S, G> {
+        LoadShed {
+            inner: Arc::new(inner),
+            signal: Arc::new(signal),
+        }
+    }
+
+    /// Returns a reference to the signal
+    pub fn signal(&self) -> &G {
+        &self.signal
+    }
+}
+
+impl<S, G> Service for LoadShed<S, G>
+    where S: Service + Sync,
+          S::Error: From<io::Error>,
+          G: Signal,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        if self.signal.is_overloaded() {
+            trace!("service overloaded; shedding call");
+            let err = io::Error::new(io::ErrorKind::Other, Overloaded);
+            return futures::failed(S::Error::from(err)).boxed();
+        }
+
+        let signal = self.signal.clone();
+        let start = Instant::now();
+
+        self.inner.call(req).then(move |res| {
+            signal.record(start.elapsed());
+            res
+        }).boxed()
+    }
+}
+
+impl<S, G> Clone for LoadShed<S, G> {
+    fn clone(&self) -> LoadShed<S, G> {
+        LoadShed {
+            inner: self.inner.clone(),
+            signal: self.signal.clone(),
+        }
+    }
+}
+
+impl<F> Signal for F
+    where F: Fn() -> bool + Send + Sync + 'static,
+{
+    fn is_overloaded(&self) -> bool {
+        self()
+    }
+}
+
+impl LatencyEwma {
+    /// Create a new `LatencyEwma` reporting overload while the average
+    /// latency is above `threshold`.
+    pub fn new(threshold: Duration) -> LatencyEwma {
+        LatencyEwma {
+            threshold: threshold,
+            ewma: Mutex::new(None),
+        }
+    }
+
+    /// Returns the average latency, once a call has completed
+    pub fn latency(&self) -> Option<Duration> {
+        self.ewma.lock().unwrap().map(|secs| {
+            Duration::new(secs as u64, (secs.fract() * 1_000_000_000.0) as u32)
+        })
+    }
+}
+
+impl Signal for LatencyEwma {
+    fn is_overloaded(&self) -> bool {
+        self.latency().map_or(false, |latency| latency > self.threshold)
+    }
+
+    fn record(&self, latency: Duration) {
+        let secs = latency.as_secs() as f64 + latency.subsec_nanos() as f64 / 1_000_000_000.0;
+        let mut ewma = self.ewma.lock().unwrap();
+
+        *ewma = Some(match *ewma {
+            Some(avg) => avg + EWMA_WEIGHT * (secs - avg),
+            None => secs,
+        });
+    }
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("service overloaded")
+    }
+}
+
+impl error::Error for Overloaded {
+    fn description(&self) -> &str {
+        "service overloaded"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::test::block_on;
+    use futures::{self, Done};
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    struct Counting {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service for Counting {
+        type Req = ();
+        type Resp = ();
+        type Error = io::Error;
+        type Fut = Done<(), io::Error>;
+
+        fn call(&self, _: ()) -> Done<(), io::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            futures::done(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_sheds_calls_while_overloaded() {
+        let overloaded = Arc::new(AtomicBool::new(false));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let signal = {
+            let overloaded = overloaded.clone();
+            move || overloaded.load(Ordering::SeqCst)
+        };
+
+        let service = LoadShed::new(Counting { calls: calls.clone() }, signal);
+
+        assert!(block_on(service.call(())).is_ok());
+
+        overloaded.store(true, Ordering::SeqCst);
+
+        match block_on(service.call(())) {
+            Err(e) => assert!(e.get_ref().map_or(false, |e| e.is::<Overloaded>())),
+            Ok(_) => panic!("expected the call to be shed"),
+        }
+
+        // The shed call never reached the service
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        overloaded.store(false, Ordering::SeqCst);
+        assert!(block_on(service.call(())).is_ok());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_latency_ewma_signal() {
+        let signal = LatencyEwma::new(Duration::from_millis(100));
+        assert!(!signal.is_overloaded());
+
+        signal.record(Duration::from_millis(50));
+        assert!(!signal.is_overloaded());
+
+        for _ in 0..20 {
+            signal.record(Duration::from_millis(500));
+        }
+
+        assert!(signal.is_overloaded());
+    }
+}
@@ -0,0 +1,310 @@
+//! Utilities for testing Tokio tasks and services.
+
+use io::{Readiness, Transport};
+use futures::Future;
+use std::{io, mem};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+
+/// A `Transport` reading frames from a queue and recording the written
+/// frames.
+///
+/// Clones share the same state, so a clone kept by the test can be used to
+/// queue frames, toggle readiness, and inspect the written frames after the
+/// transport has been handed to a task.
+///
+/// The transport is not backed by a source, so the reactor is not notified
+/// when its state changes. Tests should either drive the task directly by
+/// calling `Task::tick` or rely on another source to tick the task.
+pub struct MockTransport<In, Out> {
+    inner: Arc<Mutex<Inner<In, Out>>>,
+}
+
+struct Inner<In, Out> {
+    rd: VecDeque<io::Result<Out>>,
+    wr: Vec<In>,
+    writable: bool,
+    flushed: bool,
+    flushes: usize,
+    shutdowns: usize,
+}
+
+impl<In, Out> MockTransport<In, Out> {
+    /// Create a new `MockTransport` that reads `frames`.
+    ///
+    /// The transport starts out writable with writes flushing immediately.
+    pub fn new(frames: Vec<Out>) -> MockTransport<In, Out> {
+        MockTransport {
+            inner: Arc::new(Mutex::new(Inner {
+                rd: frames.into_iter().map(Ok).collect(),
+                wr: vec![],
+                writable: true,
+                flushed: true,
+                flushes: 0,
+                shutdowns: 0,
+            })),
+        }
+    }
+
+    /// Queue a frame to be read
+    pub fn push_read(&self, frame: Out) {
+        self.inner.lock().unwrap().rd.push_back(Ok(frame));
+    }
+
+    /// Queue an error to be returned by `read`
+    pub fn push_read_error(&self, err: io::Error) {
+        self.inner.lock().unwrap().rd.push_back(Err(err));
+    }
+
+    /// Set whether the transport is writable
+    pub fn set_writable(&self, val: bool) {
+        self.inner.lock().unwrap().writable = val;
+    }
+
+    /// Set whether writes are flushed.
+    ///
+    /// While `false`, `write` and `flush` report the data as not fully
+    /// flushed.
+    pub fn set_flushed(&self, val: bool) {
+        self.inner.lock().unwrap().flushed = val;
+    }
+
+    /// Returns the frames written since the last call
+    pub fn take_written(&self) -> Vec<In> {
+        let mut inner = self.inner.lock().unwrap();
+        mem::replace(&mut inner.wr, vec![])
+    }
+
+    /// Returns the number of times `flush` has been called
+    pub fn flushes(&self) -> usize {
+        self.inner.lock().unwrap().flushes
+    }
+
+    /// Returns the number of times `shutdown` has been called
+    pub fn shutdowns(&self) -> usize {
+        self.inner.lock().unwrap().shutdowns
+    }
+
+    fn flush_state(&self) -> Option<()> {
+        if self.inner.lock().unwrap().flushed {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl<In, Out> Clone for MockTransport<In, Out> {
+    fn clone(&self) -> MockTransport<In, Out> {
+        MockTransport { inner: self.inner.clone() }
+    }
+}
+
+impl<In, Out> Readiness for MockTransport<In, Out> {
+    fn is_readable(&self) -> bool {
+        !self.inner.lock().unwrap().rd.is_empty()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.lock().unwrap().writable
+    }
+}
+
+impl<In, Out> Transport for MockTransport<In, Out> {
+    type In = In;
+    type Out = Out;
+
+    fn read(&mut self) -> io::Result<Option<Out>> {
+        match self.inner.lock().unwrap().rd.pop_front() {
+            Some(Ok(frame)) => Ok(Some(frame)),
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, frame: In) -> io::Result<Option<()>> {
+        self.inner.lock().unwrap().wr.push(frame);
+        Ok(self.flush_state())
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        self.inner.lock().unwrap().flushes += 1;
+        Ok(self.flush_state())
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().shutdowns += 1;
+        Ok(())
+    }
+}
+
+/// Block the current thread until `future` completes, returning its result.
+///
+/// The future is driven by the futures executor, so it may be notified from
+/// any thread. No reactor is required, which makes this useful for calling a
+/// `Service` directly in a test. Futures that depend on Tokio sources still
+/// need a running reactor to drive them.
+///
+/// # Panics
+///
+/// Panics if the future is dropped without completing.
+pub fn block_on<F: Future>(future: F) -> Result<F::Item, F::Error> {
+    let (tx, rx) = mpsc::channel();
+
+    future.then(move |res| {
+        let _ = tx.send(res);
+        Ok::<(), ()>(())
+    }).forget();
+
+    rx.recv().expect("future dropped before completing")
+}
+
+#[cfg(test)]
+pub use self::logs::{capture_logs, captured_logs};
+
+// A logger can only be set once per process, so the unit tests share a
+// logger. Lines are only captured on the threads that asked for them, which
+// keeps the tests running concurrently from seeing each other's lines.
+#[cfg(test)]
+mod logs {
+    use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord};
+    use std::cell::RefCell;
+    use std::sync::{Once, ONCE_INIT};
+
+    static INIT: Once = ONCE_INIT;
+
+    thread_local!(static LINES: RefCell<Option<Vec<(String, String)>>> = RefCell::new(None));
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, _: &LogMetadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &LogRecord) {
+            LINES.with(|lines| {
+                if let Some(ref mut lines) = *lines.borrow_mut() {
+                    lines.push((record.target().to_string(), format!("{}", record.args())));
+                }
+            });
+        }
+    }
+
+    /// Start capturing the lines logged by the current thread at every
+    /// level, discarding any line captured so far
+    pub fn capture_logs() {
+        INIT.call_once(|| {
+            log::set_logger(|max| {
+                max.set(LogLevelFilter::Trace);
+                Box::new(Capture)
+            }).unwrap();
+        });
+
+        LINES.with(|lines| *lines.borrow_mut() = Some(vec![]));
+    }
+
+    /// Returns the lines logged with `target` by the current thread since
+    /// `capture_logs` was called, in order
+    pub fn captured_logs(target: &str) -> Vec<String> {
+        LINES.with(|lines| {
+            lines.borrow().as_ref().expect("log capture not started on this thread").iter()
+                .filter(|&&(ref t, _)| t == target)
+                .map(|&(_, ref line)| line.clone())
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::{Readiness, Transport};
+    use util::future;
+    use futures::{self, Future, Poll, Task};
+    use std::{io, thread};
+    use std::time::Duration;
+
+    #[test]
+    fn test_mock_transport_reads_queued_frames() {
+        let mut transport: MockTransport<(), u32> = MockTransport::new(vec![1, 2]);
+        transport.push_read_error(io::Error::new(io::ErrorKind::Other, "boom"));
+
+        assert!(transport.is_readable());
+        assert_eq!(Some(1), transport.read().unwrap());
+        assert_eq!(Some(2), transport.read().unwrap());
+        assert!(transport.read().is_err());
+
+        assert!(!transport.is_readable());
+        assert_eq!(None, transport.read().unwrap());
+    }
+
+    #[test]
+    fn test_mock_transport_records_writes() {
+        let mut transport: MockTransport<u32, ()> = MockTransport::new(vec![]);
+        let mock = transport.clone();
+
+        assert!(transport.is_writable());
+        assert_eq!(Some(()), transport.write(1).unwrap());
+
+        mock.set_writable(false);
+        mock.set_flushed(false);
+
+        assert!(!transport.is_writable());
+        assert_eq!(None, transport.write(2).unwrap());
+        assert_eq!(None, transport.flush().unwrap());
+
+        mock.set_flushed(true);
+        assert_eq!(Some(()), transport.flush().unwrap());
+
+        assert_eq!(vec![1, 2], mock.take_written());
+        assert!(mock.take_written().is_empty());
+    }
+
+    #[test]
+    fn test_block_on_ready_future() {
+        assert_eq!(Ok(1), block_on(futures::finished::<u32, ()>(1)));
+        assert_eq!(Err(2), block_on(futures::failed::<u32, u32>(2)));
+    }
+
+    #[test]
+    fn test_block_on_future_that_pends_once() {
+        // Not ready on the first poll, notifies the task to be polled again
+        struct PendOnce {
+            polled: bool,
+        }
+
+        impl Future for PendOnce {
+            type Item = u32;
+            type Error = ();
+
+            fn poll(&mut self, _: &mut Task) -> Poll<u32, ()> {
+                if self.polled {
+                    return Poll::Ok(1);
+                }
+
+                self.polled = true;
+                Poll::NotReady
+            }
+
+            fn schedule(&mut self, task: &mut Task) {
+                task.handle().notify();
+            }
+        }
+
+        assert_eq!(Ok(1), block_on(PendOnce { polled: false }));
+    }
+
+    #[test]
+    fn test_block_on_future_completed_from_other_thread() {
+        let (c, val) = future::pair::<u32, ()>();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            c.complete(1);
+        });
+
+        assert_eq!(Ok(1), block_on(val));
+    }
+}
@@ -0,0 +1,159 @@
+//! Timeouts for Tokio tasks and services.
+
+use {Service};
+use reactor::{self, ReactorHandle, Task, Tick};
+use util::delay::Delays;
+use util::timer::Timer;
+use futures::{Future, Poll};
+use mio;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Wraps a `Task`, terminating it with a `TimedOut` error if it goes longer
+/// than the idle duration without making progress.
+///
+/// A task makes progress whenever one of its sources is able to complete an
+/// operation, for example reading from or writing to a socket or receiving
+/// the value of an in-flight future.
+pub struct TimeoutTask<T> {
+    task: T,
+    idle: Duration,
+    last_progress: Instant,
+    // Created on the first tick, since it must be registered with the reactor
+    timer: Option<Timer<()>>,
+}
+
+impl<T: Task> TimeoutTask<T> {
+    /// Create a new `TimeoutTask` wrapping `task`, which times out after
+    /// `idle` without progress.
+    pub fn new(task: T, idle: Duration) -> TimeoutTask<T> {
+        TimeoutTask {
+            task: task,
+            idle: idle,
+            last_progress: Instant::now(),
+            timer: None,
+        }
+    }
+}
+
+impl<T: Task> Task for TimeoutTask<T> {
+    fn tick(&mut self) -> io::Result<Tick> {
+        let tick = try!(self.task.tick());
+        let now = Instant::now();
+
+        if try!(reactor::did_advance()) {
+            self.last_progress = now;
+        }
+
+        if let Tick::Final = tick {
+            return Ok(Tick::Final);
+        }
+
+        if self.timer.is_none() {
+            let mut timer = try!(Timer::watch(mio::timer::Timer::default()));
+            try!(set_timeout(&mut timer, self.idle));
+            self.timer = Some(timer);
+        }
+
+        let timer = self.timer.as_mut().unwrap();
+
+        // Rather than resetting the timeout on each bit of progress, check how
+        // long the task has been idle once it fires and re-arm it for the
+        // remaining time.
+        while let Some(()) = timer.poll() {
+            let idle = now.duration_since(self.last_progress);
+
+            if idle >= self.idle {
+                debug!("task timed out; idle={:?}", idle);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "task idle timeout"));
+            }
+
+            try!(set_timeout(timer, self.idle - idle));
+        }
+
+        Ok(tick)
+    }
+}
+
+/// Wraps a `Service`, failing calls with a `TimedOut` error if they take
+/// longer than the configured duration to complete.
+///
+/// Unlike `TimeoutTask`, which bounds how long a connection may be idle, this
+/// bounds the latency of individual requests. When a call times out, the
+/// future returned by the inner service is dropped.
+pub struct TimeoutService<S> {
+    inner: S,
+    timeout: Duration,
+    timer: Delays,
+}
+
+impl<S: Service> TimeoutService<S> {
+    /// Create a new `TimeoutService` wrapping `inner`, failing calls that do
+    /// not complete within `timeout`.
+    ///
+    /// The timeouts are driven by a task running on the given reactor.
+    pub fn new(reactor: &ReactorHandle, inner: S, timeout: Duration) -> TimeoutService<S> {
+        TimeoutService {
+            inner: inner,
+            timeout: timeout,
+            timer: Delays::new(reactor),
+        }
+    }
+}
+
+impl<S> Service for TimeoutService<S>
+    where S: Service,
+          S::Error: From<io::Error>,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        let resp = self.inner.call(req);
+        let timeout = match self.timer.delay(self.timeout) {
+            Some(timeout) => timeout,
+            None => {
+                warn!("timeout service timer is not running");
+                return resp.boxed();
+            }
+        };
+
+        let timeout = timeout.then(|res| {
+            let err = match res {
+                Ok(()) => io::Error::new(io::ErrorKind::TimedOut, "request timed out"),
+                Err(e) => e,
+            };
+
+            Err::<S::Resp, S::Error>(From::from(err))
+        });
+
+        // Whichever future loses the race is dropped
+        resp.select(timeout)
+            .map(|(v, _)| v)
+            .map_err(|(e, _)| e)
+            .boxed()
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), S::Error> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<S: Clone> Clone for TimeoutService<S> {
+    fn clone(&self) -> TimeoutService<S> {
+        TimeoutService {
+            inner: self.inner.clone(),
+            timeout: self.timeout,
+            timer: self.timer.clone(),
+        }
+    }
+}
+
+fn set_timeout(timer: &mut Timer<()>, delay: Duration) -> io::Result<()> {
+    match timer.set_timeout(delay, ()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "failed to set timeout")),
+    }
+}
@@ -0,0 +1,417 @@
+//! Transport combinators.
+
+use io::{Readiness, Transport};
+use proto::pipeline::Frame;
+use log::LogLevel;
+use std::fmt;
+use std::io;
+
+// Default max number of bytes of a frame's representation that are logged
+const MAX_LOG_BYTES: usize = 256;
+
+// Default min payload size for a frame to be compressed
+const MIN_COMPRESS_SIZE: usize = 128;
+
+// Leading payload byte flagging whether the rest is compressed
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Logs every frame read from and written to the wrapped transport.
+///
+/// Frames are logged at the debug level using their `Debug` representation,
+/// prefixed with the label, which is useful when bringing up a protocol. The
+/// representation is truncated to `max_log_bytes` in order to keep large or
+/// sensitive payloads out of the logs. All the work is done by the wrapped
+/// transport.
+pub struct Logged<T> {
+    inner: T,
+    label: String,
+    max_log_bytes: usize,
+}
+
+impl<T> Logged<T> {
+    /// Create a new `Logged` transport wrapping `inner`, logging frames with
+    /// the given label.
+    pub fn new<L: Into<String>>(inner: T, label: L) -> Logged<T> {
+        Logged {
+            inner: inner,
+            label: label.into(),
+            max_log_bytes: MAX_LOG_BYTES,
+        }
+    }
+
+    /// Set the max number of bytes of each frame's representation that are
+    /// logged. Defaults to 256.
+    ///
+    /// Setting this to 0 logs the frames without their contents.
+    pub fn max_log_bytes(mut self, val: usize) -> Self {
+        self.max_log_bytes = val;
+        self
+    }
+
+    /// Returns a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consume the `Logged`, returning the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn log<F: fmt::Debug>(&self, op: &str, frame: &F) {
+        if !log_enabled!(LogLevel::Debug) {
+            return;
+        }
+
+        let mut repr = format!("{:?}", frame);
+
+        if repr.len() > self.max_log_bytes {
+            let mut end = self.max_log_bytes;
+
+            while !repr.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            repr.truncate(end);
+            repr.push_str("...");
+        }
+
+        debug!("{}: {}; frame={}", self.label, op, repr);
+    }
+}
+
+impl<T: Readiness> Readiness for Logged<T> {
+    fn is_readable(&self) -> bool {
+        self.inner.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.is_writable()
+    }
+}
+
+impl<T> Transport for Logged<T>
+    where T: Transport,
+          T::In: fmt::Debug,
+          T::Out: fmt::Debug,
+{
+    type In = T::In;
+    type Out = T::Out;
+
+    fn read(&mut self) -> io::Result<Option<T::Out>> {
+        let frame = try!(self.inner.read());
+
+        if let Some(ref frame) = frame {
+            self.log("read", frame);
+        }
+
+        Ok(frame)
+    }
+
+    fn write(&mut self, frame: T::In) -> io::Result<Option<()>> {
+        self.log("write", &frame);
+        self.inner.write(frame)
+    }
+
+    fn write_batch(&mut self, frames: Vec<T::In>) -> io::Result<Option<()>> {
+        for frame in &frames {
+            self.log("write", frame);
+        }
+
+        self.inner.write_batch(frames)
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        self.inner.flush()
+    }
+
+    fn close(&mut self) -> io::Result<Option<()>> {
+        self.inner.close()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+/// Compresses and decompresses frame payloads for `Compressed`.
+///
+/// Implement this with the compression library of your choice.
+pub trait Codec {
+    /// Compress `data`
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Decompress `data`, which was returned by `compress`
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Compresses the payload of every message frame written to the wrapped
+/// transport, and decompresses the payload of every message frame read from
+/// it.
+///
+/// Payloads smaller than `min_size` are sent as is, since compressing them
+/// usually costs more than it saves, as are payloads that do not shrink once
+/// compressed. Each payload is prefixed with a byte flagging whether it is
+/// compressed, so both peers must wrap their transport with `Compressed`.
+/// Other frames are passed through untouched.
+pub struct Compressed<T, C> {
+    inner: T,
+    codec: C,
+    min_size: usize,
+}
+
+impl<T, C: Codec> Compressed<T, C> {
+    /// Create a new `Compressed` transport wrapping `inner`, compressing
+    /// payloads with `codec`.
+    pub fn new(inner: T, codec: C) -> Compressed<T, C> {
+        Compressed {
+            inner: inner,
+            codec: codec,
+            min_size: MIN_COMPRESS_SIZE,
+        }
+    }
+
+    /// Set the min number of bytes a payload must have in order to be
+    /// compressed. Defaults to 128.
+    pub fn min_size(mut self, val: usize) -> Self {
+        self.min_size = val;
+        self
+    }
+
+    /// Returns a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consume the `Compressed`, returning the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn encode<E>(&self, frame: Frame<Vec<u8>, E>) -> io::Result<Frame<Vec<u8>, E>> {
+        let payload = match frame {
+            Frame::Message(payload) => payload,
+            frame => return Ok(frame),
+        };
+
+        if payload.len() >= self.min_size {
+            let compressed = try!(self.codec.compress(&payload));
+
+            if compressed.len() < payload.len() {
+                trace!("compressed payload; len={}; compressed={}", payload.len(), compressed.len());
+                return Ok(Frame::Message(flagged(COMPRESSED, &compressed)));
+            }
+        }
+
+        Ok(Frame::Message(flagged(RAW, &payload)))
+    }
+
+    fn decode<E>(&self, frame: Frame<Vec<u8>, E>) -> io::Result<Frame<Vec<u8>, E>> {
+        let mut payload = match frame {
+            Frame::Message(payload) => payload,
+            frame => return Ok(frame),
+        };
+
+        match payload.first().cloned() {
+            Some(RAW) => {
+                payload.remove(0);
+                Ok(Frame::Message(payload))
+            }
+            Some(COMPRESSED) => {
+                let payload = try!(self.codec.decompress(&payload[1..]));
+                Ok(Frame::Message(payload))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid compression flag")),
+        }
+    }
+}
+
+fn flagged(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 1);
+    buf.push(flag);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+impl<T: Readiness, C> Readiness for Compressed<T, C> {
+    fn is_readable(&self) -> bool {
+        self.inner.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.is_writable()
+    }
+}
+
+impl<T, C, E> Transport for Compressed<T, C>
+    where T: Transport<In = Frame<Vec<u8>, E>, Out = Frame<Vec<u8>, E>>,
+          C: Codec,
+{
+    type In = Frame<Vec<u8>, E>;
+    type Out = Frame<Vec<u8>, E>;
+
+    fn read(&mut self) -> io::Result<Option<Frame<Vec<u8>, E>>> {
+        match try!(self.inner.read()) {
+            Some(frame) => self.decode(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, frame: Frame<Vec<u8>, E>) -> io::Result<Option<()>> {
+        let frame = try!(self.encode(frame));
+        self.inner.write(frame)
+    }
+
+    fn write_batch(&mut self, frames: Vec<Frame<Vec<u8>, E>>) -> io::Result<Option<()>> {
+        let mut encoded = Vec::with_capacity(frames.len());
+
+        for frame in frames {
+            encoded.push(try!(self.encode(frame)));
+        }
+
+        self.inner.write_batch(encoded)
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        self.inner.flush()
+    }
+
+    fn close(&mut self) -> io::Result<Option<()>> {
+        self.inner.close()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use io::Transport;
+    use proto::pipeline::Frame;
+    use util::test::{self, MockTransport};
+    use std::io;
+
+    type Msg = Frame<u32, io::Error>;
+
+    #[test]
+    fn test_logged_logs_frames_in_order() {
+        test::capture_logs();
+
+        let mock: MockTransport<Msg, Msg> = MockTransport::new(vec![Frame::Message(1)]);
+        let mut transport = Logged::new(mock, "logged-test").max_log_bytes(12);
+
+        assert!(transport.read().unwrap().is_some());
+        transport.write(Frame::Message(2)).unwrap();
+        transport.write(Frame::Message(123456789)).unwrap();
+
+        let lines: Vec<String> = test::captured_logs("tokio::util::transport").into_iter()
+            .filter(|line| line.starts_with("logged-test:"))
+            .collect();
+
+        assert_eq!(vec!["logged-test: read; frame=Message(1)",
+                        "logged-test: write; frame=Message(2)",
+                        "logged-test: write; frame=Message(1234..."], lines);
+
+        assert_eq!(2, transport.get_ref().take_written().len());
+    }
+
+    // Run length encoding, good enough for repetitive payloads
+    struct Rle;
+
+    impl Codec for Rle {
+        fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            let mut out = vec![];
+
+            for &byte in data {
+                let len = out.len();
+
+                if len > 0 && out[len - 1] == byte && out[len - 2] < u8::max_value() {
+                    out[len - 2] += 1;
+                } else {
+                    out.push(1);
+                    out.push(byte);
+                }
+            }
+
+            Ok(out)
+        }
+
+        fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            let mut out = vec![];
+
+            for run in data.chunks(2) {
+                if run.len() != 2 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated run"));
+                }
+
+                for _ in 0..run[0] {
+                    out.push(run[1]);
+                }
+            }
+
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trips_frames() {
+        type Bytes = Frame<Vec<u8>, io::Error>;
+
+        let mock: MockTransport<Bytes, Bytes> = MockTransport::new(vec![]);
+        let mut transport = Compressed::new(mock.clone(), Rle).min_size(16);
+
+        let payloads = vec![
+            b"tiny".to_vec(),
+            vec![7; 1000],
+            // Does not shrink when compressed
+            (0..100).collect::<Vec<u8>>(),
+        ];
+
+        for payload in &payloads {
+            transport.write(Frame::Message(payload.clone())).unwrap();
+        }
+
+        transport.write(Frame::Done).unwrap();
+
+        let written = mock.take_written();
+
+        let lens: Vec<usize> = written.iter().map(|frame| {
+            match *frame {
+                Frame::Message(ref payload) => payload.len(),
+                _ => 0,
+            }
+        }).collect();
+
+        // Only the repetitive payload is compressed
+        assert_eq!(vec![5, 9, 101, 0], lens);
+
+        for frame in written {
+            mock.push_read(frame);
+        }
+
+        for payload in payloads {
+            match transport.read().unwrap() {
+                Some(Frame::Message(read)) => assert_eq!(payload, read),
+                _ => panic!("expected message frame"),
+            }
+        }
+
+        match transport.read().unwrap() {
+            Some(Frame::Done) => {}
+            _ => panic!("expected done frame"),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Reject invalid requests before they reach a service.
+
+use {Service};
+use futures::{self, Future, Poll};
+use std::sync::Arc;
+
+/// Checks every request with a function before dispatching it to the wrapped
+/// service.
+///
+/// Requests for which the function returns an error fail immediately with
+/// that error, without calling the wrapped service. This keeps the
+/// validation of malformed requests separate from the business logic.
+pub struct Validate<S, F> {
+    inner: S,
+    f: Arc<F>,
+}
+
+impl<S, F> Validate<S, F> {
+    /// Create a new `Validate` checking the requests dispatched to `inner`
+    /// with `f`.
+    pub fn new(inner: S, f: F) -> Validate<S, F> {
+        Validate {
+            inner: inner,
+            f: Arc::new(f),
+        }
+    }
+}
+
+impl<S, F> Service for Validate<S, F>
+    where S: Service,
+          F: Fn(&S::Req) -> Result<(), S::Error> + Send + Sync + 'static,
+{
+    type Req = S::Req;
+    type Resp = S::Resp;
+    type Error = S::Error;
+    type Fut = Box<Future<Item = S::Resp, Error = S::Error>>;
+
+    fn call(&self, req: S::Req) -> Self::Fut {
+        if let Err(e) = (self.f)(&req) {
+            trace!("request failed validation");
+            return futures::failed(e).boxed();
+        }
+
+        self.inner.call(req).boxed()
+    }
+
+    fn poll_ready(&mut self) -> Poll<(), S::Error> {
+        self.inner.poll_ready()
+    }
+}
+
+impl<S: Clone, F> Clone for Validate<S, F> {
+    fn clone(&self) -> Validate<S, F> {
+        Validate {
+            inner: self.inner.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use {Service};
+    use util::test::block_on;
+    use futures::{self, Finished};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Responds with the length of the request
+    struct Len {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service for Len {
+        type Req = Vec<u8>;
+        type Resp = usize;
+        type Error = String;
+        type Fut = Finished<usize, String>;
+
+        fn call(&self, req: Vec<u8>) -> Finished<usize, String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            futures::finished(req.len())
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_oversized_requests() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = Validate::new(Len { calls: calls.clone() }, |req: &Vec<u8>| {
+            if req.len() > 4 {
+                Err("request too large".to_string())
+            } else {
+                Ok(())
+            }
+        });
+
+        assert_eq!(Ok(4), block_on(service.call(vec![0; 4])));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        assert_eq!(Err("request too large".to_string()), block_on(service.call(vec![0; 5])));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
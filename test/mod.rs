@@ -1,4 +1,10 @@
+extern crate futures;
 extern crate tokio;
 extern crate mio;
 
+mod test_multiplex;
+mod test_pipeline;
 mod test_reactor;
+mod test_tcp;
+mod test_timeout;
+mod test_udp;
@@ -0,0 +1,1371 @@
+use futures::{Future, Poll, Task, TaskHandle};
+use futures::stream::Stream;
+use tokio::io::{Readiness, Transport, TryRead, TryWrite};
+use tokio::proto::VersionHandshake;
+use tokio::proto::multiplex::{self, FlushPolicy, Frame, Keepalive, OrderedServer, RequestRejected, Server, Window, WindowSize};
+use tokio::reactor::{self, Reactor};
+use tokio::tcp::TcpStream;
+use tokio::{Service, simple_service};
+use tokio::util::channel::Receiver;
+use tokio::util::future;
+use tokio::util::balance::{AtCapacity, Balance};
+use tokio::util::pool::ClientPool;
+use mio::channel;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Msg = Frame<u32, io::Error>;
+
+// A transport that reads frames from a queue and sends written frames over a
+// channel. Frames are tagged with request ids of type `I`.
+struct MockTransport<I = u64> {
+    rd: VecDeque<Frame<u32, io::Error, I>>,
+    wr: Sender<Frame<u32, io::Error, I>>,
+}
+
+impl<I> Readiness for MockTransport<I> {
+    fn is_readable(&self) -> bool {
+        !self.rd.is_empty()
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+impl<I: Send + 'static> Transport for MockTransport<I> {
+    type In = Frame<u32, io::Error, I>;
+    type Out = Frame<u32, io::Error, I>;
+
+    fn read(&mut self) -> io::Result<Option<Frame<u32, io::Error, I>>> {
+        Ok(self.rd.pop_front())
+    }
+
+    fn write(&mut self, frame: Frame<u32, io::Error, I>) -> io::Result<Option<()>> {
+        self.wr.send(frame).unwrap();
+        Ok(Some(()))
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        Ok(Some(()))
+    }
+}
+
+#[test]
+fn test_server_writes_responses_in_completion_order() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (slow_tx, slow_rx) = mpsc::channel();
+    let slow_tx = Mutex::new(slow_tx);
+
+    // Request 1 is only completed by the test, request 2 completes immediately
+    let service = simple_service(move |req: u32| {
+        if req == 1 {
+            let (c, val) = future::pair::<u32, io::Error>();
+            slow_tx.lock().unwrap().send(c).unwrap();
+            val.boxed()
+        } else {
+            ::futures::finished(req).boxed()
+        }
+    });
+
+    let transport = MockTransport {
+        rd: vec![Frame::Message(1, 1), Frame::Message(2, 2), Frame::Done].into_iter().collect(),
+        wr: tx,
+    };
+
+    handle.oneshot(move || {
+        let server = try!(Server::new(service, transport));
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    match rx.recv().unwrap() {
+        Frame::Message(2, 2) => {}
+        _ => panic!("expected response to request 2"),
+    }
+
+    slow_rx.recv().unwrap().complete(1);
+
+    match rx.recv().unwrap() {
+        Frame::Message(1, 1) => {}
+        _ => panic!("expected response to request 1"),
+    }
+
+    // The server completes once both responses are written
+    assert!(rx.recv().is_err());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_answers_ping_with_pong() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+
+    let transport = MockTransport {
+        rd: vec![Frame::Ping, Frame::Done].into_iter().collect(),
+        wr: tx,
+    };
+
+    handle.oneshot(move || {
+        let server = try!(Server::new(service, transport));
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    match rx.recv().unwrap() {
+        Frame::Pong => {}
+        _ => panic!("expected a pong"),
+    }
+
+    assert!(rx.recv().is_err());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_waits_for_one_way_requests_before_closing() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (slow_tx, slow_rx) = mpsc::channel();
+    let slow_tx = Mutex::new(slow_tx);
+
+    // The one-way request is only completed by the test
+    let service = simple_service(move |req: u32| {
+        if req == 1 {
+            let (c, val) = future::pair::<u32, io::Error>();
+            slow_tx.lock().unwrap().send(c).unwrap();
+            val.boxed()
+        } else {
+            ::futures::finished(req).boxed()
+        }
+    });
+
+    let transport = MockTransport {
+        rd: vec![Frame::OneWay(1), Frame::Message(2, 2), Frame::Done].into_iter().collect(),
+        wr: tx,
+    };
+
+    handle.oneshot(move || {
+        let server = try!(Server::new(service, transport));
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    match rx.recv().unwrap() {
+        Frame::Message(2, 2) => {}
+        _ => panic!("expected response to request 2"),
+    }
+
+    // The server keeps running, holding on to the transport, while the
+    // one-way request is processed
+    thread::sleep(Duration::from_millis(50));
+
+    match rx.try_recv() {
+        Err(mpsc::TryRecvError::Empty) => {}
+        _ => panic!("expected the server to still be running"),
+    }
+
+    slow_rx.recv().unwrap().complete(1);
+    assert!(rx.recv().is_err());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_rejects_duplicate_request_ids() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (pending_tx, pending_rx) = mpsc::channel();
+    let pending_tx = Mutex::new(pending_tx);
+
+    // Responses are completed by the test
+    let service = simple_service(move |req: u32| {
+        let (c, val) = future::pair::<u32, io::Error>();
+        pending_tx.lock().unwrap().send((req, c)).unwrap();
+        val
+    });
+
+    let transport = MockTransport {
+        rd: vec![Frame::Message(1, 10), Frame::Message(1, 20), Frame::Done].into_iter().collect(),
+        wr: tx,
+    };
+
+    handle.oneshot(move || {
+        let server = try!(Server::new(service, transport)).reject_duplicate_ids();
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    // The second request is rejected without being dispatched, with a frame
+    // the client can't mistake for the response to the first one
+    match rx.recv().unwrap() {
+        Frame::DuplicateId(1) => {}
+        _ => panic!("expected the duplicate request to be rejected"),
+    }
+
+    let (req, c) = pending_rx.recv().unwrap();
+    assert_eq!(10, req);
+    assert!(pending_rx.try_recv().is_err());
+
+    // The original request is still answered
+    c.complete(11);
+
+    match rx.recv().unwrap() {
+        Frame::Message(1, 11) => {}
+        _ => panic!("expected response to request 1"),
+    }
+
+    assert!(rx.recv().is_err());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_writes_higher_priority_responses_first() {
+    // A `MockTransport` that is only writable once the test opens the gate
+    struct Gated {
+        inner: MockTransport,
+        writable: Arc<AtomicBool>,
+    }
+
+    impl Readiness for Gated {
+        fn is_readable(&self) -> bool {
+            self.inner.is_readable()
+        }
+
+        fn is_writable(&self) -> bool {
+            self.writable.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Transport for Gated {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            self.inner.read()
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.inner.write(frame)
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            self.inner.flush()
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (pending_tx, pending_rx) = mpsc::channel();
+    let pending_tx = Mutex::new(pending_tx);
+
+    // Responses are completed by the test
+    let service = simple_service(move |req: u32| {
+        let (c, val) = future::pair::<u32, io::Error>();
+        pending_tx.lock().unwrap().send((req, c)).unwrap();
+        val
+    });
+
+    // The last digit of a request is its priority
+    let rd: VecDeque<Msg> = vec![
+        Frame::Message(1, 10),
+        Frame::Message(2, 23),
+        Frame::Message(3, 33),
+        Frame::Message(4, 43),
+        Frame::Message(5, 53),
+        Frame::Message(6, 63),
+        Frame::Done,
+    ].into_iter().collect();
+
+    let writable = Arc::new(AtomicBool::new(false));
+
+    let transport = Gated {
+        inner: MockTransport { rd: rd, wr: tx },
+        writable: writable.clone(),
+    };
+
+    handle.oneshot(move || {
+        let server = try!(Server::new(service, transport)).priority(|req: &u32| *req % 10);
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    let mut pending: Vec<_> = (0..6).map(|_| pending_rx.recv().unwrap()).collect();
+    pending.sort_by_key(|&(req, _)| req);
+
+    // Complete in request order, the last one once the transport is writable
+    let (last, c) = pending.pop().unwrap();
+
+    for (req, c) in pending {
+        c.complete(req);
+    }
+
+    writable.store(true, Ordering::SeqCst);
+    c.complete(last);
+
+    let written: Vec<u64> = rx.iter()
+        .map(|frame| {
+            match frame {
+                Frame::Message(id, _) => id,
+                _ => panic!("expected response"),
+            }
+        })
+        .collect();
+
+    // The high priority responses are written first, but those completing
+    // long after the low priority one are written after it
+    assert_eq!(vec![2, 3, 1, 4, 5, 6], written);
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_ordered_server_writes_responses_in_request_order() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (pending_tx, pending_rx) = mpsc::channel();
+    let pending_tx = Mutex::new(pending_tx);
+
+    // Responses are completed by the test
+    let service = simple_service(move |req: u32| {
+        let (c, val) = future::pair::<u32, io::Error>();
+        pending_tx.lock().unwrap().send((req, c)).unwrap();
+        val
+    });
+
+    let rd: VecDeque<Msg> = vec![
+        Frame::Message(1, 1),
+        Frame::Message(2, 2),
+        Frame::Message(3, 3),
+        Frame::Done,
+    ].into_iter().collect();
+
+    let transport = MockTransport { rd: rd, wr: tx };
+
+    handle.oneshot(move || {
+        let server = try!(OrderedServer::new(service, transport)).first_request_id(1);
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    let mut pending: Vec<_> = (0..3).map(|_| pending_rx.recv().unwrap()).collect();
+    pending.sort_by_key(|&(req, _)| req);
+
+    let mut pending: VecDeque<_> = pending.into_iter().map(|(_, c)| c).collect();
+    let third = pending.pop_back().unwrap();
+
+    // Complete as 3, 1, 2
+    third.complete(30);
+    pending.pop_front().unwrap().complete(10);
+    pending.pop_front().unwrap().complete(20);
+
+    let written: Vec<(u64, u32)> = rx.iter()
+        .map(|frame| {
+            match frame {
+                Frame::Message(id, v) => (id, v),
+                _ => panic!("expected response"),
+            }
+        })
+        .collect();
+
+    assert_eq!(vec![(1, 10), (2, 20), (3, 30)], written);
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_with_string_request_ids() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    let service = simple_service(|req: u32| Ok::<u32, io::Error>(req + 1));
+
+    let transport = MockTransport {
+        rd: vec![
+            Frame::Message("a".to_string(), 1),
+            Frame::Message("b".to_string(), 2),
+            Frame::Done,
+        ].into_iter().collect(),
+        wr: tx,
+    };
+
+    handle.oneshot(move || {
+        let server = try!(Server::new(service, transport));
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    let mut responses: Vec<(String, u32)> = rx.iter()
+        .map(|frame| {
+            match frame {
+                Frame::Message(id, v) => (id, v),
+                _ => panic!("expected response"),
+            }
+        })
+        .collect();
+
+    responses.sort();
+    assert_eq!(vec![("a".to_string(), 2), ("b".to_string(), 3)], responses);
+
+    handle.shutdown();
+}
+
+// A transport over a TCP socket where each frame is a request id byte
+// followed by a value byte
+const ERROR: u8 = 255;
+const REJECTED: u8 = 254;
+// Request id bytes of one-way and keepalive frames
+const ONE_WAY: u8 = 253;
+const PING: u8 = 252;
+const PONG: u8 = 251;
+
+struct PairTransport {
+    stream: TcpStream,
+    rd: Vec<u8>,
+    wr: Vec<u8>,
+}
+
+impl PairTransport {
+    fn new(stream: TcpStream) -> io::Result<PairTransport> {
+        Ok(PairTransport {
+            stream: stream,
+            rd: vec![],
+            wr: vec![],
+        })
+    }
+}
+
+impl Readiness for PairTransport {
+    fn is_readable(&self) -> bool {
+        self.rd.len() >= 2 || self.stream.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+impl Transport for PairTransport {
+    type In = Msg;
+    type Out = Msg;
+
+    fn read(&mut self) -> io::Result<Option<Msg>> {
+        loop {
+            if self.rd.len() >= 2 {
+                let id = self.rd[0] as u64;
+
+                // A value of `ERROR` or `REJECTED` encodes an error or
+                // rejection frame
+                let frame = match self.rd[1] {
+                    _ if self.rd[0] == ONE_WAY => Frame::OneWay(self.rd[1] as u32),
+                    _ if self.rd[0] == PING => Frame::Ping,
+                    _ if self.rd[0] == PONG => Frame::Pong,
+                    ERROR => Frame::Error(id, io::Error::new(io::ErrorKind::Other, "error frame")),
+                    REJECTED => Frame::Rejected(id),
+                    v => Frame::Message(id, v as u32),
+                };
+
+                self.rd.drain(..2);
+                return Ok(Some(frame));
+            }
+
+            let mut buf = [0; 64];
+
+            match try!(self.stream.try_read(&mut buf)) {
+                Some(0) => return Ok(Some(Frame::Done)),
+                Some(n) => self.rd.extend_from_slice(&buf[..n]),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+        match frame {
+            Frame::Message(id, v) => {
+                self.wr.push(id as u8);
+                self.wr.push(v as u8);
+            }
+            Frame::OneWay(v) => {
+                self.wr.push(ONE_WAY);
+                self.wr.push(v as u8);
+            }
+            Frame::Ping => {
+                self.wr.push(PING);
+                self.wr.push(0);
+            }
+            Frame::Pong => {
+                self.wr.push(PONG);
+                self.wr.push(0);
+            }
+            _ => {}
+        }
+
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        while !self.wr.is_empty() {
+            match try!(self.stream.try_write(&self.wr)) {
+                Some(n) => {
+                    self.wr.drain(..n);
+                }
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(()))
+    }
+}
+
+// Block the current thread until the future completes
+fn wait_for<F: Future>(f: F) -> Result<F::Item, F::Error> {
+    let (tx, rx) = mpsc::channel();
+
+    f.then(move |res| {
+        tx.send(res).unwrap();
+        Ok::<(), ()>(())
+    }).forget();
+
+    rx.recv().unwrap()
+}
+
+// Read a request written by the client as an `(id, value)` pair
+fn read_request(sock: &mut net::TcpStream) -> (u8, u8) {
+    let mut buf = [0; 2];
+    sock.read_exact(&mut buf).unwrap();
+    (buf[0], buf[1])
+}
+
+#[test]
+fn test_client_discards_response_to_cancelled_request() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+
+    let cancelled = client.call(10);
+    let (mut sock, _) = srv.accept().unwrap();
+
+    let (first, v) = read_request(&mut sock);
+    assert_eq!(10, v);
+
+    // Cancel interest in the first response
+    drop(cancelled);
+
+    let resp = client.call(20);
+    let (second, v) = read_request(&mut sock);
+    assert_eq!(20, v);
+
+    // The cancelled request's id is still reserved
+    assert!(first != second);
+
+    // Send the late response, followed by the real one
+    sock.write_all(&[first, 99, second, 21]).unwrap();
+
+    assert_eq!(21, wait_for(resp).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_request_times_out() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+
+    let timed_out = client.call_timeout(10, Duration::from_millis(200));
+    let (mut sock, _) = srv.accept().unwrap();
+    let (first, _) = read_request(&mut sock);
+
+    // The server does not respond in time
+    match wait_for(timed_out) {
+        Err(e) => assert_eq!(io::ErrorKind::TimedOut, e.kind()),
+        Ok(_) => panic!("expected the request to time out"),
+    }
+
+    let resp = client.call_timeout(20, Duration::from_secs(10));
+    let (second, _) = read_request(&mut sock);
+
+    // The late response is discarded, releasing its id, and is not
+    // delivered to the next request
+    sock.write_all(&[first, 99, second, 21]).unwrap();
+
+    assert_eq!(21, wait_for(resp).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_reclaims_id_when_server_never_responds() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+
+    let timed_out = client.call_timeout(10, Duration::from_millis(50));
+    let (mut sock, _) = srv.accept().unwrap();
+    read_request(&mut sock);
+
+    match wait_for(timed_out) {
+        Err(e) => assert_eq!(io::ErrorKind::TimedOut, e.kind()),
+        Ok(_) => panic!("expected the request to time out"),
+    }
+
+    // The id is released once the grace period ends without a response
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(0, client.allocated_ids());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_coalesces_requests() {
+    // A `PairTransport` reporting the size of every write
+    struct Batching {
+        inner: PairTransport,
+        batches: Sender<usize>,
+    }
+
+    impl Readiness for Batching {
+        fn is_readable(&self) -> bool {
+            self.inner.is_readable()
+        }
+
+        fn is_writable(&self) -> bool {
+            self.inner.is_writable()
+        }
+    }
+
+    impl Transport for Batching {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            self.inner.read()
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.write_batch(vec![frame])
+        }
+
+        fn write_batch(&mut self, frames: Vec<Msg>) -> io::Result<Option<()>> {
+            self.batches.send(frames.len()).unwrap();
+
+            for frame in frames {
+                try!(self.inner.write(frame));
+            }
+
+            self.inner.flush()
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            self.inner.flush()
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, batches) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let policy = FlushPolicy::Coalesce {
+        max_batch: 3,
+        max_delay: Duration::from_millis(200),
+    };
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let client = multiplex::connect_with_flush_policy(&handle, srv.local_addr().unwrap(), move |stream: TcpStream| -> io::Result<Batching> {
+        Ok(Batching {
+            inner: try!(PairTransport::new(stream)),
+            batches: tx.lock().unwrap().clone(),
+        })
+    }, policy);
+
+    // A full batch is written at once
+    let resps: Vec<_> = (1..4).map(|v| client.call(v)).collect();
+    let (mut sock, _) = srv.accept().unwrap();
+
+    let mut ids = vec![];
+
+    for _ in 0..3 {
+        ids.push(read_request(&mut sock).0);
+    }
+
+    assert_eq!(3, batches.recv().unwrap());
+
+    for (i, id) in ids.into_iter().enumerate() {
+        sock.write_all(&[id, i as u8 + 10]).unwrap();
+    }
+
+    let values: Vec<u32> = resps.into_iter().map(|resp| wait_for(resp).unwrap()).collect();
+    assert_eq!(vec![10, 11, 12], values);
+
+    // A lonely request is written once it has waited for the max delay
+    let resp = client.call(4);
+    let (id, v) = read_request(&mut sock);
+    assert_eq!(4, v);
+    assert_eq!(1, batches.recv().unwrap());
+
+    sock.write_all(&[id, 40]).unwrap();
+    assert_eq!(40, wait_for(resp).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_times_out_coalesced_requests_from_write() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let policy = FlushPolicy::Coalesce {
+        max_batch: 10,
+        max_delay: Duration::from_millis(200),
+    };
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect_with_flush_policy(&handle, srv.local_addr().unwrap(), PairTransport::new, policy);
+
+    let (mut sock, _) = srv.accept().unwrap();
+
+    // The request waits longer than its timeout for the batch to fill up,
+    // the timeout only starts once it is written
+    let resp = client.call_timeout(1, Duration::from_millis(100));
+    let (id, v) = read_request(&mut sock);
+    assert_eq!(1, v);
+
+    sock.write_all(&[id, 2]).unwrap();
+    assert_eq!(2, wait_for(resp).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_waits_for_writable_transport() {
+    // A `PairTransport` that is only writable once the test opens the gate
+    struct Gated {
+        inner: PairTransport,
+        gate: Receiver<()>,
+        open: bool,
+    }
+
+    impl Readiness for Gated {
+        fn is_readable(&self) -> bool {
+            self.inner.is_readable()
+        }
+
+        fn is_writable(&self) -> bool {
+            self.open
+        }
+    }
+
+    impl Transport for Gated {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            self.inner.read()
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.inner.write(frame)
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            // The client flushes at the start of every tick
+            if let Ok(Some(())) = self.gate.recv() {
+                self.open = true;
+            }
+
+            self.inner.flush()
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (gate_tx, gate_rx) = channel::channel();
+    let gate_rx = Mutex::new(Some(gate_rx));
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect_with_capacity(&handle, srv.local_addr().unwrap(), move |stream: TcpStream| -> io::Result<Gated> {
+        Ok(Gated {
+            inner: try!(PairTransport::new(stream)),
+            gate: try!(Receiver::watch(gate_rx.lock().unwrap().take().unwrap())),
+            open: false,
+        })
+    }, 1);
+
+    // The first request takes the only slot, the others wait for capacity
+    let one = client.call(1);
+    let cancelled = client.call(2);
+    let three = client.call(3);
+    assert_eq!(2, client.waiting_requests());
+
+    let (mut sock, _) = srv.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+    // Nothing is written, and no id is allocated, while the transport is not
+    // writable
+    let mut buf = [0; 2];
+    assert!(sock.read(&mut buf).is_err());
+    assert_eq!(0, client.pending_requests());
+    assert_eq!(0, client.allocated_ids());
+    assert_eq!(2, client.waiting_requests());
+
+    // Cancel the second request while it waits for capacity
+    drop(cancelled);
+    assert_eq!(1, client.waiting_requests());
+
+    gate_tx.send(()).unwrap();
+    sock.set_read_timeout(None).unwrap();
+
+    let (id_one, v) = read_request(&mut sock);
+    assert_eq!(1, v);
+
+    // The cancelled request is never written
+    let (id_three, v) = read_request(&mut sock);
+    assert_eq!(3, v);
+    assert_eq!(0, client.waiting_requests());
+
+    sock.write_all(&[id_one, 11, id_three, 13]).unwrap();
+    assert_eq!(11, wait_for(one).unwrap());
+    assert_eq!(13, wait_for(three).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_close_waits_for_last_handle() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+    let mut other = client.clone();
+
+    let a = client.call(1);
+    let (mut sock, _) = srv.accept().unwrap();
+    let (id_a, _) = read_request(&mut sock);
+
+    let b = other.call(2);
+    let (id_b, _) = read_request(&mut sock);
+
+    let closed = client.close();
+
+    // Calls on the closed handle fail immediately
+    match wait_for(client.call(3)) {
+        Err(e) => assert_eq!(io::ErrorKind::BrokenPipe, e.kind()),
+        Ok(_) => panic!("expected call on closed handle to fail"),
+    }
+
+    // Outstanding requests still complete
+    sock.write_all(&[id_a, 11, id_b, 12]).unwrap();
+    assert_eq!(11, wait_for(a).unwrap());
+    assert_eq!(12, wait_for(b).unwrap());
+
+    // The other handle is still usable
+    let c = other.call(4);
+    let (id_c, v) = read_request(&mut sock);
+    assert_eq!(4, v);
+
+    sock.write_all(&[id_c, 13]).unwrap();
+    assert_eq!(13, wait_for(c).unwrap());
+
+    // Closing the last handle closes the connection
+    wait_for(other.close()).unwrap();
+    wait_for(closed).unwrap();
+
+    let mut buf = [0; 1];
+    assert_eq!(0, sock.read(&mut buf).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_error_frame_fails_matching_request() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+
+    let one = client.call(1);
+    let (mut sock, _) = srv.accept().unwrap();
+    let (id_one, _) = read_request(&mut sock);
+
+    let two = client.call(2);
+    let (id_two, _) = read_request(&mut sock);
+
+    // An error frame for an unknown request is dropped
+    let unknown = (0..255).find(|id| *id != id_one && *id != id_two).unwrap();
+
+    sock.write_all(&[unknown, ERROR, id_two, ERROR, id_one, 11]).unwrap();
+
+    match wait_for(two) {
+        Err(e) => assert_eq!(io::ErrorKind::Other, e.kind()),
+        Ok(_) => panic!("expected request 2 to fail"),
+    }
+
+    assert_eq!(11, wait_for(one).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_client_rejection_fails_matching_request() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+
+    let one = client.call(1);
+    let (mut sock, _) = srv.accept().unwrap();
+    let (id_one, _) = read_request(&mut sock);
+
+    let two = client.call(2);
+    let (id_two, _) = read_request(&mut sock);
+
+    // The rejection of the already answered request is dropped
+    sock.write_all(&[id_one, 11, id_one, REJECTED, id_two, REJECTED]).unwrap();
+
+    assert_eq!(11, wait_for(one).unwrap());
+
+    match wait_for(two) {
+        Err(e) => assert!(e.get_ref().map_or(false, |e| e.is::<RequestRejected>())),
+        Ok(_) => panic!("expected request 2 to be rejected"),
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_pool_reconnects_after_connection_dies() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = srv.local_addr().unwrap();
+    let pool = ClientPool::new(&handle, PairTransport::new, 2);
+
+    let resp = pool.call(addr, 1);
+    let (mut sock, _) = srv.accept().unwrap();
+    let (id, v) = read_request(&mut sock);
+    assert_eq!(1, v);
+
+    sock.write_all(&[id, 10]).unwrap();
+    assert_eq!(10, wait_for(resp).unwrap());
+    assert_eq!(1, pool.connections(&addr));
+
+    // Kill the pooled connection and wait for the client to notice
+    drop(sock);
+
+    while pool.connections(&addr) > 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // The next call opens a new connection
+    let resp = pool.call(addr, 2);
+    let (mut sock, _) = srv.accept().unwrap();
+    let (id, v) = read_request(&mut sock);
+    assert_eq!(2, v);
+
+    sock.write_all(&[id, 20]).unwrap();
+    assert_eq!(20, wait_for(resp).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_serve_round_trips_requests() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let service = simple_service(|req: u32| Ok::<u32, io::Error>(req + 1));
+    let srv = multiplex::serve(&handle, "127.0.0.1:0".parse().unwrap(), service, PairTransport::new).unwrap();
+
+    let client = multiplex::connect(&handle, *srv.local_addr(), PairTransport::new);
+
+    assert_eq!(11, wait_for(client.call(10)).unwrap());
+    assert_eq!(21, wait_for(client.call(20)).unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_try_connect_resolves_once_connected() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let service = simple_service(|req: u32| Ok::<u32, io::Error>(req + 1));
+    let srv = multiplex::serve(&handle, "127.0.0.1:0".parse().unwrap(), service, PairTransport::new).unwrap();
+
+    let client = wait_for(multiplex::try_connect(&handle, *srv.local_addr(), PairTransport::new)).unwrap();
+    assert_eq!(11, wait_for(client.call(10)).unwrap());
+
+    // Nothing listens on the address once the listener is dropped
+    let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    match wait_for(multiplex::try_connect(&handle, addr, PairTransport::new)) {
+        Err(_) => {}
+        Ok(_) => panic!("expected connect to fail"),
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_balance_dispatches_to_least_loaded_backend() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srvs = vec![net::TcpListener::bind("127.0.0.1:0").unwrap(),
+                    net::TcpListener::bind("127.0.0.1:0").unwrap()];
+
+    let clients = srvs.iter()
+        .map(|srv| multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new))
+        .collect();
+
+    let balance = Balance::new(clients);
+
+    let resps: Vec<_> = (1..5).map(|i| balance.call(i)).collect();
+    assert_eq!(vec![2, 2], balance.pending_requests());
+
+    // Each backend echoes its two requests
+    for srv in &srvs {
+        let (mut sock, _) = srv.accept().unwrap();
+
+        for _ in 0..2 {
+            let (id, v) = read_request(&mut sock);
+            sock.write_all(&[id, v]).unwrap();
+        }
+    }
+
+    for (i, resp) in (1..5).zip(resps) {
+        assert_eq!(i, wait_for(resp).unwrap());
+    }
+
+    assert_eq!(vec![0, 0], balance.pending_requests());
+
+    // Once the backend is at capacity, requests fail
+    let client = multiplex::connect(&handle, srvs[0].local_addr().unwrap(), PairTransport::new);
+    let balance = Balance::new(vec![client]).max_pending(1).at_capacity(AtCapacity::Fail);
+
+    let _pending = balance.call(1);
+
+    match wait_for(balance.call(2)) {
+        Err(e) => assert_eq!(io::ErrorKind::WouldBlock, e.kind()),
+        Ok(_) => panic!("expected request to fail"),
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_window_holds_requests_until_one_completes() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let size = WindowSize::new(2);
+    let client_size = size.clone();
+
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), move |stream: TcpStream| -> io::Result<Window<PairTransport, u64>> {
+        Ok(Window::new(try!(PairTransport::new(stream)), client_size.clone()))
+    });
+
+    let resps: Vec<_> = (1..4).map(|i| client.call(i)).collect();
+
+    let (mut sock, _) = srv.accept().unwrap();
+    let first = read_request(&mut sock);
+    let second = read_request(&mut sock);
+    assert_eq!((1, 2), (first.1, second.1));
+
+    // The third request waits for the window
+    sock.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(sock.read(&mut [0; 2]).is_err());
+    sock.set_read_timeout(None).unwrap();
+
+    sock.write_all(&[first.0, 10]).unwrap();
+
+    let third = read_request(&mut sock);
+    assert_eq!(3, third.1);
+
+    sock.write_all(&[second.0, 20, third.0, 30]).unwrap();
+
+    let resps: Vec<u32> = resps.into_iter().map(|resp| wait_for(resp).unwrap()).collect();
+    assert_eq!(vec![10, 20, 30], resps);
+    assert_eq!(2, size.get());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_keepalive_fails_connection_without_pong() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), |stream: TcpStream| {
+        PairTransport::new(stream).map(|transport| {
+            Keepalive::new(transport, Duration::from_millis(50), Duration::from_millis(300))
+        })
+    });
+
+    let resp = client.call(10);
+
+    let (mut sock, _) = srv.accept().unwrap();
+    assert_eq!(10, read_request(&mut sock).1);
+
+    // The first ping is answered
+    assert_eq!((PING, 0), read_request(&mut sock));
+    sock.write_all(&[PONG, 0]).unwrap();
+
+    // The second one is not
+    assert_eq!((PING, 0), read_request(&mut sock));
+    let start = Instant::now();
+
+    match wait_for(resp) {
+        Err(e) => assert_eq!(io::ErrorKind::BrokenPipe, e.kind()),
+        Ok(_) => panic!("expected the request to fail"),
+    }
+
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    handle.shutdown();
+}
+
+// A stream of requests pushed by the test, ending once `None` is pushed
+struct Requests {
+    inner: Arc<Mutex<RequestsInner>>,
+}
+
+struct RequestsInner {
+    reqs: VecDeque<Option<u8>>,
+    task: Option<TaskHandle>,
+}
+
+impl Requests {
+    fn new() -> (Requests, Requests) {
+        let inner = Arc::new(Mutex::new(RequestsInner {
+            reqs: VecDeque::new(),
+            task: None,
+        }));
+
+        (Requests { inner: inner.clone() }, Requests { inner: inner })
+    }
+
+    fn push(&self, req: Option<u8>) {
+        let task = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.reqs.push_back(req);
+            inner.task.take()
+        };
+
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+}
+
+impl Stream for Requests {
+    type Item = u8;
+    type Error = io::Error;
+
+    fn poll(&mut self, _: &mut Task) -> Poll<Option<u8>, io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.reqs.front().cloned() {
+            Some(Some(req)) => {
+                inner.reqs.pop_front();
+                Poll::Ok(Some(req))
+            }
+            Some(None) => Poll::Ok(None),
+            None => Poll::NotReady,
+        }
+    }
+
+    fn schedule(&mut self, task: &mut Task) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.reqs.is_empty() {
+            inner.task = Some(task.handle().clone());
+        } else {
+            task.handle().notify();
+        }
+    }
+}
+
+#[test]
+fn test_call_stream_yields_responses_in_completion_order() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+
+    let (reqs, tx) = Requests::new();
+    tx.push(Some(10));
+    tx.push(Some(20));
+
+    let resps = client.call_stream(reqs);
+
+    let (mut sock, _) = srv.accept().unwrap();
+    let mut reqs: Vec<(u8, u8)> = (0..2).map(|_| read_request(&mut sock)).collect();
+
+    // Requests are made as the stream yields them
+    tx.push(Some(30));
+    tx.push(None);
+    reqs.push(read_request(&mut sock));
+
+    let id = |v: u8| reqs.iter().find(|req| req.1 == v).unwrap().0;
+
+    // Answer the last request first
+    sock.write_all(&[id(30), 31, id(10), 11, id(20), 21]).unwrap();
+
+    match wait_for(resps.collect()) {
+        Ok(resps) => assert_eq!(vec![(2, 31), (0, 11), (1, 21)], resps),
+        Err(_) => panic!("expected every request to succeed"),
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_ready_waits_for_slow_handshake() {
+    fn handshake(stream: TcpStream) -> io::Result<VersionHandshake<TcpStream, fn(TcpStream, u8) -> PairTransport, PairTransport>> {
+        fn pair(stream: TcpStream, _: u8) -> PairTransport {
+            PairTransport::new(stream).unwrap()
+        }
+
+        Ok(VersionHandshake::new(stream, &[1], pair as fn(TcpStream, u8) -> PairTransport))
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), handshake);
+
+    let (tx, rx) = mpsc::channel();
+
+    client.ready().then(move |res| {
+        tx.send(res.is_ok()).unwrap();
+        Ok::<(), ()>(())
+    }).forget();
+
+    let (mut sock, _) = srv.accept().unwrap();
+
+    let mut buf = [0; 2];
+    sock.read_exact(&mut buf).unwrap();
+    assert_eq!([1, 1], buf);
+
+    // Not ready until the server answers the handshake
+    thread::sleep(Duration::from_millis(100));
+    assert!(rx.try_recv().is_err());
+
+    sock.write_all(&[1, 1]).unwrap();
+    assert!(rx.recv().unwrap());
+
+    let resp = client.call(10);
+    let (id, v) = read_request(&mut sock);
+    assert_eq!(10, v);
+
+    sock.write_all(&[id, 11]).unwrap();
+    assert_eq!(11, wait_for(resp).unwrap());
+
+    // A failed handshake fails the future
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), handshake);
+    let (mut sock, _) = srv.accept().unwrap();
+
+    sock.write_all(&[1, 2]).unwrap();
+
+    match wait_for(client.ready()) {
+        Err(e) => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+        Ok(_) => panic!("expected the handshake to fail"),
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_notify_completes_on_flush_without_request_id() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = multiplex::connect(&handle, srv.local_addr().unwrap(), PairTransport::new);
+
+    let (mut sock, _) = srv.accept().unwrap();
+
+    // Completes without the server answering
+    assert!(wait_for(client.notify(7)).is_ok());
+    assert_eq!(0, client.pending_requests());
+    assert_eq!((ONE_WAY, 7), read_request(&mut sock));
+
+    // The next request gets the first id
+    let resp = client.call(8);
+    assert_eq!((0, 8), read_request(&mut sock));
+
+    sock.write_all(&[0, 9]).unwrap();
+    assert_eq!(9, wait_for(resp).unwrap());
+
+    handle.shutdown();
+}
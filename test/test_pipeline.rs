@@ -0,0 +1,1346 @@
+use futures::{self, Finished, Future, Poll};
+use tokio::io::{Readiness, Transport, TryRead, TryWrite};
+use tokio::proto::{Header, LengthDelimited};
+use tokio::proto::pipeline::{self, Frame, FrameErrorPolicy, Server, ServerMetrics};
+use tokio::reactor::{self, Reactor, Task, Tick};
+use tokio::server;
+use tokio::tcp::TcpStream;
+use tokio::{Service, NewService, simple_service};
+use tokio::util::channel::Receiver;
+use tokio::util::future;
+use tokio::util::map_err::{MapErr, MapResponse};
+use tokio::util::service_fn::service_fn;
+use tokio::util::shared::SharedService;
+use mio::channel;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+type Msg = Frame<u32, io::Error>;
+
+// A transport that reads frames from a queue and sends written frames over a
+// channel
+struct MockTransport {
+    rd: VecDeque<io::Result<Msg>>,
+    wr: Sender<Msg>,
+}
+
+impl Readiness for MockTransport {
+    fn is_readable(&self) -> bool {
+        !self.rd.is_empty()
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+impl Transport for MockTransport {
+    type In = Msg;
+    type Out = Msg;
+
+    fn read(&mut self) -> io::Result<Option<Msg>> {
+        match self.rd.pop_front() {
+            Some(Ok(frame)) => Ok(Some(frame)),
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+        self.wr.send(frame).unwrap();
+        Ok(Some(()))
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        Ok(Some(()))
+    }
+}
+
+// Run a pipeline server over a `MockTransport` that reads `frames`, returning
+// every frame written before the server task completed.
+fn run<F, S, M>(frames: Vec<Msg>, new_server: F) -> Vec<Msg>
+    where F: FnOnce(MockTransport) -> io::Result<Server<S, MockTransport, M>> + Send + 'static,
+          S: Service<Req = u32, Error = io::Error>,
+          M: ServerMetrics,
+{
+    run_reads(frames.into_iter().map(Ok).collect(), new_server)
+}
+
+// Same as `run`, but the transport may also return read errors
+fn run_reads<F, S, M>(reads: Vec<io::Result<Msg>>, new_server: F) -> Vec<Msg>
+    where F: FnOnce(MockTransport) -> io::Result<Server<S, MockTransport, M>> + Send + 'static,
+          S: Service<Req = u32, Error = io::Error>,
+          M: ServerMetrics,
+{
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    let transport = MockTransport {
+        rd: reads.into_iter().collect(),
+        wr: tx,
+    };
+
+    handle.oneshot(move || {
+        let server = try!(new_server(transport));
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    // The channel closes once the server task drops the transport
+    let written = rx.iter().collect();
+
+    // The reactor must have survived the server task
+    let (tx, rx) = mpsc::channel();
+    handle.oneshot(move || tx.send(()).unwrap());
+    rx.recv().unwrap();
+
+    handle.shutdown();
+    written
+}
+
+fn messages(frames: &[Msg]) -> Vec<u32> {
+    frames.iter()
+        .filter_map(|frame| {
+            match *frame {
+                Frame::Message(v) => Some(v),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_server_grows_past_capacity() {
+    let mut frames: Vec<Msg> = (0..10).map(Frame::Message).collect();
+    frames.push(Frame::Done);
+
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req * 2));
+        Server::with_capacity(service, transport, 2)
+    });
+
+    let expect: Vec<u32> = (0..10).map(|v| v * 2).collect();
+    assert_eq!(expect, messages(&written));
+}
+
+#[test]
+fn test_server_with_service_fn() {
+    struct Rejected;
+
+    impl From<Rejected> for io::Error {
+        fn from(_: Rejected) -> io::Error {
+            io::Error::new(io::ErrorKind::Other, "rejected")
+        }
+    }
+
+    let frames = vec![Frame::Message(1), Frame::Message(0), Frame::Message(2), Frame::Done];
+
+    let written = run(frames, |transport| {
+        let mut calls = 0;
+
+        let service = service_fn(move |req: u32| {
+            calls += 1;
+
+            if req == 0 {
+                Err(Rejected)
+            } else {
+                Ok(req * 10 + calls)
+            }
+        });
+
+        Server::new(service, transport)
+    });
+
+    assert_eq!(3, written.len());
+    assert_eq!(vec![11, 23], messages(&written));
+
+    match written[1] {
+        Frame::Error(ref e) => assert_eq!(io::ErrorKind::Other, e.kind()),
+        _ => panic!("expected error frame"),
+    }
+}
+
+#[test]
+fn test_server_reports_metrics() {
+    #[derive(Clone, Default)]
+    struct Counts {
+        requests: Arc<AtomicUsize>,
+        responses: Arc<AtomicUsize>,
+        max_depth: Arc<AtomicUsize>,
+    }
+
+    impl ServerMetrics for Counts {
+        fn on_request(&mut self) {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_response(&mut self) {
+            self.responses.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_queue_depth(&mut self, depth: usize) {
+            if depth > self.max_depth.load(Ordering::SeqCst) {
+                self.max_depth.store(depth, Ordering::SeqCst);
+            }
+        }
+    }
+
+    let counts = Counts::default();
+    let metrics = counts.clone();
+
+    let frames = vec![Frame::Message(1), Frame::Message(2), Frame::Message(3), Frame::Done];
+
+    let written = run(frames, move |transport| {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        Server::with_metrics(service, transport, metrics)
+    });
+
+    assert_eq!(vec![1, 2, 3], messages(&written));
+    assert_eq!(3, counts.requests.load(Ordering::SeqCst));
+    assert_eq!(3, counts.responses.load(Ordering::SeqCst));
+    assert_eq!(3, counts.max_depth.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_server_with_mapped_service() {
+    #[derive(Debug)]
+    struct Odd(u32);
+
+    let frames = vec![Frame::Message(2), Frame::Message(3), Frame::Done];
+
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| {
+            if req % 2 == 0 {
+                Ok(req)
+            } else {
+                Err(Odd(req))
+            }
+        });
+
+        let service = MapResponse::new(service, |resp: u32| resp * 10);
+        let service = MapErr::new(service, |e: Odd| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("odd request: {}", e.0))
+        });
+
+        Server::new(service, transport)
+    });
+
+    assert_eq!(2, written.len());
+    assert_eq!(vec![20], messages(&written));
+
+    match written[1] {
+        Frame::Error(ref e) => assert_eq!(io::ErrorKind::InvalidInput, e.kind()),
+        _ => panic!("expected error frame"),
+    }
+}
+
+#[test]
+fn test_server_filters_requests() {
+    // 0 is a keepalive
+    let frames = vec![Frame::Message(0), Frame::Message(1), Frame::Message(0), Frame::Message(2), Frame::Done];
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let server_calls = calls.clone();
+
+    let written = run(frames, move |transport| {
+        let service = simple_service(move |req: u32| {
+            server_calls.fetch_add(1, Ordering::SeqCst);
+            Ok::<u32, io::Error>(req * 10)
+        });
+
+        Ok(try!(Server::new(service, transport)).filter(|req: &u32| *req != 0))
+    });
+
+    assert_eq!(vec![10, 20], messages(&written));
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_server_map_response() {
+    let frames = vec![Frame::Message(1), Frame::Message(2), Frame::Done];
+
+    // The service responds with strings, the transport writes numbers
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| Ok::<String, io::Error>(req.to_string()));
+
+        Server::with_map_response(service, transport, |resp: String| {
+            resp.parse::<u32>().unwrap() + 100
+        })
+    });
+
+    assert_eq!(vec![101, 102], messages(&written));
+}
+
+#[test]
+fn test_server_max_requests() {
+    let frames = vec![Frame::Message(1), Frame::Message(2), Frame::Done];
+
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        Ok(try!(Server::new(service, transport)).max_requests(1))
+    });
+
+    // The server completes after a single exchange
+    assert_eq!(vec![1], messages(&written));
+}
+
+#[test]
+fn test_server_waits_for_service_readiness() {
+    // A service that is only ready every other poll
+    struct EveryOther {
+        polls: usize,
+        ready: Cell<bool>,
+    }
+
+    impl Service for EveryOther {
+        type Req = u32;
+        type Resp = u32;
+        type Error = io::Error;
+        type Fut = Finished<u32, io::Error>;
+
+        fn call(&self, req: u32) -> Self::Fut {
+            assert!(self.ready.get(), "called while not ready");
+            self.ready.set(false);
+            futures::finished(req)
+        }
+
+        fn poll_ready(&mut self) -> Poll<(), io::Error> {
+            self.polls += 1;
+
+            if self.polls % 2 == 0 {
+                return Poll::NotReady;
+            }
+
+            self.ready.set(true);
+            Poll::Ok(())
+        }
+    }
+
+    let mut frames: Vec<Msg> = (0..4).map(Frame::Message).collect();
+    frames.push(Frame::Done);
+
+    let written = run(frames, |transport| {
+        let service = EveryOther { polls: 0, ready: Cell::new(false) };
+        Server::new(service, transport)
+    });
+
+    assert_eq!(vec![0, 1, 2, 3], messages(&written));
+}
+
+#[test]
+fn test_server_writes_service_readiness_error() {
+    // A service that fails once it has handled two requests
+    struct Failing {
+        polls: usize,
+    }
+
+    impl Service for Failing {
+        type Req = u32;
+        type Resp = u32;
+        type Error = io::Error;
+        type Fut = Finished<u32, io::Error>;
+
+        fn call(&self, req: u32) -> Self::Fut {
+            futures::finished(req)
+        }
+
+        fn poll_ready(&mut self) -> Poll<(), io::Error> {
+            self.polls += 1;
+
+            if self.polls > 2 {
+                return Poll::Err(io::Error::new(io::ErrorKind::ConnectionAborted, "backend gone"));
+            }
+
+            Poll::Ok(())
+        }
+    }
+
+    let mut frames: Vec<Msg> = (0..4).map(Frame::Message).collect();
+    frames.push(Frame::Done);
+
+    let written = run(frames, |transport| {
+        Server::new(Failing { polls: 0 }, transport)
+    });
+
+    // The error is written once the responses to the requests read before
+    // the failure have been written
+    assert_eq!(3, written.len());
+
+    match (&written[0], &written[1], &written[2]) {
+        (&Frame::Message(0), &Frame::Message(1), &Frame::Error(ref e)) => {
+            assert_eq!(io::ErrorKind::ConnectionAborted, e.kind());
+        }
+        _ => panic!("unexpected frames written"),
+    }
+}
+
+#[test]
+fn test_server_writes_service_errors_in_order() {
+    let frames = vec![Frame::Message(0), Frame::Message(1), Frame::Message(2), Frame::Done];
+
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| {
+            if req == 1 {
+                Err(io::Error::new(io::ErrorKind::Other, "boom"))
+            } else {
+                Ok(req)
+            }
+        });
+
+        Server::new(service, transport)
+    });
+
+    assert_eq!(3, written.len());
+
+    match (&written[0], &written[1], &written[2]) {
+        (&Frame::Message(0), &Frame::Error(ref e), &Frame::Message(2)) => {
+            assert_eq!(io::ErrorKind::Other, e.kind());
+        }
+        _ => panic!("unexpected frames written"),
+    }
+}
+
+#[test]
+fn test_server_closes_on_unrepresentable_service_error() {
+    // The transport has no way of writing this error
+    struct Rejected;
+
+    let frames = vec![Frame::Message(0), Frame::Message(1), Frame::Message(2), Frame::Done];
+
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| {
+            if req == 1 {
+                Err(Rejected)
+            } else {
+                Ok(req)
+            }
+        });
+
+        Server::with_map_response(service, transport, |resp: u32| resp)
+    });
+
+    // The connection closes in place of the failed response
+    assert_eq!(vec![0], messages(&written));
+    assert_eq!(1, written.len());
+}
+
+#[test]
+fn test_server_skips_error_frames() {
+    let frames = vec![
+        Frame::Message(1),
+        Frame::Error(io::Error::new(io::ErrorKind::InvalidData, "corrupt frame")),
+        Frame::Message(2),
+        Frame::Done,
+    ];
+
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        Ok(try!(Server::new(service, transport)).on_frame_error(FrameErrorPolicy::Skip))
+    });
+
+    assert_eq!(vec![1, 2], messages(&written));
+}
+
+#[test]
+fn test_server_read_error_drains_in_flight() {
+    let reads = vec![
+        Ok(Frame::Message(0)),
+        Ok(Frame::Message(1)),
+        Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+        Ok(Frame::Message(2)),
+    ];
+
+    let written = run_reads(reads, |transport| {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        Server::new(service, transport)
+    });
+
+    // Requests read before the error are answered, nothing after it is read
+    assert_eq!(vec![0, 1], messages(&written));
+}
+
+#[test]
+fn test_server_shutdown_handle_drains_in_flight() {
+    // No `Frame::Done`, the server only completes once signaled
+    let frames = vec![Frame::Message(0), Frame::Message(1), Frame::Message(2)];
+
+    let written = run(frames, |transport| {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        let mut server = try!(Server::new(service, transport));
+
+        try!(server.shutdown_handle()).shutdown();
+
+        Ok(server)
+    });
+
+    assert_eq!(vec![0, 1, 2], messages(&written));
+}
+
+#[test]
+fn test_server_bounds_reads_per_tick() {
+    // A transport that always has a request ready to read
+    struct Flood {
+        next: u32,
+        reads: usize,
+        max: usize,
+        wr: Sender<Msg>,
+    }
+
+    impl Readiness for Flood {
+        fn is_readable(&self) -> bool {
+            true
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    impl Transport for Flood {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            assert!(self.reads < self.max, "read past the per-tick budget");
+
+            self.reads += 1;
+            self.next += 1;
+
+            Ok(Some(Frame::Message(self.next)))
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.wr.send(frame).unwrap();
+            Ok(Some(()))
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            // The server flushes at the start of every tick
+            self.reads = 0;
+            Ok(Some(()))
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        let transport = Flood { next: 0, reads: 0, max: 4, wr: tx };
+
+        let mut server = try!(Server::new(service, transport)).max_reads_per_tick(4);
+        shutdown_tx.send(try!(server.shutdown_handle())).unwrap();
+
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    for _ in 0..10 {
+        rx.recv().unwrap();
+    }
+
+    // The shutdown signal can only be seen if the server yields
+    shutdown_rx.recv().unwrap().shutdown();
+
+    // Wait for the server to complete
+    for _ in rx.iter() {}
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_stops_reading_at_high_water_mark() {
+    // A transport that always has a request ready to read, counting reads
+    struct Endless {
+        reads: Arc<AtomicUsize>,
+        wr: Sender<Msg>,
+    }
+
+    impl Readiness for Endless {
+        fn is_readable(&self) -> bool {
+            true
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    impl Transport for Endless {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            let n = self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(Frame::Message(n as u32)))
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.wr.send(frame).unwrap();
+            Ok(Some(()))
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            Ok(Some(()))
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let reads = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    let (pending_tx, pending_rx) = mpsc::channel();
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+    // Responses are only completed by the test
+    let pending_tx = Mutex::new(pending_tx);
+    let service = simple_service(move |_: u32| {
+        let (c, val) = future::pair::<u32, io::Error>();
+        pending_tx.lock().unwrap().send(c).unwrap();
+        val
+    });
+
+    let transport = Endless { reads: reads.clone(), wr: tx };
+
+    handle.oneshot(move || {
+        let mut server = try!(Server::new(service, transport)).in_flight_water_marks(4, 2);
+        shutdown_tx.send(try!(server.shutdown_handle())).unwrap();
+
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    let mut pending: VecDeque<_> = (0..4).map(|_| pending_rx.recv().unwrap()).collect();
+
+    // No further requests are read while the service is stalled
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(4, reads.load(Ordering::SeqCst));
+
+    // Draining to the low-water mark resumes reading
+    for v in 0..2 {
+        pending.pop_front().unwrap().complete(v);
+        rx.recv().unwrap();
+    }
+
+    pending.extend((0..2).map(|_| pending_rx.recv().unwrap()));
+
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(6, reads.load(Ordering::SeqCst));
+
+    shutdown_rx.recv().unwrap().shutdown();
+
+    // Wait for the server to complete
+    for (v, c) in pending.into_iter().enumerate() {
+        c.complete(v as u32);
+    }
+
+    for _ in rx.iter() {}
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_flushed_waits_for_complete_flush() {
+    // A transport that buffers writes until the test signals the flush to
+    // complete. `false` signals a partial flush.
+    struct SlowFlush {
+        rd: VecDeque<Msg>,
+        wr: Sender<Msg>,
+        flushes: Receiver<bool>,
+        flushed: bool,
+    }
+
+    impl Readiness for SlowFlush {
+        fn is_readable(&self) -> bool {
+            !self.rd.is_empty()
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    impl Transport for SlowFlush {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            Ok(self.rd.pop_front())
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.wr.send(frame).unwrap();
+            self.flushed = false;
+            Ok(None)
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            while let Ok(Some(complete)) = self.flushes.recv() {
+                self.flushed = complete;
+            }
+
+            Ok(if self.flushed { Some(()) } else { None })
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (flush_tx, flush_rx) = channel::channel();
+    let (done_tx, done_rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        let transport = SlowFlush {
+            rd: vec![Frame::Message(1), Frame::Done].into_iter().collect(),
+            wr: tx,
+            flushes: try!(Receiver::watch(flush_rx)),
+            flushed: true,
+        };
+
+        let mut server = try!(Server::new(service, transport));
+
+        server.flushed().then(move |res| {
+            done_tx.send(res.is_ok()).unwrap();
+            Ok::<(), ()>(())
+        }).forget();
+
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    // The response is written, but not flushed
+    rx.recv().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(done_rx.try_recv().is_err());
+
+    flush_tx.send(false).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(done_rx.try_recv().is_err());
+
+    flush_tx.send(true).unwrap();
+    assert!(done_rx.recv().unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_half_duplex_waits_for_flush_before_reading() {
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Read(u32),
+        Write(u32),
+    }
+
+    // A transport reporting reads and writes, buffering writes until the test
+    // signals the flush to complete
+    struct SlowFlush {
+        rd: VecDeque<Msg>,
+        events: Sender<Event>,
+        flushes: Receiver<()>,
+        flushed: bool,
+    }
+
+    impl Readiness for SlowFlush {
+        fn is_readable(&self) -> bool {
+            !self.rd.is_empty()
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    impl Transport for SlowFlush {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            let frame = self.rd.pop_front();
+
+            if let Some(Frame::Message(v)) = frame {
+                self.events.send(Event::Read(v)).unwrap();
+            }
+
+            Ok(frame)
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            if let Frame::Message(v) = frame {
+                self.events.send(Event::Write(v)).unwrap();
+            }
+
+            self.flushed = false;
+            Ok(None)
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            while let Ok(Some(())) = self.flushes.recv() {
+                self.flushed = true;
+            }
+
+            Ok(if self.flushed { Some(()) } else { None })
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let (flush_tx, flush_rx) = channel::channel();
+
+    handle.oneshot(move || {
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        let transport = SlowFlush {
+            rd: vec![Frame::Message(1), Frame::Message(2), Frame::Done].into_iter().collect(),
+            events: tx,
+            flushes: try!(Receiver::watch(flush_rx)),
+            flushed: true,
+        };
+
+        let server = try!(Server::new(service, transport)).half_duplex();
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    assert_eq!(Event::Read(1), rx.recv().unwrap());
+    assert_eq!(Event::Write(1), rx.recv().unwrap());
+
+    // The second request is readable, but the response is not flushed yet
+    thread::sleep(Duration::from_millis(50));
+    assert!(rx.try_recv().is_err());
+
+    flush_tx.send(()).unwrap();
+    assert_eq!(Event::Read(2), rx.recv().unwrap());
+    assert_eq!(Event::Write(2), rx.recv().unwrap());
+
+    flush_tx.send(()).unwrap();
+
+    // The channel closes once the server task completes
+    assert!(rx.recv().is_err());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_writes_ready_responses_as_batch() {
+    // A transport that sends each written batch over a channel
+    struct BatchTransport {
+        rd: VecDeque<Msg>,
+        wr: Sender<Vec<Msg>>,
+    }
+
+    impl Readiness for BatchTransport {
+        fn is_readable(&self) -> bool {
+            !self.rd.is_empty()
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    impl Transport for BatchTransport {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            Ok(self.rd.pop_front())
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.write_batch(vec![frame])
+        }
+
+        fn write_batch(&mut self, frames: Vec<Msg>) -> io::Result<Option<()>> {
+            self.wr.send(frames).unwrap();
+            Ok(Some(()))
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            Ok(Some(()))
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        let mut frames: VecDeque<Msg> = (0..5).map(Frame::Message).collect();
+        frames.push_back(Frame::Done);
+
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        let transport = BatchTransport {
+            rd: frames,
+            wr: tx,
+        };
+
+        let server = try!(Server::new(service, transport));
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    // All the requests are read in a single tick, so the responses are ready
+    // at the same time and written with a single flush.
+    let batches: Vec<Vec<Msg>> = rx.iter().collect();
+    assert_eq!(1, batches.len());
+    assert_eq!(vec![0, 1, 2, 3, 4], messages(&batches[0]));
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_closes_transport() {
+    // Writes `Frame::Done` as its closing frame
+    struct ClosingTransport {
+        rd: VecDeque<Msg>,
+        wr: Sender<Msg>,
+    }
+
+    impl Readiness for ClosingTransport {
+        fn is_readable(&self) -> bool {
+            !self.rd.is_empty()
+        }
+
+        fn is_writable(&self) -> bool {
+            true
+        }
+    }
+
+    impl Transport for ClosingTransport {
+        type In = Msg;
+        type Out = Msg;
+
+        fn read(&mut self) -> io::Result<Option<Msg>> {
+            Ok(self.rd.pop_front())
+        }
+
+        fn write(&mut self, frame: Msg) -> io::Result<Option<()>> {
+            self.wr.send(frame).unwrap();
+            Ok(Some(()))
+        }
+
+        fn flush(&mut self) -> io::Result<Option<()>> {
+            Ok(Some(()))
+        }
+
+        fn close(&mut self) -> io::Result<Option<()>> {
+            self.write(Frame::Done)
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        let frames = vec![Frame::Message(1), Frame::Message(2), Frame::Done];
+
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        let transport = ClosingTransport {
+            rd: frames.into_iter().collect(),
+            wr: tx,
+        };
+
+        let server = try!(Server::new(service, transport));
+        try!(reactor::schedule(server));
+        Ok(())
+    });
+
+    let written: Vec<Msg> = rx.iter().collect();
+    assert_eq!(vec![1, 2], messages(&written));
+
+    // The closing frame is written once, after the last response
+    assert_eq!(3, written.len());
+
+    match written[2] {
+        Frame::Done => {}
+        _ => panic!("expected closing frame"),
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_recovers_transport_after_upgrade() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        // Request 0 upgrades the connection, request 3 belongs to the new
+        // protocol
+        let frames = vec![Frame::Message(1), Frame::Message(0), Frame::Message(3)];
+
+        let service = simple_service(|req: u32| Ok::<u32, io::Error>(req));
+        let transport = ::tokio::util::test::MockTransport::new(frames);
+
+        let mut server = try!(Server::new(service, transport))
+            .upgrade_on(|req: &u32| *req == 0);
+
+        // The responses complete immediately, so ticking the server
+        // directly drives it to completion
+        loop {
+            if let Tick::Final = try!(server.tick()) {
+                break;
+            }
+        }
+
+        let upgraded = server.is_upgraded();
+        let (_, mut transport) = server.into_inner();
+
+        let written = transport.take_written();
+        let next = try!(transport.read());
+
+        tx.send((upgraded, written, next)).unwrap();
+        Ok(())
+    });
+
+    let (upgraded, written, next) = rx.recv().unwrap();
+
+    assert!(upgraded);
+    assert_eq!(vec![1, 0], messages(&written));
+
+    match next {
+        Some(Frame::Message(3)) => {}
+        _ => panic!("expected the request following the upgrade"),
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_shared_service_across_servers() {
+    // Numbers the requests it receives, not `Clone`
+    struct Counter {
+        count: Cell<u32>,
+    }
+
+    impl Service for Counter {
+        type Req = u32;
+        type Resp = u32;
+        type Error = io::Error;
+        type Fut = Finished<u32, io::Error>;
+
+        fn call(&self, _: u32) -> Finished<u32, io::Error> {
+            self.count.set(self.count.get() + 1);
+            futures::finished(self.count.get())
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let service = SharedService::new(Counter { count: Cell::new(0) });
+
+    for _ in 0..2 {
+        let transport = MockTransport {
+            rd: vec![Ok(Frame::Message(0)), Ok(Frame::Message(0)), Ok(Frame::Done)].into_iter().collect(),
+            wr: tx.clone(),
+        };
+
+        let service = service.clone();
+
+        handle.oneshot(move || {
+            let server = try!(Server::new(service, transport));
+            try!(reactor::schedule(server));
+            Ok(())
+        });
+    }
+
+    drop(tx);
+
+    // Both servers call the same counter
+    let written: Vec<Msg> = rx.iter().collect();
+    let mut counts = messages(&written);
+    counts.sort();
+
+    assert_eq!(vec![1, 2, 3, 4], counts);
+
+    handle.shutdown();
+}
+
+// A transport over a TCP socket where every byte is a message
+struct ByteTransport {
+    stream: TcpStream,
+    wr: Vec<u8>,
+}
+
+impl ByteTransport {
+    fn new(stream: TcpStream) -> io::Result<ByteTransport> {
+        Ok(ByteTransport {
+            stream: stream,
+            wr: vec![],
+        })
+    }
+}
+
+impl Readiness for ByteTransport {
+    fn is_readable(&self) -> bool {
+        self.stream.is_readable()
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+impl Transport for ByteTransport {
+    type In = Frame<u8, io::Error>;
+    type Out = Frame<u8, io::Error>;
+
+    fn read(&mut self) -> io::Result<Option<Frame<u8, io::Error>>> {
+        let mut buf = [0; 1];
+
+        match try!(self.stream.try_read(&mut buf)) {
+            Some(0) => Ok(Some(Frame::Eof)),
+            Some(_) => Ok(Some(Frame::Message(buf[0]))),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, frame: Frame<u8, io::Error>) -> io::Result<Option<()>> {
+        if let Frame::Message(b) = frame {
+            self.wr.push(b);
+        }
+
+        self.flush()
+    }
+
+    fn flush(&mut self) -> io::Result<Option<()>> {
+        while !self.wr.is_empty() {
+            match try!(self.stream.try_write(&self.wr)) {
+                Some(n) => {
+                    self.wr.drain(..n);
+                }
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(()))
+    }
+}
+
+#[test]
+fn test_new_task_creates_service_per_connection() {
+    // Responds to every request with the id of the service instance
+    struct Instance {
+        id: u8,
+    }
+
+    impl Service for Instance {
+        type Req = u8;
+        type Resp = u8;
+        type Error = io::Error;
+        type Fut = Finished<u8, io::Error>;
+
+        fn call(&self, _: u8) -> Self::Fut {
+            futures::finished(self.id)
+        }
+    }
+
+    struct NewInstance {
+        next: Arc<AtomicUsize>,
+    }
+
+    impl NewService for NewInstance {
+        type Req = u8;
+        type Resp = u8;
+        type Error = io::Error;
+        type Item = Instance;
+
+        fn new_service(&self) -> io::Result<Instance> {
+            let id = self.next.fetch_add(1, Ordering::Relaxed);
+            Ok(Instance { id: id as u8 })
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let new_service = NewInstance { next: Arc::new(AtomicUsize::new(0)) };
+    let srv = server::listen(&handle,
+                             "127.0.0.1:0".parse().unwrap(),
+                             pipeline::new_task(new_service, ByteTransport::new)).unwrap();
+
+    let mut ids = vec![];
+
+    for _ in 0..2 {
+        let mut sock = net::TcpStream::connect(srv.local_addr()).unwrap();
+        let mut buf = [0; 1];
+
+        // Two requests on the same connection are handled by the same service
+        sock.write_all(b"ab").unwrap();
+        sock.read_exact(&mut buf).unwrap();
+        ids.push(buf[0]);
+        sock.read_exact(&mut buf).unwrap();
+        ids.push(buf[0]);
+    }
+
+    assert_eq!(vec![0, 0, 1, 1], ids);
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_writes_in_flight_responses_after_eof() {
+    // Responds from another thread, after the transport reached EOF
+    struct Delayed;
+
+    impl Service for Delayed {
+        type Req = u32;
+        type Resp = u32;
+        type Error = io::Error;
+        type Fut = future::Val<u32, io::Error>;
+
+        fn call(&self, req: u32) -> Self::Fut {
+            let (c, val) = future::pair();
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                c.complete(req * 10);
+            });
+
+            val
+        }
+    }
+
+    // Frames following the EOF are never read
+    let frames = vec![Frame::Message(1), Frame::Message(2), Frame::Eof, Frame::Message(3)];
+
+    let written = run(frames, |transport| Server::new(Delayed, transport));
+
+    // Both responses are written, without an error frame
+    assert_eq!(2, written.len());
+    assert_eq!(vec![10, 20], messages(&written));
+}
+
+#[test]
+fn test_server_completes_on_half_closed_connection() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let service = simple_service(|req: u8| Ok::<u8, io::Error>(req + 1));
+    let srv = server::listen(&handle,
+                             "127.0.0.1:0".parse().unwrap(),
+                             pipeline::new_task(service, ByteTransport::new)).unwrap();
+
+    let mut sock = net::TcpStream::connect(srv.local_addr()).unwrap();
+
+    sock.write_all(b"ab").unwrap();
+    sock.shutdown(net::Shutdown::Write).unwrap();
+
+    // The server writes the responses, then closes the connection
+    let mut buf = vec![];
+    sock.read_to_end(&mut buf).unwrap();
+
+    assert_eq!(b"bc".to_vec(), buf);
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_serve_round_trips_requests() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let service = simple_service(|req: u8| Ok::<u8, io::Error>(req));
+    let srv = pipeline::serve(&handle, "127.0.0.1:0".parse().unwrap(), service, ByteTransport::new).unwrap();
+
+    let mut sock = net::TcpStream::connect(srv.local_addr()).unwrap();
+    let mut buf = [0; 2];
+
+    sock.write_all(b"hi").unwrap();
+    sock.read_exact(&mut buf).unwrap();
+
+    assert_eq!(b"hi", &buf);
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_server_shuts_down_tcp_write_half_after_last_response() {
+    // Holds on to the server once it completed, keeping the socket open
+    struct Hold<T> {
+        inner: T,
+        done: bool,
+    }
+
+    impl<T: Task> Task for Hold<T> {
+        fn tick(&mut self) -> io::Result<Tick> {
+            if self.done {
+                return Ok(Tick::WouldBlock);
+            }
+
+            match try!(self.inner.tick()) {
+                Tick::Final => {
+                    self.done = true;
+                    Ok(Tick::WouldBlock)
+                }
+                tick => Ok(tick),
+            }
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = srv.local_addr().unwrap();
+
+    handle.oneshot(move || {
+        let stream = try!(TcpStream::connect(&addr));
+        let transport = LengthDelimited::new(Header::U16Be).transport(stream);
+        let service = simple_service(|req: Vec<u8>| Ok::<Vec<u8>, io::Error>(req));
+
+        let server = try!(Server::new(service, transport));
+        try!(reactor::schedule(Hold { inner: server, done: false }));
+        Ok(())
+    });
+
+    let (mut sock, _) = srv.accept().unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // Send the last request, then end the stream
+    sock.write_all(&[0, 2, b'h', b'i']).unwrap();
+    sock.shutdown(net::Shutdown::Write).unwrap();
+
+    // The response is followed by EOF although the server holds the socket
+    let mut buf = vec![];
+    sock.read_to_end(&mut buf).unwrap();
+    assert_eq!(vec![0, 2, b'h', b'i'], buf);
+
+    handle.shutdown();
+}
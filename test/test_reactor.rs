@@ -1,7 +1,11 @@
 use tokio::io::Ready;
-use tokio::reactor::{self, Config, Reactor, Task, Tick};
+use tokio::reactor::{self, Computation, Config, Interval, Reactor, Task, Tick};
+use tokio::util::channel::Receiver;
+use futures::Future;
+use mio::channel;
 use std::io;
 use std::sync::mpsc::{self, Sender};
+use std::time::{Duration, Instant};
 
 #[test]
 fn test_internal_source_state_is_cleaned_up() {
@@ -77,3 +81,392 @@ fn test_returning_error_from_task_terminates() {
 
     handle.shutdown();
 }
+
+#[test]
+fn test_yielding_task_is_ticked_after_other_tasks() {
+    struct Yielder {
+        tx: Sender<&'static str>,
+        yielded: bool,
+    }
+
+    impl Task for Yielder {
+        fn tick(&mut self) -> io::Result<Tick> {
+            self.tx.send("yielder").unwrap();
+
+            if self.yielded {
+                return Ok(Tick::Final);
+            }
+
+            // Schedule another task, then give it a chance to run
+            let tx = self.tx.clone();
+            try!(reactor::schedule(move || tx.send("other").unwrap()));
+
+            self.yielded = true;
+            Ok(Tick::Yield)
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        reactor::schedule(Yielder { tx: tx, yielded: false }).unwrap();
+    });
+
+    assert_eq!("yielder", rx.recv().unwrap());
+    assert_eq!("other", rx.recv().unwrap());
+    assert_eq!("yielder", rx.recv().unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_ready_tasks_are_ticked_round_robin() {
+    // Receives one message per tick, always having more work to do until
+    // the channel is drained
+    struct Busy {
+        id: usize,
+        rx: Receiver<()>,
+        remaining: usize,
+        tx: Sender<usize>,
+    }
+
+    impl Task for Busy {
+        fn tick(&mut self) -> io::Result<Tick> {
+            if let Ok(Some(())) = self.rx.recv() {
+                self.tx.send(self.id).unwrap();
+                self.remaining -= 1;
+
+                if self.remaining == 0 {
+                    return Ok(Tick::Final);
+                }
+            }
+
+            Ok(Tick::WouldBlock)
+        }
+    }
+
+    const TASKS: usize = 8;
+    const MESSAGES: usize = 10;
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        for id in 0..TASKS {
+            let (msg_tx, msg_rx) = channel::channel();
+
+            for _ in 0..MESSAGES {
+                msg_tx.send(()).unwrap();
+            }
+
+            reactor::schedule(Busy {
+                id: id,
+                rx: Receiver::watch(msg_rx).unwrap(),
+                remaining: MESSAGES,
+                tx: tx.clone(),
+            }).unwrap();
+        }
+    });
+
+    let ticks: Vec<usize> = rx.iter().take(TASKS * MESSAGES).collect();
+
+    // Every task is ticked once per round
+    for round in ticks.chunks(TASKS) {
+        let mut round = round.to_vec();
+        round.sort();
+        assert_eq!((0..TASKS).collect::<Vec<_>>(), round);
+    }
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_cancelling_task_drops_it() {
+    // Waits forever, reporting every tick and its drop
+    struct Idle {
+        tx: Sender<&'static str>,
+    }
+
+    impl Task for Idle {
+        fn tick(&mut self) -> io::Result<Tick> {
+            self.tx.send("tick").unwrap();
+            Ok(Tick::WouldBlock)
+        }
+    }
+
+    impl Drop for Idle {
+        fn drop(&mut self) {
+            let _ = self.tx.send("drop");
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    let task = handle.spawn(Idle { tx: tx });
+    assert_eq!("tick", rx.recv().unwrap());
+
+    task.cancel();
+    assert_eq!("drop", rx.recv().unwrap());
+
+    // Cancelling a completed task is a no-op
+    task.cancel();
+
+    let done = handle.spawn(|| Ok::<Tick, io::Error>(Tick::Final));
+    let (tx, rx) = mpsc::channel();
+
+    // Wait for the reactor to process the task
+    handle.oneshot(move || tx.send(()).unwrap());
+    rx.recv().unwrap();
+
+    done.cancel();
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_interval_invokes_callback_every_period() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+
+    handle.oneshot(move || {
+        let mut fired = 0;
+
+        let interval = Interval::new(Duration::from_millis(50), move || {
+            fired += 1;
+            tx.send(fired).unwrap();
+
+            // Stop after the third invocation
+            if fired == 3 {
+                return Err(io::Error::new(io::ErrorKind::Other, "done"));
+            }
+
+            Ok(())
+        });
+
+        reactor::schedule(interval).unwrap();
+    });
+
+    // The channel closes once the interval task is dropped
+    let fired: Vec<u32> = rx.iter().collect();
+
+    assert_eq!(vec![1, 2, 3], fired);
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_drain_drops_tasks_that_do_not_complete() {
+    // Completes once the reactor is draining, unless misbehaving
+    struct Worker {
+        name: &'static str,
+        graceful: bool,
+        tx: Sender<&'static str>,
+    }
+
+    impl Task for Worker {
+        fn tick(&mut self) -> io::Result<Tick> {
+            if self.graceful && try!(reactor::is_draining()) {
+                return Ok(Tick::Final);
+            }
+
+            Ok(Tick::WouldBlock)
+        }
+    }
+
+    impl Drop for Worker {
+        fn drop(&mut self) {
+            let _ = self.tx.send(self.name);
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    handle.schedule(Worker { name: "graceful", graceful: true, tx: tx.clone() });
+    handle.schedule(Worker { name: "stuck", graceful: false, tx: tx });
+
+    let start = Instant::now();
+    handle.drain(Duration::from_millis(100));
+
+    // The well behaved task completes right away, the other one is dropped
+    // at the deadline
+    assert_eq!("graceful", rx.recv().unwrap());
+    assert_eq!("stuck", rx.recv().unwrap());
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn test_compute_resolves_to_task_value() {
+    // Sums the numbers up to `n`, one per tick
+    struct Sum {
+        n: u32,
+        i: u32,
+        sum: u32,
+    }
+
+    impl Task for Sum {
+        fn tick(&mut self) -> io::Result<Tick> {
+            if self.i == self.n {
+                return Ok(Tick::Final);
+            }
+
+            self.i += 1;
+            self.sum += self.i;
+
+            Ok(Tick::Yield)
+        }
+    }
+
+    impl Computation for Sum {
+        type Item = u32;
+
+        fn take_value(&mut self) -> Option<u32> {
+            Some(self.sum)
+        }
+    }
+
+    // Never completes
+    struct Stuck;
+
+    impl Task for Stuck {
+        fn tick(&mut self) -> io::Result<Tick> {
+            Ok(Tick::WouldBlock)
+        }
+    }
+
+    impl Computation for Stuck {
+        type Item = u32;
+
+        fn take_value(&mut self) -> Option<u32> {
+            None
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    for task in vec![handle.compute(Sum { n: 10, i: 0, sum: 0 }), handle.compute(Stuck)] {
+        let tx = tx.clone();
+
+        task.then(move |res| {
+            tx.send(res.map_err(|e| e.kind())).unwrap();
+            Ok::<(), ()>(())
+        }).forget();
+    }
+
+    assert_eq!(Ok(55), rx.recv().unwrap());
+
+    // Draining the reactor drops the stuck task
+    handle.drain(Duration::from_millis(10));
+    assert_eq!(Err(io::ErrorKind::Other), rx.recv().unwrap());
+}
+
+#[test]
+fn test_stats_count_spawned_and_completed_tasks() {
+    // Yields a few times before completing
+    struct Countdown {
+        n: usize,
+        tx: Sender<()>,
+    }
+
+    impl Task for Countdown {
+        fn tick(&mut self) -> io::Result<Tick> {
+            if self.n == 0 {
+                self.tx.send(()).unwrap();
+                return Ok(Tick::Final);
+            }
+
+            self.n -= 1;
+            Ok(Tick::Yield)
+        }
+    }
+
+    // Never completes
+    struct Stuck;
+
+    impl Task for Stuck {
+        fn tick(&mut self) -> io::Result<Tick> {
+            Ok(Tick::WouldBlock)
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+
+    for n in 0..3 {
+        handle.schedule(Countdown { n: n, tx: tx.clone() });
+    }
+
+    handle.schedule(Stuck);
+
+    for _ in 0..3 {
+        rx.recv().unwrap();
+    }
+
+    // Wait for the reactor to be done with the completed tasks
+    handle.oneshot(move || tx.send(()).unwrap());
+    rx.recv().unwrap();
+
+    let stats = handle.stats();
+
+    assert_eq!(4, stats.spawned);
+    assert_eq!(3, stats.completed);
+    assert_eq!(1, stats.live);
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_schedules_more_tasks_than_initial_capacity() {
+    // Never completes
+    struct Stuck;
+
+    impl Task for Stuck {
+        fn tick(&mut self) -> io::Result<Tick> {
+            Ok(Tick::WouldBlock)
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    // The task slab starts out with room for 100 tasks
+    for _ in 0..250 {
+        handle.schedule(Stuck);
+    }
+
+    let (tx, rx) = mpsc::channel();
+
+    handle.oneshot(move || tx.send(()).unwrap());
+    rx.recv().unwrap();
+
+    assert_eq!(250, handle.stats().live);
+
+    handle.shutdown();
+}
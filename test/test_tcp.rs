@@ -0,0 +1,233 @@
+use tokio::io::TryRead;
+use tokio::reactor::{self, Reactor, Task, Tick};
+use tokio::server::{self, Acceptor, Upgrade};
+use tokio::tcp::{ListenerConfig, TcpConfig, TcpListener, TcpStream};
+use futures::Poll;
+use std::io;
+use std::net;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_accepted_streams_use_config() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let config = TcpConfig::new().nodelay(true);
+
+    let srv = server::Builder::new().tcp_config(config).listen(&handle, "127.0.0.1:0".parse().unwrap(), move |stream: TcpStream| {
+        tx.lock().unwrap().send(stream.nodelay().unwrap()).unwrap();
+        Ok(|| ())
+    }).unwrap();
+
+    let _sock = net::TcpStream::connect(srv.local_addr()).unwrap();
+    assert!(rx.recv().unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_accepted_streams_use_buffer_sizes() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let config = TcpConfig::new().send_buffer_size(256 * 1024).recv_buffer_size(128 * 1024);
+
+    let srv = server::Builder::new().tcp_config(config).listen(&handle, "127.0.0.1:0".parse().unwrap(), move |stream: TcpStream| {
+        let sizes = (stream.send_buffer_size().unwrap(), stream.recv_buffer_size().unwrap());
+        tx.lock().unwrap().send(sizes).unwrap();
+        Ok(|| ())
+    }).unwrap();
+
+    let _sock = net::TcpStream::connect(srv.local_addr()).unwrap();
+
+    // The OS may round the sizes up, for example Linux doubles them to
+    // account for bookkeeping overhead
+    let (send, recv) = rx.recv().unwrap();
+    assert!(send >= 256 * 1024);
+    assert!(recv >= 128 * 1024);
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_accepted_streams_know_their_peer_addr() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let srv = server::listen(&handle, "127.0.0.1:0".parse().unwrap(), move |stream: TcpStream| {
+        tx.lock().unwrap().send(stream.peer_addr().unwrap()).unwrap();
+        Ok(|| ())
+    }).unwrap();
+
+    let sock = net::TcpStream::connect(srv.local_addr()).unwrap();
+    assert_eq!(sock.local_addr().unwrap(), rx.recv().unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_accepted_streams_are_upgraded() {
+    // Yields the stream as is
+    struct Identity(Option<TcpStream>);
+
+    impl Upgrade for Identity {
+        type Io = TcpStream;
+
+        fn poll_upgrade(&mut self) -> io::Result<Option<TcpStream>> {
+            Ok(self.0.take())
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let upgrade = |stream: TcpStream| Ok::<_, io::Error>(Identity(Some(stream)));
+
+    // The socket options are applied before the upgrade, and the upgraded
+    // connection counts towards the limit
+    let builder = server::Builder::new()
+        .tcp_config(TcpConfig::new().nodelay(true))
+        .max_connections(1)
+        .upgrade(upgrade);
+
+    let srv = builder.listen(&handle, "127.0.0.1:0".parse().unwrap(), move |stream: TcpStream| {
+        tx.lock().unwrap().send((stream.local_addr().unwrap(), stream.nodelay().unwrap())).unwrap();
+        Ok(|| ())
+    }).unwrap();
+
+    let sock = net::TcpStream::connect(srv.local_addr()).unwrap();
+    assert_eq!((sock.peer_addr().unwrap(), true), rx.recv().unwrap());
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_max_connections_defers_accept() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let srv = server::Builder::new().max_connections(1).listen(&handle, "127.0.0.1:0".parse().unwrap(), move |mut stream: TcpStream| {
+        tx.lock().unwrap().send(()).unwrap();
+
+        // Keep the connection open until the peer closes it
+        Ok(move || -> io::Result<Tick> {
+            let mut buf = [0; 64];
+
+            loop {
+                match try!(stream.try_read(&mut buf)) {
+                    Some(0) => return Ok(Tick::Final),
+                    Some(_) => {}
+                    None => return Ok(Tick::WouldBlock),
+                }
+            }
+        })
+    }).unwrap();
+
+    let first = net::TcpStream::connect(srv.local_addr()).unwrap();
+    rx.recv().unwrap();
+
+    // The second connection waits until the first one closes
+    let _second = net::TcpStream::connect(srv.local_addr()).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(rx.try_recv().is_err());
+
+    drop(first);
+    rx.recv().unwrap();
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_listener_rebinds_immediately_with_reuse_address() {
+    let config = ListenerConfig::new().reuse_address(true);
+
+    let listener = config.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let _sock = net::TcpStream::connect(&addr).unwrap();
+
+    let mut accepted = listener.accept().unwrap();
+
+    while accepted.is_none() {
+        thread::sleep(Duration::from_millis(10));
+        accepted = listener.accept().unwrap();
+    }
+
+    // Closing the connection on the server side leaves the address in
+    // TIME_WAIT
+    drop(accepted);
+    drop(listener);
+
+    assert!(config.bind(&addr).is_ok());
+}
+
+#[test]
+fn test_acceptor_is_polled_by_task() {
+    // Accepts a single connection, reporting the peer address
+    struct AcceptOne {
+        acceptor: Acceptor,
+        tx: mpsc::Sender<net::SocketAddr>,
+    }
+
+    impl Task for AcceptOne {
+        fn tick(&mut self) -> io::Result<Tick> {
+            match self.acceptor.poll_accept() {
+                Poll::Ok((_, addr)) => {
+                    self.tx.send(addr).unwrap();
+                    Ok(Tick::Final)
+                }
+                Poll::NotReady => Ok(Tick::WouldBlock),
+                Poll::Err(e) => Err(e),
+            }
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (addr_tx, addr_rx) = mpsc::channel();
+    let (tx, rx) = mpsc::channel();
+
+    handle.oneshot(move || {
+        let socket = try!(TcpListener::bind(&"127.0.0.1:0".parse().unwrap()));
+        let acceptor = Acceptor::new(socket, TcpConfig::default());
+
+        addr_tx.send(try!(acceptor.local_addr())).unwrap();
+
+        try!(reactor::schedule(AcceptOne {
+            acceptor: acceptor,
+            tx: tx,
+        }));
+
+        Ok(())
+    });
+
+    let sock = net::TcpStream::connect(addr_rx.recv().unwrap()).unwrap();
+    assert_eq!(sock.local_addr().unwrap(), rx.recv().unwrap());
+
+    handle.shutdown();
+}
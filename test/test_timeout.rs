@@ -0,0 +1,86 @@
+use futures::Future;
+use tokio::reactor::{self, Reactor, Task, Tick};
+use tokio::util::future;
+use tokio::util::timeout::{TimeoutService, TimeoutTask};
+use tokio::{Service, simple_service};
+use std::io;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_timeout_task_terminates_stalled_task() {
+    // Never makes any progress
+    struct Stalled {
+        tx: Sender<()>,
+    }
+
+    impl Task for Stalled {
+        fn tick(&mut self) -> io::Result<Tick> {
+            Ok(Tick::WouldBlock)
+        }
+    }
+
+    impl Drop for Stalled {
+        fn drop(&mut self) {
+            let _ = self.tx.send(());
+        }
+    }
+
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+
+    handle.oneshot(move || {
+        let task = TimeoutTask::new(Stalled { tx: tx }, Duration::from_millis(50));
+        reactor::schedule(task).unwrap();
+    });
+
+    // The task is dropped once timed out
+    rx.recv().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_timeout_service_fails_slow_calls() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    // Responses are never completed
+    let service = simple_service(move |_: u32| {
+        let (c, val) = future::pair::<u32, io::Error>();
+        tx.lock().unwrap().send(c).unwrap();
+        val
+    });
+
+    let service = TimeoutService::new(&handle, service, Duration::from_millis(50));
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let start = Instant::now();
+
+    service.call(1).then(move |res| {
+        done_tx.send(res).unwrap();
+        Ok::<(), ()>(())
+    }).forget();
+
+    match done_rx.recv().unwrap() {
+        Err(e) => assert_eq!(io::ErrorKind::TimedOut, e.kind()),
+        Ok(_) => panic!("expected call to time out"),
+    }
+
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // The inner response future was dropped
+    assert!(rx.recv().unwrap().is_cancelled());
+
+    handle.shutdown();
+}
@@ -0,0 +1,42 @@
+use tokio::reactor::{Reactor, Task, Tick};
+use tokio::server;
+use tokio::udp::UdpSocket;
+use std::io;
+use std::net;
+
+struct Echo {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+}
+
+impl Task for Echo {
+    fn tick(&mut self) -> io::Result<Tick> {
+        while let Some((n, addr)) = try!(self.socket.recv_from(&mut self.buf)) {
+            try!(self.socket.send_to(&self.buf[..n], &addr));
+        }
+
+        Ok(Tick::WouldBlock)
+    }
+}
+
+#[test]
+fn test_udp_echo_round_trip() {
+    let reactor = Reactor::default().unwrap();
+    let handle = reactor.handle();
+    reactor.spawn();
+
+    let srv = server::listen_udp(&handle, "127.0.0.1:0".parse().unwrap(), |socket| {
+        Ok(Echo { socket: socket, buf: vec![0; 1024] })
+    }).unwrap();
+
+    let sock = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.send_to(b"hello", srv.local_addr()).unwrap();
+
+    let mut buf = [0; 1024];
+    let (n, from) = sock.recv_from(&mut buf).unwrap();
+
+    assert_eq!(b"hello", &buf[..n]);
+    assert_eq!(*srv.local_addr(), from);
+
+    handle.shutdown();
+}